@@ -0,0 +1,86 @@
+//! Benchmarks for loading a story and for the paths every request goes through: rendering a
+//! session's current node and taking a choice. Run with `cargo bench`.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use cyoa::Engine;
+use std::hint::black_box;
+
+/// The source of a story with many variables, where each node interpolates some of them, has a
+/// choice with a requirement and a choice that sets a variable.
+fn source(variables: usize, nodes: usize) -> String {
+    let mut source = String::new();
+    for i in 0..variables {
+        source.push_str(&format!("SET var{i} {i}\n"));
+    }
+    source.push_str("SET name \"Ann\"\n");
+    for i in 0..nodes {
+        let id = if i == 0 {
+            "START".to_string()
+        } else {
+            format!("node{i}")
+        };
+        let next = if i + 1 == nodes {
+            "START".to_string()
+        } else {
+            format!("node{}", i + 1)
+        };
+        let var = i % variables;
+        source.push_str(&format!(
+            "\n= {id}\n    \"{{name}} stands in room {i}. The counter reads {{var{var}}}.\"\n    \
+             [IF var{var} > 0] \"Walk on, {{name}}.\" -> {next} [THEN var{var} = {}]\n    \
+             \"Take the stairs.\" -> {next}\n",
+            i + 1
+        ));
+    }
+
+    source
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("from_program");
+    for nodes in [100, 1000, 10000] {
+        let source = source(200, nodes);
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(nodes), &source, |b, source| {
+            b.iter(|| Engine::from_program(source).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_engine(c: &mut Criterion) {
+    let story = Engine::from_program(&source(200, 50)).unwrap();
+
+    c.bench_function("new_session", |b| b.iter(|| black_box(story.new_session())));
+
+    let session = story.new_session();
+    c.bench_function("get_current_node_view", |b| {
+        b.iter(|| black_box(story.get_current_node_view(&session)))
+    });
+
+    let fixed = Engine::from_program(
+        r#"
+        = START
+            "A long corridor stretches away into the dark, lit by a single flickering lamp."
+            "Walk towards the light." -> START
+            "Turn back the way you came." -> START
+            "Wait for your eyes to adjust." -> START
+        "#,
+    )
+    .unwrap();
+    let fixed_session = fixed.new_session();
+    c.bench_function("get_current_node_view_static", |b| {
+        b.iter(|| black_box(fixed.get_current_node_view(&fixed_session)))
+    });
+
+    c.bench_function("choose_option", |b| {
+        b.iter_batched_ref(
+            || story.new_session(),
+            |session| story.choose_option(session, "node1".to_string()),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_parse, bench_engine);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+fn main() {
+    println!("cargo:rerun-if-changed=proto/cyoa.proto");
+    // Only the `grpc` feature needs the generated code, so protoc isn't run otherwise.
+    #[cfg(feature = "grpc")]
+    {
+        let mut config = tonic_prost_build::Config::new();
+        config.protoc_executable(
+            protoc_bin_vendored::protoc_bin_path().expect("No protoc for this platform"),
+        );
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_with_config(config, &["proto/cyoa.proto"], &["proto"])
+            .expect("Failed to compile proto/cyoa.proto");
+    }
+    // The C header is generated from the functions it declares, so it can't fall out of date.
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let config =
+            cbindgen::Config::from_file("cbindgen.toml").expect("Failed to read cbindgen.toml");
+        cbindgen::generate_with_config(&crate_dir, config)
+            .expect("Failed to generate include/cyoa.h")
+            .write_to_file("include/cyoa.h");
+    }
+}
@@ -0,0 +1,98 @@
+#ifndef CYOA_H
+#define CYOA_H
+
+/* This file is generated by cbindgen from src/ffi.rs. Do not edit it by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Shared, immutable story data, referenced by every session playing the story.
+ */
+typedef struct Engine Engine;
+
+/**
+ * Per-session mutable game state.
+ */
+typedef struct Session Session;
+
+/**
+ * Load a story from the source of a `.cyoa` file.
+ *
+ * Returns null if the source is invalid UTF-8 or the story has errors, or if loading it
+ * panicked. In that case, if `error_out` is not null, it is set to a newly allocated
+ * description of the errors.
+ *
+ * # Safety
+ *
+ * `source` must be a valid nul-terminated string. `error_out` must be null or valid for writes.
+ */
+struct Engine *cyoa_engine_create_from_source(const char *source,
+                                              char **error_out);
+
+/**
+ * Free an engine created by [`cyoa_engine_create_from_source`].
+ *
+ * # Safety
+ *
+ * `engine` must be null or a pointer returned by [`cyoa_engine_create_from_source`] that
+ * hasn't been freed yet. Sessions created from it must not be used afterwards.
+ */
+void cyoa_engine_free(struct Engine *engine);
+
+/**
+ * Start a new session at the beginning of the story. Returns null if that panicked.
+ *
+ * # Safety
+ *
+ * `engine` must be a valid engine pointer.
+ */
+struct Session *cyoa_session_new(const struct Engine *engine);
+
+/**
+ * Free a session created by [`cyoa_session_new`].
+ *
+ * # Safety
+ *
+ * `session` must be null or a pointer returned by [`cyoa_session_new`] that hasn't been freed yet.
+ */
+void cyoa_session_free(struct Session *session);
+
+/**
+ * The session's current node as JSON, in the same format as the server's `/current`
+ * endpoint. The returned string must be freed with [`cyoa_string_free`]. Returns null if
+ * that panicked.
+ *
+ * # Safety
+ *
+ * `engine` must be a valid engine pointer and `session` a valid session created from it.
+ */
+char *cyoa_session_current_json(const struct Engine *engine,
+                                const struct Session *session);
+
+/**
+ * Take the choice with the given id. Returns false if the choice isn't available, or if
+ * taking it panicked.
+ *
+ * # Safety
+ *
+ * `engine` must be a valid engine pointer, `session` a valid session created from it, and
+ * `choice_id` a valid nul-terminated string.
+ */
+bool cyoa_session_choose(const struct Engine *engine,
+                         struct Session *session,
+                         const char *choice_id);
+
+/**
+ * Free a string returned by this library.
+ *
+ * # Safety
+ *
+ * `s` must be null or a string returned by this library that hasn't been freed yet.
+ */
+void cyoa_string_free(char *s);
+
+#endif  /* CYOA_H */
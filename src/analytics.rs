@@ -0,0 +1,165 @@
+//! How each story has been played since the server started, served as JSON at
+//! `/admin/analytics` so authors can see where players stop and which choices nobody takes.
+
+use crate::{ServerState, metrics::Metrics};
+use axum::{Json, extract::State};
+use cyoa::{Engine, engine::DEFAULT_VARIANT};
+use serde::Serialize;
+use std::{iter, sync::Arc};
+use utoipa::ToSchema;
+
+#[derive(Serialize, ToSchema)]
+pub struct StoryAnalytics {
+    story_id: String,
+    /// How many times sessions have arrived at a node with no choices
+    endings_reached: u64,
+    /// Every node in the story, in order of id, including ones nobody has visited
+    nodes: Vec<NodeAnalytics>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NodeAnalytics {
+    node_id: String,
+    /// How many times sessions have arrived at the node
+    visits: u64,
+    /// Whether the node has no choices, so arriving at it ends the story
+    ending: bool,
+    /// How many visits weren't followed by a choice here. Undoing a choice or restarting also
+    /// counts, so this is only a rough count of players who stopped playing at the node
+    left_without_choosing: u64,
+    choices: Vec<ChoiceAnalytics>,
+    /// For nodes with `[VARIANT ...]` lines, how each phrasing of the narration has done,
+    /// starting with the main one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    variants: Vec<VariantAnalytics>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChoiceAnalytics {
+    /// The id of the choice, which is the id of the node it leads to
+    choice_id: String,
+    /// How many times the choice has been taken, directly or by a vote
+    taken: u64,
+    /// For choices with an `[IF ...]` requirement, how often it was met
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requirement: Option<RequirementAnalytics>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RequirementAnalytics {
+    /// How many sessions arriving at the node were offered the choice
+    met: u64,
+    /// How many sessions arriving at the node had the choice hidden from them
+    unmet: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct VariantAnalytics {
+    variant: String,
+    /// How many times sessions arriving at the node were shown this variant
+    shown: u64,
+    /// How many of those sessions went on to take a choice at the node
+    choices_taken: u64,
+}
+
+/// The counts for one story, laid over its current nodes. Counts for nodes a reload removed
+/// are left out.
+fn story_analytics(story_id: &str, story: &Engine, metrics: &Metrics) -> StoryAnalytics {
+    let node_visits = metrics.node_visits(story_id);
+    let choices_taken = metrics.choices_taken(story_id);
+    let variant_counts = metrics.variant_counts(story_id);
+    let requirement_counts = metrics.requirement_counts(story_id);
+
+    let mut nodes: Vec<NodeAnalytics> = story
+        .nodes()
+        .map(|(node_id, node)| {
+            let visits = node_visits.get(node_id).copied().unwrap_or(0);
+            let choices: Vec<ChoiceAnalytics> = node
+                .choices
+                .iter()
+                .map(|choice| {
+                    let key = (node_id.to_string(), choice.next_node_id.clone());
+                    ChoiceAnalytics {
+                        choice_id: choice.next_node_id.clone(),
+                        taken: choices_taken.get(&key).copied().unwrap_or(0),
+                        requirement: choice.requirement.as_ref().map(|_| {
+                            let counts = requirement_counts.get(&key).copied().unwrap_or_default();
+                            RequirementAnalytics {
+                                met: counts.met,
+                                unmet: counts.unmet,
+                            }
+                        }),
+                    }
+                })
+                .collect();
+            let taken: u64 = choices.iter().map(|choice| choice.taken).sum();
+            let variants = if node.variants.is_empty() {
+                Vec::new()
+            } else {
+                iter::once(DEFAULT_VARIANT)
+                    .chain(
+                        node.variants
+                            .iter()
+                            .map(|variant| variant.name.as_str())
+                            .filter(|name| *name != DEFAULT_VARIANT),
+                    )
+                    .map(|variant| {
+                        let counts = variant_counts
+                            .get(&(node_id.to_string(), variant.to_string()))
+                            .copied()
+                            .unwrap_or_default();
+                        VariantAnalytics {
+                            variant: variant.to_string(),
+                            shown: counts.shown,
+                            choices_taken: counts.choices_taken,
+                        }
+                    })
+                    .collect()
+            };
+
+            NodeAnalytics {
+                node_id: node_id.to_string(),
+                visits,
+                ending: choices.is_empty(),
+                left_without_choosing: if choices.is_empty() {
+                    0
+                } else {
+                    visits.saturating_sub(taken)
+                },
+                choices,
+                variants,
+            }
+        })
+        .collect();
+    nodes.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+
+    StoryAnalytics {
+        story_id: story_id.to_string(),
+        endings_reached: nodes
+            .iter()
+            .filter(|node| node.ending)
+            .map(|node| node.visits)
+            .sum(),
+        nodes,
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/analytics",
+    tag = "stories",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "How each story has been played since the server started", body = Vec<StoryAnalytics>),
+        (status = 401, description = "With `--api-key`, the API key is missing or wrong", body = crate::ErrorResponse),
+    ),
+)]
+pub async fn get_analytics(State(state): State<Arc<ServerState>>) -> Json<Vec<StoryAnalytics>> {
+    Json(
+        state
+            .stories
+            .iter()
+            .map(|story| story_analytics(&story.story_id, &story.story(), &state.metrics))
+            .collect(),
+    )
+}
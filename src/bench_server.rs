@@ -0,0 +1,255 @@
+//! Load testing a running server with simulated players, to see how the engine and the request
+//! handlers hold up under many sessions at once.
+
+use crate::walk::Rng;
+use reqwest::{Client, RequestBuilder, Url};
+use serde::Deserialize;
+use std::{
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+#[derive(clap::Args, Debug)]
+pub struct BenchServerArgs {
+    /// The server to test, e.g. `http://127.0.0.1:8080`, including any `--prefix`
+    url: Url,
+    /// How many players to simulate at once
+    #[arg(long, default_value_t = 50)]
+    players: usize,
+    /// How long to keep playing for, in seconds
+    #[arg(long, default_value_t = 10)]
+    duration_secs: u64,
+    /// The story to play, when the server serves more than one
+    #[arg(long)]
+    story: Option<String>,
+    /// Seed for choosing at random, to repeat a previous run. Defaults to the current time.
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct NewSession {
+    session_id: String,
+    session_token: String,
+}
+
+/// The parts of `/current` a player needs to take a choice.
+#[derive(Deserialize)]
+struct View {
+    choices: Vec<ChoiceId>,
+    game_over: bool,
+}
+
+#[derive(Deserialize)]
+struct ChoiceId {
+    id: String,
+}
+
+/// The kinds of request players make.
+#[derive(Clone, Copy)]
+enum Endpoint {
+    Create,
+    Current,
+    Choose,
+    Delete,
+}
+
+/// The names of each [`Endpoint`], in the order they are reported.
+const ENDPOINTS: [&str; 4] = ["create", "current", "choose", "delete"];
+
+/// How long each request of one kind took, and how many failed.
+#[derive(Default)]
+struct Timings {
+    durations: Vec<Duration>,
+    failures: usize,
+}
+
+#[derive(Default)]
+struct Results {
+    timings: [Timings; ENDPOINTS.len()],
+    playthroughs: usize,
+}
+
+/// What a player sends requests with.
+struct Player {
+    client: Client,
+    base: String,
+    results: Arc<Mutex<Results>>,
+}
+
+impl Player {
+    /// Send a request and record how long it took. Returns the body if it succeeded.
+    async fn send(
+        &self,
+        endpoint: Endpoint,
+        request: RequestBuilder,
+        token: Option<&str>,
+    ) -> Option<String> {
+        let request = match token {
+            Some(token) => request.header("X-Session-Token", token),
+            None => request,
+        };
+        let start = Instant::now();
+        let response = request.send().await;
+        let body = match response {
+            Ok(response) if response.status().is_success() => response.text().await.ok(),
+            _ => None,
+        };
+        let elapsed = start.elapsed();
+
+        let mut results = self.results.lock().await;
+        let timings = &mut results.timings[endpoint as usize];
+        timings.durations.push(elapsed);
+        if body.is_none() {
+            timings.failures += 1;
+        }
+        body
+    }
+
+    /// Play through the story from the start, taking random choices until it ends or time is
+    /// up.
+    async fn play(&self, rng: &mut Rng, deadline: Instant) {
+        let Some(session) = self
+            .send(
+                Endpoint::Create,
+                self.client.post(format!("{}/session", self.base)),
+                None,
+            )
+            .await
+            .and_then(|body| serde_json::from_str::<NewSession>(&body).ok())
+        else {
+            return;
+        };
+        let session_url = format!("{}/session/{}", self.base, session.session_id);
+        let token = Some(session.session_token.as_str());
+
+        while Instant::now() < deadline {
+            let Some(view) = self
+                .send(
+                    Endpoint::Current,
+                    self.client.get(format!("{session_url}/current")),
+                    token,
+                )
+                .await
+                .and_then(|body| serde_json::from_str::<View>(&body).ok())
+            else {
+                break;
+            };
+            if view.game_over || view.choices.is_empty() {
+                self.results.lock().await.playthroughs += 1;
+                break;
+            }
+
+            let choice = &view.choices[rng.below(view.choices.len())];
+            let url = format!("{session_url}/choose/{}", choice.id);
+            if self
+                .send(Endpoint::Choose, self.client.post(url), token)
+                .await
+                .is_none()
+            {
+                break;
+            }
+        }
+
+        self.send(Endpoint::Delete, self.client.delete(&session_url), token)
+            .await;
+    }
+}
+
+/// The duration below which the given fraction of `sorted` fall.
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    let index = ((sorted.len() as f64 * fraction).ceil() as usize).saturating_sub(1);
+    sorted[index.min(sorted.len() - 1)]
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// Have many simulated players play through a story on a running server at the same time,
+/// then report how quickly it answered.
+pub async fn run(args: BenchServerArgs) -> ExitCode {
+    crate::tls::install_crypto_provider();
+    let client = match Client::builder().timeout(Duration::from_secs(30)).build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to set up the HTTP client: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut base = args.url.as_str().trim_end_matches('/').to_string();
+    if let Some(story) = &args.story {
+        base = format!("{base}/stories/{story}");
+    }
+
+    let seed = args.seed.unwrap_or_else(Rng::seed_from_time);
+    let duration = Duration::from_secs(args.duration_secs);
+    println!(
+        "Playing with {} players for {}s (seed {seed})",
+        args.players, args.duration_secs
+    );
+
+    let results = Arc::new(Mutex::new(Results::default()));
+    let start = Instant::now();
+    let deadline = start + duration;
+    let players: Vec<_> = (0..args.players)
+        .map(|index| {
+            let player = Player {
+                client: client.clone(),
+                base: base.clone(),
+                results: Arc::clone(&results),
+            };
+            let mut rng = Rng::new(seed.wrapping_add(index as u64));
+            tokio::spawn(async move {
+                while Instant::now() < deadline {
+                    player.play(&mut rng, deadline).await;
+                }
+            })
+        })
+        .collect();
+    for player in players {
+        let _ = player.await;
+    }
+    let elapsed = start.elapsed();
+
+    let mut results = results.lock().await;
+    let total: usize = results.timings.iter().map(|t| t.durations.len()).sum();
+    let failures: usize = results.timings.iter().map(|t| t.failures).sum();
+    println!(
+        "{total} requests in {:.1}s ({:.0}/s), {failures} failed, {} playthroughs finished",
+        elapsed.as_secs_f64(),
+        total as f64 / elapsed.as_secs_f64(),
+        results.playthroughs,
+    );
+    println!(
+        "{:<10} {:>9} {:>7} {:>10} {:>10} {:>10} {:>10}",
+        "request", "count", "failed", "p50", "p95", "p99", "max"
+    );
+    for (name, timings) in ENDPOINTS.iter().zip(&mut results.timings) {
+        if timings.durations.is_empty() {
+            continue;
+        }
+        timings.durations.sort_unstable();
+        let durations = &timings.durations;
+        println!(
+            "{name:<10} {:>9} {:>7} {:>10} {:>10} {:>10} {:>10}",
+            durations.len(),
+            timings.failures,
+            format_duration(percentile(durations, 0.5)),
+            format_duration(percentile(durations, 0.95)),
+            format_duration(percentile(durations, 0.99)),
+            format_duration(*durations.last().unwrap()),
+        );
+    }
+
+    if total == 0 || failures == total {
+        eprintln!(
+            "No request succeeded. Is the server running at '{}'?",
+            args.url
+        );
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,190 @@
+//! Assembling stories in code, e.g. from a procedural generator, without writing `.cyoa` source.
+//!
+//! ```
+//! use cyoa::{ChoiceResult, StoryBuilder, engine::parser::Expression};
+//!
+//! let story = StoryBuilder::new()
+//!     .variable("torches", 0)
+//!     .node("START", |node| {
+//!         node.text("You are at the mouth of a cave.")
+//!             .choice(|choice| choice.text("Take a torch.").to("START").set("torches", 1))
+//!             .choice(|choice| {
+//!                 choice
+//!                     .text("Go in.")
+//!                     .to("CAVE")
+//!                     .require(Expression::name("torches").greater_than(0))
+//!             })
+//!     })
+//!     .node("CAVE", |node| node.text("You have {torches} torch."))
+//!     .build()
+//!     .unwrap();
+//!
+//! let mut session = story.new_session();
+//! assert_eq!(story.get_current_node_view(&session).choices.len(), 1);
+//! story.choose_option(&mut session, "START".to_string());
+//! let result = story.choose_option(&mut session, "CAVE".to_string());
+//! assert!(matches!(result, ChoiceResult::Success));
+//! assert_eq!(story.get_current_node_view(&session).display_text, "You have 1 torch.");
+//! ```
+
+use crate::{
+    Engine, ParseError,
+    engine::parser::{
+        Choice, Command, Expression, FormatString, Node, ProgramPart, TextVariant, Value,
+    },
+};
+
+/// Builds a story one definition at a time. Nothing is checked until [`StoryBuilder::build`],
+/// which reports the same errors as loading the equivalent `.cyoa` source.
+#[derive(Debug, Clone, Default)]
+pub struct StoryBuilder {
+    parts: Vec<ProgramPart>,
+}
+
+impl StoryBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Define a variable and the value every session starts with, like `SET name value`.
+    pub fn variable(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.parts.push(ProgramPart::VariableDefinition {
+            name: name.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Add a node, like `= id`. Text given to the node and its choices can interpolate
+    /// variables with `{name}`.
+    pub fn node(
+        mut self,
+        id: impl Into<String>,
+        build: impl FnOnce(NodeBuilder) -> NodeBuilder,
+    ) -> Self {
+        let node = build(NodeBuilder {
+            node: Node {
+                display_text: FormatString(Vec::new()),
+                variants: Vec::new(),
+                choices: Vec::new(),
+            },
+        })
+        .node;
+        self.parts.push(ProgramPart::NodeDefinition {
+            id: id.into(),
+            node,
+        });
+        self
+    }
+
+    /// Send sessions saved at `old_id` to `new_id`, like `RENAMED old_id -> new_id`.
+    pub fn rename(mut self, old_id: impl Into<String>, new_id: impl Into<String>) -> Self {
+        self.parts.push(ProgramPart::NodeRename {
+            old_id: old_id.into(),
+            new_id: new_id.into(),
+        });
+        self
+    }
+
+    /// Set a piece of metadata, like `META key "value"`.
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.parts.push(ProgramPart::Metadata {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// The definitions added so far, e.g. to print as `.cyoa` source with
+    /// [`print_program`](crate::engine::printer::print_program).
+    pub fn parts(&self) -> &[ProgramPart] {
+        &self.parts
+    }
+
+    /// Check the story for errors and load it.
+    pub fn build(self) -> Result<Engine, Vec<ParseError>> {
+        Engine::from_parts(&self.parts)
+    }
+}
+
+/// Builds one node of a story, passed to [`StoryBuilder::node`].
+#[derive(Debug, Clone)]
+pub struct NodeBuilder {
+    node: Node,
+}
+
+impl NodeBuilder {
+    /// The node's narration.
+    pub fn text(mut self, text: &str) -> Self {
+        self.node.display_text = text.into();
+        self
+    }
+
+    /// Another way of phrasing the narration for A/B tests, like `[VARIANT name] "text"`.
+    pub fn variant(mut self, name: impl Into<String>, text: &str) -> Self {
+        self.node.variants.push(TextVariant {
+            name: name.into(),
+            text: text.into(),
+        });
+        self
+    }
+
+    /// Add a choice, in the order it is shown.
+    pub fn choice(mut self, build: impl FnOnce(ChoiceBuilder) -> ChoiceBuilder) -> Self {
+        let choice = build(ChoiceBuilder {
+            choice: Choice {
+                requirement: None,
+                text: FormatString(Vec::new()),
+                next_node_id: String::new(),
+                command: None,
+            },
+        })
+        .choice;
+        self.node.choices.push(choice);
+        self
+    }
+}
+
+/// Builds one choice of a node, passed to [`NodeBuilder::choice`]. A choice must be given a
+/// node to lead to with [`ChoiceBuilder::to`].
+#[derive(Debug, Clone)]
+pub struct ChoiceBuilder {
+    choice: Choice,
+}
+
+impl ChoiceBuilder {
+    /// What the choice says.
+    pub fn text(mut self, text: &str) -> Self {
+        self.choice.text = text.into();
+        self
+    }
+
+    /// The id of the node the choice leads to.
+    pub fn to(mut self, next_node_id: impl Into<String>) -> Self {
+        self.choice.next_node_id = next_node_id.into();
+        self
+    }
+
+    /// Only offer the choice when `requirement` holds, like `[IF ...]`.
+    pub fn require(mut self, requirement: impl Into<Expression>) -> Self {
+        self.choice.requirement = Some(requirement.into());
+        self
+    }
+
+    /// Set a variable when the choice is taken, like `[THEN name = value]`.
+    pub fn set(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.choice.command = Some(Command::Set {
+            name: name.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Run a script when the choice is taken, like `[THEN SCRIPT "..."]`.
+    pub fn script(mut self, source: impl Into<String>) -> Self {
+        self.choice.command = Some(Command::Script {
+            source: source.into(),
+        });
+        self
+    }
+}
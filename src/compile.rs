@@ -0,0 +1,54 @@
+use crate::{StorySettings, build_story};
+use std::{fs, path::PathBuf, process::ExitCode};
+
+#[derive(clap::Args, Debug)]
+pub struct CompileArgs {
+    /// The story to compile
+    source: PathBuf,
+    /// Where to write the compiled story. Defaults to the source with a `.cyoab` extension.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// A WebAssembly plugin providing functions the story calls. Can be given more than once.
+    #[cfg(feature = "plugins")]
+    #[arg(long)]
+    plugin: Vec<PathBuf>,
+}
+
+/// Check a story and write it out in the compiled format.
+pub fn run(args: CompileArgs) -> ExitCode {
+    let settings = StorySettings {
+        history_depth: 0,
+        max_checkpoints: 0,
+        max_transcript: usize::MAX,
+        #[cfg(feature = "plugins")]
+        plugins: match crate::load_plugins(&args.plugin) {
+            Ok(plugins) => plugins,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+    let story = match build_story(&args.source, settings) {
+        Ok(story) => story,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let output = args
+        .output
+        .unwrap_or_else(|| args.source.with_extension("cyoab"));
+    if output == args.source {
+        eprintln!("'{}' is already compiled.", args.source.display());
+        return ExitCode::FAILURE;
+    }
+    if let Err(e) = fs::write(&output, story.compile()) {
+        eprintln!("Failed to write '{}': {e}", output.display());
+        return ExitCode::FAILURE;
+    }
+
+    println!("Compiled {} to {}", args.source.display(), output.display());
+    ExitCode::SUCCESS
+}
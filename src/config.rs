@@ -0,0 +1,148 @@
+//! Options for the server read from a TOML file given by `--config`, or from `CYOA_*`
+//! environment variables. Options given on the command line take precedence over environment
+//! variables, which take precedence over the file.
+
+use clap::{Arg, ArgAction, ArgMatches, Command, Parser, error::ErrorKind, parser::ValueSource};
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+/// Parse the command line, filling in options it leaves out from the environment and the
+/// config file.
+pub fn parse<T: Parser>() -> T {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let mut command = with_env_vars(T::command());
+
+    // A first pass to find the config file and which options are already set. Errors, such as
+    // missing options the file might provide, are reported by the second pass.
+    let matches = command
+        .clone()
+        .mut_args(|arg| arg.required(false))
+        .ignore_errors(true)
+        .get_matches_from(&args);
+    if matches.subcommand().is_none() {
+        args.extend(list_env_args(&command, &matches));
+        if let Some(path) = matches.get_one::<PathBuf>("config") {
+            match read_config(path).and_then(|config| config_args(&command, &matches, &config)) {
+                Ok(config_args) => args.extend(config_args),
+                Err(e) => command.error(ErrorKind::InvalidValue, e).exit(),
+            }
+        }
+    }
+
+    let matches = command
+        .try_get_matches_from_mut(args)
+        .unwrap_or_else(|e| e.exit());
+    T::from_arg_matches(&matches).unwrap_or_else(|e| e.format(&mut command).exit())
+}
+
+/// The environment variable for an option, e.g. `CYOA_PORT` for `--port`.
+fn env_var(arg: &Arg) -> String {
+    format!("CYOA_{}", arg.get_id().as_str().to_uppercase())
+}
+
+/// Whether an option can be given more than once.
+fn is_list(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append)
+}
+
+/// Lets every option be set with an environment variable. Options that can be given more than
+/// once are left to [`list_env_args`].
+fn with_env_vars(command: Command) -> Command {
+    command.mut_args(|arg| {
+        if is_list(&arg) {
+            return arg;
+        }
+        let env = env_var(&arg);
+        arg.env(env)
+    })
+}
+
+/// The command line arguments for options that can be given more than once and are set in the
+/// environment but not on the command line. Their values are separated by commas, e.g.
+/// `CYOA_SOURCE=cave.cyoa,forest.cyoa`.
+fn list_env_args(command: &Command, matches: &ArgMatches) -> Vec<OsString> {
+    let mut args = Vec::new();
+    for arg in command.get_arguments().filter(|arg| is_list(arg)) {
+        let (Some(long), Some(values)) = (arg.get_long(), std::env::var_os(env_var(arg))) else {
+            continue;
+        };
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        for value in values.to_string_lossy().split(',').map(str::trim) {
+            if !value.is_empty() {
+                args.push(format!("--{long}").into());
+                args.push(value.into());
+            }
+        }
+    }
+
+    args
+}
+
+fn read_config(path: &Path) -> Result<Table, String> {
+    let data = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config file '{}': {e}", path.display()))?;
+    data.parse()
+        .map_err(|e| format!("Failed to read config file '{}': {e}", path.display()))
+}
+
+/// The command line arguments for the options in `config` that weren't already given on the
+/// command line or in the environment.
+fn config_args(
+    command: &Command,
+    matches: &ArgMatches,
+    config: &Table,
+) -> Result<Vec<OsString>, String> {
+    let mut args = Vec::new();
+    for (key, value) in config {
+        let id = key.replace('-', "_");
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str() && arg.get_long().is_some())
+        else {
+            return Err(format!("Unknown option '{key}' in config file"));
+        };
+        if id == "config" {
+            return Err("A config file can't name another config file".to_string());
+        }
+        if matches!(
+            matches.value_source(&id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) || (is_list(arg) && std::env::var_os(env_var(arg)).is_some())
+        {
+            continue;
+        }
+
+        let flag = format!("--{}", arg.get_long().unwrap());
+        let takes_value = arg.get_action().takes_values();
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(set) if !takes_value => {
+                    if *set {
+                        args.push(flag.clone().into());
+                    }
+                }
+                Value::String(value) => {
+                    args.push(flag.clone().into());
+                    args.push(value.into());
+                }
+                Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => {
+                    args.push(flag.clone().into());
+                    args.push(value.to_string().into());
+                }
+                _ => return Err(format!("Option '{key}' in config file has the wrong type")),
+            }
+        }
+    }
+
+    Ok(args)
+}
@@ -0,0 +1,128 @@
+use clap::ValueEnum;
+use cyoa::{
+    engine::printer::print_program,
+    import::{self, ink, twee},
+};
+use std::{fs, path::PathBuf, process::ExitCode};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SourceFormat {
+    /// Twine's Twee 3 format, with SugarCube macros
+    Twee,
+    /// A subset of inkle's Ink
+    Ink,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ConvertArgs {
+    /// The story to convert
+    source: PathBuf,
+    /// The format the story is written in
+    #[arg(long, value_enum)]
+    from: SourceFormat,
+    /// Where to write the `.cyoa` story. Defaults to printing it.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+/// Translate a story written for another tool into a `.cyoa` story.
+pub fn run(args: ConvertArgs) -> ExitCode {
+    let source = match fs::read_to_string(&args.source) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!(
+                "Failed to read source file '{}': {e}",
+                args.source.display()
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let parts = match args.from {
+        SourceFormat::Twee => twee::import(&source),
+        SourceFormat::Ink => ink::import(&source),
+    };
+    // Check the translated story can be played before writing it anywhere.
+    let result = parts.and_then(|parts| import::into_engine(&parts).map(|_| parts));
+    let story = match result {
+        Ok(parts) => print_program(&parts),
+        Err(e) => {
+            eprintln!("Failed to convert '{}': {e}", args.source.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match args.output {
+        Some(path) => {
+            if let Err(e) = fs::write(&path, story) {
+                eprintln!("Failed to write '{}': {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+        None => print!("{story}"),
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for a test's files, removed when the test is done with it.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("cyoa-convert-{}", uuid::Uuid::new_v4()));
+            fs::create_dir(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn convert(dir: &TempDir, source: &str, from: SourceFormat) -> (ExitCode, PathBuf) {
+        let source_path = dir.0.join("story.txt");
+        let output = dir.0.join("story.cyoa");
+        fs::write(&source_path, source).unwrap();
+        let code = run(ConvertArgs {
+            source: source_path,
+            from,
+            output: Some(output.clone()),
+        });
+        (code, output)
+    }
+
+    #[test]
+    fn converted_stories_are_written() {
+        let dir = TempDir::new();
+        let (code, output) = convert(
+            &dir,
+            ":: Start\nHello.\n[[Bye]]\n\n:: Bye\nBye.\n",
+            SourceFormat::Twee,
+        );
+
+        assert_eq!(code, ExitCode::SUCCESS);
+        let story = fs::read_to_string(output).unwrap();
+        assert!(story.contains("= START"), "{story}");
+        assert!(story.contains(r#""Bye" -> Bye"#), "{story}");
+    }
+
+    #[test]
+    fn stories_that_cant_be_converted_arent_written() {
+        let dir = TempDir::new();
+        let (code, output) = convert(
+            &dir,
+            ":: Start\n<<goto \"Bye\">>\n\n:: Bye\nBye.\n",
+            SourceFormat::Twee,
+        );
+
+        assert_eq!(code, ExitCode::FAILURE);
+        assert!(!output.exists());
+    }
+}
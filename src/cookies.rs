@@ -0,0 +1,213 @@
+//! Sessions found from a cookie rather than an id in the path, enabled by `--cookie-sessions`,
+//! so a simple page in a browser can play without keeping track of the session itself.
+
+use crate::{
+    ApiError, AppState, CurrentQuery, ErrorResponse, ExpectedVersion, IdempotencyKey, SessionToken,
+    api_error,
+};
+use axum::{
+    Json,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header, request::Parts},
+    response::Response,
+};
+use cyoa::{ChoiceResult, CurrentNodeView, HistoryEvent};
+
+/// The cookie a story's session is kept in. Every story has its own, so a browser can play
+/// several stories on one server at once.
+fn cookie_name(story_id: &str) -> String {
+    let story_id: String = story_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("cyoa_session_{story_id}")
+}
+
+/// A `Set-Cookie` header holding a new session's id and token. The cookie can't be read by
+/// scripts, and browsers don't send it with requests started by other sites. A `secure` cookie
+/// is only sent over HTTPS.
+pub fn set_cookie(
+    story_id: &str,
+    session_id: &str,
+    session_token: &str,
+    secure: bool,
+) -> HeaderValue {
+    let secure = if secure { "; Secure" } else { "" };
+    HeaderValue::from_str(&format!(
+        "{}={session_id}.{session_token}; Path=/; HttpOnly; SameSite=Lax{secure}",
+        cookie_name(story_id)
+    ))
+    .expect("Session ids and tokens are valid in a cookie")
+}
+
+/// The session named by the request's cookie, with the token to change it.
+pub(crate) struct CookieSession {
+    session_id: String,
+    token: SessionToken,
+}
+
+impl FromRequestParts<AppState> for CookieSession {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let name = cookie_name(&state.story_id);
+        parts
+            .headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(cookie_name, _)| *cookie_name == name)
+            .and_then(|(_, value)| value.split_once('.'))
+            .map(|(session_id, token)| CookieSession {
+                session_id: session_id.to_string(),
+                token: SessionToken(Some(token.to_string())),
+            })
+            .ok_or_else(|| {
+                api_error(
+                    StatusCode::UNAUTHORIZED,
+                    "there is no session cookie, start a session with POST /session first",
+                )
+            })
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/session/current",
+    tag = "play",
+    params(("story_id" = String, Path, description = "The story's id"), CurrentQuery),
+    responses(
+        (status = 200, description = "The current node of the session in the cookie", body = CurrentNodeView),
+        (status = 401, description = "There is no session cookie", body = ErrorResponse),
+        (status = 404, description = "The session has expired or been deleted", body = ErrorResponse),
+    ),
+)]
+pub async fn get_current(
+    state: State<AppState>,
+    query: Query<CurrentQuery>,
+    cookie: CookieSession,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    crate::get_current(state, Path(cookie.session_id), query, cookie.token, headers).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/session/history",
+    tag = "play",
+    params(("story_id" = String, Path, description = "The story's id")),
+    responses(
+        (status = 200, description = "The history of the session in the cookie", body = Vec<HistoryEvent>),
+        (status = 401, description = "There is no session cookie", body = ErrorResponse),
+        (status = 404, description = "The session has expired or been deleted", body = ErrorResponse),
+    ),
+)]
+pub async fn get_history(
+    state: State<AppState>,
+    cookie: CookieSession,
+) -> Result<Json<Vec<HistoryEvent>>, ApiError> {
+    crate::get_history(state, Path(cookie.session_id), cookie.token).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/choose/{option}",
+    tag = "play",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("option" = String, Path, description = "The id of the choice to take"),
+        ("If-Match" = Option<String>, Header, description = "The session version the choice is meant for, e.g. `\"3\"`"),
+        ("expected_version" = Option<u64>, Query, description = "The same as `If-Match`, for clients that can't set headers"),
+        ("Idempotency-Key" = Option<String>, Header, description = "A key made up by the client. If a choice is sent again with the same key, the first result is returned without taking the choice again"),
+    ),
+    responses(
+        (status = 200, description = "The choice was taken", body = ChoiceResult),
+        (status = 400, description = "The choice isn't available", body = ChoiceResult),
+        (status = 401, description = "There is no session cookie", body = ErrorResponse),
+        (status = 404, description = "The session has expired or been deleted", body = ErrorResponse),
+        (status = 409, description = "The session is shared, so its choices are voted on, or it has changed since the expected version", body = ErrorResponse),
+        (status = 422, description = "The idempotency key was already used for a different choice", body = ErrorResponse),
+    ),
+)]
+pub async fn choose_option(
+    state: State<AppState>,
+    Path(option): Path<String>,
+    cookie: CookieSession,
+    expected_version: ExpectedVersion,
+    idempotency_key: IdempotencyKey,
+) -> Result<(StatusCode, Json<ChoiceResult>), ApiError> {
+    crate::choose_option(
+        state,
+        Path((cookie.session_id, option)),
+        cookie.token,
+        expected_version,
+        idempotency_key,
+    )
+    .await
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/back",
+    tag = "play",
+    params(("story_id" = String, Path, description = "The story's id")),
+    responses(
+        (status = 200, description = "The last choice was undone", body = CurrentNodeView),
+        (status = 400, description = "There is no choice to undo", body = ErrorResponse),
+        (status = 401, description = "There is no session cookie", body = ErrorResponse),
+        (status = 404, description = "The session has expired or been deleted", body = ErrorResponse),
+    ),
+)]
+pub async fn go_back(
+    state: State<AppState>,
+    cookie: CookieSession,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    crate::go_back(state, Path(cookie.session_id), cookie.token).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/restart",
+    tag = "play",
+    params(("story_id" = String, Path, description = "The story's id")),
+    responses(
+        (status = 200, description = "The session is back at the start of the story", body = CurrentNodeView),
+        (status = 401, description = "There is no session cookie", body = ErrorResponse),
+        (status = 404, description = "The session has expired or been deleted", body = ErrorResponse),
+    ),
+)]
+pub async fn restart_session(
+    state: State<AppState>,
+    cookie: CookieSession,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    crate::restart_session(state, Path(cookie.session_id), cookie.token).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_secure_cookies_are_marked_secure() {
+        let cookie = set_cookie("story", "id", "token", true);
+        assert!(
+            cookie
+                .to_str()
+                .unwrap()
+                .ends_with("; HttpOnly; SameSite=Lax; Secure")
+        );
+        let cookie = set_cookie("story", "id", "token", false);
+        assert!(!cookie.to_str().unwrap().contains("Secure"));
+    }
+}
@@ -0,0 +1,43 @@
+//! Which other origins browsers let call the server, set by `--cors-origin`.
+
+use crate::request_id::REQUEST_ID_HEADER;
+use axum::http::{HeaderName, HeaderValue, Method, header};
+use std::time::Duration;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+/// How long browsers can cache the answer to a preflight request.
+const MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Allows requests from `origins`, e.g. `https://example.com`, or from anywhere if one of them
+/// is `*`. Errors on origins that can't be sent in a header.
+pub fn layer(origins: &[String]) -> Result<CorsLayer, String> {
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::from(Any)
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin.trim_end_matches('/'))
+                    .map_err(|_| format!("'{origin}' isn't a valid CORS origin."))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::IF_MATCH,
+            HeaderName::from_static("x-session-token"),
+            HeaderName::from_static("idempotency-key"),
+            HeaderName::from_static(crate::stateless::STATE_HEADER),
+            REQUEST_ID_HEADER,
+        ])
+        // So clients that hit a rate limit can see how long to wait, and can report which
+        // request failed.
+        .expose_headers([header::RETRY_AFTER, REQUEST_ID_HEADER])
+        .max_age(MAX_AGE))
+}
@@ -0,0 +1,272 @@
+use crate::{StorySettings, build_story, walk::Rng};
+use cyoa::{ChoiceResult, Engine, RestoreError, Session, engine::parser::parse_literal};
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct DebugArgs {
+    /// The story file to debug
+    source: PathBuf,
+}
+
+/// The most choices `continue` takes before giving up, in case the story loops forever.
+const MAX_CONTINUE_STEPS: usize = 10_000;
+
+const HELP: &str = "\
+Commands:
+  show                Show the current node and its choices
+  <n>                 Take choice number n
+  back                Undo the last choice
+  restart             Start again from the beginning
+  vars                List every variable
+  set <name> <value>  Change a variable, e.g. `set gold 15` or `set name \"Sam\"`
+  goto <id>           Jump to a node without taking a choice
+  break <id>          Stop when a node is reached
+  watch <name>        Stop when a variable changes
+  delete <id|name>    Remove a breakpoint or watch
+  breakpoints         List breakpoints and watches
+  continue            Take random choices until a breakpoint, watch or ending
+  help                Show this message
+  quit                Exit the debugger";
+
+struct Debugger<'a> {
+    story: &'a Engine,
+    session: Session,
+    breakpoints: BTreeSet<String>,
+    watches: BTreeSet<String>,
+    rng: Rng,
+}
+
+/// Step through a story while inspecting and changing its state.
+pub fn run(args: DebugArgs) -> ExitCode {
+    let settings = StorySettings {
+        history_depth: usize::MAX,
+        max_checkpoints: 0,
+        max_transcript: usize::MAX,
+        #[cfg(feature = "plugins")]
+        plugins: None,
+    };
+    let story = match build_story(&args.source, settings) {
+        Ok(story) => story,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut debugger = Debugger {
+        story: &story,
+        session: story.new_session(),
+        breakpoints: BTreeSet::new(),
+        watches: BTreeSet::new(),
+        rng: Rng::new(Rng::seed_from_time()),
+    };
+    println!("Type `help` for a list of commands.");
+    debugger.show();
+
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("\n(cyoa) ");
+        io::stdout().flush().expect("Failed to write to stdout");
+        let Some(Ok(line)) = lines.next() else {
+            return ExitCode::SUCCESS;
+        };
+
+        let line = line.trim();
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+        match command {
+            "" => {}
+            "q" | "quit" => return ExitCode::SUCCESS,
+            "h" | "help" => println!("{HELP}"),
+            "s" | "show" => debugger.show(),
+            "b" | "back" => {
+                if debugger.story.go_back(&mut debugger.session) {
+                    debugger.show();
+                } else {
+                    println!("There is nothing to go back to.");
+                }
+            }
+            "restart" => {
+                debugger.story.restart_session(&mut debugger.session);
+                debugger.show();
+            }
+            "vars" => debugger.print_variables(),
+            "set" => debugger.set(argument),
+            "goto" => debugger.goto(argument),
+            "break" => debugger.add_breakpoint(argument),
+            "watch" => debugger.add_watch(argument),
+            "delete" => debugger.delete(argument),
+            "breakpoints" => debugger.print_breakpoints(),
+            "c" | "continue" => debugger.continue_until_stopped(),
+            _ => match line.parse::<usize>() {
+                Ok(number) => debugger.step(number),
+                Err(_) => println!("Unknown command '{line}'. Type `help` for a list of commands."),
+            },
+        }
+    }
+}
+
+impl Debugger<'_> {
+    fn show(&self) {
+        let view = self.story.get_current_node_view(&self.session);
+        println!("\n[{}]", self.session.current_node_id());
+        println!("{}", view.display_text);
+        if view.game_over {
+            println!("(no choices available)");
+        }
+        for (i, choice) in view.choices.iter().enumerate() {
+            println!("  {}. {} -> {}", i + 1, choice.display_text, choice.id);
+        }
+    }
+
+    fn print_variables(&self) {
+        for (name, value) in self.session.variables() {
+            let marker = if self.watches.contains(name) {
+                " (watched)"
+            } else {
+                ""
+            };
+            println!("  {name} = {value}{marker}");
+        }
+    }
+
+    fn set(&mut self, argument: &str) {
+        let Some((name, value)) = argument.split_once(' ') else {
+            println!("Usage: set <name> <value>");
+            return;
+        };
+        let Some(value) = parse_literal(value) else {
+            println!("'{}' is not a valid value.", value.trim());
+            return;
+        };
+
+        match self.story.set_variable(&mut self.session, name, value) {
+            Ok(()) => println!("  {name} = {}", self.session.variable(name).unwrap()),
+            Err(RestoreError::MismatchedVariableType { .. }) => {
+                println!("'{name}' can't be set to a value of a different type.")
+            }
+            Err(_) => println!("There is no variable with name '{name}'."),
+        }
+    }
+
+    fn goto(&mut self, node_id: &str) {
+        match self.story.jump_to_node(&mut self.session, node_id) {
+            Ok(()) => self.show(),
+            Err(_) => println!("There is no node with id '{node_id}'."),
+        }
+    }
+
+    fn add_breakpoint(&mut self, node_id: &str) {
+        if self.story.has_node(node_id) {
+            self.breakpoints.insert(node_id.to_string());
+            println!("Breakpoint set at node '{node_id}'.");
+        } else {
+            println!("There is no node with id '{node_id}'.");
+        }
+    }
+
+    fn add_watch(&mut self, name: &str) {
+        if self.session.variable(name).is_some() {
+            self.watches.insert(name.to_string());
+            println!("Watching variable '{name}'.");
+        } else {
+            println!("There is no variable with name '{name}'.");
+        }
+    }
+
+    fn delete(&mut self, argument: &str) {
+        let removed = self.breakpoints.remove(argument) | self.watches.remove(argument);
+        if !removed {
+            println!("There is no breakpoint or watch on '{argument}'.");
+        }
+    }
+
+    fn print_breakpoints(&self) {
+        if self.breakpoints.is_empty() && self.watches.is_empty() {
+            println!("No breakpoints or watches are set.");
+        }
+        for node_id in &self.breakpoints {
+            println!("  break {node_id}");
+        }
+        for name in &self.watches {
+            println!("  watch {name}");
+        }
+    }
+
+    /// Take the choice with the given number. Returns whether a breakpoint or watch was hit.
+    fn take_choice(&mut self, number: usize) -> Result<bool, String> {
+        let view = self.story.get_current_node_view(&self.session);
+        let Some(choice) = number.checked_sub(1).and_then(|i| view.choices.get(i)) else {
+            return Err(format!(
+                "'{number}' is not a choice. Enter a number from 1 to {}.",
+                view.choices.len()
+            ));
+        };
+
+        let before: HashMap<String, String> = self
+            .watches
+            .iter()
+            .map(|name| {
+                (
+                    name.clone(),
+                    self.session.variable(name).unwrap().to_string(),
+                )
+            })
+            .collect();
+        let result = self
+            .story
+            .choose_option(&mut self.session, choice.id.clone());
+        if !matches!(result, ChoiceResult::Success) {
+            return Err(result.to_string());
+        }
+
+        let mut stopped = false;
+        for (name, old) in before {
+            let new = self.session.variable(&name).unwrap().to_string();
+            if new != old {
+                println!("Watch: '{name}' changed from {old} to {new}.");
+                stopped = true;
+            }
+        }
+        if self.breakpoints.contains(self.session.current_node_id()) {
+            println!(
+                "Breakpoint: reached node '{}'.",
+                self.session.current_node_id()
+            );
+            stopped = true;
+        }
+
+        Ok(stopped)
+    }
+
+    fn step(&mut self, number: usize) {
+        match self.take_choice(number) {
+            Ok(_) => self.show(),
+            Err(e) => println!("{e}"),
+        }
+    }
+
+    fn continue_until_stopped(&mut self) {
+        for _ in 0..MAX_CONTINUE_STEPS {
+            let choices = self.story.get_current_node_view(&self.session).choices;
+            if choices.is_empty() {
+                println!("The story ended.");
+                break;
+            }
+            let number = self.rng.below(choices.len()) + 1;
+            if self
+                .take_choice(number)
+                .expect("Took a choice that was listed")
+            {
+                break;
+            }
+        }
+
+        self.show();
+    }
+}
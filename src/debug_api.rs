@@ -0,0 +1,144 @@
+//! Endpoints for putting a session into any state by hand, so bugs deep in a story can be
+//! reproduced without replaying every choice. Only served with `--debug`.
+
+use crate::{ApiError, AppState, ErrorResponse, SessionToken, api_error, get_own_session};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use cyoa::{
+    CurrentNodeView, Engine, Session, Value,
+    engine::parser::{FormatString, FormatStringPart},
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::info;
+use utoipa::ToSchema;
+
+/// A variable's value as plain JSON: `true`, `15` or `"Sam"`.
+pub fn to_value(name: &str, json: serde_json::Value) -> Result<Value, ApiError> {
+    match json {
+        serde_json::Value::Bool(value) => Ok(Value::Bool(value)),
+        serde_json::Value::Number(number) => number.as_i64().map(Value::Int).ok_or_else(|| {
+            api_error(
+                StatusCode::BAD_REQUEST,
+                format!("'{name}' can only be set to a whole number that fits in 64 bits"),
+            )
+        }),
+        serde_json::Value::String(value) => Ok(Value::String(FormatString(vec![
+            FormatStringPart::Literal(value),
+        ]))),
+        _ => Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("'{name}' can only be set to a boolean, a number or a string"),
+        )),
+    }
+}
+
+/// The current node with every variable, since that is what someone debugging wants to see.
+fn debug_view(story: &Engine, session: &Session) -> CurrentNodeView {
+    let mut view = story.get_current_node_view(session);
+    view.variables = Some(
+        session
+            .variables()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect(),
+    );
+    view
+}
+
+#[utoipa::path(
+    put,
+    path = "/stories/{story_id}/session/{session_id}/debug/variables",
+    tag = "debugging",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+    ),
+    request_body(
+        content = HashMap<String, serde_json::Value>,
+        description = "The variables to change and their new values, e.g. `{ \"gold\": 15, \"name\": \"Sam\" }`",
+    ),
+    responses(
+        (status = 200, description = "Every variable was changed", body = CurrentNodeView),
+        (status = 400, description = "A variable doesn't exist or can't hold its new value, so none were changed", body = ErrorResponse),
+        (status = 401, description = "The session's token is missing", body = ErrorResponse),
+        (status = 403, description = "The session's token is wrong", body = ErrorResponse),
+        (status = 404, description = "No such session, or the server wasn't started with `--debug`", body = ErrorResponse),
+    ),
+)]
+pub async fn set_variables(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+    Json(variables): Json<HashMap<String, serde_json::Value>>,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    let (story, mut session) = get_own_session(&state, &session_id, &token).await?;
+    // Nothing is saved until every variable has been set, so a bad one changes nothing.
+    for (name, json) in variables {
+        let value = to_value(&name, json)?;
+        if story.set_variable(&mut session, &name, value).is_err() {
+            let message = if session.variable(&name).is_some() {
+                format!("'{name}' can't be set to a value of a different type")
+            } else {
+                format!("there is no variable with name '{name}'")
+            };
+            return Err(api_error(StatusCode::BAD_REQUEST, message));
+        }
+    }
+    info!(%session_id, "Set variables for debugging");
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+
+    Ok(Json(debug_view(&story, &session)))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct JumpRequest {
+    /// The node to move the session to
+    node_id: String,
+}
+
+#[utoipa::path(
+    put,
+    path = "/stories/{story_id}/session/{session_id}/debug/node",
+    tag = "debugging",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+    ),
+    request_body = JumpRequest,
+    responses(
+        (status = 200, description = "The session was moved to the node", body = CurrentNodeView),
+        (status = 400, description = "The story has no such node", body = ErrorResponse),
+        (status = 401, description = "The session's token is missing", body = ErrorResponse),
+        (status = 403, description = "The session's token is wrong", body = ErrorResponse),
+        (status = 404, description = "No such session, or the server wasn't started with `--debug`", body = ErrorResponse),
+    ),
+)]
+pub async fn jump_to_node(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+    Json(request): Json<JumpRequest>,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    let (story, mut session) = get_own_session(&state, &session_id, &token).await?;
+    story
+        .jump_to_node(&mut session, &request.node_id)
+        .map_err(|_| {
+            api_error(
+                StatusCode::BAD_REQUEST,
+                format!("there is no node with id '{}'", request.node_id),
+            )
+        })?;
+    info!(%session_id, node_id = request.node_id, "Jumped to node for debugging");
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+
+    Ok(Json(debug_view(&story, &session)))
+}
@@ -0,0 +1,112 @@
+//! Embedding stories in a binary at compile time, so a game can ship as a single file that
+//! can't fail to load its story.
+//!
+//! Stories are checked and compiled by a build script, and loaded with [`include_story!`]:
+//!
+//! ```toml
+//! [dependencies]
+//! cyoa = { git = "https://github.com/rockysnow7/cyoa", default-features = false }
+//!
+//! [build-dependencies]
+//! cyoa = { git = "https://github.com/rockysnow7/cyoa", default-features = false }
+//! ```
+//!
+//! ```no_run
+//! // In `main` in build.rs
+//! cyoa::embed::compile_story("stories/cave.cyoa");
+//! ```
+//!
+//! ```ignore
+//! // In the game
+//! let story = cyoa::include_story!("stories/cave.cyoa");
+//! let session = story.new_session();
+//! ```
+//!
+//! [`include_story!`]: crate::include_story
+
+use crate::{Engine, engine::parser::parse_program};
+use std::{
+    env, fs,
+    path::{Component, Path},
+};
+
+/// Check the story at `path`, relative to the package root, and compile it into the build's
+/// output directory for [`include_story!`](crate::include_story) to embed. Call it from a build
+/// script. Every problem with the story is reported as a build error, and the build is rerun
+/// whenever the story changes.
+///
+/// The compiled story is kept at the same path inside the output directory, so `path` can't be
+/// absolute or use `..` to leave the package.
+pub fn compile_story(path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        fail(&[format!(
+            "'{}' must be relative to the package root, without '..'.",
+            path.display()
+        )]);
+    }
+    println!("cargo::rerun-if-changed={}", path.display());
+
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => fail(&[format!(
+            "Failed to read source file '{}': {e}",
+            path.display()
+        )]),
+    };
+    let (rest, _) = parse_program(&source).expect("Failed to parse nodes");
+    if !rest.trim().is_empty() {
+        let line = source[..source.len() - rest.trim_start().len()]
+            .matches('\n')
+            .count()
+            + 1;
+        fail(&[format!(
+            "{}:{line}: The story could not be parsed from here on.",
+            path.display()
+        )]);
+    }
+    let story = match Engine::from_program(&source) {
+        Ok(story) => story,
+        Err(errors) => fail(
+            &errors
+                .iter()
+                .map(|error| format!("{}: {error}", path.display()))
+                .collect::<Vec<_>>(),
+        ),
+    };
+
+    let out_dir = env::var("OUT_DIR").expect("compile_story must be called from a build script");
+    let output = Path::new(&out_dir).join(format!("{}.cyoab", path.display()));
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).expect("Failed to create the output directory");
+    }
+    fs::write(&output, story.compile()).expect("Failed to write the compiled story");
+}
+
+/// Report each message as a build error and stop the build.
+fn fail(messages: &[String]) -> ! {
+    for message in messages {
+        println!("cargo::error={message}");
+    }
+    std::process::exit(1);
+}
+
+/// Load a story that a build script compiled with
+/// [`embed::compile_story`](crate::embed::compile_story), as an [`Engine`](crate::Engine). The
+/// path must be the same one given to `compile_story`. The story was checked when it was
+/// compiled, so loading it can't fail.
+#[macro_export]
+macro_rules! include_story {
+    ($path:literal) => {
+        $crate::Engine::from_compiled(include_bytes!(concat!(
+            env!("OUT_DIR"),
+            "/",
+            $path,
+            ".cyoab"
+        )))
+        .expect("The story was compiled by a different version of cyoa")
+    };
+}
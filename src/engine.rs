@@ -1,13 +1,39 @@
-mod parser;
+pub mod analysis;
+pub mod explore;
+mod interned;
+pub mod parser;
+#[cfg(feature = "plugins")]
+pub mod plugins;
+pub mod printer;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod tokens;
 
+use analysis::Analysis;
+use interned::{Effect, Expr, Interner, Text, TextPart, Variables};
 use parser::{
-    Command, Expression, FormatString, FormatStringPart, Node, ProgramPart, Value, parse_program,
+    Command, Expression, FormatString, FormatStringPart, Node, ProgramPart, Value,
+    parse_program_with, starts_definition,
+};
+use printer::print_program;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt::{Display, Write},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, BufRead},
+    mem,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
-use serde::Serialize;
-use std::{collections::HashMap, fmt::Display, time::Instant};
 
+/// A problem with a story that stops an [`Engine`] from being built from it.
 #[derive(Debug)]
-pub enum ParseError<'a> {
+pub enum ParseError {
     MissingStartNode,
     BadReferenceInOption {
         parent_node_id: String,
@@ -21,9 +47,12 @@ pub enum ParseError<'a> {
         parent_node_id: String,
         bad_name: String,
     },
-    InvalidExpression {
+    /// Part of an expression, `location`, whose value can't be of the type it is used as.
+    TypeError {
         parent_node_id: String,
-        expression: Expression<'a>,
+        expected: &'static str,
+        found: &'static str,
+        location: Expression,
     },
     BadReferenceInCommand {
         parent_node_id: String,
@@ -31,95 +60,1381 @@ pub enum ParseError<'a> {
     },
     InvalidCommand {
         parent_node_id: String,
-        command: Command<'a>,
+        command: Command,
+    },
+    BadReferenceInRename {
+        old_id: String,
+        new_id: String,
+    },
+    /// A `SCRIPT` command that doesn't compile, or any `SCRIPT` command without the `scripting`
+    /// feature.
+    InvalidScript {
+        parent_node_id: String,
+        script: String,
+        message: String,
+    },
+    /// A call to a function that no plugin provides.
+    UnknownFunction {
+        parent_node_id: String,
+        name: String,
+    },
+    /// A `{@name}` segment without the fallback shown when no text is provided for it.
+    MissingFallback {
+        parent_node_id: String,
+        name: String,
+    },
+    /// Variables whose values can interpolate each other in a loop, starting and ending with
+    /// the same name.
+    InterpolationCycle {
+        names: Vec<String>,
+    },
+    /// A `SET` command giving a variable a value of a different type than its default.
+    TypeMismatch {
+        parent_node_id: String,
+        name: String,
+        expected: &'static str,
+        found: &'static str,
     },
 }
 
-impl Display for ParseError<'_> {
+impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::MissingStartNode => f.write_fmt(format_args!("Your program is missing a 'START' node, which is required as the entry point of the game.")),
             Self::BadReferenceInOption { parent_node_id, bad_id } => f.write_fmt(format_args!("The node with id '{parent_node_id}' contains an option that references a non-existent node with id '{bad_id}'.")),
             Self::BadReferenceInString { parent_node_id, bad_name } => f.write_fmt(format_args!("The node with id '{parent_node_id}' contains a string that references a non-existent variable with name '{bad_name}'.")),
             Self::BadReferenceInExpression { parent_node_id, bad_name } => f.write_fmt(format_args!("The node with id '{parent_node_id}' contains an expression that references a non-existent variable with name '{bad_name}'.")),
-            Self::InvalidExpression { parent_node_id, expression } => f.write_fmt(format_args!("The node with id '{parent_node_id}' contains an expression that is invalid: {expression}.")),
+            Self::TypeError { parent_node_id, expected, found, location } => f.write_fmt(format_args!("The node with id '{parent_node_id}' uses '{location}', which is {found}, where {expected} is needed.")),
             Self::BadReferenceInCommand { parent_node_id, bad_name } => f.write_fmt(format_args!("The node with id '{parent_node_id}' contains a command that references a non-existent variable with name '{bad_name}'.")),
             Self::InvalidCommand { parent_node_id, command } => f.write_fmt(format_args!("The node with id '{parent_node_id}' contains a command that is invalid: '{command}'.")),
+            Self::BadReferenceInRename { old_id, new_id } => f.write_fmt(format_args!("The node with id '{old_id}' is renamed to a non-existent node with id '{new_id}'.")),
+            Self::InvalidScript { parent_node_id, script, message } => f.write_fmt(format_args!("The node with id '{parent_node_id}' contains a script that is invalid: '{script}': {message}")),
+            Self::UnknownFunction { parent_node_id, name } => f.write_fmt(format_args!("The node with id '{parent_node_id}' calls a function named '{name}' that no plugin provides.")),
+            Self::MissingFallback { parent_node_id, name } => f.write_fmt(format_args!("The node with id '{parent_node_id}' contains '{{@{name}}}' without a fallback. Write it as '{{@{name}|fallback text}}'.")),
+            Self::TypeMismatch { parent_node_id, name, expected, found } => f.write_fmt(format_args!("The node with id '{parent_node_id}' sets the variable '{name}' to {found}, but it holds {expected}.")),
+            Self::InterpolationCycle { names } => f.write_fmt(format_args!("The variable '{}' can interpolate itself ('{}'), so it could never be shown.", names[0], names.join("' -> '"))),
+        }
+    }
+}
+
+impl ParseError {
+    /// The id of the node the error was found in, if it belongs to one.
+    pub fn node_id(&self) -> Option<&str> {
+        match self {
+            Self::MissingStartNode | Self::InterpolationCycle { .. } => None,
+            Self::BadReferenceInOption { parent_node_id, .. }
+            | Self::BadReferenceInString { parent_node_id, .. }
+            | Self::BadReferenceInExpression { parent_node_id, .. }
+            | Self::TypeError { parent_node_id, .. }
+            | Self::BadReferenceInCommand { parent_node_id, .. }
+            | Self::InvalidCommand { parent_node_id, .. }
+            | Self::InvalidScript { parent_node_id, .. }
+            | Self::UnknownFunction { parent_node_id, .. }
+            | Self::MissingFallback { parent_node_id, .. }
+            | Self::TypeMismatch { parent_node_id, .. } => Some(parent_node_id),
+            Self::BadReferenceInRename { old_id, .. } => Some(old_id),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A reason a saved session can't be played against a story.
+#[derive(Debug)]
+pub enum RestoreError {
+    UnknownNode { node_id: String },
+    UnknownVariable { name: String },
+    MismatchedVariableType { name: String },
+}
+
+impl Display for RestoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownNode { node_id } => f.write_fmt(format_args!("The saved session is at a node with id '{node_id}', which does not exist in this story.")),
+            Self::UnknownVariable { name } => f.write_fmt(format_args!("The saved session contains a variable with name '{name}', which does not exist in this story.")),
+            Self::MismatchedVariableType { name } => f.write_fmt(format_args!("The saved session contains a variable with name '{name}' whose value does not match the type of that variable in this story.")),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+/// A reason a checkpoint couldn't be saved or loaded.
+#[derive(Debug)]
+pub enum CheckpointError {
+    TooManyCheckpoints { max_checkpoints: usize },
+    UnknownSlot { slot: String },
+}
+
+impl Display for CheckpointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooManyCheckpoints { max_checkpoints } => f.write_fmt(format_args!(
+                "This session already has the maximum of {max_checkpoints} checkpoints."
+            )),
+            Self::UnknownSlot { slot } => f.write_fmt(format_args!(
+                "This session has no checkpoint in slot '{slot}'."
+            )),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+/// A reason a compiled story couldn't be loaded.
+#[derive(Debug)]
+pub enum CompiledStoryError {
+    NotCompiled,
+    UnsupportedFormat {
+        format: u8,
+    },
+    Corrupt {
+        message: String,
+    },
+    /// The file was read, but the story in it has errors, e.g. because it was edited by hand.
+    Invalid(Vec<ParseError>),
+}
+
+impl Display for CompiledStoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotCompiled => f.write_str("The file is not a compiled story."),
+            Self::UnsupportedFormat { format } => f.write_fmt(format_args!(
+                "The story was compiled with format {format}, but only format {COMPILED_FORMAT} is supported. Compile it again with this version of cyoa."
+            )),
+            Self::Corrupt { message } => {
+                f.write_fmt(format_args!("The compiled story is corrupt: {message}"))
+            }
+            Self::Invalid(errors) => {
+                f.write_fmt(format_args!(
+                    "The compiled story has {} errors:",
+                    errors.len()
+                ))?;
+                for error in errors {
+                    f.write_fmt(format_args!("\n{error}"))?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
-#[derive(Serialize)]
+impl std::error::Error for CompiledStoryError {}
+
+/// A reason a story couldn't be loaded with [`Engine::from_reader`].
+#[derive(Debug)]
+pub enum ReadStoryError {
+    Io(io::Error),
+    /// The story couldn't be parsed from this line on, counting from 1.
+    Unparsable {
+        line: usize,
+    },
+    Invalid(Vec<ParseError>),
+}
+
+impl Display for ReadStoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => f.write_fmt(format_args!("Failed to read the story: {e}")),
+            Self::Unparsable { line } => f.write_fmt(format_args!(
+                "The story could not be parsed from line {line} on."
+            )),
+            Self::Invalid(errors) => {
+                f.write_fmt(format_args!("The story has {} errors:", errors.len()))?;
+                for error in errors {
+                    f.write_fmt(format_args!("\n{error}"))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReadStoryError {}
+
+/// How many variables deep one variable's value can interpolate others.
+const MAX_INTERPOLATION_DEPTH: usize = 32;
+/// The longest a single piece of text can become when its variables are filled in, in bytes.
+const MAX_TEXT_LENGTH: usize = 1 << 20;
+
+/// A reason some text couldn't be filled in for a session. Stories are checked for variables
+/// that interpolate themselves when they are loaded, so these only come from values set from
+/// outside the story, e.g. by an imported session or a debugger.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// Filling in the variable would go more than [`MAX_INTERPOLATION_DEPTH`] variables deep,
+    /// usually because it interpolates itself.
+    TooDeep { name: String },
+    /// The text would be longer than [`MAX_TEXT_LENGTH`] bytes, or fill in that many variables.
+    TooLong,
+    /// The text interpolates a variable the story doesn't have.
+    UnknownVariable { name: String },
+    /// The session has a value for the variable of a different type than the story gives it,
+    /// usually because it was saved against another story.
+    MismatchedVariableType { name: String },
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooDeep { name } => f.write_fmt(format_args!("The variable '{name}' interpolates other variables more than {MAX_INTERPOLATION_DEPTH} deep, so it probably interpolates itself.")),
+            Self::TooLong => f.write_fmt(format_args!("The text is longer than {MAX_TEXT_LENGTH} bytes once its variables are filled in.")),
+            Self::UnknownVariable { name } => f.write_fmt(format_args!("The text interpolates a variable with name '{name}', which does not exist in this story.")),
+            Self::MismatchedVariableType { name } => f.write_fmt(format_args!("The session's value for the variable '{name}' is not of the type the story gives it.")),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// Text being filled in for a session, which stops growing at [`MAX_TEXT_LENGTH`] bytes.
+struct Output {
+    text: String,
+    /// How many variables deep the value being written is.
+    depth: usize,
+    /// How many variables have been filled in, which is limited like the length so values that
+    /// are empty can't take forever either.
+    filled: usize,
+    /// Whether the text has reached its longest, so nothing more is written.
+    full: bool,
+    /// The first problem met. Writing carries on past it as well as it can.
+    error: Option<EvalError>,
+}
+
+impl Output {
+    fn new(capacity: usize) -> Self {
+        Output {
+            text: String::with_capacity(capacity),
+            depth: 0,
+            filled: 0,
+            full: false,
+            error: None,
+        }
+    }
+
+    fn push(&mut self, s: &str) {
+        if self.full {
+            return;
+        }
+        if self.text.len() + s.len() <= MAX_TEXT_LENGTH {
+            self.text.push_str(s);
+            return;
+        }
+        let mut end = MAX_TEXT_LENGTH - self.text.len();
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.text.push_str(&s[..end]);
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.full = true;
+        self.fail(EvalError::TooLong);
+    }
+
+    fn fail(&mut self, error: EvalError) {
+        self.error.get_or_insert(error);
+    }
+}
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.push(s);
+        Ok(())
+    }
+}
+
+/// The start of every compiled story.
+const COMPILED_MAGIC: &[u8] = b"CYOAB";
+/// Bumped whenever the layout of [`CompiledStory`] changes.
+const COMPILED_FORMAT: u8 = 2;
+
+/// The text variant a node's main narration belongs to, for sessions in no other variant.
+pub const DEFAULT_VARIANT: &str = "a";
+
+/// How many idempotency keys each session remembers. The oldest are forgotten first.
+const MAX_CHOICE_KEYS: usize = 32;
+
+/// A story's definitions, gathered one at a time as they are parsed.
+#[derive(Default)]
+struct Definitions {
+    variables: HashMap<String, Value>,
+    nodes: HashMap<String, Node>,
+    renamed_nodes: HashMap<String, String>,
+    metadata: HashMap<String, String>,
+}
+
+impl Definitions {
+    /// Add a definition. A later definition of the same node or variable replaces an earlier
+    /// one.
+    fn add(&mut self, part: ProgramPart) {
+        match part {
+            ProgramPart::VariableDefinition { name, value } => {
+                self.variables.insert(name, value);
+            }
+            ProgramPart::NodeDefinition { id, node } => {
+                self.nodes.insert(id, node);
+            }
+            ProgramPart::NodeRename { old_id, new_id } => {
+                self.renamed_nodes.insert(old_id, new_id);
+            }
+            ProgramPart::Metadata { key, value } => {
+                self.metadata.insert(key, value);
+            }
+        }
+    }
+
+    /// An engine holding these definitions, not yet checked for errors.
+    fn into_engine(self, version: u64) -> Engine {
+        let mut engine = Engine {
+            version,
+            renamed_nodes: self.renamed_nodes,
+            metadata: self.metadata,
+            ..Engine::new()
+        };
+        engine.set_story(self.variables, self.nodes);
+        engine
+    }
+}
+
+/// The story data saved by [`Engine::compile`].
+#[derive(Serialize, Deserialize)]
+struct CompiledStory {
+    version: u64,
+    default_variables: HashMap<String, Value>,
+    all_nodes: HashMap<String, Node>,
+    renamed_nodes: HashMap<String, String>,
+    metadata: HashMap<String, String>,
+}
+
+/// A reason a vote couldn't be cast or resolved.
+#[derive(Debug)]
+pub enum VoteError {
+    UnknownParticipant {
+        participant_id: String,
+    },
+    InvalidOption {
+        chosen_option: String,
+    },
+    RequirementNotMet {
+        chosen_option: String,
+        requirement: String,
+    },
+    NoVotes,
+    /// The choice with the most votes couldn't be taken.
+    ChoiceFailed(ChoiceResult),
+}
+
+impl Display for VoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownParticipant { participant_id } => f.write_fmt(format_args!(
+                "'{participant_id}' has not joined this session."
+            )),
+            Self::InvalidOption { chosen_option } => f.write_fmt(format_args!(
+                "'{chosen_option}' is not one of the available choices."
+            )),
+            Self::RequirementNotMet {
+                chosen_option,
+                requirement,
+            } => f.write_fmt(format_args!(
+                "'{chosen_option}' can only be voted for when '{requirement}'."
+            )),
+            Self::NoVotes => f.write_str("Nobody has voted yet."),
+            Self::ChoiceFailed(result) => result.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for VoteError {}
+
+/// A choice the player can currently take.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct ChoiceView {
     pub display_text: String,
     pub id: String,
 }
 
-#[derive(Serialize)]
+/// Everything a client needs to show the player where they are in the story.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct CurrentNodeView {
+    /// The id of the node the session is at, e.g. to key save data on. Left out for stories
+    /// with `META hide_node_ids "true"`, which consider it a spoiler.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// The session's [`Session::turns`] when the view was made.
+    pub turn: usize,
     pub display_text: String,
     pub choices: Vec<ChoiceView>,
     pub game_over: bool,
+    pub can_go_back: bool,
+    /// The session's [`Session::version`] when the view was made.
+    pub version: u64,
+    /// Every variable in the session, for debugging. Left out unless a caller fills it in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "openapi", schema(schema_with = variables_schema))]
+    pub variables: Option<HashMap<String, Value>>,
 }
 
-#[derive(Serialize)]
+/// The outcome of [`Engine::choose_option`]. Anything other than `Success` leaves the session
+/// as it was.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub enum ChoiceResult {
     Success,
+    /// No choice at the current node leads to `chosen_option`.
     InvalidOption {
         current_node_id: String,
         chosen_option: String,
     },
+    /// The choice is at the current node, but isn't offered because `requirement` is false.
+    RequirementNotMet {
+        current_node_id: String,
+        chosen_option: String,
+        requirement: String,
+    },
+    /// The session is at an ending, so there are no choices left to take.
+    GameAlreadyOver {
+        current_node_id: String,
+    },
+    /// The session was inactive for too long and has been removed. The engine never returns
+    /// this itself, since it doesn't keep track of sessions, but servers do.
+    SessionExpired,
+    /// The choice's command failed, e.g. a script raised an error.
+    RuntimeError {
+        message: String,
+    },
+}
+
+impl Display for ChoiceResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Success => f.write_str("The choice was taken."),
+            Self::InvalidOption {
+                current_node_id,
+                chosen_option,
+            } => f.write_fmt(format_args!(
+                "'{chosen_option}' is not a choice at the node with id '{current_node_id}'."
+            )),
+            Self::RequirementNotMet {
+                current_node_id,
+                chosen_option,
+                requirement,
+            } => f.write_fmt(format_args!(
+                "'{chosen_option}' can only be chosen at the node with id '{current_node_id}' when '{requirement}'."
+            )),
+            Self::GameAlreadyOver { current_node_id } => f.write_fmt(format_args!(
+                "The story has already ended at the node with id '{current_node_id}', so there are no choices to take."
+            )),
+            Self::SessionExpired => f.write_str("The session has expired."),
+            Self::RuntimeError { message } => {
+                f.write_fmt(format_args!("The choice could not be taken: {message}"))
+            }
+        }
+    }
+}
+
+/// Callbacks for what happens to the sessions playing a story, registered with
+/// [`Engine::add_observer`], e.g. to keep analytics or send notifications without wrapping every
+/// call to the engine. Every callback does nothing unless it is implemented.
+///
+/// ```
+/// use cyoa::{Engine, EngineObserver, Session};
+/// use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+///
+/// #[derive(Default)]
+/// struct Endings(AtomicUsize);
+///
+/// impl EngineObserver for Endings {
+///     fn on_game_over(&self, _session: &Session) {
+///         self.0.fetch_add(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// let mut story = Engine::from_program(r#"
+///     = START
+///         "You are in a dark room."
+///         "Open the door." -> outside
+///
+///     = outside
+///         "You are free."
+/// "#).unwrap();
+/// let endings = Arc::new(Endings::default());
+/// story.add_observer(endings.clone());
+///
+/// let mut session = story.new_session();
+/// story.choose_option(&mut session, "outside".to_string());
+/// assert_eq!(endings.0.load(Ordering::Relaxed), 1);
+/// ```
+pub trait EngineObserver: Send + Sync {
+    /// A session was started with [`Engine::new_session`] or [`Engine::new_seeded_session`].
+    fn on_session_created(&self, session: &Session) {
+        let _ = session;
+    }
+
+    /// A session arrived at the node it is now at, by starting, taking a choice, restarting,
+    /// loading a checkpoint or jumping there.
+    fn on_node_entered(&self, session: &Session, node_id: &str) {
+        let _ = (session, node_id);
+    }
+
+    /// A session took the choice leading to `choice_id`, before its command has run.
+    fn on_choice_taken(&self, session: &Session, choice_id: &str) {
+        let _ = (session, choice_id);
+    }
+
+    /// A choice's command, or [`Engine::set_variable`], changed one of a session's variables.
+    fn on_variable_changed(&self, session: &Session, name: &str, old: &Value, new: &Value) {
+        let _ = (session, name, old, new);
+    }
+
+    /// A session arrived at a node with no choices, so the story is over.
+    fn on_game_over(&self, session: &Session) {
+        let _ = session;
+    }
+}
+
+/// Fills in the `{@name|fallback}` segments of a story's text when it is shown, e.g. with a
+/// template engine, a procedural generator or a language model. Registered with
+/// [`Engine::set_text_provider`]. Text can be asked for more than once for the same visit, so
+/// providers that are slow or random should cache what they return for a session.
+///
+/// ```
+/// use cyoa::{Engine, Session, TextProvider};
+/// use std::sync::Arc;
+///
+/// struct Weather;
+///
+/// impl TextProvider for Weather {
+///     fn provide(&self, name: &str, _session: &Session) -> Option<String> {
+///         (name == "weather").then(|| "It is raining.".to_string())
+///     }
+/// }
+///
+/// let mut story = Engine::from_program(r#"
+///     = START
+///         "You step outside. {@weather|The sky is grey.}"
+/// "#).unwrap();
+/// let session = story.new_session();
+/// assert_eq!(story.get_current_node_view(&session).display_text, "You step outside. The sky is grey.");
+///
+/// story.set_text_provider(Arc::new(Weather));
+/// assert_eq!(story.get_current_node_view(&session).display_text, "You step outside. It is raining.");
+/// ```
+pub trait TextProvider: Send + Sync {
+    /// The text for the segment called `name`, or `None` to show its fallback.
+    fn provide(&self, name: &str, session: &Session) -> Option<String>;
 }
 
 /// Per-session mutable game state.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Session {
-    last_active_at: Instant,
-    variables: HashMap<String, Value>,
+    /// When the session was started. Unknown for sessions saved before this was recorded.
+    #[serde(default)]
+    created_at: Option<SystemTime>,
+    last_active_at: SystemTime,
+    variables: Variables,
     current_node_id: String,
+    /// The states before each of the most recent choices, oldest first.
+    #[serde(default)]
+    undo_stack: VecDeque<SessionSnapshot>,
+    /// The most recent things that have happened in this session, oldest first.
+    #[serde(default)]
+    transcript: Vec<HistoryEvent>,
+    /// How many events have been dropped from the front of the transcript to keep it short.
+    #[serde(default)]
+    dropped_events: usize,
+    /// Named save slots, kept in the order they were first saved.
+    #[serde(default)]
+    checkpoints: Vec<(String, SessionSnapshot)>,
+    /// The version of the story this session was last played against.
+    #[serde(default)]
+    story_version: u64,
+    /// The players sharing this session, who vote on each choice. Empty unless the session
+    /// is shared.
+    #[serde(default)]
+    participants: Vec<String>,
+    /// The votes cast so far as (participant, choice) pairs, oldest first.
+    #[serde(default)]
+    votes: Vec<(String, String)>,
+    /// How many events had been recorded when the votes were cast. Anything that moves the
+    /// story on records an event, which makes the votes stale.
+    #[serde(default)]
+    votes_round: usize,
+    /// A secret that has to be presented to change the session, if one has been set.
+    #[serde(default)]
+    secret: Option<String>,
+    /// Goes up by one every time the session's state changes.
+    #[serde(default)]
+    version: u64,
+    /// The most recent choices made with an idempotency key, as (key, choice, result), oldest
+    /// first.
+    #[serde(default)]
+    choice_keys: VecDeque<(String, String, ChoiceResult)>,
+    /// Which text variant the session is shown, if the story has any.
+    #[serde(default)]
+    variant: Option<String>,
+    /// How many choices have been taken, including ones later undone. Sessions saved before
+    /// this was kept count them from their transcript instead.
+    #[serde(default)]
+    turns: Option<usize>,
+    /// Choices taken before the session was restored from a snapshot, which its transcript
+    /// doesn't have. Only used by sessions without `turns`.
+    #[serde(default)]
+    earlier_turns: usize,
 }
 
 impl Session {
+    /// Whether the session has been inactive for at least the given number of hours.
     pub fn is_expired(&self, session_timeout_hours: f32) -> bool {
-        let hours = self.last_active_at.elapsed().as_secs_f32() / 60.0 / 60.0;
+        // A clock that has gone backwards counts as no time having passed.
+        let hours = now()
+            .duration_since(self.last_active_at)
+            .unwrap_or_default()
+            .as_secs_f32()
+            / 60.0
+            / 60.0;
 
         hours >= session_timeout_hours
     }
 
+    /// When the session was started, if that is known.
+    pub fn created_at(&self) -> Option<SystemTime> {
+        self.created_at
+    }
+
+    /// Which of the story's text variants the session was assigned to, if it has any.
+    pub fn variant(&self) -> Option<&str> {
+        self.variant.as_deref()
+    }
+
+    pub fn last_active_at(&self) -> SystemTime {
+        self.last_active_at
+    }
+
+    /// How many choices have been taken in this session, including ones later undone.
+    pub fn turns(&self) -> usize {
+        self.turns.unwrap_or_else(|| {
+            self.earlier_turns
+                + self
+                    .transcript
+                    .iter()
+                    .filter(|event| matches!(event, HistoryEvent::ChoiceTaken { .. }))
+                    .count()
+        })
+    }
+
+    /// How many events the session has recorded, including ones since dropped from its
+    /// transcript. Goes up whenever the story moves on.
+    pub fn events_recorded(&self) -> usize {
+        self.dropped_events + self.transcript.len()
+    }
+
+    /// Mark the session as active now, postponing its expiry.
     pub fn update_last_active_at(&mut self) {
-        self.last_active_at = Instant::now();
+        self.last_active_at = now();
+    }
+
+    /// Record when the session was last active, for stores that keep track of it apart from
+    /// the rest of the session.
+    pub fn set_last_active_at(&mut self, last_active_at: SystemTime) {
+        self.last_active_at = last_active_at;
+    }
+
+    /// A number that goes up every time the session moves on or its variables change, so a
+    /// client can tell whether the session has changed since it last looked.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Make the version go up for a change the engine doesn't count, e.g. a session being
+    /// written back by a server that has to tell its writes apart.
+    pub fn bump_version(&mut self) {
+        self.version += 1;
+    }
+
+    /// The id of the node the player is currently at.
+    pub fn current_node_id(&self) -> &str {
+        &self.current_node_id
+    }
+
+    /// The current value of one of the story's variables.
+    pub fn variable(&self, name: &str) -> Option<&Value> {
+        self.variables.get(name)
+    }
+
+    /// The current values of all of the story's variables, as name and value, sorted by name.
+    pub fn variables(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.variables.iter()
+    }
+
+    /// The players sharing this session, in the order they joined.
+    pub fn participants(&self) -> &[String] {
+        &self.participants
+    }
+
+    /// Whether the session is shared by players who vote on its choices.
+    pub fn is_shared(&self) -> bool {
+        !self.participants.is_empty()
+    }
+
+    /// Require a secret to be presented before the session is changed. The engine doesn't
+    /// check it itself; it is kept with the session for whatever is hosting it.
+    pub fn set_secret(&mut self, secret: impl Into<String>) {
+        self.secret = Some(secret.into());
+    }
+
+    /// Whether the given secret may change the session. Anything may if no secret is set.
+    pub fn check_secret(&self, secret: Option<&str>) -> bool {
+        let Some(expected) = &self.secret else {
+            return true;
+        };
+        let Some(secret) = secret else {
+            return false;
+        };
+        // Compare every byte, so how long the check takes doesn't give the secret away.
+        expected.len() == secret.len()
+            && expected
+                .bytes()
+                .zip(secret.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// The choice made with an idempotency key, and what came of it, if the key has been used
+    /// recently.
+    pub fn choice_for_key(&self, key: &str) -> Option<(&str, &ChoiceResult)> {
+        self.choice_keys
+            .iter()
+            .find(|(k, _, _)| k == key)
+            .map(|(_, choice, result)| (choice.as_str(), result))
+    }
+
+    /// Remember the choice made with an idempotency key, so a retry can be answered without
+    /// taking it again. Only the last few keys are kept.
+    pub fn remember_choice_key(&mut self, key: String, choice: String, result: ChoiceResult) {
+        if self.choice_keys.len() >= MAX_CHOICE_KEYS {
+            self.choice_keys.pop_front();
+        }
+        self.choice_keys.push_back((key, choice, result));
+    }
+
+    /// The votes cast on the current choice, as (participant, choice) pairs.
+    fn current_votes(&self) -> &[(String, String)] {
+        if self.votes_round == self.events_recorded() {
+            &self.votes
+        } else {
+            &[]
+        }
     }
 }
 
-/// Shared, immutable story data. Loaded once at startup and referenced by all sessions.
-pub struct Engine<'a> {
-    default_variables: HashMap<String, Value>,
-    all_nodes: HashMap<String, Node<'a>>,
+/// A single step in a session's playthrough. Timestamps are milliseconds since the Unix epoch.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum HistoryEvent {
+    NodeVisited {
+        node_id: String,
+        display_text: String,
+        timestamp: u64,
+    },
+    ChoiceTaken {
+        choice_id: String,
+        display_text: String,
+        timestamp: u64,
+    },
+    WentBack {
+        node_id: String,
+        timestamp: u64,
+    },
+    Restarted {
+        timestamp: u64,
+    },
+    CheckpointLoaded {
+        slot: String,
+        timestamp: u64,
+    },
+}
+
+/// The type of a value, worked out before a story is played.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Bool,
+    Int,
+    String,
+}
+
+impl Type {
+    fn of(value: &Value) -> Self {
+        match value {
+            Value::Bool(_) => Type::Bool,
+            Value::Int(_) => Type::Int,
+            Value::String(_) => Type::String,
+        }
+    }
+
+    /// The type as it is described to a story's author.
+    fn name(self) -> &'static str {
+        match self {
+            Type::Bool => "true or false",
+            Type::Int => "a whole number",
+            Type::String => "text",
+        }
+    }
+}
+
+/// Follow the variables `name` can interpolate, adding each loop met to `cycles`, turned to
+/// start with its smallest name and closed with it again.
+fn find_cycles<'a>(
+    name: &'a str,
+    edges: &BTreeMap<&'a str, BTreeSet<&'a str>>,
+    path: &mut Vec<&'a str>,
+    done: &mut BTreeSet<&'a str>,
+    cycles: &mut BTreeSet<Vec<String>>,
+) {
+    if let Some(start) = path.iter().position(|&other| other == name) {
+        let mut cycle = path[start..].to_vec();
+        let smallest = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap_or(0);
+        cycle.rotate_left(smallest);
+        cycle.push(cycle[0]);
+        cycles.insert(cycle.into_iter().map(str::to_string).collect());
+        return;
+    }
+    if done.contains(name) {
+        return;
+    }
+
+    path.push(name);
+    for &other in edges.get(name).into_iter().flatten() {
+        find_cycles(other, edges, path, done, cycles);
+    }
+    path.pop();
+    done.insert(name);
+}
+
+/// The names of the `{@name}` segments in a string that have no fallback.
+fn missing_fallbacks(text: &FormatString) -> impl Iterator<Item = &str> {
+    text.0.iter().filter_map(|part| match part {
+        FormatStringPart::Dynamic {
+            name,
+            fallback: None,
+        } => Some(name.as_str()),
+        _ => None,
+    })
 }
 
-impl<'a> Engine<'a> {
+/// The current time. `SystemTime::now` panics in the browser, so ask JavaScript there instead.
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+fn now() -> SystemTime {
+    UNIX_EPOCH + std::time::Duration::from_millis(js_sys::Date::now() as u64)
+}
+
+fn now_timestamp() -> u64 {
+    now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The part of a session worth saving: where the player is and what they've done.
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SessionSnapshot {
+    pub current_node_id: String,
+    #[cfg_attr(feature = "openapi", schema(schema_with = variables_schema))]
+    pub variables: HashMap<String, Value>,
+    /// The session's text variant, kept so a restored session sees the same phrasing. A
+    /// session restored without one is assigned a new one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// The session's [`Session::turns`], so a restored session carries on counting from there.
+    #[serde(default)]
+    pub turns: usize,
+}
+
+/// utoipa takes any type named `Value` to mean arbitrary JSON, so variables point at the
+/// schema for [`Value`] by hand.
+#[cfg(feature = "openapi")]
+fn variables_schema() -> utoipa::openapi::Object {
+    utoipa::openapi::ObjectBuilder::new()
+        .additional_properties(Some(utoipa::openapi::Ref::from_schema_name("Value")))
+        .build()
+}
+
+/// Shared, immutable story data, referenced by every session playing the story.
+pub struct Engine {
+    version: u64,
+    default_variables: Variables,
+    /// Every node's id, sorted, so that a node's index is its position here.
+    node_ids: Vec<String>,
+    /// The nodes as they were defined, by index.
+    nodes: Vec<Node>,
+    /// The nodes with their names resolved to indices, by index.
+    interned_nodes: Vec<interned::Node>,
+    /// Old node ids mapped to their new ids, so sessions from earlier versions of the story can be migrated.
+    renamed_nodes: HashMap<String, String>,
+    /// Information about the story from `META` lines, e.g. its title.
+    metadata: HashMap<String, String>,
+    history_depth: usize,
+    max_checkpoints: usize,
+    max_transcript: usize,
+    /// Counts sessions assigned a text variant, so variants can be handed out in turn.
+    next_variant: AtomicUsize,
+    #[cfg(feature = "scripting")]
+    scripts: scripting::Scripts,
+    #[cfg(feature = "plugins")]
+    plugins: Option<Arc<plugins::Plugins>>,
+    observers: Vec<Arc<dyn EngineObserver>>,
+    text_provider: Option<Arc<dyn TextProvider>>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    /// Create an engine with no nodes or variables. Most users want [`Engine::from_program`].
     pub fn new() -> Self {
         Engine {
-            default_variables: HashMap::new(),
-            all_nodes: HashMap::new(),
+            version: 0,
+            default_variables: Variables::new(HashMap::new()),
+            node_ids: Vec::new(),
+            nodes: Vec::new(),
+            interned_nodes: Vec::new(),
+            renamed_nodes: HashMap::new(),
+            metadata: HashMap::new(),
+            history_depth: 0,
+            max_checkpoints: 0,
+            max_transcript: usize::MAX,
+            next_variant: AtomicUsize::new(0),
+            #[cfg(feature = "scripting")]
+            scripts: scripting::Scripts::new("").expect("Failed to compile no scripts"),
+            #[cfg(feature = "plugins")]
+            plugins: None,
+            observers: Vec::new(),
+            text_provider: None,
         }
     }
 
+    /// Set how many choices each session can undo. Zero disables going back.
+    pub fn set_history_depth(&mut self, history_depth: usize) {
+        self.history_depth = history_depth;
+    }
+
+    /// Set how many checkpoints each session can hold. Zero disables checkpoints.
+    pub fn set_max_checkpoints(&mut self, max_checkpoints: usize) {
+        self.max_checkpoints = max_checkpoints;
+    }
+
+    /// Set how many events each session's transcript keeps, dropping the oldest ones beyond
+    /// that. Unlimited by default. Zero stops sessions recording a transcript at all.
+    pub fn set_max_transcript(&mut self, max_transcript: usize) {
+        self.max_transcript = max_transcript;
+    }
+
+    /// Give `SCRIPT` commands the functions defined in `source`, a Rhai script usually kept next
+    /// to the story.
+    #[cfg(feature = "scripting")]
+    pub fn set_scripts(&mut self, source: &str) -> Result<(), scripting::ScriptError> {
+        self.scripts = scripting::Scripts::new(source)?;
+        Ok(())
+    }
+
+    /// Call `observer` back whenever something happens to a session playing the story.
+    pub fn add_observer(&mut self, observer: Arc<dyn EngineObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Fill in `{@name|fallback}` segments with `provider`. Without one, every segment shows
+    /// its fallback, so the story reads the same every time.
+    pub fn set_text_provider(&mut self, provider: Arc<dyn TextProvider>) {
+        self.text_provider = Some(provider);
+    }
+
     /// Create a fresh session starting at the beginning of the story.
     pub fn new_session(&self) -> Session {
+        self.start_session(self.assign_variant())
+    }
+
+    /// Create a fresh session whose text variant is picked by `seed` rather than in turn, so
+    /// every session with the same seed sees the story phrased the same way.
+    pub fn new_seeded_session(&self, seed: u64) -> Session {
+        let variants = self.variants();
+        let variant = (!variants.is_empty())
+            .then(|| variants[(seed % variants.len() as u64) as usize].to_string());
+        self.start_session(variant)
+    }
+
+    fn start_session(&self, variant: Option<String>) -> Session {
+        let mut session = self.blank_session(variant);
+        for observer in &self.observers {
+            observer.on_session_created(&session);
+        }
+        self.enter_node(&mut session);
+
+        session
+    }
+
+    /// A session at `START` that nothing has been told about and that hasn't entered the node.
+    fn blank_session(&self, variant: Option<String>) -> Session {
         Session {
-            last_active_at: Instant::now(),
+            created_at: Some(now()),
+            last_active_at: now(),
             variables: self.default_variables.clone(),
             current_node_id: "START".to_string(),
+            undo_stack: VecDeque::new(),
+            transcript: Vec::new(),
+            dropped_events: 0,
+            checkpoints: Vec::new(),
+            story_version: self.version,
+            participants: Vec::new(),
+            votes: Vec::new(),
+            votes_round: 0,
+            secret: None,
+            version: 0,
+            choice_keys: VecDeque::new(),
+            variant,
+            turns: Some(0),
+            earlier_turns: 0,
+        }
+    }
+
+    /// Every text variant used in the story, starting with [`DEFAULT_VARIANT`], or none if no
+    /// node has a `[VARIANT ...]` line.
+    pub fn variants(&self) -> Vec<&str> {
+        let mut variants: Vec<&str> = self
+            .nodes
+            .iter()
+            .flat_map(|node| &node.variants)
+            .map(|variant| variant.name.as_str())
+            .filter(|name| *name != DEFAULT_VARIANT)
+            .collect();
+        if variants.is_empty() {
+            return variants;
+        }
+        variants.sort_unstable();
+        variants.dedup();
+        variants.insert(0, DEFAULT_VARIANT);
+
+        variants
+    }
+
+    /// The variant for a new session. Variants are handed out in turn, so each is shown to
+    /// about the same number of sessions.
+    fn assign_variant(&self) -> Option<String> {
+        let variants = self.variants();
+        if variants.is_empty() {
+            return None;
         }
+        let index = self.next_variant.fetch_add(1, Ordering::Relaxed) % variants.len();
+        Some(variants[index].to_string())
+    }
+
+    /// Which variant of a node's narration the session is shown: its own, if the node has
+    /// that variant, and otherwise [`DEFAULT_VARIANT`]. `None` if the node has no variants.
+    pub fn shown_variant(&self, node_id: &str, session: &Session) -> Option<&str> {
+        let node = &self.nodes[self.node_index(node_id)?];
+        if node.variants.is_empty() {
+            return None;
+        }
+        Some(
+            node.variants
+                .iter()
+                .find(|variant| session.variant.as_deref() == Some(variant.name.as_str()))
+                .map_or(DEFAULT_VARIANT, |variant| variant.name.as_str()),
+        )
+    }
+
+    /// The narration of a node in the session's variant.
+    fn node_text<'a>(&self, session: &Session, node: &'a interned::Node) -> &'a Text {
+        node.variants
+            .iter()
+            .find(|(name, _)| session.variant.as_deref() == Some(name.as_str()))
+            .map_or(&node.text, |(_, text)| text)
+    }
+
+    /// Send a session back to the beginning of the story with its variables reset.
+    /// The transcript and any checkpoints that still fit the story are kept, so the
+    /// earlier playthrough stays in the session's history.
+    pub fn restart_session(&self, session: &mut Session) {
+        session.variables = self.default_variables.clone();
+        session.current_node_id = "START".to_string();
+        session.undo_stack.clear();
+        session.checkpoints = mem::take(&mut session.checkpoints)
+            .into_iter()
+            .filter_map(|(slot, snapshot)| Some((slot, self.fit_snapshot(snapshot)?)))
+            .collect();
+        session.story_version = self.version;
+        self.record(
+            session,
+            HistoryEvent::Restarted {
+                timestamp: now_timestamp(),
+            },
+        );
+        self.enter_node(session);
+    }
+
+    /// Save the session's current state into a checkpoint slot, overwriting any
+    /// checkpoint already in that slot. If no slot is given, a numbered one is picked.
+    /// Returns the slot the checkpoint was saved in.
+    pub fn save_checkpoint(
+        &self,
+        session: &mut Session,
+        slot: Option<String>,
+    ) -> Result<String, CheckpointError> {
+        let slot = slot.unwrap_or_else(|| {
+            (1..)
+                .map(|i| i.to_string())
+                .find(|slot| !session.checkpoints.iter().any(|(s, _)| s == slot))
+                .unwrap()
+        });
+        let snapshot = self.snapshot_session(session);
+
+        if let Some((_, existing)) = session.checkpoints.iter_mut().find(|(s, _)| *s == slot) {
+            *existing = snapshot;
+        } else if session.checkpoints.len() >= self.max_checkpoints {
+            return Err(CheckpointError::TooManyCheckpoints {
+                max_checkpoints: self.max_checkpoints,
+            });
+        } else {
+            session.checkpoints.push((slot.clone(), snapshot));
+        }
+
+        Ok(slot)
+    }
+
+    /// Return a session to the state saved in a checkpoint slot. The undo history
+    /// is cleared, since it belongs to the path the player is leaving.
+    pub fn load_checkpoint(
+        &self,
+        session: &mut Session,
+        slot: &str,
+    ) -> Result<(), CheckpointError> {
+        let (_, snapshot) = session
+            .checkpoints
+            .iter()
+            .find(|(s, _)| s == slot)
+            .ok_or_else(|| CheckpointError::UnknownSlot {
+                slot: slot.to_string(),
+            })?;
+
+        session.current_node_id = snapshot.current_node_id.clone();
+        session.variables = self.variables_from(&snapshot.variables);
+        session.undo_stack.clear();
+        self.record(
+            session,
+            HistoryEvent::CheckpointLoaded {
+                slot: slot.to_string(),
+                timestamp: now_timestamp(),
+            },
+        );
+        self.enter_node(session);
+
+        Ok(())
+    }
+
+    /// What has happened in a session so far, oldest first. Only the most recent events are
+    /// kept; see [`Engine::set_max_transcript`].
+    pub fn history<'b>(&self, session: &'b Session) -> &'b [HistoryEvent] {
+        &session.transcript
+    }
+
+    /// Add an event to a session's transcript, dropping the oldest ones if it is full.
+    fn record(&self, session: &mut Session, event: HistoryEvent) {
+        session.transcript.push(event);
+        let excess = session.transcript.len().saturating_sub(self.max_transcript);
+        session.transcript.drain(..excess);
+        session.dropped_events += excess;
+    }
+
+    /// Record a session arriving at its current node, and let observers know.
+    fn enter_node(&self, session: &mut Session) {
+        self.record_node_visit(session);
+        for observer in &self.observers {
+            observer.on_node_entered(session, &session.current_node_id);
+        }
+        if self.is_game_over(session) {
+            for observer in &self.observers {
+                observer.on_game_over(session);
+            }
+        }
+    }
+
+    /// Anything that moves a session to a node ends here, so this is also where its version
+    /// goes up.
+    fn record_node_visit(&self, session: &mut Session) {
+        session.version += 1;
+        let display_text =
+            self.render(session, self.node_text(session, self.current_node(session)));
+        self.record(
+            session,
+            HistoryEvent::NodeVisited {
+                node_id: session.current_node_id.clone(),
+                display_text,
+                timestamp: now_timestamp(),
+            },
+        );
+    }
+
+    /// Identifies the story source this engine was built from, so sessions from
+    /// an older version of the story can be detected.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// A value from one of the story's `META` lines, e.g. `metadata("title")`.
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+
+    /// Every `META` line in the story, as key and value, in no particular order.
+    pub fn all_metadata(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Whether a session was last played against this version of the story.
+    pub fn session_is_current(&self, session: &Session) -> bool {
+        session.story_version == self.version
+    }
+
+    /// Fill in a session loaded from storage with the story's default values for the
+    /// variables it didn't change, which aren't saved. Sessions that haven't been rebased can
+    /// still be played, but look their variables up by name.
+    pub fn rebase_session(&self, session: &mut Session) {
+        session.variables.rebase(&self.default_variables);
+    }
+
+    /// Bring a snapshot in line with this story: renamed nodes are followed, variables that
+    /// no longer exist or have changed type are dropped, and new variables take their
+    /// default values. Returns `None` if the snapshot is at a node that no longer exists.
+    fn fit_snapshot(&self, snapshot: SessionSnapshot) -> Option<SessionSnapshot> {
+        let current_node_id = if self.has_node(&snapshot.current_node_id) {
+            snapshot.current_node_id
+        } else {
+            self.renamed_nodes.get(&snapshot.current_node_id)?.clone()
+        };
+
+        let mut variables = self.default_variables.clone();
+        for (name, value) in snapshot.variables {
+            if variables
+                .get(&name)
+                .is_some_and(|var| mem::discriminant(var) == mem::discriminant(&value))
+            {
+                variables.set(&name, value);
+            }
+        }
+
+        Some(SessionSnapshot {
+            current_node_id,
+            variables: variables.to_map(),
+            variant: snapshot.variant,
+            turns: snapshot.turns,
+        })
+    }
+
+    /// Carry a session played against an older version of this story over to this one.
+    /// Undo history and checkpoints that no longer fit the story are dropped.
+    pub fn migrate_session(&self, mut session: Session) -> Result<Session, RestoreError> {
+        let snapshot = self
+            .fit_snapshot(self.snapshot_session(&session))
+            .ok_or_else(|| RestoreError::UnknownNode {
+                node_id: session.current_node_id.clone(),
+            })?;
+        session.current_node_id = snapshot.current_node_id;
+        session.variables = self.variables_from(&snapshot.variables);
+        session.undo_stack = mem::take(&mut session.undo_stack)
+            .into_iter()
+            .filter_map(|snapshot| self.fit_snapshot(snapshot))
+            .collect();
+        session.checkpoints = mem::take(&mut session.checkpoints)
+            .into_iter()
+            .filter_map(|(slot, snapshot)| Some((slot, self.fit_snapshot(snapshot)?)))
+            .collect();
+        session.story_version = self.version;
+        session.version += 1;
+
+        Ok(session)
+    }
+
+    /// Capture a session's progress so it can be saved and restored later.
+    pub fn snapshot_session(&self, session: &Session) -> SessionSnapshot {
+        SessionSnapshot {
+            current_node_id: session.current_node_id.clone(),
+            variables: session.variables.to_map(),
+            variant: session.variant.clone(),
+            turns: session.turns(),
+        }
+    }
+
+    /// Rebuild a session from a snapshot, checking that it still fits this story.
+    /// Variables missing from the snapshot fall back to their default values.
+    pub fn restore_session(&self, snapshot: SessionSnapshot) -> Result<Session, RestoreError> {
+        if !self.has_node(&snapshot.current_node_id) {
+            return Err(RestoreError::UnknownNode {
+                node_id: snapshot.current_node_id,
+            });
+        }
+
+        let mut variables = self.default_variables.clone();
+        for (name, value) in snapshot.variables {
+            match variables.get(&name) {
+                Some(var) if mem::discriminant(var) == mem::discriminant(&value) => {
+                    variables.set(&name, value);
+                }
+                Some(_) => return Err(RestoreError::MismatchedVariableType { name }),
+                None => return Err(RestoreError::UnknownVariable { name }),
+            }
+        }
+
+        let variant = match snapshot.variant {
+            Some(variant) if self.variants().contains(&variant.as_str()) => Some(variant),
+            _ => self.assign_variant(),
+        };
+        let mut session = Session {
+            created_at: Some(now()),
+            last_active_at: now(),
+            variables,
+            current_node_id: snapshot.current_node_id,
+            undo_stack: VecDeque::new(),
+            transcript: Vec::new(),
+            dropped_events: 0,
+            checkpoints: Vec::new(),
+            story_version: self.version,
+            participants: Vec::new(),
+            votes: Vec::new(),
+            votes_round: 0,
+            secret: None,
+            version: 0,
+            choice_keys: VecDeque::new(),
+            variant,
+            turns: Some(snapshot.turns),
+            earlier_turns: 0,
+        };
+        self.record_node_visit(&mut session);
+
+        Ok(session)
     }
 
     fn bad_names_in_string(&self, s: &FormatString) -> Vec<String> {
         let mut bad_names = Vec::new();
         for part in &s.0 {
-            if let FormatStringPart::Name(name) = part {
-                if !self.default_variables.contains_key(name) {
-                    bad_names.push(name.to_string());
-                }
+            if let FormatStringPart::Name(name) = part
+                && !self.default_variables.contains(name)
+            {
+                bad_names.push(name.to_string());
             }
         }
         bad_names
@@ -130,7 +1445,7 @@ impl<'a> Engine<'a> {
         match expr {
             Expression::Value(_) => {}
             Expression::Name(name) => {
-                if !self.default_variables.contains_key(name) {
+                if !self.default_variables.contains(name) {
                     bad_names.push(name.to_string());
                 }
             }
@@ -141,38 +1456,64 @@ impl<'a> Engine<'a> {
                 bad_names.extend(self.bad_names_in_expression(left));
                 bad_names.extend(self.bad_names_in_expression(right));
             }
+            Expression::Call { args, .. } => {
+                for arg in args {
+                    bad_names.extend(self.bad_names_in_expression(arg));
+                }
+            }
         }
         bad_names
     }
 
-    fn expression_is_valid(&self, expr: &Expression) -> bool {
+    /// The type of an expression's value, adding an error to `errors` for each part of it used
+    /// as a type it can't have. `None` if it can't be known before the story is played, as for
+    /// what a function returns or a variable that doesn't exist.
+    fn expression_type(
+        &self,
+        expr: &Expression,
+        parent_node_id: &str,
+        errors: &mut Vec<ParseError>,
+    ) -> Option<Type> {
+        let expect = |expected: Type,
+                      found: Option<Type>,
+                      location: &Expression,
+                      errors: &mut Vec<ParseError>| {
+            if let Some(found) = found
+                && found != expected
+            {
+                errors.push(ParseError::TypeError {
+                    parent_node_id: parent_node_id.to_string(),
+                    expected: expected.name(),
+                    found: found.name(),
+                    location: location.clone(),
+                });
+            }
+        };
+
         match expr {
-            Expression::Value(_) => true,
-            Expression::Name(name) => self.default_variables.contains_key(name),
+            Expression::Value(value) => Some(Type::of(value)),
+            Expression::Name(name) => self.default_variables.get(name).map(Type::of),
+            // Values of different types are never equal, so both sides must be of one type.
             Expression::Equals { left, right } | Expression::NotEquals { left, right } => {
-                self.expression_is_valid(left) && self.expression_is_valid(right)
+                let left_type = self.expression_type(left, parent_node_id, errors);
+                let right_type = self.expression_type(right, parent_node_id, errors);
+                if let Some(left_type) = left_type {
+                    expect(left_type, right_type, right, errors);
+                }
+                Some(Type::Bool)
             }
             Expression::GreaterThan { left, right } | Expression::LessThan { left, right } => {
-                let left_is_int = if let Expression::Value(Value::Int(_)) = left {
-                    true
-                } else if let Expression::Name(name) = left {
-                    matches!(self.default_variables.get(name), Some(Value::Int(_)))
-                } else {
-                    false
-                };
-                let right_is_int = if let Expression::Value(Value::Int(_)) = right {
-                    true
-                } else if let Expression::Name(name) = right {
-                    matches!(self.default_variables.get(name), Some(Value::Int(_)))
-                } else {
-                    false
-                };
-
-                if left_is_int && right_is_int {
-                    self.expression_is_valid(left) && self.expression_is_valid(right)
-                } else {
-                    false
+                let left_type = self.expression_type(left, parent_node_id, errors);
+                let right_type = self.expression_type(right, parent_node_id, errors);
+                expect(Type::Int, left_type, left, errors);
+                expect(Type::Int, right_type, right, errors);
+                Some(Type::Bool)
+            }
+            Expression::Call { args, .. } => {
+                for arg in args {
+                    self.expression_type(arg, parent_node_id, errors);
                 }
+                None
             }
         }
     }
@@ -181,13 +1522,19 @@ impl<'a> Engine<'a> {
         let mut bad_names = Vec::new();
         match command {
             Command::Set { name, value } => {
-                if !self.default_variables.contains_key(*name) {
+                if !self.default_variables.contains(name) {
                     bad_names.push(name.to_string());
                 }
                 if let Value::String(s) = value {
                     bad_names.extend(self.bad_names_in_string(s));
                 }
             }
+            Command::Script { .. } => {}
+            Command::Call { args, .. } => {
+                for arg in args {
+                    bad_names.extend(self.bad_names_in_expression(arg));
+                }
+            }
         }
         bad_names
     }
@@ -195,29 +1542,160 @@ impl<'a> Engine<'a> {
     fn command_is_valid(&self, command: &Command) -> bool {
         match command {
             Command::Set { name, value } => {
-                self.default_variables.contains_key(*name)
+                self.default_variables.contains(name)
                     && match value {
                         Value::Int(_) | Value::Bool(_) => true,
                         Value::String(s) => self.bad_names_in_string(s).is_empty(),
                     }
             }
+            Command::Script { .. } => true,
+            Command::Call { .. } => true,
         }
     }
 
-    fn errors(&self) -> Vec<ParseError<'a>> {
+    /// Whether a plugin provides the function called `name`.
+    fn has_function(&self, name: &str) -> bool {
+        #[cfg(feature = "plugins")]
+        return self
+            .plugins
+            .as_ref()
+            .is_some_and(|plugins| plugins.provides(name));
+        #[cfg(not(feature = "plugins"))]
+        {
+            let _ = name;
+            false
+        }
+    }
+
+    /// Calls to functions that no plugin provides.
+    fn function_errors(&self) -> Vec<ParseError> {
+        fn calls<'a>(expression: &'a Expression, names: &mut Vec<&'a str>) {
+            match expression {
+                Expression::Value(_) | Expression::Name(_) => {}
+                Expression::Equals { left, right }
+                | Expression::NotEquals { left, right }
+                | Expression::GreaterThan { left, right }
+                | Expression::LessThan { left, right } => {
+                    calls(left, names);
+                    calls(right, names);
+                }
+                Expression::Call { name, args } => {
+                    names.push(name);
+                    for arg in args {
+                        calls(arg, names);
+                    }
+                }
+            }
+        }
+
         let mut errors = Vec::new();
+        for (id, node) in self.nodes() {
+            for choice in &node.choices {
+                let mut names = Vec::new();
+                if let Some(requirement) = &choice.requirement {
+                    calls(requirement, &mut names);
+                }
+                if let Some(Command::Call { name, args }) = &choice.command {
+                    names.push(name);
+                    for arg in args {
+                        calls(arg, &mut names);
+                    }
+                }
+                for name in names {
+                    if !self.has_function(name) {
+                        errors.push(ParseError::UnknownFunction {
+                            parent_node_id: id.to_string(),
+                            name: name.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+        errors
+    }
+
+    /// Why a `SCRIPT` command can't be run, if it can't.
+    fn script_error(&self, script: &str) -> Option<String> {
+        #[cfg(feature = "scripting")]
+        return self.scripts.check(script).err().map(|e| e.to_string());
+        #[cfg(not(feature = "scripting"))]
+        {
+            let _ = script;
+            Some("cyoa was built without the `scripting` feature".to_string())
+        }
+    }
+
+    /// Every loop of variables whose values can interpolate each other, going by their default
+    /// values and every value they are `SET` to. Each loop starts with its smallest name.
+    fn interpolation_cycles(&self) -> Vec<Vec<String>> {
+        let mut edges: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        let sets = self
+            .nodes()
+            .flat_map(|(_, node)| &node.choices)
+            .filter_map(|choice| match &choice.command {
+                Some(Command::Set { name, value }) => Some((name.as_str(), value)),
+                _ => None,
+            });
+        for (name, value) in self.default_variables.iter().chain(sets) {
+            if let Value::String(s) = value {
+                for part in &s.0 {
+                    if let FormatStringPart::Name(other) = part {
+                        edges.entry(name).or_default().insert(other);
+                    }
+                }
+            }
+        }
+
+        let mut cycles = BTreeSet::new();
+        let mut done = BTreeSet::new();
+        let mut path = Vec::new();
+        for &name in edges.keys() {
+            find_cycles(name, &edges, &mut path, &mut done, &mut cycles);
+        }
+        cycles.into_iter().collect()
+    }
 
-        if !self.all_nodes.contains_key("START") {
+    fn errors(&self) -> Vec<ParseError> {
+        let mut errors = Vec::new();
+
+        if !self.has_node("START") {
             errors.push(ParseError::MissingStartNode);
         }
 
-        for (id, node) in self.all_nodes.iter() {
-            for name in self.bad_names_in_string(&node.display_text) {
+        for names in self.interpolation_cycles() {
+            errors.push(ParseError::InterpolationCycle { names });
+        }
+
+        for (old_id, new_id) in self.renamed_nodes.iter() {
+            if !self.has_node(new_id) {
+                errors.push(ParseError::BadReferenceInRename {
+                    old_id: old_id.to_string(),
+                    new_id: new_id.to_string(),
+                });
+            }
+        }
+
+        for (id, node) in self.nodes() {
+            let texts = std::iter::once(&node.display_text)
+                .chain(node.variants.iter().map(|variant| &variant.text));
+            for name in texts
+                .clone()
+                .flat_map(|text| self.bad_names_in_string(text))
+            {
                 errors.push(ParseError::BadReferenceInString {
                     parent_node_id: id.to_string(),
                     bad_name: name,
                 });
             }
+            for name in texts
+                .chain(node.choices.iter().map(|choice| &choice.text))
+                .flat_map(missing_fallbacks)
+            {
+                errors.push(ParseError::MissingFallback {
+                    parent_node_id: id.to_string(),
+                    name: name.to_string(),
+                });
+            }
 
             for choice in &node.choices {
                 for name in self.bad_names_in_string(&choice.text) {
@@ -228,7 +1706,7 @@ impl<'a> Engine<'a> {
                 }
 
                 let next_node_id = &choice.next_node_id;
-                if !self.all_nodes.contains_key(next_node_id.as_str()) {
+                if !self.has_node(next_node_id) {
                     errors.push(ParseError::BadReferenceInOption {
                         parent_node_id: id.to_string(),
                         bad_id: next_node_id.to_string(),
@@ -243,101 +1721,389 @@ impl<'a> Engine<'a> {
                         });
                     }
 
-                    if !self.expression_is_valid(requirement) {
-                        errors.push(ParseError::InvalidExpression {
-                            parent_node_id: id.to_string(),
-                            expression: requirement.clone(),
-                        });
-                    }
-                }
+                    self.expression_type(requirement, id, &mut errors);
+                }
+
+                if let Some(command) = choice.command.as_ref() {
+                    for name in self.bad_names_in_command(command) {
+                        errors.push(ParseError::BadReferenceInCommand {
+                            parent_node_id: id.to_string(),
+                            bad_name: name,
+                        });
+                    }
+
+                    if !self.command_is_valid(command) {
+                        errors.push(ParseError::InvalidCommand {
+                            parent_node_id: id.to_string(),
+                            command: command.clone(),
+                        });
+                    }
+
+                    if let Command::Set { name, value } = command
+                        && let Some(default) = self.default_variables.get(name)
+                        && Type::of(default) != Type::of(value)
+                    {
+                        errors.push(ParseError::TypeMismatch {
+                            parent_node_id: id.to_string(),
+                            name: name.to_string(),
+                            expected: Type::of(default).name(),
+                            found: Type::of(value).name(),
+                        });
+                    }
+
+                    if let Command::Call { args, .. } = command {
+                        for arg in args {
+                            self.expression_type(arg, id, &mut errors);
+                        }
+                    }
+
+                    if let Command::Script { source } = command
+                        && let Some(message) = self.script_error(source)
+                    {
+                        errors.push(ParseError::InvalidScript {
+                            parent_node_id: id.to_string(),
+                            script: source.to_string(),
+                            message,
+                        });
+                    }
+                }
+            }
+        }
+
+        errors
+    }
+
+    /// Build an engine from the source of a `.cyoa` story, checking it for errors.
+    pub fn from_program(source: &str) -> Result<Self, Vec<ParseError>> {
+        Self::unchecked_from_program(source).checked()
+    }
+
+    /// Build an engine from a `.cyoa` story as it is read, a definition at a time, checking it
+    /// for errors. Only the definition being read is held as text, so loading a huge story file
+    /// needs little more memory than the story itself. The engine is the same as one built by
+    /// [`Engine::from_program`] from the whole source, except that a story that can't be parsed
+    /// to the end fails to load, rather than ending where it stopped being parsed.
+    pub fn from_reader(reader: impl BufRead) -> Result<Self, ReadStoryError> {
+        Self::unchecked_from_reader(reader)?
+            .checked()
+            .map_err(ReadStoryError::Invalid)
+    }
+
+    /// Build an engine from a story's definitions, e.g. ones loaded from the JSON story format,
+    /// checking it for errors.
+    pub fn from_parts(parts: &[ProgramPart]) -> Result<Self, Vec<ParseError>> {
+        let mut hasher = DefaultHasher::new();
+        print_program(parts).hash(&mut hasher);
+        Self::assemble(parts, hasher.finish()).checked()
+    }
+
+    /// Like [`Engine::from_program`], for stories that call functions from `plugins`.
+    #[cfg(feature = "plugins")]
+    pub fn from_program_with_plugins(
+        source: &str,
+        plugins: Arc<plugins::Plugins>,
+    ) -> Result<Self, Vec<ParseError>> {
+        let mut engine = Self::unchecked_from_program(source);
+        engine.plugins = Some(plugins);
+        engine.checked()
+    }
+
+    /// Like [`Engine::from_reader`], for stories that call functions from `plugins`.
+    #[cfg(feature = "plugins")]
+    pub fn from_reader_with_plugins(
+        reader: impl BufRead,
+        plugins: Arc<plugins::Plugins>,
+    ) -> Result<Self, ReadStoryError> {
+        let mut engine = Self::unchecked_from_reader(reader)?;
+        engine.plugins = Some(plugins);
+        engine.checked().map_err(ReadStoryError::Invalid)
+    }
+
+    /// Like [`Engine::from_parts`], for stories that call functions from `plugins`.
+    #[cfg(feature = "plugins")]
+    pub fn from_parts_with_plugins(
+        parts: &[ProgramPart],
+        plugins: Arc<plugins::Plugins>,
+    ) -> Result<Self, Vec<ParseError>> {
+        let mut hasher = DefaultHasher::new();
+        print_program(parts).hash(&mut hasher);
+        let mut engine = Self::assemble(parts, hasher.finish());
+        engine.plugins = Some(plugins);
+        engine.checked()
+    }
+
+    /// Give a compiled story the plugins it calls functions from. Fails if the story calls a
+    /// function none of them provide.
+    #[cfg(feature = "plugins")]
+    pub fn set_plugins(&mut self, plugins: Arc<plugins::Plugins>) -> Result<(), Vec<ParseError>> {
+        self.plugins = Some(plugins);
+        let errors = self.function_errors();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn checked(self) -> Result<Self, Vec<ParseError>> {
+        let mut errors = self.errors();
+        errors.extend(self.function_errors());
+        if errors.is_empty() {
+            Ok(self)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// An engine holding a story's definitions, not yet checked for errors.
+    fn assemble(parts: &[ProgramPart], version: u64) -> Self {
+        let mut definitions = Definitions::default();
+        for part in parts {
+            definitions.add(part.clone());
+        }
+        definitions.into_engine(version)
+    }
 
-                if let Some(command) = choice.command.as_ref() {
-                    for name in self.bad_names_in_command(command) {
-                        errors.push(ParseError::BadReferenceInCommand {
-                            parent_node_id: id.to_string(),
-                            bad_name: name,
-                        });
-                    }
+    fn unchecked_from_program(source: &str) -> Self {
+        let mut definitions = Definitions::default();
+        parse_program_with(source, |part| definitions.add(part));
+        let mut hasher = DefaultHasher::new();
+        source.hash(&mut hasher);
+        definitions.into_engine(hasher.finish())
+    }
 
-                    if !self.command_is_valid(command) {
-                        errors.push(ParseError::InvalidCommand {
-                            parent_node_id: id.to_string(),
-                            command: command.clone(),
-                        });
-                    }
+    fn unchecked_from_reader(mut reader: impl BufRead) -> Result<Self, ReadStoryError> {
+        let mut definitions = Definitions::default();
+        // Hashed as it is read, to the same version as hashing the whole source would give.
+        let mut hasher = DefaultHasher::new();
+        // The text read since the last definition that was parsed, and the line it starts on.
+        let mut pending = String::new();
+        let mut pending_line = 1;
+        // Strings can't contain quotes, so a line that looks like the start of a definition is
+        // only part of a string that spans lines if a quote before it is still open.
+        let mut in_string = false;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let finished = reader.read_line(&mut line).map_err(ReadStoryError::Io)? == 0;
+            if finished || (starts_definition(&line) && !in_string) {
+                let rest = parse_program_with(&pending, |part| definitions.add(part));
+                if !rest.trim().is_empty() {
+                    let parsed = &pending[..pending.len() - rest.trim_start().len()];
+                    return Err(ReadStoryError::Unparsable {
+                        line: pending_line + parsed.matches('\n').count(),
+                    });
                 }
+                pending_line += pending.matches('\n').count();
+                pending.clear();
+            }
+            if finished {
+                break;
             }
+            hasher.write(line.as_bytes());
+            in_string ^= line.matches('"').count() % 2 == 1;
+            pending.push_str(&line);
         }
+        hasher.write_u8(0xff);
 
-        errors
+        Ok(definitions.into_engine(hasher.finish()))
     }
 
-    pub fn from_program(source: &'a str) -> Result<Self, Vec<ParseError<'a>>> {
-        let (_, parts) = parse_program(source).expect("Failed to parse nodes");
-        let variable_defs: Vec<_> = parts
-            .iter()
-            .filter(|part| matches!(part, ProgramPart::VariableDefinition { .. }))
-            .collect();
-        let node_defs: Vec<_> = parts
-            .iter()
-            .filter(|part| matches!(part, ProgramPart::NodeDefinition { .. }))
-            .collect();
+    /// Save the story in the compiled format, which [`Engine::from_compiled`] can load without
+    /// parsing or checking it again. Settings like the history depth aren't saved.
+    pub fn compile(&self) -> Vec<u8> {
+        let story = CompiledStory {
+            version: self.version,
+            default_variables: self.default_variables.to_map(),
+            all_nodes: self
+                .nodes()
+                .map(|(id, node)| (id.to_string(), node.clone()))
+                .collect(),
+            renamed_nodes: self.renamed_nodes.clone(),
+            metadata: self.metadata.clone(),
+        };
+        let mut bytes = COMPILED_MAGIC.to_vec();
+        bytes.push(COMPILED_FORMAT);
+        postcard::to_extend(&story, bytes).expect("Failed to compile story")
+    }
 
-        let mut engine = Engine::new();
-        for var_def in variable_defs {
-            if let ProgramPart::VariableDefinition { name, value } = var_def {
-                engine
-                    .default_variables
-                    .insert(name.to_string(), value.clone());
-            } else {
-                unreachable!()
-            };
-        }
-        for node_def in node_defs {
-            if let ProgramPart::NodeDefinition { id, node } = node_def {
-                engine.add_node(id.to_string(), node.clone());
-            } else {
-                unreachable!()
-            };
+    /// Load a story saved by [`Engine::compile`]. The story is checked again, since the file
+    /// could have been changed since, but functions it calls are only checked once plugins are
+    /// given to it with `set_plugins`.
+    pub fn from_compiled(bytes: &[u8]) -> Result<Self, CompiledStoryError> {
+        let bytes = bytes
+            .strip_prefix(COMPILED_MAGIC)
+            .ok_or(CompiledStoryError::NotCompiled)?;
+        let (&format, bytes) = bytes.split_first().ok_or(CompiledStoryError::NotCompiled)?;
+        if format != COMPILED_FORMAT {
+            return Err(CompiledStoryError::UnsupportedFormat { format });
         }
+        let story: CompiledStory =
+            postcard::from_bytes(bytes).map_err(|e| CompiledStoryError::Corrupt {
+                message: e.to_string(),
+            })?;
 
+        let mut engine = Engine {
+            version: story.version,
+            renamed_nodes: story.renamed_nodes,
+            metadata: story.metadata,
+            ..Engine::new()
+        };
+        engine.set_story(story.default_variables, story.all_nodes);
         let errors = engine.errors();
         if errors.is_empty() {
             Ok(engine)
         } else {
-            Err(errors)
+            Err(CompiledStoryError::Invalid(errors))
         }
     }
 
-    pub fn add_node(&mut self, id: String, node: Node<'a>) {
-        self.all_nodes.insert(id, node);
+    /// Add a node to the story, replacing any node with the same id. Every node is resolved
+    /// again, so whole stories are better built with [`Engine::from_program`] or
+    /// [`StoryBuilder`](crate::StoryBuilder).
+    pub fn add_node(&mut self, id: String, node: Node) {
+        let variables = self.default_variables.to_map();
+        let mut nodes: HashMap<_, _> = mem::take(&mut self.node_ids)
+            .into_iter()
+            .zip(mem::take(&mut self.nodes))
+            .collect();
+        nodes.insert(id, node);
+        self.set_story(variables, nodes);
+    }
+
+    /// Replace the story's variables and nodes, resolving the names they use to indices.
+    fn set_story(&mut self, variables: HashMap<String, Value>, nodes: HashMap<String, Node>) {
+        let mut nodes: Vec<_> = nodes.into_iter().collect();
+        nodes.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let (node_ids, nodes): (Vec<_>, Vec<_>) = nodes.into_iter().unzip();
+        self.default_variables = Variables::new(variables);
+        let interner = Interner {
+            variables: &self.default_variables,
+        };
+        self.interned_nodes = nodes.iter().map(|node| interner.node(node)).collect();
+        self.node_ids = node_ids;
+        self.nodes = nodes;
+    }
+
+    /// The index of the node with the given id.
+    fn node_index(&self, node_id: &str) -> Option<usize> {
+        self.node_ids
+            .binary_search_by(|id| id.as_str().cmp(node_id))
+            .ok()
+    }
+
+    /// A set of variables with the story's defaults overwritten by `variables`, e.g. from a
+    /// snapshot.
+    fn variables_from(&self, variables: &HashMap<String, Value>) -> Variables {
+        let mut result = self.default_variables.clone();
+        result.update(variables.clone());
+        result
     }
 
-    fn value_to_string(&self, session: &Session, value: &Value) -> String {
+    #[cfg(feature = "python")]
+    pub(crate) fn value_to_string(&self, session: &Session, value: &Value) -> String {
+        let mut out = Output::new(0);
+        self.write_value(session, value, &mut out);
+        out.text
+    }
+
+    fn write_value(&self, session: &Session, value: &Value, out: &mut Output) {
         match value {
-            Value::Int(i) => i.to_string(),
-            Value::Bool(b) => b.to_string(),
-            Value::String(s) => self.evaluate_string(session, s),
+            Value::Int(i) => write!(out, "{i}").unwrap(),
+            Value::Bool(b) => write!(out, "{b}").unwrap(),
+            Value::String(s) => self.write_string(session, s, out),
+        }
+    }
+
+    /// Fill in a node's or choice's text for a session.
+    fn render(&self, session: &Session, text: &Text) -> String {
+        self.render_checked(session, text, &mut None)
+    }
+
+    /// Like [`Engine::render`], also keeping the first problem met in `error` if it has none
+    /// yet.
+    fn render_checked(
+        &self,
+        session: &Session,
+        text: &Text,
+        error: &mut Option<EvalError>,
+    ) -> String {
+        // Text without anything to fill in is copied as it is.
+        if let Some(s) = text.as_literal() {
+            return s.to_string();
+        }
+        let mut out = Output::new(text.literal_len());
+        for part in &text.0 {
+            match part {
+                TextPart::Literal(s) => out.push(s),
+                TextPart::Variable(index) => {
+                    match session.variables.at(*index, &self.default_variables) {
+                        Ok(value) => self.write_value(session, value, &mut out),
+                        // What can't be filled in is shown as it was written.
+                        Err(e) => {
+                            let name = self.default_variables.name_at(*index);
+                            write!(out, "{{{name}}}").unwrap();
+                            out.fail(e);
+                        }
+                    }
+                }
+                TextPart::Dynamic { name, fallback } => {
+                    self.write_dynamic(session, name, fallback, &mut out)
+                }
+            }
+        }
+        if error.is_none() {
+            *error = out.error;
         }
+
+        out.text
+    }
+
+    /// Append the text a [`TextProvider`] gives for `name`, or `fallback`.
+    fn write_dynamic(&self, session: &Session, name: &str, fallback: &str, out: &mut Output) {
+        let text = self
+            .text_provider
+            .as_ref()
+            .and_then(|provider| provider.provide(name, session));
+        out.push(text.as_deref().unwrap_or(fallback));
     }
 
     fn evaluate_string(&self, session: &Session, input: &FormatString) -> String {
-        let mut result = String::new();
+        let mut out = Output::new(0);
+        self.write_string(session, input, &mut out);
+        out.text
+    }
+
+    fn write_string(&self, session: &Session, input: &FormatString, out: &mut Output) {
         for part in &input.0 {
+            if out.full {
+                return;
+            }
             match part {
-                FormatStringPart::Literal(s) => result.push_str(s),
-                FormatStringPart::Name(name) => {
-                    let var_value = session
-                        .variables
-                        .get(name)
-                        .map(|v| self.value_to_string(session, v))
-                        .unwrap();
-                    result.push_str(var_value.as_str());
+                FormatStringPart::Literal(s) => out.push(s),
+                FormatStringPart::Name(name) => match session.variables.get(name) {
+                    Some(_) if out.filled >= MAX_TEXT_LENGTH => out.stop(),
+                    Some(value) if out.depth < MAX_INTERPOLATION_DEPTH => {
+                        out.filled += 1;
+                        out.depth += 1;
+                        self.write_value(session, value, out);
+                        out.depth -= 1;
+                    }
+                    // What can't be filled in is shown as it was written.
+                    Some(_) => {
+                        out.fail(EvalError::TooDeep { name: name.clone() });
+                        write!(out, "{{{name}}}").unwrap();
+                    }
+                    None => {
+                        out.fail(EvalError::UnknownVariable { name: name.clone() });
+                        write!(out, "{{{name}}}").unwrap();
+                    }
+                },
+                FormatStringPart::Dynamic { name, fallback } => {
+                    self.write_dynamic(session, name, fallback.as_deref().unwrap_or_default(), out)
                 }
             }
         }
-
-        result
     }
 
     fn values_are_equal(&self, session: &Session, left: &Value, right: &Value) -> bool {
@@ -351,113 +2117,996 @@ impl<'a> Engine<'a> {
         }
     }
 
-    fn evaluate_expression(&self, session: &Session, input: &Expression) -> Value {
+    /// The value of an expression, borrowed from the story or the session where it can be.
+    fn evaluate_expression<'a>(&'a self, session: &'a Session, input: &'a Expr) -> Cow<'a, Value> {
         match input {
-            Expression::Value(v) => v.clone(),
-            Expression::Name(name) => session.variables.get(name).unwrap().clone(),
-            Expression::Equals { left, right } => {
+            Expr::Value(v) => Cow::Borrowed(v),
+            // A value of the wrong type counts as the story's default, as it would once the
+            // session is rebased onto the story.
+            Expr::Variable(index) => Cow::Borrowed(
+                session
+                    .variables
+                    .at(*index, &self.default_variables)
+                    .unwrap_or_else(|_| self.default_variables.value(*index)),
+            ),
+            Expr::Equals(left, right) => {
                 let left_val = self.evaluate_expression(session, left);
                 let right_val = self.evaluate_expression(session, right);
-                Value::Bool(self.values_are_equal(session, &left_val, &right_val))
+                Cow::Owned(Value::Bool(
+                    self.values_are_equal(session, &left_val, &right_val),
+                ))
             }
-            Expression::NotEquals { left, right } => {
+            Expr::NotEquals(left, right) => {
                 let left_val = self.evaluate_expression(session, left);
                 let right_val = self.evaluate_expression(session, right);
-                Value::Bool(!self.values_are_equal(session, &left_val, &right_val))
+                Cow::Owned(Value::Bool(
+                    !self.values_are_equal(session, &left_val, &right_val),
+                ))
             }
-            Expression::GreaterThan { left, right } => {
+            Expr::GreaterThan(left, right) => {
                 let left_val = self.evaluate_expression(session, left);
                 let right_val = self.evaluate_expression(session, right);
-                match (left_val, right_val) {
+                Cow::Owned(match (&*left_val, &*right_val) {
                     (Value::Int(l), Value::Int(r)) => Value::Bool(l > r),
-                    _ => panic!("GreaterThan operator can only be applied to integers"),
-                }
+                    // Only a function can return something else here, which is never greater.
+                    _ => Value::Bool(false),
+                })
             }
-            Expression::LessThan { left, right } => {
+            Expr::LessThan(left, right) => {
                 let left_val = self.evaluate_expression(session, left);
                 let right_val = self.evaluate_expression(session, right);
-                match (left_val, right_val) {
+                Cow::Owned(match (&*left_val, &*right_val) {
                     (Value::Int(l), Value::Int(r)) => Value::Bool(l < r),
-                    _ => panic!("LessThan operator can only be applied to integers"),
-                }
+                    // Only a function can return something else here, which is never less.
+                    _ => Value::Bool(false),
+                })
+            }
+            Expr::Call { name, args } => {
+                let args = self.evaluate_args(session, args);
+                Cow::Owned(self.call_function(session, name, &args))
             }
         }
     }
 
-    fn get_current_node<'b>(&'b self, session: &Session) -> &'b Node<'a> {
-        self.all_nodes
-            .get(session.current_node_id.as_str())
-            .unwrap()
+    /// The values of a function's arguments, with any strings interpolated.
+    fn evaluate_args(&self, session: &Session, args: &[Expr]) -> Vec<Value> {
+        args.iter()
+            .map(|arg| match &*self.evaluate_expression(session, arg) {
+                Value::String(s) => Value::String(FormatString(vec![FormatStringPart::Literal(
+                    self.evaluate_string(session, s),
+                )])),
+                value => value.clone(),
+            })
+            .collect()
+    }
+
+    /// Call a function from an expression. A function that fails is `false`.
+    fn call_function(&self, session: &Session, name: &str, args: &[Value]) -> Value {
+        #[cfg(feature = "plugins")]
+        if let Some(plugins) = &self.plugins
+            && let Ok(value) = plugins.evaluate(name, args, &session.variables.to_map())
+        {
+            return value;
+        }
+        let _ = (session, name, args);
+        Value::Bool(false)
+    }
+
+    fn current_index(&self, session: &Session) -> usize {
+        self.node_index(&session.current_node_id).unwrap()
+    }
+
+    fn current_node(&self, session: &Session) -> &interned::Node {
+        &self.interned_nodes[self.current_index(session)]
+    }
+
+    /// The choices at the session's current node, as they were defined and with their names
+    /// resolved.
+    fn current_choices(
+        &self,
+        session: &Session,
+    ) -> impl Iterator<Item = (&parser::Choice, &interned::Choice)> {
+        let index = self.current_index(session);
+        self.nodes[index]
+            .choices
+            .iter()
+            .zip(&self.interned_nodes[index].choices)
     }
 
+    /// The ids of every choice at the session's current node, including ones whose
+    /// requirements aren't met.
     pub fn get_valid_options_ids(&self, session: &Session) -> Vec<&str> {
-        self.get_current_node(session)
+        self.nodes[self.current_index(session)]
             .choices
             .iter()
             .map(|choice| choice.next_node_id.as_str())
             .collect()
     }
 
+    /// The ids of the choices at the session's current node that [`Engine::choose_option`]
+    /// would take, i.e. those without a requirement or whose requirement is met.
+    fn met_options_ids(&self, session: &Session) -> Vec<&str> {
+        self.current_choices(session)
+            .filter(|(_, interned)| {
+                interned.requirement.as_ref().is_none_or(|requirement| {
+                    self.evaluate_expression(session, requirement).is_truthy()
+                })
+            })
+            .map(|(choice, _)| choice.next_node_id.as_str())
+            .collect()
+    }
+
+    /// Whether the requirement of each choice at the session's current node that has one is
+    /// met, as choice id and result.
+    pub fn evaluate_requirements(&self, session: &Session) -> Vec<(&str, bool)> {
+        self.current_choices(session)
+            .filter_map(|(choice, interned)| {
+                let requirement = interned.requirement.as_ref()?;
+                Some((
+                    choice.next_node_id.as_str(),
+                    self.evaluate_expression(session, requirement).is_truthy(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Render the session's current node, with only the choices whose requirements are met.
+    /// Text that can't be filled in, e.g. because a variable interpolates itself, is shown as
+    /// far as it can be. Use [`Engine::try_get_current_node_view`] to find out when that happens.
     pub fn get_current_node_view(&self, session: &Session) -> CurrentNodeView {
-        let current_node = self.get_current_node(session);
+        self.node_view(session, &mut None)
+    }
 
-        let display_text = self.evaluate_string(session, &current_node.display_text);
-        let choices = current_node
-            .choices
-            .iter()
-            .filter_map(|choice| {
-                if let Some(req) = &choice.requirement {
-                    if !self.evaluate_expression(session, req).is_truthy() {
-                        return None;
-                    }
+    /// Like [`Engine::get_current_node_view`], but fails if any of the text can't be filled in.
+    pub fn try_get_current_node_view(
+        &self,
+        session: &Session,
+    ) -> Result<CurrentNodeView, EvalError> {
+        let mut error = None;
+        let view = self.node_view(session, &mut error);
+        match error {
+            Some(error) => Err(error),
+            None => Ok(view),
+        }
+    }
+
+    fn node_view(&self, session: &Session, error: &mut Option<EvalError>) -> CurrentNodeView {
+        let node = self.current_node(session);
+        let node_id = (self.metadata("hide_node_ids") != Some("true"))
+            .then(|| session.current_node_id.clone());
+        let display_text = self.render_checked(session, self.node_text(session, node), error);
+        if let Some(choices) = &node.static_choices {
+            return CurrentNodeView {
+                node_id,
+                turn: session.turns(),
+                display_text,
+                choices: choices.clone(),
+                game_over: choices.is_empty(),
+                can_go_back: !session.undo_stack.is_empty(),
+                version: session.version,
+                variables: None,
+            };
+        }
+
+        let choices = self
+            .current_choices(session)
+            .filter_map(|(choice, interned)| {
+                if let Some(req) = &interned.requirement
+                    && !self.evaluate_expression(session, req).is_truthy()
+                {
+                    return None;
                 }
 
                 Some(ChoiceView {
                     id: choice.next_node_id.to_string(),
-                    display_text: self.evaluate_string(session, &choice.text),
+                    display_text: self.render_checked(session, &interned.text, error),
                 })
             })
             .collect();
-        let game_over = current_node.choices.is_empty();
+        let game_over = self.is_game_over(session);
 
         CurrentNodeView {
+            node_id,
+            turn: session.turns(),
             display_text,
             choices,
             game_over,
+            can_go_back: !session.undo_stack.is_empty(),
+            version: session.version,
+            variables: None,
         }
     }
 
-    fn do_command(&self, session: &mut Session, command: &Command) {
-        match command {
-            Command::Set { name, value } => {
-                let var = session.variables.get_mut(*name).unwrap();
-                *var = value.clone();
+    /// Render a node as a session with the given variables would see it, without needing a
+    /// session, e.g. for an editor's live preview. Variables left out take their default values.
+    /// The node's main narration is shown, rather than any variant. The view's `version` is
+    /// always 0.
+    pub fn preview_node(
+        &self,
+        node_id: &str,
+        variables: &HashMap<String, Value>,
+    ) -> Result<CurrentNodeView, RestoreError> {
+        let session = self.restore_session(SessionSnapshot {
+            current_node_id: node_id.to_string(),
+            variables: variables.clone(),
+            variant: Some(DEFAULT_VARIANT.to_string()),
+            turns: 0,
+        })?;
+        let mut view = self.get_current_node_view(&session);
+        view.version = 0;
+
+        Ok(view)
+    }
+
+    /// Whether the session is at a node with no choices, so the story is over.
+    pub fn is_game_over(&self, session: &Session) -> bool {
+        self.current_node(session).choices.is_empty()
+    }
+
+    /// Run a command, saying why if it failed.
+    fn do_command(&self, session: &mut Session, effect: &Effect) -> Result<(), String> {
+        match effect {
+            Effect::Set { variable, value } => {
+                session
+                    .variables
+                    .set_at(*variable, &self.default_variables, value)
+                    .map_err(|e| e.to_string())?;
+            }
+            // A script that fails leaves the variables as they were.
+            #[cfg(feature = "scripting")]
+            Effect::Script { source } => {
+                let variables = self
+                    .scripts
+                    .run(source, &session.variables.to_map())
+                    .map_err(|e| e.to_string())?;
+                session.variables.update(variables);
+            }
+            // Stories with scripts can't be built without the `scripting` feature.
+            #[cfg(not(feature = "scripting"))]
+            Effect::Script { .. } => {}
+            // A function that fails leaves the variables as they were.
+            Effect::Call { name, args } => {
+                let args = self.evaluate_args(session, args);
+                #[cfg(feature = "plugins")]
+                if let Some(plugins) = &self.plugins {
+                    let variables = plugins
+                        .run(name, &args, &session.variables.to_map())
+                        .map_err(|e| e.to_string())?;
+                    session.variables.update(variables);
+                }
+                let _ = (name, args);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Let observers know about every variable that is different from `old_variables`.
+    fn notify_variable_changes(&self, session: &Session, old_variables: &Variables) {
+        for (name, new) in session.variables.iter() {
+            if let Some(old) = old_variables.get(name)
+                && old != new
+            {
+                for observer in &self.observers {
+                    observer.on_variable_changed(session, name, old, new);
+                }
             }
         }
     }
 
+    /// Take the choice leading to the given node, running its command if it has one. If more
+    /// than one choice leads there, the first whose requirement is met is taken.
     pub fn choose_option(&self, session: &mut Session, next_node_id: String) -> ChoiceResult {
-        let valid_options = self.get_valid_options_ids(session);
-        if !valid_options.contains(&next_node_id.as_str()) {
+        self.rebase_session(session);
+        let current_node_id = || session.current_node_id.to_string();
+        if self.is_game_over(session) {
+            return ChoiceResult::GameAlreadyOver {
+                current_node_id: current_node_id(),
+            };
+        }
+        let (first, choice) = {
+            let mut matching = self
+                .current_choices(session)
+                .filter(|(choice, _)| choice.next_node_id == next_node_id)
+                .peekable();
+            let first = matching.peek().map(|&(first, _)| first);
+            let choice = matching.find(|(_, choice)| {
+                choice.requirement.as_ref().is_none_or(|requirement| {
+                    self.evaluate_expression(session, requirement).is_truthy()
+                })
+            });
+            (first, choice)
+        };
+        let Some(first) = first else {
             return ChoiceResult::InvalidOption {
-                current_node_id: session.current_node_id.to_string(),
-                chosen_option: next_node_id.to_string(),
+                current_node_id: current_node_id(),
+                chosen_option: next_node_id,
+            };
+        };
+        let Some((_, choice)) = choice else {
+            return ChoiceResult::RequirementNotMet {
+                current_node_id: current_node_id(),
+                chosen_option: next_node_id,
+                requirement: first
+                    .requirement
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_default(),
             };
+        };
+
+        // Everything that needs the session as it was is worked out before the command runs,
+        // so a command that fails can be undone by putting the variables back.
+        let snapshot = (self.history_depth > 0).then(|| self.snapshot_session(session));
+        let display_text = self.render(session, &choice.text);
+        let old_variables = choice.effect.as_ref().map(|_| session.variables.clone());
+        if let Some(command) = &choice.effect
+            && let Err(message) = self.do_command(session, command)
+        {
+            session.variables = old_variables.expect("Variables were saved before the command");
+            return ChoiceResult::RuntimeError { message };
         }
 
-        let choice = self
-            .get_current_node(session)
-            .choices
-            .iter()
-            .find(|choice| choice.next_node_id == next_node_id)
-            .unwrap()
-            .clone();
-        if let Some(command) = &choice.command {
-            self.do_command(session, command);
+        if let Some(snapshot) = snapshot {
+            if session.undo_stack.len() >= self.history_depth {
+                session.undo_stack.pop_front();
+            }
+            session.undo_stack.push_back(snapshot);
+        }
+        session.turns = Some(session.turns() + 1);
+        self.record(
+            session,
+            HistoryEvent::ChoiceTaken {
+                choice_id: next_node_id.clone(),
+                display_text,
+                timestamp: now_timestamp(),
+            },
+        );
+        for observer in &self.observers {
+            observer.on_choice_taken(session, &next_node_id);
+        }
+        if let Some(old_variables) = old_variables {
+            self.notify_variable_changes(session, &old_variables);
         }
 
         session.current_node_id = next_node_id;
+        self.enter_node(session);
 
         ChoiceResult::Success
     }
+
+    /// Undo the most recent choice. Returns whether there was a choice to undo.
+    pub fn go_back(&self, session: &mut Session) -> bool {
+        match session.undo_stack.pop_back() {
+            Some(snapshot) => {
+                session.current_node_id = snapshot.current_node_id;
+                session.variables = self.variables_from(&snapshot.variables);
+                self.record(
+                    session,
+                    HistoryEvent::WentBack {
+                        node_id: session.current_node_id.clone(),
+                        timestamp: now_timestamp(),
+                    },
+                );
+                session.version += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add a player to a session, making it a shared session whose choices are voted on.
+    /// Joining twice has no effect.
+    pub fn join_session(&self, session: &mut Session, participant_id: String) {
+        if !session.participants.contains(&participant_id) {
+            session.participants.push(participant_id);
+        }
+    }
+
+    /// Vote for one of the current choices whose requirement is met, replacing any earlier vote
+    /// by the same player.
+    pub fn cast_vote(
+        &self,
+        session: &mut Session,
+        participant_id: &str,
+        choice_id: String,
+    ) -> Result<(), VoteError> {
+        if !session.participants.iter().any(|p| p == participant_id) {
+            return Err(VoteError::UnknownParticipant {
+                participant_id: participant_id.to_string(),
+            });
+        }
+        if !self.met_options_ids(session).contains(&choice_id.as_str()) {
+            let requirement = self
+                .current_choices(session)
+                .find(|(choice, _)| choice.next_node_id == choice_id)
+                .map(|(choice, _)| choice.requirement.as_ref().map(ToString::to_string));
+            return Err(match requirement {
+                Some(requirement) => VoteError::RequirementNotMet {
+                    chosen_option: choice_id,
+                    requirement: requirement.unwrap_or_default(),
+                },
+                None => VoteError::InvalidOption {
+                    chosen_option: choice_id,
+                },
+            });
+        }
+
+        if session.votes_round != session.events_recorded() {
+            session.votes.clear();
+            session.votes_round = session.events_recorded();
+        }
+        session.votes.retain(|(p, _)| p != participant_id);
+        session.votes.push((participant_id.to_string(), choice_id));
+
+        Ok(())
+    }
+
+    /// How many votes each of the current choices has, in the order the choices are shown.
+    /// Choices nobody has voted for are left out.
+    pub fn vote_tally(&self, session: &Session) -> Vec<(String, usize)> {
+        let votes = session.current_votes();
+        let mut tally: Vec<(String, usize)> = Vec::new();
+        for choice_id in self.met_options_ids(session) {
+            let count = votes.iter().filter(|(_, c)| c == choice_id).count();
+            if count > 0 && !tally.iter().any(|(c, _)| c == choice_id) {
+                tally.push((choice_id.to_string(), count));
+            }
+        }
+        tally
+    }
+
+    /// Whether every player sharing the session has voted on the current choice.
+    pub fn everyone_has_voted(&self, session: &Session) -> bool {
+        session.is_shared() && session.current_votes().len() == session.participants.len()
+    }
+
+    /// Take the choice with the most votes, with ties going to the choice shown first.
+    /// Returns the choice that was taken, or why it couldn't be, in which case the session is
+    /// left as it was.
+    pub fn resolve_vote(&self, session: &mut Session) -> Result<String, VoteError> {
+        let tally = self.vote_tally(session);
+        let (choice_id, _) = tally
+            .iter()
+            .rev()
+            .max_by_key(|(_, count)| *count)
+            .ok_or(VoteError::NoVotes)?;
+        let choice_id = choice_id.clone();
+        match self.choose_option(session, choice_id.clone()) {
+            ChoiceResult::Success => Ok(choice_id),
+            result => Err(VoteError::ChoiceFailed(result)),
+        }
+    }
+
+    /// Every node in the story, with its id, sorted by id.
+    pub fn nodes(&self) -> impl Iterator<Item = (&str, &Node)> {
+        self.node_ids.iter().map(String::as_str).zip(&self.nodes)
+    }
+
+    /// Which nodes can be reached and how far they are from `START`, which can lead to an
+    /// ending, and where the story loops, with nodes sorted by id.
+    pub fn analysis(&self) -> Analysis {
+        Analysis::of(self.nodes())
+    }
+
+    /// Whether the story has a node with the given id.
+    pub fn has_node(&self, node_id: &str) -> bool {
+        self.node_index(node_id).is_some()
+    }
+
+    /// Move a session straight to a node without taking a choice, e.g. from a debugger.
+    pub fn jump_to_node(&self, session: &mut Session, node_id: &str) -> Result<(), RestoreError> {
+        if !self.has_node(node_id) {
+            return Err(RestoreError::UnknownNode {
+                node_id: node_id.to_string(),
+            });
+        }
+
+        session.current_node_id = node_id.to_string();
+        self.enter_node(session);
+        Ok(())
+    }
+
+    /// Overwrite one of a session's variables with a value of the same type, e.g. from a
+    /// debugger.
+    pub fn set_variable(
+        &self,
+        session: &mut Session,
+        name: &str,
+        value: Value,
+    ) -> Result<(), RestoreError> {
+        self.rebase_session(session);
+        match session.variables.get(name) {
+            Some(var) if mem::discriminant(var) == mem::discriminant(&value) => {
+                let old = var.clone();
+                session.variables.set(name, value);
+                session.version += 1;
+                if let Some(new) = session.variables.get(name)
+                    && *new != old
+                {
+                    for observer in &self.observers {
+                        observer.on_variable_changed(session, name, &old, new);
+                    }
+                }
+                Ok(())
+            }
+            Some(_) => Err(RestoreError::MismatchedVariableType {
+                name: name.to_string(),
+            }),
+            None => Err(RestoreError::UnknownVariable {
+                name: name.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn story(source: &str) -> Engine {
+        Engine::from_program(source).unwrap_or_else(|errors| {
+            panic!(
+                "The story has errors: {}",
+                errors
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )
+        })
+    }
+
+    fn errors(source: &str) -> Vec<ParseError> {
+        match Engine::from_program(source) {
+            Ok(_) => panic!("The story loaded without errors"),
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn expressions_whose_types_fit_are_accepted() {
+        story(
+            r#"
+SET name "Ann"
+SET gold 0
+
+= START
+    "Hello."
+    [IF name = "Bob"] "Greet Bob." -> START
+    [IF gold > 3] "Buy." -> START
+"#,
+        );
+    }
+
+    #[test]
+    fn comparing_values_of_different_types_is_an_error() {
+        let errors = errors(
+            r#"
+SET name "Ann"
+
+= START
+    "Hello."
+    [IF name = 3] "Count." -> START
+"#,
+        );
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [ParseError::TypeError { parent_node_id, .. }] if parent_node_id == "START"
+            ),
+            "{errors:?}"
+        );
+    }
+
+    #[test]
+    fn variables_that_interpolate_each_other_are_an_error() {
+        let errors = errors(
+            r#"
+SET a "{b}"
+SET b "{a}"
+
+= START
+    "{a}"
+"#,
+        );
+        assert!(
+            errors
+                .iter()
+                .any(|error| matches!(error, ParseError::InterpolationCycle { .. })),
+            "{errors:?}"
+        );
+    }
+
+    #[test]
+    fn a_restored_variable_that_interpolates_itself_is_shown_as_written() {
+        let story = story(
+            r#"
+SET a "x"
+
+= START
+    "Say {a}."
+"#,
+        );
+        let session = story
+            .restore_session(SessionSnapshot {
+                current_node_id: "START".to_string(),
+                variables: HashMap::from([(
+                    "a".to_string(),
+                    Value::String(FormatString(vec![FormatStringPart::Name("a".to_string())])),
+                )]),
+                variant: None,
+                turns: 0,
+            })
+            .unwrap();
+
+        assert!(matches!(
+            story.try_get_current_node_view(&session),
+            Err(EvalError::TooDeep { name }) if name == "a"
+        ));
+        assert!(
+            story
+                .get_current_node_view(&session)
+                .display_text
+                .contains("{a}")
+        );
+    }
+
+    const TWO_ROOMS: &str = r#"
+SET x 0
+
+= START
+    "A room."
+    "Go on." -> end [THEN x = 1]
+
+= end
+    "The end."
+"#;
+
+    #[test]
+    fn compiled_stories_load_and_play_the_same() {
+        let original = story(TWO_ROOMS);
+        let compiled = Engine::from_compiled(&original.compile()).unwrap();
+
+        let mut session = compiled.new_session();
+        assert!(matches!(
+            compiled.choose_option(&mut session, "end".to_string()),
+            ChoiceResult::Success
+        ));
+        assert_eq!(session.variable("x"), Some(&Value::Int(1)));
+        assert_eq!(compiled.version(), original.version());
+    }
+
+    #[test]
+    fn files_that_arent_compiled_stories_are_refused() {
+        assert!(matches!(
+            Engine::from_compiled(TWO_ROOMS.as_bytes()),
+            Err(CompiledStoryError::NotCompiled)
+        ));
+    }
+
+    #[test]
+    fn compiled_stories_with_errors_are_refused() {
+        let original = story(TWO_ROOMS);
+        let mut all_nodes: HashMap<String, Node> = original
+            .nodes()
+            .map(|(id, node)| (id.to_string(), node.clone()))
+            .collect();
+        all_nodes.remove("end");
+        let mut bytes = COMPILED_MAGIC.to_vec();
+        bytes.push(COMPILED_FORMAT);
+        let bytes = postcard::to_extend(
+            &CompiledStory {
+                version: original.version(),
+                default_variables: original.default_variables.to_map(),
+                all_nodes,
+                renamed_nodes: HashMap::new(),
+                metadata: HashMap::new(),
+            },
+            bytes,
+        )
+        .unwrap();
+
+        let Err(CompiledStoryError::Invalid(errors)) = Engine::from_compiled(&bytes) else {
+            panic!("The story loaded without errors");
+        };
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [ParseError::BadReferenceInOption { bad_id, .. }] if bad_id == "end"
+            ),
+            "{errors:?}"
+        );
+    }
+
+    const FORK: &str = r#"
+SET x 0
+
+= START
+    "Left or right?"
+    "Go left." -> left_path
+    "Wait." -> START
+    [IF x > 0] "Go right." -> right_path
+
+= left_path
+    "A tall tree."
+
+= right_path
+    "A small cottage."
+"#;
+
+    fn shared_session(story: &Engine, players: &[&str]) -> Session {
+        let mut session = story.new_session();
+        for player in players {
+            story.join_session(&mut session, player.to_string());
+        }
+        session
+    }
+
+    #[test]
+    fn the_choice_with_the_most_votes_is_taken() {
+        let story = story(FORK);
+        let mut session = shared_session(&story, &["ann", "bob", "cat"]);
+        story
+            .cast_vote(&mut session, "ann", "START".to_string())
+            .unwrap();
+        story
+            .cast_vote(&mut session, "bob", "left_path".to_string())
+            .unwrap();
+        assert!(!story.everyone_has_voted(&session));
+        story
+            .cast_vote(&mut session, "cat", "left_path".to_string())
+            .unwrap();
+        assert!(story.everyone_has_voted(&session));
+        assert_eq!(
+            story.vote_tally(&session),
+            [("left_path".to_string(), 2), ("START".to_string(), 1)]
+        );
+
+        assert_eq!(story.resolve_vote(&mut session).unwrap(), "left_path");
+        assert_eq!(session.current_node_id(), "left_path");
+    }
+
+    #[test]
+    fn votes_that_cant_be_counted_are_errors() {
+        let story = story(FORK);
+        let mut session = shared_session(&story, &["ann"]);
+        assert!(matches!(
+            story.cast_vote(&mut session, "bob", "left_path".to_string()),
+            Err(VoteError::UnknownParticipant { .. })
+        ));
+        assert!(matches!(
+            story.cast_vote(&mut session, "ann", "cellar".to_string()),
+            Err(VoteError::InvalidOption { .. })
+        ));
+        assert!(matches!(
+            story.cast_vote(&mut session, "ann", "right_path".to_string()),
+            Err(VoteError::RequirementNotMet { .. })
+        ));
+        assert!(matches!(
+            story.resolve_vote(&mut session),
+            Err(VoteError::NoVotes)
+        ));
+        assert_eq!(session.current_node_id(), "START");
+    }
+
+    #[test]
+    fn only_the_most_recent_events_are_kept() {
+        let mut story = story(FORK);
+        story.set_max_transcript(3);
+        let mut session = story.new_session();
+        for _ in 0..5 {
+            story.choose_option(&mut session, "START".to_string());
+        }
+
+        let history = story.history(&session);
+        assert_eq!(history.len(), 3);
+        assert!(matches!(
+            history,
+            [
+                HistoryEvent::NodeVisited { .. },
+                HistoryEvent::ChoiceTaken { .. },
+                HistoryEvent::NodeVisited { .. },
+            ]
+        ));
+        assert_eq!(session.events_recorded(), 11);
+        assert_eq!(session.turns(), 5);
+    }
+
+    #[test]
+    fn votes_go_stale_once_the_transcript_is_full() {
+        let mut story = story(FORK);
+        story.set_max_transcript(1);
+        let mut session = shared_session(&story, &["ann", "bob"]);
+        story
+            .cast_vote(&mut session, "ann", "START".to_string())
+            .unwrap();
+        story.choose_option(&mut session, "START".to_string());
+
+        assert!(story.vote_tally(&session).is_empty());
+    }
+
+    #[test]
+    fn saved_sessions_only_keep_their_changed_variables() {
+        let story = story(
+            r#"
+SET name "Ann"
+SET gold 0
+
+= START
+    "{name} has {gold} gold."
+    "Dig." -> START [THEN gold = 5]
+"#,
+        );
+        let mut session = story.new_session();
+        story.choose_option(&mut session, "START".to_string());
+        let saved = serde_json::to_value(&session).unwrap();
+        assert_eq!(
+            saved["variables"],
+            serde_json::json!({ "gold": { "Int": 5 } })
+        );
+
+        let mut loaded: Session = serde_json::from_value(saved).unwrap();
+        assert_eq!(
+            story.get_current_node_view(&loaded).display_text,
+            "Ann has 5 gold."
+        );
+        story.rebase_session(&mut loaded);
+        assert_eq!(loaded.variables().count(), 2);
+    }
+
+    #[test]
+    fn sessions_saved_without_a_turn_count_count_their_transcript() {
+        let story = story(FORK);
+        let mut session = story.new_session();
+        story.choose_option(&mut session, "START".to_string());
+        story.choose_option(&mut session, "START".to_string());
+
+        let mut saved = serde_json::to_value(&session).unwrap();
+        saved.as_object_mut().unwrap().remove("turns");
+        let mut session: Session = serde_json::from_value(saved).unwrap();
+        assert_eq!(session.turns(), 2);
+
+        story.choose_option(&mut session, "START".to_string());
+        assert_eq!(session.turns(), 3);
+    }
+
+    #[test]
+    fn read_stories_match_parsed_ones() {
+        // The second line of the narration looks like the start of a node, but is in a string.
+        let source = r#"SET gold 0
+
+= START
+    "Welcome.
+= not a node"
+    "Go on." -> end
+
+= end
+    "The end."
+"#;
+        let read = Engine::from_reader(source.as_bytes()).unwrap();
+        let parsed = story(source);
+        assert_eq!(read.version, parsed.version);
+        assert_eq!(
+            read.nodes().map(|(id, _)| id).collect::<Vec<_>>(),
+            parsed.nodes().map(|(id, _)| id).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reading_stops_at_the_first_unparsable_definition() {
+        let source = r#"= START
+    "Welcome."
+    "Go on." -> end
+
+= end
+    "The end." ->
+
+= later
+    "Never read."
+"#;
+        match Engine::from_reader(source.as_bytes()) {
+            Err(ReadStoryError::Unparsable { line }) => assert_eq!(line, 6),
+            Err(e) => panic!("{e}"),
+            Ok(_) => panic!("The story loaded"),
+        }
+    }
+
+    const CAVE: &str = r#"
+SET gold 0
+SET mood "calm"
+
+= START
+    "Outside."
+    "Enter the cave." -> cave [THEN gold = 5]
+
+= cave
+    "A cave."
+    "Leave." -> START
+"#;
+
+    /// A session that has gone into the cave and found gold.
+    fn session_in_cave(old: &Engine) -> Session {
+        let mut session = old.new_session();
+        assert!(matches!(
+            old.choose_option(&mut session, "cave".to_string()),
+            ChoiceResult::Success
+        ));
+        session
+    }
+
+    #[test]
+    fn migrated_sessions_follow_renamed_nodes() {
+        let mut old = story(CAVE);
+        old.set_history_depth(5);
+        let new = story(
+            r#"
+SET gold 0
+SET mood 1
+RENAMED cave -> cavern
+
+= START
+    "Outside."
+    "Enter the cavern." -> cavern
+
+= cavern
+    "A cavern."
+    "Leave." -> START
+"#,
+        );
+        let session = session_in_cave(&old);
+        assert!(!new.session_is_current(&session));
+
+        let mut migrated = new.migrate_session(session).unwrap();
+
+        assert!(new.session_is_current(&migrated));
+        assert_eq!(migrated.current_node_id(), "cavern");
+        assert_eq!(migrated.turns(), 1);
+        // Variables are kept unless their type changed.
+        assert_eq!(migrated.variable("gold"), Some(&Value::Int(5)));
+        assert_eq!(migrated.variable("mood"), Some(&Value::Int(1)));
+        assert!(new.go_back(&mut migrated));
+        assert_eq!(migrated.current_node_id(), "START");
+    }
+
+    #[test]
+    fn sessions_at_removed_nodes_cant_be_migrated() {
+        let old = story(CAVE);
+        let new = story(
+            r#"
+SET gold 0
+
+= START
+    "Outside, with nowhere to go."
+"#,
+        );
+
+        let result = new.migrate_session(session_in_cave(&old));
+
+        assert!(
+            matches!(&result, Err(RestoreError::UnknownNode { node_id }) if node_id == "cave"),
+            "{:?}",
+            result.err()
+        );
+    }
+
+    #[test]
+    fn renaming_to_a_missing_node_is_an_error() {
+        let errors = errors(
+            r#"
+RENAMED cave -> cavern
+
+= START
+    "Outside."
+"#,
+        );
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [ParseError::BadReferenceInRename { old_id, new_id }]
+                    if old_id == "cave" && new_id == "cavern"
+            ),
+            "{errors:?}"
+        );
+    }
 }
@@ -0,0 +1,211 @@
+//! The shape of a story's graph of nodes and choices: which nodes can be reached, how far they
+//! are from the start, which can still lead to an ending, and where the story loops.
+//!
+//! Requirements are ignored, so a node counts as reachable if any chain of choices leads to it,
+//! even one no session could take.
+
+use super::parser::Node;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+/// What is known about one node.
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct NodeAnalysis {
+    pub id: String,
+    /// Whether any chain of choices leads here from `START`.
+    pub reachable: bool,
+    /// The fewest choices needed to get here from `START`, if it can be reached.
+    pub distance: Option<usize>,
+    /// Whether any chain of choices leads from here to an ending, a node without choices.
+    pub can_end: bool,
+    /// Whether the node is part of one of the story's [`Analysis::loops`].
+    pub in_loop: bool,
+}
+
+/// The shape of a whole story, from [`Analysis::of`] or [`crate::Engine::analysis`].
+#[derive(Debug, Clone, Serialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Analysis {
+    /// Every node, in the order they were given.
+    pub nodes: Vec<NodeAnalysis>,
+    /// Groups of nodes that chains of choices can go around and around, each of them able to
+    /// lead to every other. These are the strongly connected components of the story's graph
+    /// with more than one node, or one node with a choice leading back to itself.
+    pub loops: Vec<Vec<String>>,
+    #[serde(skip)]
+    index: HashMap<String, usize>,
+}
+
+impl Analysis {
+    /// Analyse a story from its nodes. Choices leading to nodes that aren't given are ignored.
+    pub fn of<'a>(nodes: impl IntoIterator<Item = (&'a str, &'a Node)>) -> Self {
+        let nodes: Vec<(&str, &Node)> = nodes.into_iter().collect();
+        let index: HashMap<String, usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, (id, _))| (id.to_string(), i))
+            .collect();
+        let edges: Vec<Vec<usize>> = nodes
+            .iter()
+            .map(|(_, node)| {
+                node.choices
+                    .iter()
+                    .filter_map(|choice| index.get(&choice.next_node_id).copied())
+                    .collect()
+            })
+            .collect();
+        let mut reverse = vec![Vec::new(); nodes.len()];
+        for (from, targets) in edges.iter().enumerate() {
+            for &to in targets {
+                reverse[to].push(from);
+            }
+        }
+
+        let distances = match index.get("START") {
+            Some(&start) => distances_from(start, &edges),
+            None => vec![None; nodes.len()],
+        };
+        let endings = nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, node))| node.choices.is_empty())
+            .map(|(i, _)| i);
+        let can_end = reached_from(endings, &reverse);
+
+        let mut in_loop = vec![false; nodes.len()];
+        let mut loops = Vec::new();
+        for mut component in components(&edges, &reverse) {
+            let only = component[0];
+            if component.len() == 1 && !edges[only].contains(&only) {
+                continue;
+            }
+            component.sort_unstable();
+            for &i in &component {
+                in_loop[i] = true;
+            }
+            loops.push(component);
+        }
+        loops.sort_unstable();
+
+        Analysis {
+            nodes: nodes
+                .iter()
+                .enumerate()
+                .map(|(i, (id, _))| NodeAnalysis {
+                    id: id.to_string(),
+                    reachable: distances[i].is_some(),
+                    distance: distances[i],
+                    can_end: can_end[i],
+                    in_loop: in_loop[i],
+                })
+                .collect(),
+            loops: loops
+                .into_iter()
+                .map(|component| {
+                    component
+                        .into_iter()
+                        .map(|i| nodes[i].0.to_string())
+                        .collect()
+                })
+                .collect(),
+            index,
+        }
+    }
+
+    /// What is known about the node with id `id`, if the story has one.
+    pub fn node(&self, id: &str) -> Option<&NodeAnalysis> {
+        self.index.get(id).map(|&i| &self.nodes[i])
+    }
+
+    /// The nodes that no chain of choices leads to from `START`.
+    pub fn unreachable(&self) -> impl Iterator<Item = &NodeAnalysis> {
+        self.nodes.iter().filter(|node| !node.reachable)
+    }
+}
+
+/// The fewest edges from `start` to each node, if it can be reached.
+fn distances_from(start: usize, edges: &[Vec<usize>]) -> Vec<Option<usize>> {
+    let mut distances = vec![None; edges.len()];
+    distances[start] = Some(0);
+    let mut to_visit = VecDeque::from([start]);
+    while let Some(from) = to_visit.pop_front() {
+        let distance = distances[from].map(|d| d + 1);
+        for &to in &edges[from] {
+            if distances[to].is_none() {
+                distances[to] = distance;
+                to_visit.push_back(to);
+            }
+        }
+    }
+    distances
+}
+
+/// Whether each node can be reached from any of `starts`.
+fn reached_from(starts: impl IntoIterator<Item = usize>, edges: &[Vec<usize>]) -> Vec<bool> {
+    let mut reached = vec![false; edges.len()];
+    let mut to_visit: Vec<usize> = starts.into_iter().collect();
+    for &start in &to_visit {
+        reached[start] = true;
+    }
+    while let Some(from) = to_visit.pop() {
+        for &to in &edges[from] {
+            if !reached[to] {
+                reached[to] = true;
+                to_visit.push(to);
+            }
+        }
+    }
+    reached
+}
+
+/// The strongly connected components of the graph, found with Kosaraju's algorithm. Both
+/// passes keep their own stack rather than recursing, so long chains of nodes can't overflow
+/// the call stack.
+fn components(edges: &[Vec<usize>], reverse: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    // Every node, ordered by when a depth-first search finished with it.
+    let mut finished = Vec::with_capacity(edges.len());
+    let mut visited = vec![false; edges.len()];
+    for root in 0..edges.len() {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        let mut stack = vec![(root, 0)];
+        while let Some((node, next)) = stack.last_mut() {
+            if let Some(&to) = edges[*node].get(*next) {
+                *next += 1;
+                if !visited[to] {
+                    visited[to] = true;
+                    stack.push((to, 0));
+                }
+            } else {
+                finished.push(*node);
+                stack.pop();
+            }
+        }
+    }
+
+    // Searching backwards from the last to finish, each search finds one component.
+    let mut assigned = vec![false; edges.len()];
+    let mut components = Vec::new();
+    for &root in finished.iter().rev() {
+        if assigned[root] {
+            continue;
+        }
+        assigned[root] = true;
+        let mut component = vec![root];
+        let mut to_visit = vec![root];
+        while let Some(node) = to_visit.pop() {
+            for &from in &reverse[node] {
+                if !assigned[from] {
+                    assigned[from] = true;
+                    component.push(from);
+                    to_visit.push(from);
+                }
+            }
+        }
+        components.push(component);
+    }
+    components
+}
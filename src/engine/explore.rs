@@ -0,0 +1,348 @@
+//! Searching every way a story can be played, tracking its variables, to answer questions like
+//! "can the player reach `good_ending` without picking up the lamp?".
+//!
+//! Unlike [`super::analysis`], requirements and commands are taken into account: a state is a
+//! node together with the value of every variable, and only choices whose requirements are met
+//! are followed. Scripts and plugin functions are run as they would be when playing, so a story
+//! that uses randomness is only explored as one run of them happened to go.
+
+use super::{
+    Engine, ParseError,
+    interned::{Expr, Interner, Variables},
+    parser::Expression,
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Display,
+};
+
+/// Where to look for a way to get to, and how hard to look.
+#[derive(Debug, Clone)]
+pub struct Goal {
+    /// The node to reach.
+    pub node_id: String,
+    /// Something that must be true on arriving there, e.g. `lamp = false`.
+    pub condition: Option<Expression>,
+    /// Nodes the way there must not pass through.
+    pub avoid: Vec<String>,
+    /// The most choices to take from `START`.
+    pub max_depth: usize,
+    /// The most different states to look at.
+    pub max_states: usize,
+}
+
+impl Goal {
+    /// Reach `node_id` in any state, looking up to 100 choices ahead and at up to 100,000
+    /// states.
+    pub fn new(node_id: impl Into<String>) -> Self {
+        Goal {
+            node_id: node_id.into(),
+            condition: None,
+            avoid: Vec::new(),
+            max_depth: 100,
+            max_states: 100_000,
+        }
+    }
+}
+
+/// What [`Engine::explore`] found.
+#[derive(Debug, Clone)]
+pub struct Exploration {
+    /// The fewest choices that reach the goal, as the ids of the choices to take from `START`,
+    /// if there are any.
+    pub path: Option<Vec<String>>,
+    /// How many different states were looked at.
+    pub states: usize,
+    /// Whether every state the story can get into was looked at, rather than the search
+    /// stopping at [`Goal::max_depth`] or [`Goal::max_states`]. If it was and there is no
+    /// path, the goal can never be reached.
+    pub complete: bool,
+}
+
+/// A reason a [`Goal`] doesn't fit a story.
+#[derive(Debug)]
+pub enum ExploreError {
+    UnknownNode {
+        node_id: String,
+    },
+    UnknownVariable {
+        name: String,
+    },
+    /// Part of the condition, `location`, whose value can't be of the type it is used as.
+    TypeError {
+        expected: &'static str,
+        found: &'static str,
+        location: Expression,
+    },
+}
+
+impl Display for ExploreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownNode { node_id } => {
+                f.write_fmt(format_args!("There is no node with id '{node_id}'."))
+            }
+            Self::UnknownVariable { name } => {
+                f.write_fmt(format_args!("There is no variable with name '{name}'."))
+            }
+            Self::TypeError {
+                expected,
+                found,
+                location,
+            } => f.write_fmt(format_args!(
+                "The condition uses '{location}', which is {found}, where {expected} is needed."
+            )),
+        }
+    }
+}
+
+impl std::error::Error for ExploreError {}
+
+/// A node and the variables on arriving there, with how it was reached.
+struct State {
+    node: usize,
+    variables: Variables,
+    depth: usize,
+    /// The state this one was reached from and the choice taken there.
+    came_from: Option<(usize, String)>,
+}
+
+impl Engine {
+    /// Search every way the story can be played, a choice at a time from `START`, for the
+    /// fewest choices that reach `goal`.
+    pub fn explore(&self, goal: &Goal) -> Result<Exploration, ExploreError> {
+        let node_index = |node_id: &String| {
+            self.node_index(node_id)
+                .ok_or_else(|| ExploreError::UnknownNode {
+                    node_id: node_id.clone(),
+                })
+        };
+        let target = node_index(&goal.node_id)?;
+        let avoid = goal
+            .avoid
+            .iter()
+            .map(node_index)
+            .collect::<Result<HashSet<_>, _>>()?;
+        let condition = goal
+            .condition
+            .as_ref()
+            .map(|condition| self.check_condition(condition))
+            .transpose()?;
+
+        // Requirements and commands are run against a session that is never shown to anyone,
+        // so observers don't hear about any of this.
+        let mut scratch = self.blank_session(None);
+        let start = self.current_index(&scratch);
+        if avoid.contains(&start) {
+            return Ok(Exploration {
+                path: None,
+                states: 0,
+                complete: true,
+            });
+        }
+        let mut states = vec![State {
+            node: start,
+            variables: scratch.variables.clone(),
+            depth: 0,
+            came_from: None,
+        }];
+        let mut seen = HashSet::from([(start, scratch.variables.changes().to_vec())]);
+        let mut to_visit = VecDeque::from([0]);
+        let mut complete = true;
+
+        while let Some(current) = to_visit.pop_front() {
+            let State {
+                node,
+                ref variables,
+                depth,
+                ..
+            } = states[current];
+            scratch.current_node_id = self.node_ids[node].clone();
+            scratch.variables = variables.clone();
+            let reached = node == target
+                && condition.as_ref().is_none_or(|condition| {
+                    self.evaluate_expression(&scratch, condition).is_truthy()
+                });
+            if reached {
+                return Ok(Exploration {
+                    path: Some(path_to(&states, current)),
+                    states: states.len(),
+                    complete,
+                });
+            }
+
+            let choices: Vec<_> = self.nodes[node]
+                .choices
+                .iter()
+                .zip(&self.interned_nodes[node].choices)
+                .collect();
+            if depth == goal.max_depth {
+                complete &= choices.is_empty();
+                continue;
+            }
+
+            // Taking a choice runs the first choice with its id whose requirement is met, as
+            // `choose_option` does. One whose command fails can't be taken at all.
+            let mut taken = HashSet::new();
+            let mut next_states = Vec::new();
+            for (choice, interned) in choices {
+                let id = choice.next_node_id.as_str();
+                let offered = interned.requirement.as_ref().is_none_or(|requirement| {
+                    self.evaluate_expression(&scratch, requirement).is_truthy()
+                });
+                if !offered || !taken.insert(id) {
+                    continue;
+                }
+                let Some(next) = self.node_index(id).filter(|next| !avoid.contains(next)) else {
+                    continue;
+                };
+                scratch.variables = states[current].variables.clone();
+                if let Some(effect) = &interned.effect
+                    && self.do_command(&mut scratch, effect).is_err()
+                {
+                    continue;
+                }
+                next_states.push((next, scratch.variables.clone(), id));
+            }
+
+            for (next, variables, id) in next_states {
+                if !seen.insert((next, variables.changes().to_vec())) {
+                    continue;
+                }
+                if states.len() == goal.max_states {
+                    complete = false;
+                    break;
+                }
+                states.push(State {
+                    node: next,
+                    variables,
+                    depth: depth + 1,
+                    came_from: Some((current, id.to_string())),
+                });
+                to_visit.push_back(states.len() - 1);
+            }
+        }
+
+        Ok(Exploration {
+            path: None,
+            states: states.len(),
+            complete,
+        })
+    }
+
+    /// Check that a goal's condition only uses variables the story has, as the right types.
+    fn check_condition(&self, condition: &Expression) -> Result<Expr, ExploreError> {
+        if let Some(name) = self.bad_names_in_expression(condition).into_iter().next() {
+            return Err(ExploreError::UnknownVariable { name });
+        }
+        let mut errors = Vec::new();
+        self.expression_type(condition, "", &mut errors);
+        if let Some(ParseError::TypeError {
+            expected,
+            found,
+            location,
+            ..
+        }) = errors.into_iter().next()
+        {
+            return Err(ExploreError::TypeError {
+                expected,
+                found,
+                location,
+            });
+        }
+
+        let interner = Interner {
+            variables: &self.default_variables,
+        };
+        Ok(interner.expression(condition))
+    }
+}
+
+/// The ids of the choices taken to get from `START` to the state at `index`.
+fn path_to(states: &[State], mut index: usize) -> Vec<String> {
+    let mut path = Vec::new();
+    while let Some((previous, choice_id)) = &states[index].came_from {
+        path.push(choice_id.clone());
+        index = *previous;
+    }
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::parser::parse_condition;
+
+    /// The cottage can only be reached once the player has gone left and come back.
+    const FORK: &str = r#"
+SET x 0
+
+= START
+    "Left or right?"
+    "Go left." -> left_path
+    [IF x > 0] "Go right." -> right_path
+
+= left_path
+    "A tall tree."
+    "Go back." -> START [THEN x = 1]
+
+= right_path
+    "A small cottage."
+"#;
+
+    fn story() -> Engine {
+        Engine::from_program(FORK).unwrap_or_else(|_| panic!("The story has errors"))
+    }
+
+    #[test]
+    fn the_fewest_choices_that_meet_requirements_are_found() {
+        let exploration = story().explore(&Goal::new("right_path")).unwrap();
+        assert_eq!(
+            exploration.path,
+            Some(vec![
+                "left_path".to_string(),
+                "START".to_string(),
+                "right_path".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn a_node_that_cant_be_reached_without_an_avoided_node_has_no_path() {
+        let goal = Goal {
+            avoid: vec!["left_path".to_string()],
+            ..Goal::new("right_path")
+        };
+        let exploration = story().explore(&goal).unwrap();
+        assert_eq!(exploration.path, None);
+        assert!(exploration.complete);
+    }
+
+    #[test]
+    fn a_condition_that_never_holds_on_arriving_has_no_path() {
+        let goal = Goal {
+            condition: parse_condition("x = 0"),
+            ..Goal::new("right_path")
+        };
+        let exploration = story().explore(&goal).unwrap();
+        assert_eq!(exploration.path, None);
+        assert!(exploration.complete);
+    }
+
+    #[test]
+    fn goals_that_dont_fit_the_story_are_errors() {
+        assert!(matches!(
+            story().explore(&Goal::new("cellar")),
+            Err(ExploreError::UnknownNode { node_id }) if node_id == "cellar"
+        ));
+        let goal = Goal {
+            condition: parse_condition("gold > 1"),
+            ..Goal::new("right_path")
+        };
+        assert!(matches!(
+            story().explore(&goal),
+            Err(ExploreError::UnknownVariable { name }) if name == "gold"
+        ));
+    }
+}
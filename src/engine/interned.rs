@@ -0,0 +1,547 @@
+//! Stories with their node ids and variable names resolved to indices when they are loaded, so
+//! sessions can be played without looking anything up by name.
+//!
+//! Node ids and variable names are sorted, and each one's index is its position in that order.
+//! Two versions of a story with the same nodes and variables therefore agree on every index, so
+//! a session saved by one can be played by the other. The names themselves are only kept for
+//! errors and serialization.
+
+use super::{
+    ChoiceView, EvalError,
+    parser::{self, Command, Expression, FormatString, FormatStringPart, Value},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, ser::SerializeMap};
+use std::{collections::HashMap, mem, sync::Arc};
+
+/// An index for a name the story doesn't define. Only stories that weren't checked for errors
+/// have these, and they panic when they reach one, as they would looking the name up.
+const UNKNOWN: u32 = u32::MAX;
+
+/// A story's variables and their default values, shared by the engine and its sessions.
+#[derive(Debug)]
+struct Defaults {
+    names: Vec<String>,
+    values: Vec<Value>,
+    /// Whether these are a session's values loaded from storage rather than a story's
+    /// defaults, in which case they only cover what the session changed.
+    loaded: bool,
+}
+
+/// A set of values for a story's variables, kept as the story's defaults and the changes made
+/// to them, so sessions don't each hold a copy of every variable.
+#[derive(Debug, Clone)]
+pub(crate) struct Variables {
+    /// Shared by an engine and every session it starts, which also tells cheaply whether their
+    /// indices agree.
+    defaults: Arc<Defaults>,
+    /// The variables that differ from their defaults, as index and value, sorted by index.
+    changes: Vec<(u32, Value)>,
+}
+
+impl Variables {
+    pub(crate) fn new(variables: HashMap<String, Value>) -> Self {
+        Self::with_defaults(variables, false)
+    }
+
+    fn with_defaults(variables: HashMap<String, Value>, loaded: bool) -> Self {
+        let mut variables: Vec<_> = variables.into_iter().collect();
+        variables.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let (names, values) = variables.into_iter().unzip();
+        Variables {
+            defaults: Arc::new(Defaults {
+                names,
+                values,
+                loaded,
+            }),
+            changes: Vec::new(),
+        }
+    }
+
+    /// The index of the variable called `name`.
+    pub(crate) fn index_of(&self, name: &str) -> Option<u32> {
+        self.defaults
+            .names
+            .binary_search_by(|n| n.as_str().cmp(name))
+            .ok()
+            .map(|index| index as u32)
+    }
+
+    /// The value at `index`, which has to be an index into these variables.
+    pub(crate) fn value(&self, index: u32) -> &Value {
+        match self.changes.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(change) => &self.changes[change].1,
+            Err(_) => &self.defaults.values[index as usize],
+        }
+    }
+
+    fn set_value(&mut self, index: u32, value: Value) {
+        let is_default = self.defaults.values[index as usize] == value;
+        match self.changes.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(change) if is_default => {
+                self.changes.remove(change);
+            }
+            Ok(change) => self.changes[change].1 = value,
+            Err(change) if !is_default => self.changes.insert(change, (index, value)),
+            Err(_) => {}
+        }
+    }
+
+    /// Like [`Variables::set_value`], but only copies the value if it has to be kept.
+    fn set_value_ref(&mut self, index: u32, value: &Value) {
+        let is_default = self.defaults.values[index as usize] == *value;
+        match self.changes.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(change) if is_default => {
+                self.changes.remove(change);
+            }
+            Ok(change) if self.changes[change].1 != *value => {
+                self.changes[change].1 = value.clone()
+            }
+            Err(change) if !is_default => self.changes.insert(change, (index, value.clone())),
+            _ => {}
+        }
+    }
+
+    /// The variables that differ from the story's defaults, by index. Two sets of variables
+    /// with the same defaults are equal if these are.
+    pub(crate) fn changes(&self) -> &[(u32, Value)] {
+        &self.changes
+    }
+
+    /// The name of the variable at `index`.
+    pub(crate) fn name_at(&self, index: u32) -> &str {
+        &self.defaults.names[index as usize]
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Value> {
+        self.index_of(name).map(|index| self.value(index))
+    }
+
+    /// Set the variable called `name`. Returns whether there is one.
+    pub(crate) fn set(&mut self, name: &str, value: Value) -> bool {
+        let Some(index) = self.index_of(name) else {
+            return false;
+        };
+        self.set_value(index, value);
+        true
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.index_of(name).is_some()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        let mut changes = self.changes.iter().peekable();
+        self.defaults
+            .names
+            .iter()
+            .zip(&self.defaults.values)
+            .enumerate()
+            .map(move |(index, (name, default))| {
+                match changes.next_if(|(i, _)| *i as usize == index) {
+                    Some((_, value)) => (name.as_str(), value),
+                    None => (name.as_str(), default),
+                }
+            })
+    }
+
+    pub(crate) fn to_map(&self) -> HashMap<String, Value> {
+        self.iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect()
+    }
+
+    /// Overwrite the variables that `variables` has values for.
+    pub(crate) fn update(&mut self, variables: HashMap<String, Value>) {
+        for (name, value) in variables {
+            self.set(&name, value);
+        }
+    }
+
+    /// Share the defaults of `story`, e.g. after being loaded from a store, so that only the
+    /// changes to them are kept from now on. Values for variables `story` doesn't have, or of
+    /// a different type, are dropped.
+    pub(crate) fn rebase(&mut self, story: &Variables) {
+        if Arc::ptr_eq(&self.defaults, &story.defaults) {
+            return;
+        }
+        let mut rebased = Variables {
+            defaults: Arc::clone(&story.defaults),
+            changes: Vec::new(),
+        };
+        for (name, value) in self.iter() {
+            if let Some(index) = rebased.index_of(name)
+                && mem::discriminant(rebased.value(index)) == mem::discriminant(value)
+            {
+                rebased.set_value_ref(index, value);
+            }
+        }
+        *self = rebased;
+    }
+
+    /// The value at `index` in `story`'s variables. Values that haven't been rebased onto
+    /// `story` are found by name, and fall back to `story`'s default if there isn't one.
+    pub(crate) fn at<'a>(
+        &'a self,
+        index: u32,
+        story: &'a Variables,
+    ) -> Result<&'a Value, EvalError> {
+        if Arc::ptr_eq(&self.defaults, &story.defaults) {
+            return Ok(self.value(index));
+        }
+        let default = story.value(index);
+        match self.get(story.name_at(index)) {
+            Some(value) if mem::discriminant(value) == mem::discriminant(default) => Ok(value),
+            Some(_) => Err(EvalError::MismatchedVariableType {
+                name: story.name_at(index).to_string(),
+            }),
+            None => Ok(default),
+        }
+    }
+
+    /// Set the value at `index` in `story`'s variables, rebasing onto `story` first if these
+    /// aren't values for it.
+    pub(crate) fn set_at(
+        &mut self,
+        index: u32,
+        story: &Variables,
+        value: &Value,
+    ) -> Result<(), EvalError> {
+        self.rebase(story);
+        if mem::discriminant(story.value(index)) != mem::discriminant(value) {
+            return Err(EvalError::MismatchedVariableType {
+                name: story.name_at(index).to_string(),
+            });
+        }
+        self.set_value_ref(index, value);
+        Ok(())
+    }
+}
+
+impl Serialize for Variables {
+    /// Variables are saved by name, as a map, so saved sessions don't depend on the indices.
+    /// Only the values that differ from the story's defaults are saved.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.defaults.loaded {
+            let mut map = serializer.serialize_map(Some(self.defaults.names.len()))?;
+            for (name, value) in self.iter() {
+                map.serialize_entry(name, value)?;
+            }
+            return map.end();
+        }
+        let mut map = serializer.serialize_map(Some(self.changes.len()))?;
+        for (index, value) in &self.changes {
+            map.serialize_entry(&self.defaults.names[*index as usize], value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Variables {
+    /// The variables a session saved, which only become a full set of values once they are
+    /// rebased onto its story's defaults.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::deserialize(deserializer)
+            .map(|variables| Variables::with_defaults(variables, true))
+    }
+}
+
+/// A [`FormatString`] with its variables resolved.
+#[derive(Debug)]
+pub(crate) struct Text(pub(crate) Vec<TextPart>);
+
+impl Text {
+    /// The length of the text that doesn't need filling in, as a guess at how long it will be.
+    pub(crate) fn literal_len(&self) -> usize {
+        self.0
+            .iter()
+            .map(|part| match part {
+                TextPart::Literal(s) => s.len(),
+                TextPart::Variable(_) | TextPart::Dynamic { .. } => 0,
+            })
+            .sum()
+    }
+
+    /// The text, if it has nothing to fill in.
+    pub(crate) fn as_literal(&self) -> Option<&str> {
+        match self.0.as_slice() {
+            [] => Some(""),
+            [TextPart::Literal(s)] => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum TextPart {
+    Literal(String),
+    Variable(u32),
+    Dynamic { name: String, fallback: String },
+}
+
+/// An [`Expression`] with its variables resolved.
+#[derive(Debug)]
+pub(crate) enum Expr {
+    Value(Value),
+    Variable(u32),
+    Equals(Box<Expr>, Box<Expr>),
+    NotEquals(Box<Expr>, Box<Expr>),
+    GreaterThan(Box<Expr>, Box<Expr>),
+    LessThan(Box<Expr>, Box<Expr>),
+    Call { name: String, args: Vec<Expr> },
+}
+
+/// A [`Command`] with its variables resolved.
+#[derive(Debug)]
+pub(crate) enum Effect {
+    Set {
+        variable: u32,
+        value: Value,
+    },
+    #[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+    Script {
+        source: String,
+    },
+    Call {
+        name: String,
+        args: Vec<Expr>,
+    },
+}
+
+/// A [`parser::Choice`] with its variables resolved.
+#[derive(Debug)]
+pub(crate) struct Choice {
+    pub(crate) requirement: Option<Expr>,
+    pub(crate) text: Text,
+    pub(crate) effect: Option<Effect>,
+}
+
+/// A [`parser::Node`] with its choices and variables resolved.
+#[derive(Debug)]
+pub(crate) struct Node {
+    pub(crate) text: Text,
+    /// The node's variants, as name and text.
+    pub(crate) variants: Vec<(String, Text)>,
+    pub(crate) choices: Vec<Choice>,
+    /// The choices as every session sees them, if none of them has a requirement or anything
+    /// to fill in, so they don't have to be worked out for each view.
+    pub(crate) static_choices: Option<Vec<ChoiceView>>,
+}
+
+/// Resolves the variable names in a story's nodes.
+pub(crate) struct Interner<'a> {
+    pub(crate) variables: &'a Variables,
+}
+
+impl Interner<'_> {
+    pub(crate) fn node(&self, node: &parser::Node) -> Node {
+        let choices: Vec<_> = node
+            .choices
+            .iter()
+            .map(|choice| Choice {
+                requirement: choice.requirement.as_ref().map(|e| self.expression(e)),
+                text: self.text(&choice.text),
+                effect: choice.command.as_ref().map(|c| self.command(c)),
+            })
+            .collect();
+        let static_choices = node
+            .choices
+            .iter()
+            .zip(&choices)
+            .map(|(source, choice)| {
+                Some(ChoiceView {
+                    display_text: choice.text.as_literal()?.to_string(),
+                    id: source.next_node_id.clone(),
+                })
+                .filter(|_| choice.requirement.is_none())
+            })
+            .collect();
+
+        Node {
+            text: self.text(&node.display_text),
+            variants: node
+                .variants
+                .iter()
+                .map(|variant| (variant.name.clone(), self.text(&variant.text)))
+                .collect(),
+            choices,
+            static_choices,
+        }
+    }
+
+    fn variable(&self, name: &str) -> u32 {
+        self.variables.index_of(name).unwrap_or(UNKNOWN)
+    }
+
+    fn text(&self, text: &FormatString) -> Text {
+        let mut parts = Vec::new();
+        for part in &text.0 {
+            match (part, parts.last_mut()) {
+                // Literals next to each other are joined, so text with nothing to fill in is a
+                // single literal.
+                (FormatStringPart::Literal(s), Some(TextPart::Literal(last))) => last.push_str(s),
+                (FormatStringPart::Literal(s), _) => parts.push(TextPart::Literal(s.clone())),
+                (FormatStringPart::Name(name), _) => {
+                    parts.push(TextPart::Variable(self.variable(name)))
+                }
+                (FormatStringPart::Dynamic { name, fallback }, _) => {
+                    parts.push(TextPart::Dynamic {
+                        name: name.clone(),
+                        fallback: fallback.clone().unwrap_or_default(),
+                    })
+                }
+            }
+        }
+
+        Text(parts)
+    }
+
+    pub(crate) fn expression(&self, expression: &Expression) -> Expr {
+        let pair = |left: &Expression, right: &Expression| {
+            (
+                Box::new(self.expression(left)),
+                Box::new(self.expression(right)),
+            )
+        };
+        match expression {
+            Expression::Value(value) => Expr::Value(value.clone()),
+            Expression::Name(name) => Expr::Variable(self.variable(name)),
+            Expression::Equals { left, right } => {
+                let (left, right) = pair(left, right);
+                Expr::Equals(left, right)
+            }
+            Expression::NotEquals { left, right } => {
+                let (left, right) = pair(left, right);
+                Expr::NotEquals(left, right)
+            }
+            Expression::GreaterThan { left, right } => {
+                let (left, right) = pair(left, right);
+                Expr::GreaterThan(left, right)
+            }
+            Expression::LessThan { left, right } => {
+                let (left, right) = pair(left, right);
+                Expr::LessThan(left, right)
+            }
+            Expression::Call { name, args } => Expr::Call {
+                name: name.clone(),
+                args: args.iter().map(|arg| self.expression(arg)).collect(),
+            },
+        }
+    }
+
+    fn command(&self, command: &Command) -> Effect {
+        match command {
+            Command::Set { name, value } => Effect::Set {
+                variable: self.variable(name),
+                value: value.clone(),
+            },
+            Command::Script { source } => Effect::Script {
+                source: source.clone(),
+            },
+            Command::Call { name, args } => Effect::Call {
+                name: name.clone(),
+                args: args.iter().map(|arg| self.expression(arg)).collect(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn story_variables() -> Variables {
+        Variables::new(HashMap::from([
+            ("gold".to_string(), Value::Int(0)),
+            ("health".to_string(), Value::Int(10)),
+        ]))
+    }
+
+    #[test]
+    fn only_changes_from_the_defaults_are_kept() {
+        let mut variables = story_variables();
+        assert!(variables.set("gold", Value::Int(5)));
+        assert_eq!(variables.changes(), [(0, Value::Int(5))]);
+        assert_eq!(variables.get("gold"), Some(&Value::Int(5)));
+        assert_eq!(variables.get("health"), Some(&Value::Int(10)));
+
+        assert!(variables.set("gold", Value::Int(0)));
+        assert!(variables.changes().is_empty());
+    }
+
+    #[test]
+    fn setting_a_variable_the_story_doesnt_have_fails() {
+        let mut variables = story_variables();
+        assert!(!variables.set("mana", Value::Int(1)));
+        assert_eq!(variables.get("mana"), None);
+        assert!(variables.changes().is_empty());
+    }
+
+    #[test]
+    fn loaded_variables_share_the_story_defaults_once_rebased() {
+        let story = story_variables();
+        let mut session = story.clone();
+        session.set("health", Value::Int(3));
+        let json = serde_json::to_string(&session).unwrap();
+
+        let mut loaded: Variables = serde_json::from_str(&json).unwrap();
+        assert!(!Arc::ptr_eq(&loaded.defaults, &story.defaults));
+        loaded.rebase(&story);
+        assert!(Arc::ptr_eq(&loaded.defaults, &story.defaults));
+        assert_eq!(loaded.changes(), session.changes());
+    }
+
+    #[test]
+    fn only_changed_variables_are_saved() {
+        let mut session = story_variables();
+        session.set("health", Value::Int(3));
+        let saved = json!({ "health": { "Int": 3 } });
+        assert_eq!(serde_json::to_value(&session).unwrap(), saved);
+
+        let loaded: Variables = serde_json::from_value(saved.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), saved);
+    }
+
+    #[test]
+    fn variables_that_werent_rebased_are_looked_up_by_name() {
+        let story = story_variables();
+        let mut loaded: Variables =
+            serde_json::from_value(json!({ "health": { "Int": 3 } })).unwrap();
+        assert_eq!(loaded.at(0, &story), Ok(&Value::Int(0)));
+        assert_eq!(loaded.at(1, &story), Ok(&Value::Int(3)));
+
+        loaded.set_at(0, &story, &Value::Int(7)).unwrap();
+        assert!(Arc::ptr_eq(&loaded.defaults, &story.defaults));
+        assert_eq!(loaded.changes(), [(0, Value::Int(7)), (1, Value::Int(3))]);
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_errors() {
+        let story = story_variables();
+        let loaded: Variables =
+            serde_json::from_value(json!({ "gold": { "Bool": true } })).unwrap();
+        assert_eq!(
+            loaded.at(0, &story),
+            Err(EvalError::MismatchedVariableType {
+                name: "gold".to_string()
+            })
+        );
+
+        let mut session = story.clone();
+        assert!(session.set_at(0, &story, &Value::Bool(true)).is_err());
+        assert!(session.changes().is_empty());
+    }
+
+    #[test]
+    fn rebasing_drops_values_the_story_cant_use() {
+        let story = story_variables();
+        let mut loaded: Variables = serde_json::from_value(json!({
+            "gold": { "Bool": true },
+            "mana": { "Int": 3 },
+            "health": { "Int": 4 },
+        }))
+        .unwrap();
+        loaded.rebase(&story);
+        assert_eq!(loaded.changes(), [(1, Value::Int(4))]);
+    }
+}
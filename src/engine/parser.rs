@@ -1,3 +1,5 @@
+//! The syntax tree for `.cyoa` stories and the parser that produces it.
+
 use std::fmt::Display;
 
 use nom::{
@@ -6,23 +8,102 @@ use nom::{
     bytes::complete::tag,
     character::complete::{alphanumeric1, char, multispace0, multispace1},
     combinator::opt,
-    multi::{many0, many1},
+    multi::{many0, many1, separated_list0},
     sequence::{delimited, pair, preceded, separated_pair, terminated},
 };
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+/// A piece of a [`FormatString`]: literal text, a `{name}` to interpolate, or a
+/// `{@name|fallback}` for a [`TextProvider`](crate::engine::TextProvider) to fill in.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub enum FormatStringPart {
     Literal(String),
     Name(String),
+    /// Text generated when the story is shown. The fallback is shown when there is no provider
+    /// or it has nothing to say, and a story without one isn't loaded.
+    Dynamic {
+        name: String,
+        fallback: Option<String>,
+    },
 }
 
-#[derive(Debug, Clone)]
+/// A quoted string from a story, which may interpolate variables.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct FormatString(pub Vec<FormatStringPart>);
 
-#[derive(Debug, Clone)]
+impl Display for FormatString {
+    /// The string as it appears in the source, without the quotes.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for part in &self.0 {
+            match part {
+                FormatStringPart::Literal(s) => f.write_str(s)?,
+                FormatStringPart::Name(name) => f.write_fmt(format_args!("{{{name}}}"))?,
+                FormatStringPart::Dynamic { name, fallback } => match fallback {
+                    Some(fallback) => f.write_fmt(format_args!("{{@{name}|{fallback}}}"))?,
+                    None => f.write_fmt(format_args!("{{@{name}}}"))?,
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<&str> for FormatString {
+    /// Text as it would be written between quotes in a story, with `{name}` interpolations and
+    /// `{@name|fallback}` segments. Braces that don't surround either are kept as they are.
+    fn from(text: &str) -> Self {
+        let mut parts = Vec::new();
+        let mut rest = text;
+        while let Some(open) = rest.find('{') {
+            if let Ok((after, part)) = parse_format_string_part_dynamic(&rest[open..]) {
+                if open > 0 {
+                    parts.push(FormatStringPart::Literal(rest[..open].to_string()));
+                }
+                parts.push(part);
+                rest = after;
+                continue;
+            }
+            let after = &rest[open + 1..];
+            let name_length = after
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            if name_length == 0 || !after[name_length..].starts_with('}') {
+                parts.push(FormatStringPart::Literal(rest[..=open].to_string()));
+                rest = after;
+                continue;
+            }
+            if open > 0 {
+                parts.push(FormatStringPart::Literal(rest[..open].to_string()));
+            }
+            parts.push(FormatStringPart::Name(after[..name_length].to_string()));
+            rest = &after[name_length + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(FormatStringPart::Literal(rest.to_string()));
+        }
+
+        // Neighbouring literals are merged, as the parser would have read them.
+        let mut merged: Vec<FormatStringPart> = Vec::new();
+        for part in parts {
+            match (merged.last_mut(), part) {
+                (Some(FormatStringPart::Literal(last)), FormatStringPart::Literal(literal)) => {
+                    last.push_str(&literal);
+                }
+                (_, part) => merged.push(part),
+            }
+        }
+        FormatString(merged)
+    }
+}
+
+/// The value of a variable or literal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub enum Value {
     Bool(bool),
-    Int(i32),
+    Int(i64),
     String(FormatString),
 }
 
@@ -37,49 +118,111 @@ impl Value {
 }
 
 impl Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Value::Bool(b) => f.write_str(if *b { "true" } else { "false" }),
             Value::Int(i) => f.write_str(&i.to_string()),
-            Value::String(format_string) => {
-                let s = format_string
-                    .0
-                    .iter()
-                    .map(|part| match part {
-                        FormatStringPart::Literal(s) => s.clone(),
-                        FormatStringPart::Name(name) => format!("{{{name}}}"),
-                    })
-                    .collect::<String>();
-                f.write_fmt(format_args!("\"{s}\""))
-            }
+            Value::String(format_string) => f.write_fmt(format_args!("\"{format_string}\"")),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum Expression<'a> {
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Self {
+        Value::Int(value.into())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<&str> for Value {
+    /// A string value, which may interpolate variables with `{name}`.
+    fn from(value: &str) -> Self {
+        Value::String(value.into())
+    }
+}
+
+/// A condition from an `[IF ...]` requirement.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Expression {
     Value(Value),
     Name(String),
     Equals {
-        left: &'a Expression<'a>,
-        right: &'a Expression<'a>,
+        left: Box<Expression>,
+        right: Box<Expression>,
     },
     NotEquals {
-        left: &'a Expression<'a>,
-        right: &'a Expression<'a>,
+        left: Box<Expression>,
+        right: Box<Expression>,
     },
     GreaterThan {
-        left: &'a Expression<'a>,
-        right: &'a Expression<'a>,
+        left: Box<Expression>,
+        right: Box<Expression>,
     },
     LessThan {
-        left: &'a Expression<'a>,
-        right: &'a Expression<'a>,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+    /// A function from a plugin, e.g. `roll_dice(6)`. Only supported with the `plugins` feature.
+    Call {
+        name: String,
+        args: Vec<Expression>,
     },
 }
 
-impl Display for Expression<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Expression {
+    /// The value of the variable called `name`.
+    pub fn name(name: impl Into<String>) -> Self {
+        Self::Name(name.into())
+    }
+
+    pub fn equals(self, right: impl Into<Expression>) -> Self {
+        Self::Equals {
+            left: Box::new(self),
+            right: Box::new(right.into()),
+        }
+    }
+
+    pub fn not_equals(self, right: impl Into<Expression>) -> Self {
+        Self::NotEquals {
+            left: Box::new(self),
+            right: Box::new(right.into()),
+        }
+    }
+
+    pub fn greater_than(self, right: impl Into<Expression>) -> Self {
+        Self::GreaterThan {
+            left: Box::new(self),
+            right: Box::new(right.into()),
+        }
+    }
+
+    pub fn less_than(self, right: impl Into<Expression>) -> Self {
+        Self::LessThan {
+            left: Box::new(self),
+            right: Box::new(right.into()),
+        }
+    }
+}
+
+impl<T: Into<Value>> From<T> for Expression {
+    fn from(value: T) -> Self {
+        Self::Value(value.into())
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::Value(v) => f.write_str(v.to_string().as_str()),
             Self::Name(name) => f.write_str(name),
@@ -87,35 +230,72 @@ impl Display for Expression<'_> {
             Self::NotEquals { left, right } => f.write_fmt(format_args!("({left} != {right})")),
             Self::GreaterThan { left, right } => f.write_fmt(format_args!("({left} > {right})")),
             Self::LessThan { left, right } => f.write_fmt(format_args!("({left} < {right})")),
+            Self::Call { name, args } => f.write_fmt(format_args!("{name}({})", join(args))),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum Command<'a> {
-    Set { name: &'a str, value: Value },
+/// A side effect from a `[THEN ...]` command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    Set {
+        name: String,
+        value: Value,
+    },
+    /// `SCRIPT "..."`, run with the story's script functions. Only supported with the
+    /// `scripting` feature.
+    Script {
+        source: String,
+    },
+    /// A function from a plugin, e.g. `add_gold(5)`. Only supported with the `plugins` feature.
+    Call {
+        name: String,
+        args: Vec<Expression>,
+    },
 }
 
-impl Display for Command<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::Set { name, value } => f.write_fmt(format_args!("SET {name} {value}")),
+            Self::Script { source } => f.write_fmt(format_args!("SCRIPT \"{source}\"")),
+            Self::Call { name, args } => f.write_fmt(format_args!("{name}({})", join(args))),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Choice<'a> {
-    pub requirement: Option<Expression<'a>>,
+/// A function's arguments, separated by commas.
+fn join(args: &[Expression]) -> String {
+    args.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A choice leading from one node to another.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Choice {
+    pub requirement: Option<Expression>,
     pub text: FormatString,
     pub next_node_id: String,
-    pub command: Option<Command<'a>>,
+    pub command: Option<Command>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Node<'a> {
+/// Another way of phrasing a node's narration, shown to sessions in the variant's bucket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TextVariant {
+    pub name: String,
+    pub text: FormatString,
+}
+
+/// A scene: narration followed by the choices available from it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Node {
     pub display_text: FormatString,
-    pub choices: Vec<Choice<'a>>,
+    /// Alternatives to `display_text` from `[VARIANT name] "..."` lines, for A/B tests.
+    #[serde(default)]
+    pub variants: Vec<TextVariant>,
+    pub choices: Vec<Choice>,
 }
 
 fn parse_name(input: &str) -> IResult<&str, String> {
@@ -128,9 +308,26 @@ fn parse_id_definition(input: &str) -> IResult<&str, String> {
     preceded(pair(char('='), multispace0), parse_name).parse(input)
 }
 
+/// A call to a plugin function, e.g. `roll_dice(6)`.
+fn parse_call(input: &str) -> IResult<&str, (String, Vec<Expression>)> {
+    pair(
+        parse_name,
+        delimited(
+            pair(char('('), multispace0),
+            separated_list0(
+                delimited(multispace0, char(','), multispace0),
+                parse_expression,
+            ),
+            pair(multispace0, char(')')),
+        ),
+    )
+    .parse(input)
+}
+
 fn parse_primary_expression(input: &str) -> IResult<&str, Expression> {
     alt((
         parse_value.map(Expression::Value),
+        parse_call.map(|(name, args)| Expression::Call { name, args }),
         parse_name.map(Expression::Name),
     ))
     .parse(input)
@@ -148,8 +345,8 @@ fn parse_expression(input: &str) -> IResult<&str, Expression> {
     )
     .parse(input)
     {
-        let left = Box::leak(Box::new(left));
-        let right = Box::leak(Box::new(right));
+        let left = Box::new(left);
+        let right = Box::new(right);
         Ok((
             input,
             match op {
@@ -180,15 +377,33 @@ fn parse_command_set(input: &str) -> IResult<&str, Command> {
         delimited(multispace0, char('='), multispace0),
         parse_value,
     )
-        .map(|(name, _, value)| Command::Set {
-            name: Box::leak(name.into_boxed_str()),
-            value,
-        })
+        .map(|(name, _, value)| Command::Set { name, value })
         .parse(input)
 }
 
+/// A script is taken as it is, without interpolating variables.
+fn parse_command_script(input: &str) -> IResult<&str, Command> {
+    preceded(
+        pair(tag("SCRIPT"), multispace1),
+        delimited(
+            char('"'),
+            nom::bytes::complete::take_while(|c: char| c != '"'),
+            char('"'),
+        ),
+    )
+    .map(|source: &str| Command::Script {
+        source: source.to_string(),
+    })
+    .parse(input)
+}
+
 fn parse_command_inner(input: &str) -> IResult<&str, Command> {
-    alt((parse_command_set,)).parse(input)
+    alt((
+        parse_command_script,
+        parse_call.map(|(name, args)| Command::Call { name, args }),
+        parse_command_set,
+    ))
+    .parse(input)
 }
 
 fn parse_command(input: &str) -> IResult<&str, Command> {
@@ -219,18 +434,33 @@ fn parse_choice(input: &str) -> IResult<&str, Choice> {
         .parse(input)
 }
 
-fn parse_node_body(input: &str) -> IResult<&str, Node> {
+fn parse_variant(input: &str) -> IResult<&str, TextVariant> {
     pair(
+        delimited(
+            (char('['), multispace0, tag("VARIANT"), multispace1),
+            parse_name,
+            (multispace0, char(']')),
+        ),
         preceded(multispace0, parse_format_string),
-        many0(delimited(multispace0, parse_choice, multispace0)),
     )
-    .map(|(display_text, choices)| Node {
-        display_text,
-        choices,
-    })
+    .map(|(name, text)| TextVariant { name, text })
     .parse(input)
 }
 
+fn parse_node_body(input: &str) -> IResult<&str, Node> {
+    (
+        preceded(multispace0, parse_format_string),
+        many0(preceded(multispace0, parse_variant)),
+        many0(delimited(multispace0, parse_choice, multispace0)),
+    )
+        .map(|(display_text, variants, choices)| Node {
+            display_text,
+            variants,
+            choices,
+        })
+        .parse(input)
+}
+
 fn parse_node_definition(input: &str) -> IResult<&str, (String, Node)> {
     pair(parse_id_definition, parse_node_body).parse(input)
 }
@@ -244,7 +474,7 @@ fn parse_bool(input: &str) -> IResult<&str, Value> {
 }
 
 fn parse_int(input: &str) -> IResult<&str, Value> {
-    nom::character::complete::i32.map(Value::Int).parse(input)
+    nom::character::complete::i64.map(Value::Int).parse(input)
 }
 
 fn parse_format_string_part_literal(input: &str) -> IResult<&str, FormatStringPart> {
@@ -259,12 +489,32 @@ fn parse_format_string_part_name(input: &str) -> IResult<&str, FormatStringPart>
         .parse(input)
 }
 
+fn parse_format_string_part_dynamic(input: &str) -> IResult<&str, FormatStringPart> {
+    delimited(
+        pair(char('{'), char('@')),
+        pair(
+            parse_name,
+            opt(preceded(
+                char('|'),
+                nom::bytes::complete::take_while(|c: char| c != '"' && c != '}'),
+            )),
+        ),
+        char('}'),
+    )
+    .map(|(name, fallback)| FormatStringPart::Dynamic {
+        name,
+        fallback: fallback.map(str::to_string),
+    })
+    .parse(input)
+}
+
 fn parse_format_string(input: &str) -> IResult<&str, FormatString> {
     delimited(
         char('"'),
         many0(alt((
             parse_format_string_part_literal,
             parse_format_string_part_name,
+            parse_format_string_part_dynamic,
         ))),
         char('"'),
     )
@@ -291,9 +541,44 @@ fn parse_variable_definition(input: &str) -> IResult<&str, (String, Value)> {
     .parse(input)
 }
 
-pub enum ProgramPart<'a> {
-    NodeDefinition { id: String, node: Node<'a> },
+fn parse_node_rename(input: &str) -> IResult<&str, (String, String)> {
+    preceded(
+        tag("RENAMED"),
+        separated_pair(
+            preceded(multispace1, parse_name),
+            delimited(multispace0, tag("->"), multispace0),
+            parse_name,
+        ),
+    )
+    .parse(input)
+}
+
+fn parse_metadata(input: &str) -> IResult<&str, (String, String)> {
+    preceded(
+        tag("META"),
+        pair(
+            preceded(multispace1, parse_name),
+            preceded(
+                multispace1,
+                delimited(
+                    char('"'),
+                    nom::bytes::complete::take_while(|c: char| c != '"'),
+                    char('"'),
+                ),
+            ),
+        ),
+    )
+    .map(|(key, value)| (key, value.to_string()))
+    .parse(input)
+}
+
+/// A top-level definition in a story.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ProgramPart {
+    NodeDefinition { id: String, node: Node },
     VariableDefinition { name: String, value: Value },
+    NodeRename { old_id: String, new_id: String },
+    Metadata { key: String, value: String },
 }
 
 fn parse_program_part(input: &str) -> IResult<&str, ProgramPart> {
@@ -301,10 +586,53 @@ fn parse_program_part(input: &str) -> IResult<&str, ProgramPart> {
         parse_node_definition.map(|(id, node)| ProgramPart::NodeDefinition { id, node }),
         parse_variable_definition
             .map(|(name, value)| ProgramPart::VariableDefinition { name, value }),
+        parse_node_rename.map(|(old_id, new_id)| ProgramPart::NodeRename { old_id, new_id }),
+        parse_metadata.map(|(key, value)| ProgramPart::Metadata { key, value }),
     ))
     .parse(input)
 }
 
+/// Parse as much of a story as possible into its top-level definitions.
 pub fn parse_program(input: &str) -> IResult<&str, Vec<ProgramPart>> {
     many0(delimited(multispace0, parse_program_part, multispace0)).parse(input)
 }
+
+/// Like [`parse_program`], but hands each definition to `add` as soon as it is parsed instead
+/// of collecting them. Returns the part of the input that couldn't be parsed.
+pub fn parse_program_with(input: &str, mut add: impl FnMut(ProgramPart)) -> &str {
+    let mut rest = input;
+    loop {
+        match delimited(multispace0, parse_program_part, multispace0).parse(rest) {
+            // Like `many0`, stop at a definition that doesn't consume anything.
+            Ok((remaining, part)) if remaining.len() < rest.len() => {
+                add(part);
+                rest = remaining;
+            }
+            _ => return rest,
+        }
+    }
+}
+
+/// Whether a line of a story can only be the start of a new top-level definition, so that
+/// everything before it can be parsed on its own.
+pub fn starts_definition(line: &str) -> bool {
+    ["=", "SET", "RENAMED", "META"]
+        .iter()
+        .any(|keyword| line.starts_with(keyword))
+}
+
+/// Parse an expression written as it would be in a story's `[IF ...]`, e.g. `gold > 5`.
+pub fn parse_condition(input: &str) -> Option<Expression> {
+    match parse_expression(input.trim()) {
+        Ok(("", expression)) => Some(expression),
+        _ => None,
+    }
+}
+
+/// Parse a single value written as it would be in a story, e.g. `15`, `true` or `"a string"`.
+pub fn parse_literal(input: &str) -> Option<Value> {
+    match parse_value(input.trim()) {
+        Ok(("", value)) => Some(value),
+        _ => None,
+    }
+}
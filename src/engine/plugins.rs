@@ -0,0 +1,242 @@
+//! WebAssembly plugins that add functions stories can call, e.g. `[IF roll_dice(6) > 3]` or
+//! `[THEN add_gold(5)]`.
+//!
+//! A plugin is a WebAssembly component built against the `plugin` world in `wit/plugin.wit`.
+//! Plugins are sandboxed: they have no access to files, the network or the clock, only see the
+//! variables of the session calling them, and are stopped if they run for too long or use too
+//! much memory.
+
+use super::parser::{FormatString, FormatStringPart, Value};
+use std::{
+    collections::{HashMap, hash_map::RandomState},
+    fmt::Display,
+    hash::BuildHasher,
+    path::Path,
+};
+use wasmtime::{
+    Config, Store, StoreLimits, StoreLimitsBuilder,
+    component::{Component, HasSelf, Linker},
+};
+
+mod bindings {
+    wasmtime::component::bindgen!({
+        path: "wit/plugin.wit",
+        world: "plugin",
+    });
+}
+
+use bindings::{Plugin, PluginImports, PluginPre};
+
+/// How much work a single call can do before it is stopped.
+const FUEL_PER_CALL: u64 = 10_000_000;
+/// How much memory a plugin can use, in bytes.
+const MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// A plugin that couldn't be loaded, or a function call that failed.
+#[derive(Debug)]
+pub struct PluginError(String);
+
+impl Display for PluginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+/// What a plugin can reach while it runs.
+struct Host {
+    limits: StoreLimits,
+}
+
+impl PluginImports for Host {
+    fn random(&mut self) -> u64 {
+        RandomState::new().hash_one(std::time::SystemTime::now())
+    }
+}
+
+/// A loaded plugin, ready to be instantiated for each call.
+struct Loaded {
+    name: String,
+    pre: PluginPre<Host>,
+}
+
+/// Every plugin a story can call, and which of them provides each function.
+pub struct Plugins {
+    engine: wasmtime::Engine,
+    plugins: Vec<Loaded>,
+    /// Function names mapped to the index of the plugin that provides them.
+    functions: HashMap<String, usize>,
+}
+
+impl Plugins {
+    /// Load the plugins at `paths`. A function provided by more than one plugin is called from
+    /// the first one that provides it.
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self, PluginError> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config).map_err(|e| PluginError(e.to_string()))?;
+        let mut linker = Linker::new(&engine);
+        Plugin::add_to_linker::<_, HasSelf<_>>(&mut linker, |host| host)
+            .map_err(|e| PluginError(e.to_string()))?;
+
+        let mut plugins = Plugins {
+            engine,
+            plugins: Vec::new(),
+            functions: HashMap::new(),
+        };
+        for path in paths {
+            let path = path.as_ref();
+            let name = path.display().to_string();
+            let error =
+                |e: wasmtime::Error| PluginError(format!("Failed to load plugin '{name}': {e:#}"));
+            let component = Component::from_file(&plugins.engine, path).map_err(error)?;
+            let pre = PluginPre::new(linker.instantiate_pre(&component).map_err(error)?)
+                .map_err(error)?;
+            let index = plugins.plugins.len();
+            plugins.plugins.push(Loaded { name, pre });
+            let (mut store, plugin) = plugins.instantiate(index)?;
+            let functions = plugin
+                .call_functions(&mut store)
+                .map_err(|e| plugins.error(index, e))?;
+            for function in functions {
+                plugins.functions.entry(function).or_insert(index);
+            }
+        }
+        Ok(plugins)
+    }
+
+    /// Whether a plugin provides the function called `name`.
+    pub fn provides(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// The names of every function the plugins provide, in no particular order.
+    pub fn functions(&self) -> impl Iterator<Item = &str> {
+        self.functions.keys().map(String::as_str)
+    }
+
+    /// Call a function used in an expression.
+    pub(crate) fn evaluate(
+        &self,
+        name: &str,
+        args: &[Value],
+        variables: &HashMap<String, Value>,
+    ) -> Result<Value, PluginError> {
+        let index = self.index(name)?;
+        let (mut store, plugin) = self.instantiate(index)?;
+        let args = args.iter().map(to_plugin).collect::<Result<Vec<_>, _>>()?;
+        let variables = to_plugin_variables(variables)?;
+        plugin
+            .call_evaluate(&mut store, name, &args, &variables)
+            .map_err(|e| self.error(index, e))?
+            .map(from_plugin)
+            .map_err(|e| PluginError(format!("{name} failed: {e}")))
+    }
+
+    /// Call a function used as a command, returning the variables as it left them. Fails,
+    /// changing nothing, if it sets a variable the story doesn't define or changes a variable's
+    /// type.
+    pub(crate) fn run(
+        &self,
+        name: &str,
+        args: &[Value],
+        variables: &HashMap<String, Value>,
+    ) -> Result<HashMap<String, Value>, PluginError> {
+        let index = self.index(name)?;
+        let (mut store, plugin) = self.instantiate(index)?;
+        let args = args.iter().map(to_plugin).collect::<Result<Vec<_>, _>>()?;
+        let changes = plugin
+            .call_run(&mut store, name, &args, &to_plugin_variables(variables)?)
+            .map_err(|e| self.error(index, e))?
+            .map_err(|e| PluginError(format!("{name} failed: {e}")))?;
+
+        let mut variables = variables.clone();
+        for change in changes {
+            let value = from_plugin(change.value);
+            match variables.get_mut(&change.name) {
+                Some(variable)
+                    if std::mem::discriminant(variable) == std::mem::discriminant(&value) =>
+                {
+                    *variable = value;
+                }
+                _ => {
+                    return Err(PluginError(format!(
+                        "{name} can't set '{}' to {value}",
+                        change.name
+                    )));
+                }
+            }
+        }
+        Ok(variables)
+    }
+
+    fn index(&self, name: &str) -> Result<usize, PluginError> {
+        self.functions
+            .get(name)
+            .copied()
+            .ok_or_else(|| PluginError(format!("no plugin provides a function named '{name}'")))
+    }
+
+    /// A fresh instance of a plugin, so no call can see what an earlier one left behind.
+    fn instantiate(&self, index: usize) -> Result<(Store<Host>, Plugin), PluginError> {
+        let mut store = Store::new(
+            &self.engine,
+            Host {
+                limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+            },
+        );
+        store.limiter(|host| &mut host.limits);
+        store
+            .set_fuel(FUEL_PER_CALL)
+            .map_err(|e| self.error(index, e))?;
+        let plugin = self.plugins[index]
+            .pre
+            .instantiate(&mut store)
+            .map_err(|e| self.error(index, e))?;
+        Ok((store, plugin))
+    }
+
+    fn error(&self, index: usize, e: wasmtime::Error) -> PluginError {
+        PluginError(format!(
+            "Plugin '{}' failed: {e:#}",
+            self.plugins[index].name
+        ))
+    }
+}
+
+/// Plugins only take 32 bit integers, so a call with a larger one fails.
+fn to_plugin(value: &Value) -> Result<bindings::Value, PluginError> {
+    Ok(match value {
+        Value::Bool(b) => bindings::Value::Boolean(*b),
+        Value::Int(i) => bindings::Value::Integer(
+            i32::try_from(*i)
+                .map_err(|_| PluginError(format!("{i} is too large to pass to a plugin")))?,
+        ),
+        Value::String(s) => bindings::Value::Text(s.to_string()),
+    })
+}
+
+fn to_plugin_variables(
+    variables: &HashMap<String, Value>,
+) -> Result<Vec<bindings::Variable>, PluginError> {
+    variables
+        .iter()
+        .map(|(name, value)| {
+            Ok(bindings::Variable {
+                name: name.clone(),
+                value: to_plugin(value)?,
+            })
+        })
+        .collect()
+}
+
+/// Text from a plugin is taken as it is, without interpolating variables.
+fn from_plugin(value: bindings::Value) -> Value {
+    match value {
+        bindings::Value::Boolean(b) => Value::Bool(b),
+        bindings::Value::Integer(i) => Value::Int(i.into()),
+        bindings::Value::Text(s) if s.is_empty() => Value::String(FormatString(Vec::new())),
+        bindings::Value::Text(s) => Value::String(FormatString(vec![FormatStringPart::Literal(s)])),
+    }
+}
@@ -0,0 +1,107 @@
+//! Turns a parsed story back into `.cyoa` source in the canonical format.
+
+use super::parser::{Choice, Command, Expression, ProgramPart};
+
+const INDENT: &str = "    ";
+
+/// The story as `.cyoa` source, with metadata first, then variables, then renames, then nodes
+/// in the order they were defined.
+pub fn print_program(parts: &[ProgramPart]) -> String {
+    let mut sections = Vec::new();
+    let metadata: Vec<String> = parts
+        .iter()
+        .filter_map(|part| match part {
+            ProgramPart::Metadata { key, value } => Some(format!("META {key} \"{value}\"\n")),
+            _ => None,
+        })
+        .collect();
+    let variables: Vec<String> = parts
+        .iter()
+        .filter_map(|part| match part {
+            ProgramPart::VariableDefinition { name, value } => {
+                Some(format!("SET {name} {value}\n"))
+            }
+            _ => None,
+        })
+        .collect();
+    let renames: Vec<String> = parts
+        .iter()
+        .filter_map(|part| match part {
+            ProgramPart::NodeRename { old_id, new_id } => {
+                Some(format!("RENAMED {old_id} -> {new_id}\n"))
+            }
+            _ => None,
+        })
+        .collect();
+    for lines in [metadata, variables, renames] {
+        if !lines.is_empty() {
+            sections.push(lines.concat());
+        }
+    }
+
+    for part in parts {
+        if let ProgramPart::NodeDefinition { id, node } = part {
+            let mut section = format!("= {id}\n{INDENT}\"{}\"\n", node.display_text);
+            for variant in &node.variants {
+                section.push_str(&format!(
+                    "{INDENT}[VARIANT {}] \"{}\"\n",
+                    variant.name, variant.text
+                ));
+            }
+            for choice in &node.choices {
+                section.push_str(&format!("{INDENT}{}\n", format_choice(choice)));
+            }
+            sections.push(section);
+        }
+    }
+
+    sections.join("\n")
+}
+
+fn format_choice(choice: &Choice) -> String {
+    let mut line = String::new();
+    if let Some(requirement) = &choice.requirement {
+        line.push_str(&format!("[IF {}] ", format_expression(requirement)));
+    }
+    line.push_str(&format!("\"{}\" -> {}", choice.text, choice.next_node_id));
+    if let Some(command) = &choice.command {
+        line.push_str(&format!(" [THEN {}]", format_command(command)));
+    }
+    line
+}
+
+/// An expression as it is written in a story. Unlike its `Display` output, this has no
+/// brackets, which the story syntax doesn't allow.
+fn format_expression(expression: &Expression) -> String {
+    match expression {
+        Expression::Value(value) => value.to_string(),
+        Expression::Name(name) => name.clone(),
+        Expression::Equals { left, right } => binary(left, "=", right),
+        Expression::NotEquals { left, right } => binary(left, "!=", right),
+        Expression::GreaterThan { left, right } => binary(left, ">", right),
+        Expression::LessThan { left, right } => binary(left, "<", right),
+        Expression::Call { name, args } => call(name, args),
+    }
+}
+
+fn binary(left: &Expression, op: &str, right: &Expression) -> String {
+    format!(
+        "{} {op} {}",
+        format_expression(left),
+        format_expression(right)
+    )
+}
+
+fn call(name: &str, args: &[Expression]) -> String {
+    let args: Vec<String> = args.iter().map(format_expression).collect();
+    format!("{name}({})", args.join(", "))
+}
+
+/// A command as it is written in a story, e.g. `x = 1`.
+fn format_command(command: &Command) -> String {
+    match command {
+        Command::Set { name, value } => format!("{name} = {value}"),
+        Command::Script { source } => format!("SCRIPT \"{source}\""),
+        Command::Call { name, args } => call(name, args),
+    }
+}
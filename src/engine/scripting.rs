@@ -0,0 +1,129 @@
+//! Running `[THEN SCRIPT "..."]` commands with [Rhai](https://rhai.rs), for logic that stories
+//! can't express otherwise.
+//!
+//! Scripts are sandboxed: they can't touch files, the network or other modules, and are stopped
+//! if they run for too long or build values that are too large. The only way for a script to
+//! affect the story is through the session's variables, with `get(name)` and `set(name, value)`.
+//! Only variables the story defines can be used, and `set` keeps each variable's type.
+
+use super::parser::{FormatString, FormatStringPart, Value};
+use rhai::{Dynamic, EvalAltResult, module_resolvers::DummyModuleResolver};
+use std::{cell::RefCell, collections::HashMap, fmt::Display};
+
+/// How many operations a script can perform before it is stopped.
+const MAX_OPERATIONS: u64 = 100_000;
+
+thread_local! {
+    /// The variables of the session a script is running for. Scripts run synchronously, so the
+    /// variables are only ever seen by the script that put them here.
+    static VARIABLES: RefCell<HashMap<String, Value>> = RefCell::new(HashMap::new());
+}
+
+/// A script that couldn't be compiled, or that failed while running.
+#[derive(Debug)]
+pub struct ScriptError(String);
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<rhai::ParseError> for ScriptError {
+    fn from(e: rhai::ParseError) -> Self {
+        Self(e.to_string())
+    }
+}
+
+impl From<Box<EvalAltResult>> for ScriptError {
+    fn from(e: Box<EvalAltResult>) -> Self {
+        Self(e.to_string())
+    }
+}
+
+/// The functions a story's scripts can call, and the sandbox they run in.
+pub struct Scripts {
+    engine: rhai::Engine,
+    functions: rhai::AST,
+}
+
+impl Scripts {
+    /// Compile the functions defined in `source`. Anything else in it is ignored.
+    pub fn new(source: &str) -> Result<Self, ScriptError> {
+        let engine = sandbox();
+        let functions = engine.compile(source)?.clone_functions_only();
+        Ok(Scripts { engine, functions })
+    }
+
+    /// Check that a `SCRIPT` command compiles.
+    pub(crate) fn check(&self, script: &str) -> Result<(), ScriptError> {
+        self.engine.compile(script)?;
+        Ok(())
+    }
+
+    /// Run a `SCRIPT` command against a session's variables, returning them as the script left
+    /// them.
+    pub(crate) fn run(
+        &self,
+        script: &str,
+        variables: &HashMap<String, Value>,
+    ) -> Result<HashMap<String, Value>, ScriptError> {
+        let ast = self.functions.merge(&self.engine.compile(script)?);
+        VARIABLES.set(variables.clone());
+        let result = self.engine.run_ast(&ast);
+        let variables = VARIABLES.take();
+        result?;
+        Ok(variables)
+    }
+}
+
+fn sandbox() -> rhai::Engine {
+    let mut engine = rhai::Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(10_000)
+        .set_max_array_size(1_000)
+        .set_max_map_size(1_000)
+        .set_module_resolver(DummyModuleResolver::new())
+        .on_print(|_| {})
+        .on_debug(|_, _, _| {})
+        .disable_symbol("eval");
+    engine.register_fn("get", get);
+    engine.register_fn("set", set);
+    engine
+}
+
+fn get(name: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    VARIABLES.with_borrow(|variables| match variables.get(name) {
+        Some(Value::Bool(b)) => Ok(Dynamic::from_bool(*b)),
+        Some(Value::Int(i)) => Ok(Dynamic::from_int(*i)),
+        Some(Value::String(s)) => Ok(Dynamic::from(s.to_string())),
+        None => Err(format!("the story has no variable named '{name}'").into()),
+    })
+}
+
+fn set(name: &str, value: Dynamic) -> Result<(), Box<EvalAltResult>> {
+    VARIABLES.with_borrow_mut(|variables| {
+        let Some(variable) = variables.get_mut(name) else {
+            return Err(format!("the story has no variable named '{name}'").into());
+        };
+        *variable = match variable {
+            Value::Bool(_) => value.as_bool().map(Value::Bool).ok(),
+            Value::Int(_) => value.as_int().ok().map(Value::Int),
+            Value::String(_) => value.into_string().ok().map(|s| {
+                let parts = if s.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![FormatStringPart::Literal(s)]
+                };
+                Value::String(FormatString(parts))
+            }),
+        }
+        .ok_or_else(|| format!("'{name}' can't be set to a value of a different type"))?;
+        Ok(())
+    })
+}
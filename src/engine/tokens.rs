@@ -0,0 +1,245 @@
+//! Splitting a story's source into classified spans for syntax highlighting.
+
+use serde::Serialize;
+
+/// What a span of a story's source is, for choosing how to highlight it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    /// `SET`, `RENAMED`, `META`, `IF`, `THEN`, `SCRIPT`, `VARIANT`, `true` and `false`.
+    Keyword,
+    /// A node's id where it is defined with `= id`, or referred to after `->`.
+    NodeId,
+    /// A variable's name, including `{name}` interpolations inside strings.
+    Variable,
+    /// Quoted text, split around any interpolations it contains.
+    String,
+    /// A `{@name|fallback}` segment inside a string, filled in when the story is shown.
+    Dynamic,
+    Number,
+    /// The name of a plugin function being called, e.g. `roll_dice` in `roll_dice(6)`.
+    Function,
+    /// `->`, `=`, `!=`, `>` and `<`.
+    Operator,
+    /// The brackets around `[IF ...]`, `[THEN ...]` and `[VARIANT ...]`, and the parentheses
+    /// and commas of function calls.
+    Punctuation,
+    /// A `META` key or a variant's name.
+    Label,
+    /// Anything that isn't part of the story's syntax.
+    Unknown,
+}
+
+/// A span of a story's source. `start` and `end` are byte offsets, with `end` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+const KEYWORDS: [&str; 9] = [
+    "SET", "RENAMED", "META", "IF", "THEN", "SCRIPT", "VARIANT", "true", "false",
+];
+
+/// Which kind of `[...]` the lexer is inside, if any.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Context {
+    TopLevel,
+    Requirement,
+    Command,
+    Variant,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Split a story's source into classified spans, in order, skipping whitespace. Unlike
+/// [`Engine::from_program`](crate::Engine::from_program) this never fails: anything that can't be
+/// classified is returned as [`TokenKind::Unknown`], so stories can be highlighted while they
+/// are being written.
+///
+/// ```
+/// use cyoa::{TokenKind, tokenize};
+///
+/// let source = r#"= START "Hello." "Go." -> end"#;
+/// let kinds: Vec<TokenKind> = tokenize(source).iter().map(|token| token.kind).collect();
+/// assert_eq!(
+///     kinds,
+///     [
+///         TokenKind::Operator,
+///         TokenKind::NodeId,
+///         TokenKind::String,
+///         TokenKind::String,
+///         TokenKind::Operator,
+///         TokenKind::NodeId,
+///     ]
+/// );
+/// ```
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut context = Context::TopLevel;
+    let mut index = 0;
+
+    while let Some(c) = source[index..].chars().next() {
+        let rest = &source[index..];
+        if c.is_whitespace() {
+            index += c.len_utf8();
+            continue;
+        }
+        let previous = tokens.last().map(|token| &source[token.start..token.end]);
+
+        if c == '"' {
+            // `META` values and scripts are taken as they are, without interpolation.
+            let interpolates = !matches!(
+                tokens
+                    .len()
+                    .checked_sub(2)
+                    .map(|i| &source[tokens[i].start..tokens[i].end]),
+                Some("META")
+            ) && previous != Some("SCRIPT");
+            index = lex_string(source, index, interpolates, &mut tokens);
+            continue;
+        }
+
+        let (kind, length) = if rest.starts_with("->") || rest.starts_with("!=") {
+            (TokenKind::Operator, 2)
+        } else if matches!(c, '=' | '>' | '<') {
+            (TokenKind::Operator, 1)
+        } else if matches!(c, '[' | '(' | ')' | ',') {
+            (TokenKind::Punctuation, 1)
+        } else if c == ']' {
+            context = Context::TopLevel;
+            (TokenKind::Punctuation, 1)
+        } else if c.is_ascii_digit()
+            || (matches!(c, '-' | '+') && rest[1..].starts_with(|c: char| c.is_ascii_digit()))
+        {
+            let digits = rest[1..]
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len() - 1);
+            (TokenKind::Number, 1 + digits)
+        } else if is_name_char(c) {
+            let length = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            let word = &rest[..length];
+            let kind = if KEYWORDS.contains(&word) {
+                match word {
+                    "IF" => context = Context::Requirement,
+                    "THEN" => context = Context::Command,
+                    "VARIANT" => context = Context::Variant,
+                    _ => {}
+                }
+                TokenKind::Keyword
+            } else if rest[length..].starts_with('(') {
+                TokenKind::Function
+            } else if matches!(previous, Some("->" | "RENAMED"))
+                || (previous == Some("=") && context == Context::TopLevel)
+            {
+                TokenKind::NodeId
+            } else if previous == Some("META") || context == Context::Variant {
+                TokenKind::Label
+            } else if previous == Some("SET")
+                || matches!(context, Context::Requirement | Context::Command)
+            {
+                TokenKind::Variable
+            } else {
+                TokenKind::Unknown
+            };
+            (kind, length)
+        } else {
+            (TokenKind::Unknown, c.len_utf8())
+        };
+
+        tokens.push(Token {
+            kind,
+            start: index,
+            end: index + length,
+        });
+        index += length;
+    }
+
+    tokens
+}
+
+/// The length of the `{@name|fallback}` segment at the start of `text`, if there is one.
+fn dynamic_length(text: &str) -> Option<usize> {
+    let rest = text.strip_prefix("{@")?;
+    let name_length = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+    if name_length == 0 {
+        return None;
+    }
+    let rest = &rest[name_length..];
+    let fallback_length = match rest.strip_prefix('|') {
+        Some(fallback) => 1 + fallback.find(['"', '}']).unwrap_or(fallback.len()),
+        None => 0,
+    };
+    rest[fallback_length..]
+        .starts_with('}')
+        .then_some(2 + name_length + fallback_length + 1)
+}
+
+/// Lex the string starting at `start`, returning the offset just after it. An unterminated
+/// string runs to the end of the source.
+fn lex_string(source: &str, start: usize, interpolates: bool, tokens: &mut Vec<Token>) -> usize {
+    let mut literal_start = start;
+    let mut index = start + 1;
+    let end = loop {
+        let rest = &source[index..];
+        let Some(c) = rest.chars().next() else {
+            break index;
+        };
+        if c == '"' {
+            break index + 1;
+        }
+        if interpolates && let Some(length) = dynamic_length(rest) {
+            if literal_start < index {
+                tokens.push(Token {
+                    kind: TokenKind::String,
+                    start: literal_start,
+                    end: index,
+                });
+            }
+            tokens.push(Token {
+                kind: TokenKind::Dynamic,
+                start: index,
+                end: index + length,
+            });
+            index += length;
+            literal_start = index;
+            continue;
+        }
+        if interpolates && c == '{' {
+            let name_length = rest[1..]
+                .find(|c| !is_name_char(c))
+                .unwrap_or(rest.len() - 1);
+            if name_length > 0 && rest[1 + name_length..].starts_with('}') {
+                if literal_start < index {
+                    tokens.push(Token {
+                        kind: TokenKind::String,
+                        start: literal_start,
+                        end: index,
+                    });
+                }
+                let name_end = index + name_length + 2;
+                tokens.push(Token {
+                    kind: TokenKind::Variable,
+                    start: index,
+                    end: name_end,
+                });
+                literal_start = name_end;
+                index = name_end;
+                continue;
+            }
+        }
+        index += c.len_utf8();
+    };
+
+    if literal_start < end {
+        tokens.push(Token {
+            kind: TokenKind::String,
+            start: literal_start,
+            end,
+        });
+    }
+    end
+}
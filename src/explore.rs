@@ -0,0 +1,103 @@
+use crate::{StorySettings, build_story};
+use cyoa::{Goal, engine::parser::parse_condition};
+use std::{path::PathBuf, process::ExitCode};
+
+#[derive(clap::Args, Debug)]
+pub struct ExploreArgs {
+    /// The story file to search
+    source: PathBuf,
+    /// The node to look for a way to reach
+    #[arg(long)]
+    to: String,
+    /// A condition that must hold on arriving, written as in `[IF ...]`, e.g. `lamp = false`
+    #[arg(long = "where")]
+    condition: Option<String>,
+    /// A node the way there must not pass through. Can be given more than once.
+    #[arg(long)]
+    avoid: Vec<String>,
+    /// Check that there is no way to reach the node, failing if there is one or if the search
+    /// couldn't look everywhere
+    #[arg(long)]
+    never: bool,
+    /// The most choices to take from `START`
+    #[arg(long, default_value_t = 100)]
+    max_depth: usize,
+    /// The most different combinations of node and variables to look at
+    #[arg(long, default_value_t = 100_000)]
+    max_states: usize,
+}
+
+/// Search every way a story can be played for the fewest choices that reach a node, printing
+/// them as steps of a test file.
+pub fn run(args: ExploreArgs) -> ExitCode {
+    let settings = StorySettings {
+        history_depth: 0,
+        max_checkpoints: 0,
+        max_transcript: usize::MAX,
+        #[cfg(feature = "plugins")]
+        plugins: None,
+    };
+    let story = match build_story(&args.source, settings) {
+        Ok(story) => story,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut goal = Goal::new(&args.to);
+    if let Some(condition) = &args.condition {
+        match parse_condition(condition) {
+            Some(condition) => goal.condition = Some(condition),
+            None => {
+                eprintln!("Failed to parse the condition '{condition}'.");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    goal.avoid = args.avoid;
+    goal.max_depth = args.max_depth;
+    goal.max_states = args.max_states;
+
+    let exploration = match story.explore(&goal) {
+        Ok(exploration) => exploration,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let found = exploration.path.is_some();
+    match exploration.path {
+        Some(path) => {
+            println!(
+                "'{}' can be reached with {} choices, after looking at {} states:",
+                args.to,
+                path.len(),
+                exploration.states
+            );
+            for choice_id in path {
+                println!("choose {choice_id}");
+            }
+        }
+        None if exploration.complete => println!(
+            "'{}' can never be reached. Every one of the {} states the story can get into was checked.",
+            args.to, exploration.states
+        ),
+        None => println!(
+            "No way to reach '{}' was found in {} states, but the search stopped before it looked everywhere. Pass a larger --max-depth or --max-states to look further.",
+            args.to, exploration.states
+        ),
+    }
+
+    let answered = if args.never {
+        !found && exploration.complete
+    } else {
+        found
+    };
+    if answered {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
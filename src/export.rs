@@ -0,0 +1,86 @@
+use crate::read_story_parts;
+use clap::ValueEnum;
+use cyoa::engine::parser::ProgramPart;
+use std::{fs, path::PathBuf, process::ExitCode};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ExportFormat {
+    /// The story's definitions as JSON, which can be loaded in place of `.cyoa` source
+    Json,
+    /// A single web page that plays the story without a server
+    Html,
+}
+
+/// The page `--format html` embeds stories in.
+const PLAYER: &str = include_str!("export/player.html");
+
+#[derive(clap::Args, Debug)]
+pub struct ExportArgs {
+    /// The story to export
+    source: PathBuf,
+    #[arg(long, value_enum)]
+    format: ExportFormat,
+    /// Where to write the exported story. Defaults to printing it.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+/// Write a story out in another format.
+pub fn run(args: ExportArgs) -> ExitCode {
+    let parts = match read_story_parts(&args.source) {
+        Ok(parts) => parts,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let exported = match args.format {
+        ExportFormat::Json => {
+            let mut json = serde_json::to_string_pretty(&parts).expect("Failed to serialize story");
+            json.push('\n');
+            json
+        }
+        ExportFormat::Html => {
+            let title = parts
+                .iter()
+                .find_map(|part| match part {
+                    ProgramPart::Metadata { key, value } if key == "title" => Some(value.clone()),
+                    _ => None,
+                })
+                .or_else(|| {
+                    args.source
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().to_string())
+                })
+                .unwrap_or_default();
+            // `<` only appears inside strings, where it can be escaped so the story can't
+            // close the script tag it is embedded in.
+            let json = serde_json::to_string(&parts)
+                .expect("Failed to serialize story")
+                .replace('<', "\\u003c");
+            PLAYER
+                .replace("{{title}}", &escape_html(&title))
+                .replace("{{story}}", &json)
+        }
+    };
+
+    match args.output {
+        Some(path) => {
+            if let Err(e) = fs::write(&path, exported) {
+                eprintln!("Failed to write '{}': {e}", path.display());
+                return ExitCode::FAILURE;
+            }
+        }
+        None => print!("{exported}"),
+    }
+
+    ExitCode::SUCCESS
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,236 @@
+//! A C interface for driving stories from other engines and languages.
+//!
+//! Engines and sessions are opaque pointers owned by the caller, who must free them with
+//! [`cyoa_engine_free`] and [`cyoa_session_free`]. Every string returned by this module
+//! must be freed with [`cyoa_string_free`]. The header for these functions is
+//! `include/cyoa.h`, which the build script generates from this file.
+//!
+//! A panic never unwinds into the caller. Functions that panic return null or `false`
+//! instead, as they do when they fail.
+
+use crate::{ChoiceResult, Engine, Session};
+use std::{
+    any::Any,
+    ffi::{CStr, CString, c_char},
+    panic::{self, AssertUnwindSafe},
+    ptr,
+};
+
+fn into_c_string(s: String) -> *mut c_char {
+    // Interior nul bytes can't be represented in a C string, so drop them.
+    CString::new(s.replace('\0', "")).unwrap().into_raw()
+}
+
+/// Run the body of an exported function, returning `on_panic` if it panics, since unwinding
+/// into the caller is undefined behaviour.
+fn catch_panic<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+/// What a panic was about, from the value it was started with.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown error")
+}
+
+/// Load a story from the source of a `.cyoa` file.
+///
+/// Returns null if the source is invalid UTF-8 or the story has errors, or if loading it
+/// panicked. In that case, if `error_out` is not null, it is set to a newly allocated
+/// description of the errors.
+///
+/// # Safety
+///
+/// `source` must be a valid nul-terminated string. `error_out` must be null or valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cyoa_engine_create_from_source(
+    source: *const c_char,
+    error_out: *mut *mut c_char,
+) -> *mut Engine {
+    let result = panic::catch_unwind(|| {
+        unsafe { CStr::from_ptr(source) }
+            .to_str()
+            .map_err(|e| format!("The story source is not valid UTF-8: {e}"))
+            .and_then(|source| {
+                Engine::from_program(source).map_err(|errors| {
+                    let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                    messages.join("\n")
+                })
+            })
+    })
+    .unwrap_or_else(|payload| {
+        Err(format!(
+            "Loading the story panicked: {}",
+            panic_message(payload.as_ref())
+        ))
+    });
+
+    match result {
+        Ok(engine) => Box::into_raw(Box::new(engine)),
+        Err(message) => {
+            if !error_out.is_null() {
+                unsafe { *error_out = into_c_string(message) };
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Free an engine created by [`cyoa_engine_create_from_source`].
+///
+/// # Safety
+///
+/// `engine` must be null or a pointer returned by [`cyoa_engine_create_from_source`] that
+/// hasn't been freed yet. Sessions created from it must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cyoa_engine_free(engine: *mut Engine) {
+    if !engine.is_null() {
+        catch_panic((), || drop(unsafe { Box::from_raw(engine) }));
+    }
+}
+
+/// Start a new session at the beginning of the story. Returns null if that panicked.
+///
+/// # Safety
+///
+/// `engine` must be a valid engine pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cyoa_session_new(engine: *const Engine) -> *mut Session {
+    catch_panic(ptr::null_mut(), || {
+        let engine = unsafe { &*engine };
+        Box::into_raw(Box::new(engine.new_session()))
+    })
+}
+
+/// Free a session created by [`cyoa_session_new`].
+///
+/// # Safety
+///
+/// `session` must be null or a pointer returned by [`cyoa_session_new`] that hasn't been freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cyoa_session_free(session: *mut Session) {
+    if !session.is_null() {
+        catch_panic((), || drop(unsafe { Box::from_raw(session) }));
+    }
+}
+
+/// The session's current node as JSON, in the same format as the server's `/current`
+/// endpoint. The returned string must be freed with [`cyoa_string_free`]. Returns null if
+/// that panicked.
+///
+/// # Safety
+///
+/// `engine` must be a valid engine pointer and `session` a valid session created from it.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cyoa_session_current_json(
+    engine: *const Engine,
+    session: *const Session,
+) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        let (engine, session) = unsafe { (&*engine, &*session) };
+        let view = engine.get_current_node_view(session);
+        into_c_string(serde_json::to_string(&view).expect("Failed to serialize view"))
+    })
+}
+
+/// Take the choice with the given id. Returns false if the choice isn't available, or if
+/// taking it panicked.
+///
+/// # Safety
+///
+/// `engine` must be a valid engine pointer, `session` a valid session created from it, and
+/// `choice_id` a valid nul-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cyoa_session_choose(
+    engine: *const Engine,
+    session: *mut Session,
+    choice_id: *const c_char,
+) -> bool {
+    catch_panic(false, || {
+        let (engine, session) = unsafe { (&*engine, &mut *session) };
+        let choice_id = unsafe { CStr::from_ptr(choice_id) }
+            .to_string_lossy()
+            .to_string();
+
+        matches!(
+            engine.choose_option(session, choice_id),
+            ChoiceResult::Success
+        )
+    })
+}
+
+/// Free a string returned by this library.
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by this library that hasn't been freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn cyoa_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        catch_panic((), || drop(unsafe { CString::from_raw(s) }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORY: &CStr = c"= START
+    \"Hello.\"
+    \"Leave.\" -> end
+
+= end
+    \"Bye.\"
+";
+
+    fn take_string(s: *mut c_char) -> String {
+        assert!(!s.is_null());
+        let string = unsafe { CStr::from_ptr(s) }.to_str().unwrap().to_string();
+        unsafe { cyoa_string_free(s) };
+        string
+    }
+
+    #[test]
+    fn a_story_can_be_played_through_the_c_interface() {
+        unsafe {
+            let engine = cyoa_engine_create_from_source(STORY.as_ptr(), ptr::null_mut());
+            assert!(!engine.is_null());
+            let session = cyoa_session_new(engine);
+            assert!(take_string(cyoa_session_current_json(engine, session)).contains("Hello."));
+
+            assert!(!cyoa_session_choose(engine, session, c"nowhere".as_ptr()));
+            assert!(cyoa_session_choose(engine, session, c"end".as_ptr()));
+            assert!(take_string(cyoa_session_current_json(engine, session)).contains("Bye."));
+
+            cyoa_session_free(session);
+            cyoa_engine_free(engine);
+        }
+    }
+
+    #[test]
+    fn loading_a_broken_story_reports_its_errors() {
+        let mut error = ptr::null_mut();
+        let engine = unsafe {
+            cyoa_engine_create_from_source(
+                c"SET name \"Ann\"\n= START\n    \"Hi.\"\n    [IF name = 3] \"Count.\" -> START\n"
+                    .as_ptr(),
+                &mut error,
+            )
+        };
+
+        assert!(engine.is_null());
+        assert!(!take_string(error).is_empty());
+    }
+
+    #[test]
+    fn panics_are_turned_into_failures() {
+        assert!(catch_panic(ptr::null_mut::<Engine>(), || panic!("boom")).is_null());
+        assert!(!catch_panic(false, || panic!("boom")));
+
+        let payload = panic::catch_unwind(|| panic!("{} went wrong", "something")).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "something went wrong");
+    }
+}
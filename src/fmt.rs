@@ -0,0 +1,84 @@
+use cyoa::engine::{parser::parse_program, printer::print_program};
+use std::{fs, path::PathBuf, process::ExitCode};
+
+#[derive(clap::Args, Debug)]
+pub struct FmtArgs {
+    /// The story files to format
+    #[arg(required = true)]
+    sources: Vec<PathBuf>,
+    /// Don't change any files, but fail if any of them aren't formatted
+    #[arg(long)]
+    check: bool,
+}
+
+/// Rewrite story files in the canonical format, or check that they already are.
+pub fn run(args: FmtArgs) -> ExitCode {
+    let mut failed = false;
+    for path in &args.sources {
+        let result = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read source file '{}': {e}", path.display()))
+            .and_then(|source| {
+                format_story(&source)
+                    .map(|formatted| (source, formatted))
+                    .map_err(|e| format!("{}: {e}", path.display()))
+            });
+        let (source, formatted) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("{e}");
+                failed = true;
+                continue;
+            }
+        };
+
+        if source == formatted {
+            continue;
+        }
+        if args.check {
+            println!("{} is not formatted", path.display());
+            failed = true;
+        } else if let Err(e) = fs::write(path, formatted) {
+            eprintln!("Failed to write '{}': {e}", path.display());
+            failed = true;
+        } else {
+            println!("Formatted {}", path.display());
+        }
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn format_story(source: &str) -> Result<String, String> {
+    let (rest, parts) = parse_program(source).expect("Failed to parse nodes");
+    if !rest.trim().is_empty() {
+        // Formatting would silently drop everything the parser couldn't read.
+        let line = source[..source.len() - rest.len()].matches('\n').count() + 1;
+        return Err(format!(
+            "The story could not be parsed from line {line} on, so it was not formatted."
+        ));
+    }
+
+    Ok(print_program(&parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stories_are_formatted_with_consistent_indentation() {
+        let formatted = format_story("= START\n  \"Hi.\"\n        \"Go.\" -> START\n").unwrap();
+        assert_eq!(formatted, "= START\n    \"Hi.\"\n    \"Go.\" -> START\n");
+        assert_eq!(format_story(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn stories_that_cant_be_parsed_arent_formatted() {
+        let error = format_story("= START\n    \"Hi.\"\n???\n").unwrap_err();
+        assert!(error.contains("line 3"), "{error}");
+    }
+}
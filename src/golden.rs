@@ -0,0 +1,279 @@
+//! Golden transcripts: a playthrough recorded with the text it showed, replayed later to check
+//! the text hasn't changed.
+
+use crate::{StorySettings, build_story, play};
+use cyoa::{ChoiceResult, Engine, HistoryEvent};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct RecordArgs {
+    /// The story file to play
+    source: PathBuf,
+    /// Where to save the transcript
+    transcript: PathBuf,
+    /// Take the choice leading to this node instead of playing in the terminal. Can be given
+    /// more than once, and the choices are taken in order.
+    #[arg(long)]
+    choose: Vec<String>,
+    /// Picks the text variant the session sees, so it is the same when the transcript is
+    /// verified
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// Transcripts saved by `cyoa record`
+    #[arg(required = true)]
+    transcripts: Vec<PathBuf>,
+    /// Play this story instead of the one each transcript was recorded from
+    #[arg(long)]
+    story: Option<PathBuf>,
+}
+
+/// A recorded playthrough.
+#[derive(Serialize, Deserialize)]
+struct Transcript {
+    /// The story file, relative to the directory the transcript is in, so the two can be moved
+    /// together.
+    story: PathBuf,
+    seed: u64,
+    /// The start of the story, then one step for each choice taken.
+    steps: Vec<Step>,
+}
+
+/// What was shown after taking a choice.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Step {
+    /// The choice taken to get here, or none for the start of the story.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    choice: Option<String>,
+    node_id: String,
+    text: String,
+    choices: Vec<String>,
+}
+
+fn settings() -> StorySettings {
+    StorySettings {
+        history_depth: usize::MAX,
+        max_checkpoints: 0,
+        max_transcript: usize::MAX,
+        #[cfg(feature = "plugins")]
+        plugins: None,
+    }
+}
+
+/// What a session sees at its current node, having taken `choice` to get there.
+fn step(story: &Engine, session: &cyoa::Session, choice: Option<String>) -> Step {
+    let view = story.get_current_node_view(session);
+    Step {
+        choice,
+        node_id: session.current_node_id().to_string(),
+        text: view.display_text,
+        choices: view
+            .choices
+            .into_iter()
+            .map(|choice| choice.display_text)
+            .collect(),
+    }
+}
+
+/// Take `choices` in order from the start of the story, recording what is shown at each
+/// step. Stops with an error at the first choice that can't be taken, along with the steps
+/// up to it.
+fn play_through<'a>(
+    story: &Engine,
+    seed: u64,
+    choices: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<Step>, (Vec<Step>, String)> {
+    let mut session = story.new_seeded_session(seed);
+    let mut steps = vec![step(story, &session, None)];
+    for choice in choices {
+        let result = story.choose_option(&mut session, choice.to_string());
+        if !matches!(result, ChoiceResult::Success) {
+            return Err((steps, result.to_string()));
+        }
+        steps.push(step(story, &session, Some(choice.to_string())));
+    }
+
+    Ok(steps)
+}
+
+/// The choices that lead to where a session is, leaving out any that were undone.
+fn choices_taken(history: &[HistoryEvent]) -> Vec<String> {
+    let mut choices = Vec::new();
+    for event in history {
+        match event {
+            HistoryEvent::ChoiceTaken { choice_id, .. } => choices.push(choice_id.clone()),
+            HistoryEvent::WentBack { .. } => {
+                choices.pop();
+            }
+            HistoryEvent::Restarted { .. } => choices.clear(),
+            HistoryEvent::NodeVisited { .. } | HistoryEvent::CheckpointLoaded { .. } => {}
+        }
+    }
+    choices
+}
+
+/// The path to `story` from the directory `transcript` is in. Falls back to the absolute path
+/// to `story` if either can't be found.
+fn relative_to(story: &Path, transcript: &Path) -> PathBuf {
+    let directory = transcript.parent().unwrap_or(Path::new("")).join(".");
+    let (Ok(story), Ok(directory)) = (story.canonicalize(), directory.canonicalize()) else {
+        return story.to_path_buf();
+    };
+
+    let mut story_components = story.components().peekable();
+    let mut directory_components = directory.components().peekable();
+    while let (Some(a), Some(b)) = (story_components.peek(), directory_components.peek())
+        && a == b
+    {
+        story_components.next();
+        directory_components.next();
+    }
+    directory_components
+        .map(|_| Component::ParentDir)
+        .chain(story_components)
+        .collect()
+}
+
+/// Record a playthrough, either played in the terminal or from `--choose`, and save it with
+/// the text shown at each step.
+pub fn record(args: RecordArgs) -> ExitCode {
+    let story = match build_story(&args.source, settings()) {
+        Ok(story) => story,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let choices = if args.choose.is_empty() {
+        let mut session = story.new_seeded_session(args.seed);
+        play::play(&story, &mut session);
+        choices_taken(story.history(&session))
+    } else {
+        args.choose
+    };
+    let steps = match play_through(&story, args.seed, choices.iter().map(String::as_str)) {
+        Ok(steps) => steps,
+        Err((_, e)) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let transcript = Transcript {
+        story: relative_to(&args.source, &args.transcript),
+        seed: args.seed,
+        steps,
+    };
+    let json = serde_json::to_string_pretty(&transcript).expect("Failed to serialize transcript");
+    if let Err(e) = fs::write(&args.transcript, json + "\n") {
+        eprintln!(
+            "Failed to write transcript '{}': {e}",
+            args.transcript.display()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    println!(
+        "Recorded {} choices to '{}'.",
+        transcript.steps.len() - 1,
+        args.transcript.display()
+    );
+    ExitCode::SUCCESS
+}
+
+/// Replay a transcript's choices, describing the first step that isn't shown as it was
+/// recorded.
+fn verify_transcript(
+    path: &Path,
+    transcript: &Transcript,
+    story_override: Option<&PathBuf>,
+) -> Result<(), String> {
+    let source = match story_override {
+        Some(source) => source.clone(),
+        None => path
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(&transcript.story),
+    };
+    let story = build_story(&source, settings())?;
+    let choices = transcript
+        .steps
+        .iter()
+        .filter_map(|step| step.choice.as_deref());
+    let (steps, error) = match play_through(&story, transcript.seed, choices) {
+        Ok(steps) => (steps, None),
+        Err((steps, e)) => (steps, Some(e)),
+    };
+
+    for (number, (expected, actual)) in transcript.steps.iter().zip(&steps).enumerate() {
+        if expected == actual {
+            continue;
+        }
+        let at = match &expected.choice {
+            Some(choice) => format!("step {number}, after choosing '{choice}'"),
+            None => "the start".to_string(),
+        };
+        if expected.node_id != actual.node_id {
+            return Err(format!(
+                "At {at}, expected to be at the node with id '{}', but was at '{}'.",
+                expected.node_id, actual.node_id
+            ));
+        }
+        if expected.text != actual.text {
+            return Err(format!(
+                "At {at}, expected the text\n    {}\nbut it was\n    {}",
+                expected.text, actual.text
+            ));
+        }
+        return Err(format!(
+            "At {at}, expected the choices\n    {}\nbut they were\n    {}",
+            expected.choices.join("\n    "),
+            actual.choices.join("\n    ")
+        ));
+    }
+
+    match error {
+        Some(e) => Err(format!("At step {}: {e}", steps.len())),
+        None => Ok(()),
+    }
+}
+
+/// Replay recorded transcripts, failing if any of them no longer shows the same text.
+pub fn verify(args: VerifyArgs) -> ExitCode {
+    let mut failures = 0;
+    for path in &args.transcripts {
+        let result = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read transcript: {e}"))
+            .and_then(|json| {
+                serde_json::from_str::<Transcript>(&json)
+                    .map_err(|e| format!("Failed to parse transcript: {e}"))
+            })
+            .and_then(|transcript| verify_transcript(path, &transcript, args.story.as_ref()));
+        match result {
+            Ok(()) => println!("PASS {}", path.display()),
+            Err(e) => {
+                failures += 1;
+                println!("FAIL {}: {e}", path.display());
+            }
+        }
+    }
+
+    println!(
+        "\n{} passed, {failures} failed",
+        args.transcripts.len() - failures
+    );
+    if failures == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
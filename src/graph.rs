@@ -0,0 +1,165 @@
+use crate::read_story_parts;
+use clap::ValueEnum;
+use cyoa::{
+    Analysis,
+    engine::parser::{Node, ProgramPart},
+};
+use serde::Serialize;
+use std::{path::PathBuf, process::ExitCode};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum GraphFormat {
+    /// Graphviz, e.g. `cyoa graph story.cyoa | dot -Tsvg > story.svg`
+    Dot,
+    /// A list of nodes and a list of edges
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct GraphArgs {
+    /// The story file to draw
+    source: PathBuf,
+    #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+    format: GraphFormat,
+}
+
+#[derive(Serialize)]
+struct GraphNode<'a> {
+    id: &'a str,
+    display_text: String,
+    /// Whether any chain of choices leads here from `START`.
+    reachable: bool,
+    /// The fewest choices needed to get here from `START`.
+    distance: Option<usize>,
+    /// Whether any chain of choices leads from here to an ending.
+    can_end: bool,
+}
+
+/// A choice, leading from the node it is in to the node it takes the player to.
+#[derive(Serialize)]
+struct GraphEdge<'a> {
+    from: &'a str,
+    to: &'a str,
+    text: String,
+    requirement: Option<String>,
+    command: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Graph<'a> {
+    nodes: Vec<GraphNode<'a>>,
+    edges: Vec<GraphEdge<'a>>,
+    /// Groups of nodes that chains of choices can go around and around.
+    loops: Vec<Vec<String>>,
+}
+
+/// Print the story's nodes and the choices between them.
+pub fn run(args: GraphArgs) -> ExitCode {
+    let parts = match read_story_parts(&args.source) {
+        Ok(parts) => parts,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let graph = build_graph(&parts);
+    match args.format {
+        GraphFormat::Dot => print!("{}", to_dot(&graph)),
+        GraphFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&graph).expect("Failed to serialize graph")
+        ),
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// The graph of the story's nodes, in the order they are defined.
+fn build_graph(parts: &[ProgramPart]) -> Graph<'_> {
+    let nodes: Vec<(&str, &Node)> = parts
+        .iter()
+        .filter_map(|part| match part {
+            ProgramPart::NodeDefinition { id, node } => Some((id.as_str(), node)),
+            _ => None,
+        })
+        .collect();
+    let analysis = Analysis::of(nodes.iter().copied());
+
+    Graph {
+        nodes: nodes
+            .iter()
+            .zip(&analysis.nodes)
+            .map(|((id, node), analysis)| GraphNode {
+                id,
+                display_text: node.display_text.to_string(),
+                reachable: analysis.reachable,
+                distance: analysis.distance,
+                can_end: analysis.can_end,
+            })
+            .collect(),
+        edges: nodes
+            .iter()
+            .flat_map(|(id, node)| {
+                node.choices.iter().map(move |choice| GraphEdge {
+                    from: id,
+                    to: &choice.next_node_id,
+                    text: choice.text.to_string(),
+                    requirement: choice.requirement.as_ref().map(|r| r.to_string()),
+                    command: choice.command.as_ref().map(|c| c.to_string()),
+                })
+            })
+            .collect(),
+        loops: analysis.loops,
+    }
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn to_dot(graph: &Graph) -> String {
+    let mut dot = String::from("digraph story {\n    node [shape=box];\n");
+    for node in &graph.nodes {
+        let ends_story = !graph.edges.iter().any(|edge| edge.from == node.id);
+        let mut style = match (node.id == "START", ends_story) {
+            (true, _) => ", style=bold",
+            (false, true) => ", peripheries=2",
+            (false, false) => "",
+        }
+        .to_string();
+        // Nodes that can't be reached are greyed out, and ones that can't lead to an ending are
+        // drawn in red.
+        if !node.reachable {
+            style.push_str(", color=grey, fontcolor=grey");
+        } else if !node.can_end {
+            style.push_str(", color=red");
+        }
+        dot.push_str(&format!(
+            "    \"{}\" [label=\"{}\\n{}\"{style}];\n",
+            escape_dot(node.id),
+            escape_dot(node.id),
+            escape_dot(&node.display_text)
+        ));
+    }
+
+    for edge in &graph.edges {
+        let mut label = escape_dot(&edge.text);
+        if let Some(requirement) = &edge.requirement {
+            label.push_str(&format!("\\n[IF {}]", escape_dot(requirement)));
+        }
+        if let Some(command) = &edge.command {
+            label.push_str(&format!("\\n[THEN {}]", escape_dot(command)));
+        }
+        dot.push_str(&format!(
+            "    \"{}\" -> \"{}\" [label=\"{label}\"];\n",
+            escape_dot(edge.from),
+            escape_dot(edge.to)
+        ));
+    }
+
+    dot.push_str("}\n");
+    dot
+}
@@ -0,0 +1,383 @@
+//! A GraphQL endpoint at `{prefix}/graphql`, built with the `graphql` feature, so rich
+//! clients can fetch a story's metadata, a session's current node and its history in one
+//! round trip. It plays the same sessions as the rest of the API.
+
+use crate::{
+    ApiError, AppState, ExpectedVersion, IdempotencyKey, MAX_IDEMPOTENCY_KEY_LENGTH, ServerState,
+    SessionToken, api_error, get_readable_session, rate_limit::ClientAddr,
+};
+use async_graphql::{
+    Context, EmptySubscription, ErrorExtensions, Object, Schema, SimpleObject, Union,
+};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use cyoa::{ChoiceResult, CurrentNodeView, Engine, Session};
+use std::sync::Arc;
+
+/// How deeply queries can nest, so one request can't ask for an enormous response.
+const MAX_DEPTH: usize = 10;
+
+pub type CyoaSchema = Schema<Query, Mutation, EmptySubscription>;
+
+pub fn schema(state: Arc<ServerState>) -> CyoaSchema {
+    Schema::build(Query, Mutation, EmptySubscription)
+        .data(state)
+        .limit_depth(MAX_DEPTH)
+        .finish()
+}
+
+/// Runs a query or mutation. The session token is read from the `X-Session-Token` header or
+/// the `token` query parameter, as for the rest of the API.
+pub async fn execute(
+    State(schema): State<CyoaSchema>,
+    token: SessionToken,
+    client: ClientAddr,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request.data(token).data(client)).await)
+}
+
+/// The API's error as a GraphQL error, with the HTTP status it would have had as a `status`
+/// extension.
+fn error((status, Json(error)): ApiError) -> async_graphql::Error {
+    async_graphql::Error::new(error.error)
+        .extend_with(|_, extensions| extensions.set("status", status.as_u16()))
+}
+
+/// The story with the given id, which can be left out when only one story is served.
+fn find_story(ctx: &Context<'_>, story_id: Option<&str>) -> async_graphql::Result<AppState> {
+    ctx.data::<Arc<ServerState>>()?
+        .find_story(story_id)
+        .map_err(error)
+}
+
+/// Loads a session the request's token lets it read.
+async fn load_session(
+    ctx: &Context<'_>,
+    state: AppState,
+    session_id: String,
+) -> async_graphql::Result<GraphqlSession> {
+    let token = ctx.data::<SessionToken>()?;
+    let (story, session) = get_readable_session(&state, &session_id, token)
+        .await
+        .map_err(error)?;
+
+    Ok(GraphqlSession {
+        state,
+        session_id,
+        story,
+        session,
+    })
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Every story served by this server.
+    async fn stories(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Story>> {
+        Ok(ctx
+            .data::<Arc<ServerState>>()?
+            .stories
+            .iter()
+            .map(|state| Story(Arc::clone(state)))
+            .collect())
+    }
+
+    /// A story, which can be left out when only one story is served.
+    async fn story(&self, ctx: &Context<'_>, id: Option<String>) -> async_graphql::Result<Story> {
+        find_story(ctx, id.as_deref()).map(Story)
+    }
+
+    /// A session of a story. With `--private-sessions`, the session's token is needed.
+    async fn session(
+        &self,
+        ctx: &Context<'_>,
+        story_id: Option<String>,
+        id: String,
+    ) -> async_graphql::Result<GraphqlSession> {
+        let state = find_story(ctx, story_id.as_deref())?;
+        load_session(ctx, state, id).await
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    /// Start a new session at the beginning of a story.
+    async fn create_session(
+        &self,
+        ctx: &Context<'_>,
+        story_id: Option<String>,
+    ) -> async_graphql::Result<CreatedSession> {
+        let state = find_story(ctx, story_id.as_deref())?;
+        ctx.data::<Arc<ServerState>>()?
+            .check_session_limits(ctx.data::<ClientAddr>()?)
+            .map_err(error)?;
+        let (_, Json(created)) = crate::create_session(State(Arc::clone(&state)))
+            .await
+            .map_err(error)?;
+        let (story, session) = crate::read_session(&state, &created.session_id)
+            .await
+            .map_err(error)?;
+
+        Ok(CreatedSession {
+            session_token: created.session_token,
+            session: GraphqlSession {
+                state,
+                session_id: created.session_id,
+                story,
+                session,
+            },
+        })
+    }
+
+    /// Take one of the current node's choices. Needs the session's token.
+    async fn choose(
+        &self,
+        ctx: &Context<'_>,
+        story_id: Option<String>,
+        session_id: String,
+        choice_id: String,
+        #[graphql(desc = "Only take the choice if the session is still at this version")]
+        expected_version: Option<u64>,
+        #[graphql(desc = "If a choice is sent again with the same key, it isn't taken again")]
+        idempotency_key: Option<String>,
+    ) -> async_graphql::Result<GraphqlSession> {
+        let state = find_story(ctx, story_id.as_deref())?;
+        if idempotency_key
+            .as_ref()
+            .is_some_and(|key| key.len() > MAX_IDEMPOTENCY_KEY_LENGTH)
+        {
+            return Err(error(api_error(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "idempotencyKey can be at most {MAX_IDEMPOTENCY_KEY_LENGTH} characters long"
+                ),
+            )));
+        }
+        let token = ctx.data::<SessionToken>()?;
+        let (status, Json(result)) = crate::choose_option(
+            State(Arc::clone(&state)),
+            Path((session_id.clone(), choice_id)),
+            SessionToken(token.0.clone()),
+            ExpectedVersion(expected_version),
+            IdempotencyKey(idempotency_key),
+        )
+        .await
+        .map_err(error)?;
+        if !matches!(result, ChoiceResult::Success) {
+            return Err(error(api_error(status, result)));
+        }
+
+        load_session(ctx, state, session_id).await
+    }
+}
+
+pub struct Story(AppState);
+
+#[Object]
+impl Story {
+    async fn id(&self) -> &str {
+        &self.0.story_id
+    }
+
+    /// The story's `META title`, if it has one.
+    async fn title(&self) -> Option<String> {
+        self.0.story().metadata("title").map(str::to_string)
+    }
+
+    /// With `--daily-seed`, the seed shared by every session started today.
+    async fn daily_seed(&self) -> Option<u64> {
+        self.0
+            .daily_seed
+            .then(|| crate::daily_seed(std::time::SystemTime::now()))
+    }
+
+    /// Every `META` line in the story, in order of key.
+    async fn metadata(&self) -> Vec<MetadataEntry> {
+        let story = self.0.story();
+        let mut metadata: Vec<MetadataEntry> = story
+            .all_metadata()
+            .map(|(key, value)| MetadataEntry {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+            .collect();
+        metadata.sort_by(|a, b| a.key.cmp(&b.key));
+
+        metadata
+    }
+}
+
+#[derive(SimpleObject)]
+struct MetadataEntry {
+    key: String,
+    value: String,
+}
+
+#[derive(SimpleObject)]
+pub struct CreatedSession {
+    /// Needed to change the session, in the `X-Session-Token` header.
+    session_token: String,
+    session: GraphqlSession,
+}
+
+#[derive(SimpleObject)]
+struct CurrentNode {
+    display_text: String,
+    choices: Vec<Choice>,
+    game_over: bool,
+    can_go_back: bool,
+    /// Goes up every time the session changes.
+    version: u64,
+    /// The node the session is at. Null for stories that hide node ids.
+    node_id: Option<String>,
+    /// How many choices have been taken, including ones later undone.
+    turn: u64,
+}
+
+impl From<CurrentNodeView> for CurrentNode {
+    fn from(view: CurrentNodeView) -> Self {
+        CurrentNode {
+            display_text: view.display_text,
+            choices: view
+                .choices
+                .into_iter()
+                .map(|choice| Choice {
+                    id: choice.id,
+                    display_text: choice.display_text,
+                })
+                .collect(),
+            game_over: view.game_over,
+            can_go_back: view.can_go_back,
+            version: view.version,
+            node_id: view.node_id,
+            turn: view.turn as u64,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct Choice {
+    id: String,
+    display_text: String,
+}
+
+/// Timestamps are milliseconds since the Unix epoch.
+#[derive(Union)]
+enum HistoryEvent {
+    NodeVisited(NodeVisited),
+    ChoiceTaken(ChoiceTaken),
+    WentBack(WentBack),
+    Restarted(Restarted),
+    CheckpointLoaded(CheckpointLoaded),
+}
+
+#[derive(SimpleObject)]
+struct NodeVisited {
+    node_id: String,
+    display_text: String,
+    timestamp: u64,
+}
+
+#[derive(SimpleObject)]
+struct ChoiceTaken {
+    choice_id: String,
+    display_text: String,
+    timestamp: u64,
+}
+
+#[derive(SimpleObject)]
+struct WentBack {
+    node_id: String,
+    timestamp: u64,
+}
+
+#[derive(SimpleObject)]
+struct Restarted {
+    timestamp: u64,
+}
+
+#[derive(SimpleObject)]
+struct CheckpointLoaded {
+    slot: String,
+    timestamp: u64,
+}
+
+impl From<cyoa::HistoryEvent> for HistoryEvent {
+    fn from(event: cyoa::HistoryEvent) -> Self {
+        match event {
+            cyoa::HistoryEvent::NodeVisited {
+                node_id,
+                display_text,
+                timestamp,
+            } => HistoryEvent::NodeVisited(NodeVisited {
+                node_id,
+                display_text,
+                timestamp,
+            }),
+            cyoa::HistoryEvent::ChoiceTaken {
+                choice_id,
+                display_text,
+                timestamp,
+            } => HistoryEvent::ChoiceTaken(ChoiceTaken {
+                choice_id,
+                display_text,
+                timestamp,
+            }),
+            cyoa::HistoryEvent::WentBack { node_id, timestamp } => {
+                HistoryEvent::WentBack(WentBack { node_id, timestamp })
+            }
+            cyoa::HistoryEvent::Restarted { timestamp } => {
+                HistoryEvent::Restarted(Restarted { timestamp })
+            }
+            cyoa::HistoryEvent::CheckpointLoaded { slot, timestamp } => {
+                HistoryEvent::CheckpointLoaded(CheckpointLoaded { slot, timestamp })
+            }
+        }
+    }
+}
+
+/// A session, loaded once for every field asked for.
+pub struct GraphqlSession {
+    state: AppState,
+    session_id: String,
+    story: Arc<Engine>,
+    session: Session,
+}
+
+#[Object(name = "Session")]
+impl GraphqlSession {
+    async fn id(&self) -> &str {
+        &self.session_id
+    }
+
+    async fn story(&self) -> Story {
+        Story(Arc::clone(&self.state))
+    }
+
+    /// Where the session is in its story.
+    async fn current(&self) -> CurrentNode {
+        self.story.get_current_node_view(&self.session).into()
+    }
+
+    /// Everything that has happened in the session, oldest first.
+    async fn history(&self) -> Vec<HistoryEvent> {
+        self.story
+            .history(&self.session)
+            .iter()
+            .cloned()
+            .map(Into::into)
+            .collect()
+    }
+
+    /// How many choices have been taken, including ones later undone.
+    async fn turns(&self) -> usize {
+        self.session.turns()
+    }
+}
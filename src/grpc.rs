@@ -0,0 +1,258 @@
+//! The API over gRPC, served on `--grpc-port` when built with the `grpc` feature, for game
+//! backends that already talk gRPC. It is described by `proto/cyoa.proto` and plays the same
+//! sessions as the HTTP API.
+
+use crate::{
+    ApiError, AppState, ExpectedVersion, IdempotencyKey, MAX_IDEMPOTENCY_KEY_LENGTH, ServerState,
+    SessionToken, api_error, get_readable_session, rate_limit::ClientAddr, secrets_match,
+};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use cyoa::{ChoiceResult, CurrentNodeView, HistoryEvent};
+use proto::{
+    ChooseRequest, CreateSessionRequest, CreateSessionResponse, CurrentNode, GetCurrentRequest,
+    GetHistoryRequest, History,
+    history_event::Event,
+    stories_server::{Stories, StoriesServer},
+};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tonic::{
+    Request, Response, Status,
+    service::Interceptor,
+    transport::{Server, server::TcpIncoming},
+};
+use tracing::{error, info};
+
+mod proto {
+    tonic::include_proto!("cyoa.v1");
+}
+
+/// The gRPC status closest to each HTTP status the handlers return.
+fn status((code, Json(error)): ApiError) -> Status {
+    let message = error.error;
+    match code {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+            Status::invalid_argument(message)
+        }
+        StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+        StatusCode::FORBIDDEN => Status::permission_denied(message),
+        StatusCode::NOT_FOUND | StatusCode::GONE => Status::not_found(message),
+        StatusCode::CONFLICT => Status::failed_precondition(message),
+        StatusCode::TOO_MANY_REQUESTS => Status::resource_exhausted(message),
+        StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+fn token(session_token: String) -> SessionToken {
+    SessionToken((!session_token.is_empty()).then_some(session_token))
+}
+
+impl From<CurrentNodeView> for CurrentNode {
+    fn from(view: CurrentNodeView) -> Self {
+        CurrentNode {
+            display_text: view.display_text,
+            choices: view
+                .choices
+                .into_iter()
+                .map(|choice| proto::Choice {
+                    id: choice.id,
+                    display_text: choice.display_text,
+                })
+                .collect(),
+            game_over: view.game_over,
+            can_go_back: view.can_go_back,
+            version: view.version,
+            node_id: view.node_id,
+            turn: view.turn as u64,
+        }
+    }
+}
+
+impl From<HistoryEvent> for proto::HistoryEvent {
+    fn from(event: HistoryEvent) -> Self {
+        let (timestamp, event) = match event {
+            HistoryEvent::NodeVisited {
+                node_id,
+                display_text,
+                timestamp,
+            } => (
+                timestamp,
+                Event::NodeVisited(proto::NodeVisited {
+                    node_id,
+                    display_text,
+                }),
+            ),
+            HistoryEvent::ChoiceTaken {
+                choice_id,
+                display_text,
+                timestamp,
+            } => (
+                timestamp,
+                Event::ChoiceTaken(proto::ChoiceTaken {
+                    choice_id,
+                    display_text,
+                }),
+            ),
+            HistoryEvent::WentBack { node_id, timestamp } => {
+                (timestamp, Event::WentBack(proto::WentBack { node_id }))
+            }
+            HistoryEvent::Restarted { timestamp } => {
+                (timestamp, Event::Restarted(proto::Restarted {}))
+            }
+            HistoryEvent::CheckpointLoaded { slot, timestamp } => (
+                timestamp,
+                Event::CheckpointLoaded(proto::CheckpointLoaded { slot }),
+            ),
+        };
+
+        proto::HistoryEvent {
+            timestamp,
+            event: Some(event),
+        }
+    }
+}
+
+struct StoriesService {
+    state: Arc<ServerState>,
+}
+
+impl StoriesService {
+    /// The story with the given id, which can be left empty when only one story is served.
+    fn story(&self, story_id: &str) -> Result<AppState, Status> {
+        self.state
+            .find_story((!story_id.is_empty()).then_some(story_id))
+            .map_err(status)
+    }
+}
+
+#[tonic::async_trait]
+impl Stories for StoriesService {
+    async fn create_session(
+        &self,
+        request: Request<CreateSessionRequest>,
+    ) -> Result<Response<CreateSessionResponse>, Status> {
+        let state = self.story(&request.get_ref().story_id)?;
+        let client = ClientAddr::new(
+            &request.metadata().clone().into_headers(),
+            request.remote_addr().map(|addr| addr.ip()),
+        );
+        self.state.check_session_limits(&client).map_err(status)?;
+        let (_, Json(created)) = crate::create_session(State(state)).await.map_err(status)?;
+
+        Ok(Response::new(CreateSessionResponse {
+            session_id: created.session_id,
+            session_token: created.session_token,
+        }))
+    }
+
+    async fn get_current(
+        &self,
+        request: Request<GetCurrentRequest>,
+    ) -> Result<Response<CurrentNode>, Status> {
+        let request = request.into_inner();
+        let state = self.story(&request.story_id)?;
+        let (story, session) =
+            get_readable_session(&state, &request.session_id, &token(request.session_token))
+                .await
+                .map_err(status)?;
+
+        Ok(Response::new(story.get_current_node_view(&session).into()))
+    }
+
+    async fn choose(
+        &self,
+        request: Request<ChooseRequest>,
+    ) -> Result<Response<CurrentNode>, Status> {
+        let request = request.into_inner();
+        let state = self.story(&request.story_id)?;
+        if request.idempotency_key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+            return Err(Status::invalid_argument(format!(
+                "idempotency_key can be at most {MAX_IDEMPOTENCY_KEY_LENGTH} characters long"
+            )));
+        }
+        let idempotency_key = IdempotencyKey(
+            (!request.idempotency_key.is_empty()).then_some(request.idempotency_key),
+        );
+        let (code, Json(result)) = crate::choose_option(
+            State(Arc::clone(&state)),
+            Path((request.session_id.clone(), request.choice_id)),
+            token(request.session_token.clone()),
+            ExpectedVersion(request.expected_version),
+            idempotency_key,
+        )
+        .await
+        .map_err(status)?;
+        if !matches!(result, ChoiceResult::Success) {
+            return Err(status(api_error(code, result)));
+        }
+
+        let (story, session) =
+            get_readable_session(&state, &request.session_id, &token(request.session_token))
+                .await
+                .map_err(status)?;
+        Ok(Response::new(story.get_current_node_view(&session).into()))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<GetHistoryRequest>,
+    ) -> Result<Response<History>, Status> {
+        let request = request.into_inner();
+        let state = self.story(&request.story_id)?;
+        let Json(events) = crate::get_history(
+            State(state),
+            Path(request.session_id),
+            token(request.session_token),
+        )
+        .await
+        .map_err(status)?;
+
+        Ok(Response::new(History {
+            events: events.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+/// Turns away calls without one of the keys in `authorization: Bearer <key>` metadata, when
+/// `--api-key-scope all` asks for keys on every endpoint.
+#[derive(Clone)]
+struct RequireApiKey(Arc<Vec<String>>);
+
+impl Interceptor for RequireApiKey {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        if self.0.is_empty() {
+            return Ok(request);
+        }
+        let key = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match key {
+            Some(key) if self.0.iter().any(|expected| secrets_match(expected, key)) => Ok(request),
+            _ => Err(Status::unauthenticated("missing or wrong API key")),
+        }
+    }
+}
+
+/// Serves the gRPC interface on `listener` until the server stops. `api_keys` are only asked
+/// for if there are any.
+pub async fn serve(listener: TcpListener, state: Arc<ServerState>, api_keys: Arc<Vec<String>>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving gRPC on {addr}");
+    }
+    let service =
+        StoriesServer::with_interceptor(StoriesService { state }, RequireApiKey(api_keys));
+    if let Err(e) = Server::builder()
+        .add_service(service)
+        .serve_with_incoming(TcpIncoming::from(listener))
+        .await
+    {
+        error!("gRPC server failed: {e}");
+    }
+}
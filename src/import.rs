@@ -0,0 +1,60 @@
+//! Importers for stories written for other interactive fiction tools.
+//!
+//! Each importer turns a story into the same [`ProgramPart`]s the `.cyoa` parser produces,
+//! which can be printed as `.cyoa` source with [`print_program`] or loaded with [`into_engine`].
+
+pub mod ink;
+pub mod twee;
+
+use crate::{
+    Engine, ParseError,
+    engine::{parser::ProgramPart, printer::print_program},
+};
+use std::fmt::Display;
+
+/// A reason a story couldn't be imported.
+#[derive(Debug)]
+pub enum ImportError {
+    /// The story has nothing in it that could become a node.
+    Empty,
+    /// The story uses something that has no equivalent in `.cyoa` stories.
+    Unsupported { location: String, feature: String },
+    /// The story was translated, but the result has errors, e.g. links to missing passages.
+    Story(Vec<ParseError>),
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => f.write_str("The story is empty."),
+            Self::Unsupported { location, feature } => f.write_fmt(format_args!(
+                "{location} uses {feature}, which can't be imported."
+            )),
+            Self::Story(errors) => {
+                f.write_str("The imported story has the following errors:\n")?;
+                for (i, error) in errors.iter().enumerate() {
+                    f.write_fmt(format_args!("\n{}. {error}", i + 1))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// Build an engine from an imported story.
+pub fn into_engine(parts: &[ProgramPart]) -> Result<Engine, ImportError> {
+    Engine::from_program(&print_program(parts)).map_err(ImportError::Story)
+}
+
+/// Turn a name from another tool into a valid node id or variable name, which may only
+/// contain letters, digits and underscores.
+fn to_identifier(name: &str) -> String {
+    let id: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if id.is_empty() { "_".to_string() } else { id }
+}
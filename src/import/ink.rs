@@ -0,0 +1,484 @@
+//! An importer for a subset of [Ink](https://github.com/inkle/ink), inkle's scripting
+//! language for interactive stories.
+//!
+//! - `VAR name = value` becomes a variable.
+//! - Knots (`=== name ===`) become nodes. The knot the story first diverts to becomes `START`.
+//! - Choices (`*` and `+`) become choices, with `{condition}` as their requirement and their
+//!   divert as their target. Text after a choice becomes a node of its own.
+//! - `~ name = value` after a choice is run when the choice is taken. In a knot, it is run
+//!   by the choices leading into the knot.
+//! - A knot that ends with a divert instead of choices gets a single "Continue" choice.
+//! - `-> END` and `-> DONE` end the story.
+//!
+//! Stitches, gathers, nested choices, functions, tunnels, threads, arithmetic and inline
+//! logic other than `{name}` are reported as unsupported.
+
+use super::{ImportError, to_identifier};
+use crate::{
+    Value,
+    engine::parser::{
+        Choice, Command, Expression, FormatString, FormatStringPart, Node, ProgramPart,
+        parse_literal,
+    },
+};
+use std::collections::HashMap;
+
+/// The node `-> END` and `-> DONE` lead to.
+const END_ID: &str = "END";
+
+#[derive(Default)]
+struct Knot {
+    name: String,
+    text: Vec<String>,
+    choices: Vec<InkChoice>,
+    divert: Option<String>,
+    sets: Vec<(String, Value)>,
+}
+
+#[derive(Default)]
+struct InkChoice {
+    text: String,
+    condition: Option<Expression>,
+    divert: Option<String>,
+    set: Option<(String, Value)>,
+    /// Text shown after the choice is taken, before following its divert.
+    text_after: Vec<String>,
+}
+
+/// Translate an Ink story into `.cyoa` definitions.
+pub fn import(source: &str) -> Result<Vec<ProgramPart>, ImportError> {
+    let mut variables = Vec::new();
+    // Content before the first knot, which is where an Ink story starts.
+    let mut knots = vec![Knot::default()];
+    for (i, line) in source.lines().enumerate() {
+        let unsupported = |feature: &str| ImportError::Unsupported {
+            location: format!("Line {}", i + 1),
+            feature: feature.to_string(),
+        };
+        let line = line.split("//").next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let knot = knots.last_mut().unwrap();
+        if line.starts_with("==") {
+            let name = line.trim_matches('=').trim();
+            if name.starts_with("function ") {
+                return Err(unsupported("a function"));
+            }
+            knots.push(Knot {
+                name: name.to_string(),
+                ..Knot::default()
+            });
+        } else if line.starts_with('=') {
+            return Err(unsupported("a stitch"));
+        } else if let Some(definition) = line
+            .strip_prefix("VAR ")
+            .or_else(|| line.strip_prefix("CONST "))
+        {
+            variables.push(parse_assignment(definition).ok_or_else(|| unsupported(line))?);
+        } else if line.starts_with('*') || line.starts_with('+') {
+            knot.choices
+                .push(parse_choice(line).ok_or_else(|| unsupported("this choice"))?);
+        } else if line.starts_with("->") || !line.contains("->") && line.starts_with('-') {
+            let Some(target) = line.strip_prefix("->") else {
+                return Err(unsupported("a gather"));
+            };
+            let target = parse_divert(target).ok_or_else(|| unsupported(line))?;
+            match knot.choices.last_mut() {
+                Some(choice) => choice.divert = Some(target),
+                None => knot.divert = Some(target),
+            }
+        } else if let Some(assignment) = line.strip_prefix('~') {
+            let set = parse_assignment(assignment).ok_or_else(|| unsupported(line))?;
+            match knot.choices.last_mut() {
+                Some(choice) if choice.set.is_some() => {
+                    return Err(unsupported("more than one assignment after a choice"));
+                }
+                Some(choice) => choice.set = Some(set),
+                None => knot.sets.push(set),
+            }
+        } else {
+            let (text, divert) = match line.split_once("->") {
+                Some((text, target)) => (
+                    text,
+                    Some(parse_divert(target).ok_or_else(|| unsupported(line))?),
+                ),
+                None => (line, None),
+            };
+            let text = text.replace("<>", "").trim().to_string();
+            match knot.choices.last_mut() {
+                Some(choice) => {
+                    choice.text_after.push(text);
+                    if divert.is_some() {
+                        choice.divert = divert;
+                    }
+                }
+                None => {
+                    knot.text.push(text);
+                    if divert.is_some() {
+                        knot.divert = divert;
+                    }
+                }
+            }
+        }
+    }
+
+    // A story that only diverts to its first knot starts at that knot.
+    let top = &knots[0];
+    let top_is_empty = top.text.is_empty() && top.choices.is_empty() && top.sets.is_empty();
+    let start = if top_is_empty {
+        match &top.divert {
+            Some(target) => Some(target.clone()),
+            None => knots.get(1).map(|knot| knot.name.clone()),
+        }
+    } else {
+        None
+    };
+    if top_is_empty {
+        knots.remove(0);
+    }
+    if knots.is_empty() {
+        return Err(ImportError::Empty);
+    }
+
+    let id_of = |name: &str| -> String {
+        if Some(name) == start.as_deref() || name.is_empty() {
+            "START".to_string()
+        } else if name == "END" || name == "DONE" {
+            END_ID.to_string()
+        } else {
+            to_identifier(name)
+        }
+    };
+
+    let mut nodes: Vec<(String, Node)> = Vec::new();
+    let mut sets_by_id: HashMap<String, Vec<(String, Value)>> = HashMap::new();
+    let mut ends = false;
+    for knot in knots {
+        let id = id_of(&knot.name);
+        let location = format!("The knot '{}'", knot.name);
+        let mut choices = Vec::new();
+        let mut after_nodes = Vec::new();
+        for (i, choice) in knot.choices.into_iter().enumerate() {
+            let Some(divert) = choice.divert else {
+                return Err(ImportError::Unsupported {
+                    location,
+                    feature: "a choice without a divert".to_string(),
+                });
+            };
+            let target = id_of(&divert);
+            ends |= target == END_ID;
+
+            let next_node_id = if choice.text_after.is_empty() {
+                target
+            } else {
+                let after_id = format!("{id}_{}", i + 1);
+                after_nodes.push((
+                    after_id.clone(),
+                    Node {
+                        display_text: to_format_string(&choice.text_after.join(" "), &location)?,
+                        variants: Vec::new(),
+                        choices: vec![continue_to(target)],
+                    },
+                ));
+                after_id
+            };
+            choices.push(Choice {
+                requirement: choice.condition,
+                text: to_format_string(&choice.text, &location)?,
+                next_node_id,
+                command: choice.set.map(|(name, value)| Command::Set { name, value }),
+            });
+        }
+        if choices.is_empty()
+            && let Some(divert) = knot.divert
+        {
+            let target = id_of(&divert);
+            ends |= target == END_ID;
+            if target != END_ID {
+                choices.push(continue_to(target));
+            }
+        }
+
+        sets_by_id.insert(id.clone(), knot.sets);
+        let display_text = to_format_string(&knot.text.join(" "), &location)?;
+        nodes.push((
+            id,
+            Node {
+                display_text,
+                variants: Vec::new(),
+                choices,
+            },
+        ));
+        nodes.extend(after_nodes);
+    }
+    if ends && !nodes.iter().any(|(id, _)| id == END_ID) {
+        nodes.push((
+            END_ID.to_string(),
+            Node {
+                display_text: FormatString(Vec::new()),
+                variants: Vec::new(),
+                choices: Vec::new(),
+            },
+        ));
+    }
+
+    // The `.cyoa` language has no commands on nodes, so a knot's assignments are run by the
+    // choices leading into it instead.
+    for (_, node) in nodes.iter_mut() {
+        for choice in node.choices.iter_mut() {
+            let Some(sets) = sets_by_id.get(&choice.next_node_id) else {
+                continue;
+            };
+            match (sets.as_slice(), &choice.command) {
+                ([], _) => {}
+                ([(name, value)], None) => {
+                    choice.command = Some(Command::Set {
+                        name: name.clone(),
+                        value: value.clone(),
+                    });
+                }
+                _ => {
+                    return Err(ImportError::Unsupported {
+                        location: format!("The knot '{}'", choice.next_node_id),
+                        feature: "more than one assignment on the way into it".to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    let mut parts: Vec<ProgramPart> = variables
+        .into_iter()
+        .map(|(name, value)| ProgramPart::VariableDefinition { name, value })
+        .collect();
+    parts.extend(
+        nodes
+            .into_iter()
+            .map(|(id, node)| ProgramPart::NodeDefinition { id, node }),
+    );
+    Ok(parts)
+}
+
+fn continue_to(next_node_id: String) -> Choice {
+    Choice {
+        requirement: None,
+        text: FormatString(vec![FormatStringPart::Literal("Continue".to_string())]),
+        next_node_id,
+        command: None,
+    }
+}
+
+/// `* {condition} Text [shown only in the choice] shown only after choosing -> target`
+fn parse_choice(line: &str) -> Option<InkChoice> {
+    let rest = line.strip_prefix(['*', '+'])?.trim_start();
+    if rest.starts_with(['*', '+']) {
+        // Nested choices.
+        return None;
+    }
+
+    let mut choice = InkChoice::default();
+    let mut rest = rest;
+    if let Some(after) = rest.strip_prefix('{') {
+        let (condition, after) = after.split_once('}')?;
+        choice.condition = Some(parse_condition(condition)?);
+        rest = after.trim_start();
+    }
+    if let Some((text, target)) = rest.split_once("->") {
+        choice.divert = Some(parse_divert(target)?);
+        rest = text;
+    }
+    // Text before the brackets is shown in both places, text inside only in the choice and
+    // text after only once it has been taken.
+    match rest.split_once('[') {
+        Some((both, after)) => {
+            let (only_choice, only_after) = after.split_once(']')?;
+            choice.text = format!("{both}{only_choice}").trim().to_string();
+            let text_after = format!("{both}{only_after}").trim().to_string();
+            if !text_after.is_empty() {
+                choice.text_after.push(text_after);
+            }
+        }
+        None => {
+            choice.text = rest.trim().to_string();
+            choice.text_after.push(choice.text.clone());
+        }
+    }
+
+    Some(choice)
+}
+
+fn parse_divert(target: &str) -> Option<String> {
+    let target = target.trim();
+    let valid = !target.is_empty()
+        && target
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then(|| target.to_string())
+}
+
+/// `name = value`, with a literal value.
+fn parse_assignment(assignment: &str) -> Option<(String, Value)> {
+    let (name, value) = assignment.split_once('=')?;
+    let name = parse_divert(name)?;
+    Some((name, parse_literal(value)?))
+}
+
+/// A condition with at most one comparison, e.g. `gold > 3` or `has_key`.
+fn parse_condition(condition: &str) -> Option<Expression> {
+    let operand = |word: &str| {
+        let word = word.trim();
+        parse_literal(word)
+            .map(Expression::Value)
+            .or_else(|| parse_divert(word).map(Expression::Name))
+    };
+
+    for op in ["==", "!=", ">", "<"] {
+        let Some((left, right)) = condition.split_once(op) else {
+            continue;
+        };
+        let left = Box::new(operand(left)?);
+        let right = Box::new(operand(right)?);
+        return Some(match op {
+            "==" => Expression::Equals { left, right },
+            "!=" => Expression::NotEquals { left, right },
+            ">" => Expression::GreaterThan { left, right },
+            _ => Expression::LessThan { left, right },
+        });
+    }
+
+    operand(condition)
+}
+
+/// Ink text as a `.cyoa` string, with `{name}` interpolating a variable.
+fn to_format_string(text: &str, location: &str) -> Result<FormatString, ImportError> {
+    let text = text.replace('"', "'");
+    let mut parts = Vec::new();
+    let mut rest = text.as_str();
+    while let Some((literal, after)) = rest.split_once('{') {
+        let (name, after) = after.split_once('}').unwrap_or((after, ""));
+        let Some(name) = parse_divert(name) else {
+            return Err(ImportError::Unsupported {
+                location: location.to_string(),
+                feature: format!("{{{name}}}"),
+            });
+        };
+        if !literal.is_empty() {
+            parts.push(FormatStringPart::Literal(literal.to_string()));
+        }
+        parts.push(FormatStringPart::Name(name));
+        rest = after;
+    }
+    if !rest.is_empty() {
+        parts.push(FormatStringPart::Literal(rest.to_string()));
+    }
+
+    Ok(FormatString(parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::printer::print_program, import::into_engine};
+
+    const CAVE: &str = r#"VAR gold = 0
+VAR name = "stranger"
+
+-> outside
+
+=== outside ===
+You stand outside a cave, {name}. // The player's name is never asked for.
+* [Go in] -> cave
+* {gold > 0} [Buy a map]
+  ~ gold = 0
+  The map is useless.
+  -> home
+
+=== cave ===
+~ gold = 5
+It is dark.
+-> home
+
+=== home ===
+You are home.
+-> END
+"#;
+
+    fn unsupported(source: &str) -> (String, String) {
+        match import(source) {
+            Err(ImportError::Unsupported { location, feature }) => (location, feature),
+            Err(e) => panic!("{e}"),
+            Ok(_) => panic!("The story was imported"),
+        }
+    }
+
+    #[test]
+    fn knots_choices_and_diverts_become_nodes_and_choices() {
+        let parts = import(CAVE).unwrap();
+
+        assert_eq!(
+            print_program(&parts),
+            r#"SET gold 0
+SET name "stranger"
+
+= START
+    "You stand outside a cave, {name}."
+    "Go in" -> cave [THEN gold = 5]
+    [IF gold > 0] "Buy a map" -> START_2 [THEN gold = 0]
+
+= START_2
+    "The map is useless."
+    "Continue" -> home
+
+= cave
+    "It is dark."
+    "Continue" -> home
+
+= home
+    "You are home."
+
+= END
+    ""
+"#
+        );
+        assert!(into_engine(&parts).is_ok());
+    }
+
+    #[test]
+    fn constructs_without_an_equivalent_are_rejected() {
+        assert_eq!(
+            unsupported("-> a\n=== a ===\nHi.\n= stitch\nThere.\n"),
+            ("Line 4".to_string(), "a stitch".to_string())
+        );
+        assert_eq!(
+            unsupported("-> a\n=== a ===\n* [A] -> a\n- Gathered.\n"),
+            ("Line 4".to_string(), "a gather".to_string())
+        );
+        assert_eq!(
+            unsupported("-> a\n=== a ===\n* [A]\n** [B] -> END\n"),
+            ("Line 4".to_string(), "this choice".to_string())
+        );
+        assert_eq!(
+            unsupported("=== function double(x) ===\n~ return x * 2\n"),
+            ("Line 1".to_string(), "a function".to_string())
+        );
+        assert_eq!(
+            unsupported("-> a\n=== a ===\n* [A]\nAnd then?\n"),
+            (
+                "The knot 'a'".to_string(),
+                "a choice without a divert".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn diverts_to_missing_knots_are_errors() {
+        let parts = import("-> a\n=== a ===\n* [On] -> nowhere\n").unwrap();
+        assert!(matches!(into_engine(&parts), Err(ImportError::Story(_))));
+        assert!(matches!(
+            import("// Nothing here.\n"),
+            Err(ImportError::Empty)
+        ));
+    }
+}
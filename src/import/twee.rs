@@ -0,0 +1,485 @@
+//! An importer for [Twee 3](https://github.com/iftechfoundation/twine-specs/blob/master/twee-3-specification.md),
+//! the text format for Twine stories.
+//!
+//! Passages become nodes and links become choices. SugarCube's `<<set>>` and `<<if>>` macros
+//! are supported where they map onto the `.cyoa` language:
+//!
+//! - `<<set $x to 5>>` in `StoryInit` or the start passage gives `x` its starting value.
+//! - `<<set $x to 5>>` in any other passage is run by the choices leading into it.
+//! - `[[Text|Target][$x to 5]]` links run their setter when taken.
+//! - `<<if $x gt 3>>` around links makes them choices with requirements.
+
+use super::{ImportError, to_identifier};
+use crate::{
+    Value,
+    engine::parser::{
+        Choice, Command, Expression, FormatString, FormatStringPart, Node, ProgramPart,
+        parse_literal,
+    },
+};
+use std::collections::{BTreeMap, HashMap};
+
+/// Passages that hold information about the story rather than being part of it.
+const SPECIAL_PASSAGES: [&str; 3] = ["StoryTitle", "StoryData", "StoryInit"];
+
+struct Passage {
+    name: String,
+    tags: Vec<String>,
+    body: String,
+}
+
+/// A link out of a passage, before it is turned into a choice.
+struct Link {
+    text: String,
+    target: String,
+    requirement: Option<Expression>,
+    setter: Option<(String, Value)>,
+}
+
+/// A passage's contents, split into what the `.cyoa` language can represent.
+#[derive(Default)]
+struct PassageBody {
+    text: String,
+    links: Vec<Link>,
+    sets: Vec<(String, Value)>,
+}
+
+/// Translate a Twee story into `.cyoa` definitions.
+pub fn import(source: &str) -> Result<Vec<ProgramPart>, ImportError> {
+    let passages = split_passages(source);
+    let find = |name: &str| passages.iter().find(|passage| passage.name == name);
+
+    let story_passages: Vec<&Passage> = passages
+        .iter()
+        .filter(|passage| !SPECIAL_PASSAGES.contains(&passage.name.as_str()))
+        .filter(|passage| {
+            !passage
+                .tags
+                .iter()
+                .any(|tag| tag == "script" || tag == "stylesheet")
+        })
+        .collect();
+    let start_name = find("StoryData")
+        .and_then(|data| serde_json::from_str::<serde_json::Value>(&data.body).ok())
+        .and_then(|data| data.get("start")?.as_str().map(str::to_string))
+        .or_else(|| find("Start").map(|passage| passage.name.clone()))
+        .or_else(|| story_passages.first().map(|passage| passage.name.clone()))
+        .ok_or(ImportError::Empty)?;
+
+    // The start passage must be called START, and every other passage needs a distinct id.
+    let mut ids: HashMap<&str, String> = HashMap::new();
+    let mut taken: Vec<String> = vec!["START".to_string()];
+    ids.insert(&start_name, "START".to_string());
+    for passage in &story_passages {
+        if passage.name == start_name {
+            continue;
+        }
+        let base = to_identifier(&passage.name);
+        let mut id = base.clone();
+        let mut n = 2;
+        while taken.contains(&id) {
+            id = format!("{base}_{n}");
+            n += 1;
+        }
+        taken.push(id.clone());
+        ids.insert(&passage.name, id);
+    }
+    let id_of = |name: &str| {
+        ids.get(name)
+            .cloned()
+            .unwrap_or_else(|| to_identifier(name))
+    };
+
+    let mut bodies = Vec::new();
+    for passage in &story_passages {
+        bodies.push(parse_body(passage)?);
+    }
+
+    // Later values win, so the start passage's sets override StoryInit's.
+    let mut variables: BTreeMap<String, Value> = BTreeMap::new();
+    let mut starting_values = Vec::new();
+    if let Some(init) = find("StoryInit") {
+        starting_values.extend(parse_body(init)?.sets);
+    }
+    for (passage, body) in story_passages.iter().zip(&bodies) {
+        for (name, value) in body
+            .sets
+            .iter()
+            .chain(body.links.iter().flat_map(|l| &l.setter))
+        {
+            variables
+                .entry(name.clone())
+                .or_insert_with(|| zero_value(value));
+        }
+        if passage.name == start_name {
+            starting_values.extend(body.sets.iter().cloned());
+        }
+    }
+    for (name, value) in starting_values {
+        variables.insert(name, value);
+    }
+
+    let sets_by_id: HashMap<String, Vec<(String, Value)>> = story_passages
+        .iter()
+        .zip(&bodies)
+        .filter(|(passage, _)| passage.name != start_name)
+        .map(|(passage, body)| (id_of(&passage.name), body.sets.clone()))
+        .collect();
+
+    let mut parts = Vec::new();
+    if let Some(title) = find("StoryTitle") {
+        parts.push(ProgramPart::Metadata {
+            key: "title".to_string(),
+            value: title.body.trim().replace('"', "'"),
+        });
+    }
+    for (name, value) in variables {
+        parts.push(ProgramPart::VariableDefinition { name, value });
+    }
+
+    for (passage, body) in story_passages.iter().zip(bodies) {
+        let mut choices = Vec::new();
+        for link in body.links {
+            let next_node_id = id_of(&link.target);
+            let mut command = link
+                .setter
+                .map(|(name, value)| Command::Set { name, value });
+            // The `.cyoa` language has no commands on nodes, so a passage's sets are run by
+            // the choices leading into it instead.
+            if let Some(sets) = sets_by_id.get(&next_node_id)
+                && !sets.is_empty()
+            {
+                if sets.len() > 1 || command.is_some() {
+                    return Err(ImportError::Unsupported {
+                        location: format!("The passage '{}'", link.target),
+                        feature: "more than one <<set>> on the way into it".to_string(),
+                    });
+                }
+                let (name, value) = sets[0].clone();
+                command = Some(Command::Set { name, value });
+            }
+
+            choices.push(Choice {
+                requirement: link.requirement,
+                text: to_format_string(&link.text),
+                next_node_id,
+                command,
+            });
+        }
+
+        parts.push(ProgramPart::NodeDefinition {
+            id: id_of(&passage.name),
+            node: Node {
+                display_text: to_format_string(&body.text),
+                variants: Vec::new(),
+                choices,
+            },
+        });
+    }
+
+    Ok(parts)
+}
+
+/// Split a Twee file into passages, each starting with a `:: Name [tags] {metadata}` line.
+fn split_passages(source: &str) -> Vec<Passage> {
+    let mut passages: Vec<Passage> = Vec::new();
+    for line in source.lines() {
+        if let Some(header) = line.strip_prefix("::") {
+            let (name, rest) = split_header(header);
+            let tags = rest
+                .trim_start()
+                .strip_prefix('[')
+                .and_then(|rest| rest.split_once(']'))
+                .map(|(tags, _)| tags.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default();
+            passages.push(Passage {
+                name,
+                tags,
+                body: String::new(),
+            });
+        } else if let Some(passage) = passages.last_mut() {
+            passage.body.push_str(line);
+            passage.body.push('\n');
+        }
+    }
+
+    passages
+}
+
+/// Split a passage header into its unescaped name and everything after it.
+fn split_header(header: &str) -> (String, &str) {
+    let mut name = String::new();
+    let mut chars = header.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => name.extend(chars.next().map(|(_, c)| c)),
+            '[' | '{' => return (name.trim().to_string(), &header[i..]),
+            _ => name.push(c),
+        }
+    }
+
+    (name.trim().to_string(), "")
+}
+
+fn parse_body(passage: &Passage) -> Result<PassageBody, ImportError> {
+    let unsupported = |feature: String| ImportError::Unsupported {
+        location: format!("The passage '{}'", passage.name),
+        feature,
+    };
+
+    let mut body = PassageBody::default();
+    let mut condition: Option<Expression> = None;
+    let mut rest = passage.body.as_str();
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("[[") {
+            let (inner, after) = after
+                .split_once("]]")
+                .ok_or_else(|| unsupported("an unclosed link".to_string()))?;
+            let mut link = parse_link(inner).ok_or_else(|| unsupported(format!("[[{inner}]]")))?;
+            link.requirement = condition.clone();
+            body.links.push(link);
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("<<") {
+            let (inner, after) = after
+                .split_once(">>")
+                .ok_or_else(|| unsupported("an unclosed macro".to_string()))?;
+            let inner = inner.trim();
+            let (name, arguments) = inner.split_once(' ').unwrap_or((inner, ""));
+            match name {
+                "set" if condition.is_none() => {
+                    let set =
+                        parse_set(arguments).ok_or_else(|| unsupported(format!("<<{inner}>>")))?;
+                    body.sets.push(set);
+                }
+                "if" if condition.is_none() => {
+                    let expression = parse_condition(arguments)
+                        .ok_or_else(|| unsupported(format!("<<{inner}>>")))?;
+                    condition = Some(expression);
+                }
+                "/if" if condition.is_some() => condition = None,
+                _ => return Err(unsupported(format!("<<{inner}>>"))),
+            }
+            rest = after;
+        } else {
+            let c = rest.chars().next().unwrap();
+            if condition.is_some() && !c.is_whitespace() {
+                return Err(unsupported("text inside <<if>>".to_string()));
+            }
+            body.text.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    if condition.is_some() {
+        return Err(unsupported("an unclosed <<if>>".to_string()));
+    }
+    Ok(body)
+}
+
+/// A link in any of Twine's forms: `Target`, `Text|Target`, `Text->Target` or
+/// `Target<-Text`, optionally followed by a SugarCube setter, as in `[[Text|Target][$x to 1]]`.
+fn parse_link(inner: &str) -> Option<Link> {
+    let (link, setter) = match inner.split_once("][") {
+        Some((link, setter)) => (link, Some(parse_set(setter)?)),
+        None => (inner, None),
+    };
+    let (text, target) = if let Some((text, target)) = link.split_once('|') {
+        (text, target)
+    } else if let Some((text, target)) = link.rsplit_once("->") {
+        (text, target)
+    } else if let Some((target, text)) = link.split_once("<-") {
+        (text, target)
+    } else {
+        (link, link)
+    };
+
+    Some(Link {
+        text: text.trim().to_string(),
+        target: target.trim().to_string(),
+        requirement: None,
+        setter,
+    })
+}
+
+/// `$name to value` or `$name = value`.
+fn parse_set(arguments: &str) -> Option<(String, Value)> {
+    let (name, value) = arguments
+        .split_once(" to ")
+        .or_else(|| arguments.split_once('='))?;
+    let name = name.trim().strip_prefix('$')?;
+    Some((to_identifier(name), parse_value(value)?))
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    let value = value.trim();
+    if let Some(string) = value
+        .strip_prefix('\'')
+        .and_then(|value| value.strip_suffix('\''))
+    {
+        return Some(Value::String(to_format_string(string)));
+    }
+    parse_literal(value)
+}
+
+/// A SugarCube condition with at most one comparison, e.g. `$gold gt 3` or `$has_key`.
+fn parse_condition(condition: &str) -> Option<Expression> {
+    let words: Vec<&str> = condition.split_whitespace().collect();
+    let operand = |word: &str| match word.strip_prefix('$') {
+        Some(name) => Some(Expression::Name(to_identifier(name))),
+        None => parse_value(word).map(Expression::Value),
+    };
+
+    let (left, op, right) = match words.as_slice() {
+        [word] => return operand(word),
+        [left, "is", "not", right] => (left, "!=", right),
+        [left, op, right] => (left, *op, right),
+        _ => return None,
+    };
+    let left = Box::new(operand(left)?);
+    let right = Box::new(operand(right)?);
+    match op {
+        "is" | "eq" | "==" | "===" => Some(Expression::Equals { left, right }),
+        "neq" | "!=" | "!==" => Some(Expression::NotEquals { left, right }),
+        "gt" | ">" => Some(Expression::GreaterThan { left, right }),
+        "lt" | "<" => Some(Expression::LessThan { left, right }),
+        _ => None,
+    }
+}
+
+/// The value a variable has before anything sets it: the "empty" value of its type.
+fn zero_value(value: &Value) -> Value {
+    match value {
+        Value::Bool(_) => Value::Bool(false),
+        Value::Int(_) => Value::Int(0),
+        Value::String(_) => Value::String(FormatString(Vec::new())),
+    }
+}
+
+/// Passage text as a `.cyoa` string. `$name` becomes `{name}`, whitespace is collapsed, and
+/// characters that can't appear in `.cyoa` strings are replaced.
+fn to_format_string(text: &str) -> FormatString {
+    let text = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('"', "'")
+        .replace('{', "(")
+        .replace('}', ")");
+
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let starts_name = chars
+            .peek()
+            .is_some_and(|next| next.is_ascii_alphabetic() || *next == '_');
+        if c == '$' && starts_name {
+            let mut name = String::new();
+            while let Some(next) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(next);
+            }
+            if !literal.is_empty() {
+                parts.push(FormatStringPart::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(FormatStringPart::Name(name));
+        } else {
+            literal.push(c);
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(FormatStringPart::Literal(literal));
+    }
+
+    FormatString(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::printer::print_program, import::into_engine};
+
+    const CAVE: &str = r#":: StoryTitle
+The "Cave"
+
+:: StoryData
+{"ifid": "D674C58C-DEFA-4F70-B7A2-27742230C0FC", "start": "Outside"}
+
+:: StoryInit
+<<set $gold to 0>>
+
+:: Outside [start]
+You stand outside a cave, $name.
+<<set $name to 'stranger'>>
+[[Go in|The Cave]]
+[[Leave->Home][$gold to 1]]
+
+:: The Cave
+It is dark.
+<<set $torch to true>>
+<<if $gold gt 0>>[[Buy a map|Home]]<</if>>
+[[Home]]
+
+:: Home
+You are home.
+
+:: Styles [stylesheet]
+body { color: red; }
+"#;
+
+    fn unsupported(source: &str) -> (String, String) {
+        match import(source) {
+            Err(ImportError::Unsupported { location, feature }) => (location, feature),
+            Err(e) => panic!("{e}"),
+            Ok(_) => panic!("The story was imported"),
+        }
+    }
+
+    #[test]
+    fn passages_and_links_become_nodes_and_choices() {
+        let parts = import(CAVE).unwrap();
+
+        assert_eq!(
+            print_program(&parts),
+            r#"META title "The 'Cave'"
+
+SET gold 0
+SET name "stranger"
+SET torch false
+
+= START
+    "You stand outside a cave, {name}."
+    "Go in" -> The_Cave [THEN torch = true]
+    "Leave" -> Home [THEN gold = 1]
+
+= The_Cave
+    "It is dark."
+    [IF gold > 0] "Buy a map" -> Home
+    "Home" -> Home
+
+= Home
+    "You are home."
+"#
+        );
+        assert!(into_engine(&parts).is_ok());
+    }
+
+    #[test]
+    fn macros_without_an_equivalent_are_rejected() {
+        let (location, feature) = unsupported(":: Start\n<<goto \"Home\">>\n\n:: Home\nHome.\n");
+        assert_eq!(location, "The passage 'Start'");
+        assert_eq!(feature, "<<goto \"Home\">>");
+
+        let (_, feature) = unsupported(":: Start\n<<if $gold gt 0>>[[Home]]\n\n:: Home\nHome.\n");
+        assert_eq!(feature, "an unclosed <<if>>");
+
+        let (_, feature) = unsupported(":: Start\n<<if $gold gt 0>>Rich!<</if>>\n");
+        assert_eq!(feature, "text inside <<if>>");
+    }
+
+    #[test]
+    fn links_to_missing_passages_are_errors() {
+        let parts = import(":: Start\n[[Nowhere]]\n").unwrap();
+        assert!(matches!(into_engine(&parts), Err(ImportError::Story(_))));
+        assert!(matches!(import(""), Err(ImportError::Empty)));
+    }
+}
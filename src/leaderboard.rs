@@ -0,0 +1,273 @@
+//! Which endings players reach and how quickly, enabled by `--leaderboard`. Every ending
+//! reached is appended to a file, so the leaderboard served at `{prefix}/leaderboard` survives
+//! restarts.
+
+use crate::ServerState;
+use axum::{
+    Json,
+    extract::{Query, State},
+};
+use cyoa::{Engine, Session};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+use utoipa::{IntoParams, ToSchema};
+
+/// One playthrough that reached an ending, as kept in the leaderboard's file.
+#[derive(Serialize, Deserialize)]
+struct Run {
+    story_id: String,
+    ending_id: String,
+    /// How many choices were taken, including ones later undone.
+    turns: usize,
+    /// How long the session had been going, in milliseconds, if known.
+    played_ms: Option<u64>,
+    /// When the ending was reached, in milliseconds since the Unix epoch.
+    finished_at: u64,
+}
+
+/// What is known about one ending of a story, built up from its runs.
+#[derive(Clone, Copy, Default)]
+struct EndingRecord {
+    reached: u64,
+    fewest_turns: Option<usize>,
+    fastest_ms: Option<u64>,
+}
+
+impl EndingRecord {
+    fn add(&mut self, run: &Run) {
+        self.reached += 1;
+        self.fewest_turns = Some(self.fewest_turns.map_or(run.turns, |t| t.min(run.turns)));
+        if let Some(played_ms) = run.played_ms {
+            self.fastest_ms = Some(self.fastest_ms.map_or(played_ms, |ms| ms.min(played_ms)));
+        }
+    }
+}
+
+pub struct Leaderboard {
+    file: Arc<Mutex<File>>,
+    /// Keyed by story id, then ending id.
+    endings: Mutex<HashMap<String, HashMap<String, EndingRecord>>>,
+}
+
+impl Leaderboard {
+    /// Opens the leaderboard kept in `path`, creating the file if it doesn't exist yet.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let mut endings: HashMap<String, HashMap<String, EndingRecord>> = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for (index, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line.map_err(|e| {
+                        format!("Failed to read leaderboard '{}': {e}", path.display())
+                    })?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let run: Run = serde_json::from_str(&line).map_err(|e| {
+                        format!(
+                            "Failed to read leaderboard '{}' at line {}: {e}",
+                            path.display(),
+                            index + 1
+                        )
+                    })?;
+                    endings
+                        .entry(run.story_id.clone())
+                        .or_default()
+                        .entry(run.ending_id.clone())
+                        .or_default()
+                        .add(&run);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read leaderboard '{}': {e}",
+                    path.display()
+                ));
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open leaderboard '{}': {e}", path.display()))?;
+
+        Ok(Leaderboard {
+            file: Arc::new(Mutex::new(file)),
+            endings: Mutex::new(endings),
+        })
+    }
+
+    /// Record a session that has just reached an ending. The run is written to the file in the
+    /// background, and a failed write is logged rather than holding up the player.
+    pub fn record(&self, story_id: &str, session: &Session) {
+        let now = SystemTime::now();
+        let run = Run {
+            story_id: story_id.to_string(),
+            ending_id: session.current_node_id().to_string(),
+            turns: session.turns(),
+            played_ms: session.created_at().map(|created_at| {
+                now.duration_since(created_at)
+                    .unwrap_or_default()
+                    .as_millis() as u64
+            }),
+            finished_at: now
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+        self.endings
+            .lock()
+            .unwrap()
+            .entry(run.story_id.clone())
+            .or_default()
+            .entry(run.ending_id.clone())
+            .or_default()
+            .add(&run);
+
+        let mut line = serde_json::to_string(&run).expect("Failed to serialize run");
+        line.push('\n');
+        let file = Arc::clone(&self.file);
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+                warn!("Failed to write to leaderboard: {e}");
+            }
+        });
+    }
+
+    /// The leaderboard for one story, laid over its current endings. Endings a reload removed
+    /// are left out, and endings nobody has reached yet are included.
+    fn story_leaderboard(&self, story_id: &str, story: &Engine) -> StoryLeaderboard {
+        let records = self
+            .endings
+            .lock()
+            .unwrap()
+            .get(story_id)
+            .cloned()
+            .unwrap_or_default();
+        let mut endings: Vec<EndingEntry> = story
+            .nodes()
+            .filter(|(_, node)| node.choices.is_empty())
+            .map(|(node_id, _)| {
+                let record = records.get(node_id).copied().unwrap_or_default();
+                EndingEntry {
+                    ending_id: node_id.to_string(),
+                    title: story
+                        .metadata(&format!("ending_{node_id}"))
+                        .map(str::to_string),
+                    reached: record.reached,
+                    share: 0.0,
+                    fewest_turns: record.fewest_turns,
+                    fastest_ms: record.fastest_ms,
+                }
+            })
+            .collect();
+        endings.sort_by(|a, b| a.ending_id.cmp(&b.ending_id));
+        let completions: u64 = endings.iter().map(|ending| ending.reached).sum();
+        if completions > 0 {
+            for ending in &mut endings {
+                ending.share = ending.reached as f64 / completions as f64;
+            }
+        }
+
+        StoryLeaderboard {
+            story_id: story_id.to_string(),
+            completions,
+            endings,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StoryLeaderboard {
+    story_id: String,
+    /// How many times sessions have reached any ending
+    completions: u64,
+    /// Every ending in the story, in the order asked for
+    endings: Vec<EndingEntry>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct EndingEntry {
+    /// The id of the node with no choices that ends the story
+    ending_id: String,
+    /// The ending's name, from a `META ending_<node_id> "..."` line in the story
+    title: Option<String>,
+    /// How many times sessions have reached the ending
+    reached: u64,
+    /// The fraction of completions that reached this ending, from 0 to 1
+    share: f64,
+    /// The fewest choices any session took to reach the ending
+    fewest_turns: Option<usize>,
+    /// The shortest time any session took to reach the ending, in milliseconds
+    fastest_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaderboardSort {
+    /// Endings reached the most quickly first, then those reached in the fewest turns
+    #[default]
+    Fastest,
+    /// Endings reached the fewest times first
+    Rarest,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct LeaderboardQuery {
+    /// How to order each story's endings
+    #[serde(default)]
+    #[param(inline)]
+    sort: LeaderboardSort,
+}
+
+#[utoipa::path(
+    get,
+    path = "/leaderboard",
+    tag = "stories",
+    params(LeaderboardQuery),
+    responses(
+        (status = 200, description = "The endings of each story, ranked", body = Vec<StoryLeaderboard>),
+        (status = 404, description = "The server wasn't started with `--leaderboard`", body = crate::ErrorResponse),
+    ),
+)]
+pub async fn get_leaderboard(
+    State(state): State<Arc<ServerState>>,
+    Query(query): Query<LeaderboardQuery>,
+) -> Json<Vec<StoryLeaderboard>> {
+    let leaderboard = state
+        .leaderboard
+        .as_ref()
+        .expect("The leaderboard is only served with --leaderboard");
+    Json(
+        state
+            .stories
+            .iter()
+            .map(|story| {
+                let mut board = leaderboard.story_leaderboard(&story.story_id, &story.story());
+                match query.sort {
+                    // Unreached endings, with no times, go last.
+                    LeaderboardSort::Fastest => board.endings.sort_by_key(|ending| {
+                        (
+                            ending.fastest_ms.is_none(),
+                            ending.fastest_ms,
+                            ending.fewest_turns.is_none(),
+                            ending.fewest_turns,
+                        )
+                    }),
+                    LeaderboardSort::Rarest => {
+                        board.endings.sort_by_key(|ending| ending.reached);
+                    }
+                }
+                board
+            })
+            .collect(),
+    )
+}
@@ -0,0 +1,54 @@
+//! A scripting language and runtime for choose-your-own-adventure style interactive fiction.
+//!
+//! A story is loaded into an [`Engine`], which holds everything that is shared between
+//! players. Each player's progress lives in a [`Session`], which is passed to the engine
+//! to render the current scene and to take choices.
+//!
+//! ```
+//! use cyoa::{ChoiceResult, Engine};
+//!
+//! let story = Engine::from_program(r#"
+//!     = START
+//!         "You are in a dark room."
+//!         "Open the door." -> outside
+//!
+//!     = outside
+//!         "You are free."
+//! "#).unwrap();
+//!
+//! let mut session = story.new_session();
+//! let view = story.get_current_node_view(&session);
+//! assert_eq!(view.display_text, "You are in a dark room.");
+//!
+//! let result = story.choose_option(&mut session, view.choices[0].id.clone());
+//! assert!(matches!(result, ChoiceResult::Success));
+//! assert!(story.get_current_node_view(&session).game_over);
+//! ```
+//!
+//! The HTTP server that ships with this crate is built on top of this API and is only
+//! compiled with the `server` feature, which is enabled by default.
+
+pub mod builder;
+pub mod embed;
+pub mod engine;
+pub mod import;
+
+pub use builder::StoryBuilder;
+pub use engine::{
+    CheckpointError, ChoiceResult, ChoiceView, CompiledStoryError, CurrentNodeView, Engine,
+    EngineObserver, EvalError, HistoryEvent, ParseError, ReadStoryError, RestoreError, Session,
+    SessionSnapshot, TextProvider, VoteError,
+    analysis::{Analysis, NodeAnalysis},
+    explore::{Exploration, ExploreError, Goal},
+    parser::Value,
+    tokens::{Token, TokenKind, tokenize},
+};
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+#[cfg(feature = "python")]
+pub mod python;
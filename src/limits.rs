@@ -0,0 +1,64 @@
+//! Limits on the size of requests, how long they can take and how many are handled at once, so
+//! one misbehaving client can't tie up the server for everyone else.
+
+use crate::api_error;
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+
+pub struct Limits {
+    pub timeout: Duration,
+    pub max_uri_length: usize,
+    /// A permit for every request that can be handled at once, if there is a limit.
+    pub concurrency: Option<Arc<Semaphore>>,
+}
+
+/// Turns away requests with overlong URIs with a `414`, and requests that arrive while the
+/// server is already handling as many as it can with a `503`. Requests that take longer than
+/// the timeout are given up on with a `503` too.
+///
+/// Only the time until the response starts counts, so event streams and WebSockets can stay
+/// open for as long as they like.
+pub async fn enforce(State(limits): State<Arc<Limits>>, request: Request, next: Next) -> Response {
+    let uri_length = request.uri().to_string().len();
+    if uri_length > limits.max_uri_length {
+        return api_error(
+            StatusCode::URI_TOO_LONG,
+            format!(
+                "the URI is {uri_length} characters long, but can be at most {}",
+                limits.max_uri_length
+            ),
+        )
+        .into_response();
+    }
+    let _permit = match &limits.concurrency {
+        Some(concurrency) => match Arc::clone(concurrency).try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                return api_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the server is busy, try again shortly",
+                )
+                .into_response();
+            }
+        },
+        None => None,
+    };
+
+    match tokio::time::timeout(limits.timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => api_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "the request took longer than {}s to handle",
+                limits.timeout.as_secs()
+            ),
+        )
+        .into_response(),
+    }
+}
@@ -0,0 +1,331 @@
+//! Pushing changes to sessions to clients as they happen, rather than making them poll.
+
+use crate::{
+    ApiError, AppState, SessionToken, get_own_session, get_readable_session, get_session,
+    read_session, voting::vote_status,
+};
+use axum::{
+    extract::{
+        Path, State, WebSocketUpgrade,
+        ws::{Message, WebSocket},
+    },
+    response::{
+        Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+};
+use cyoa::{ChoiceResult, CurrentNodeView, Value};
+use futures_util::{Stream, stream};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    sync::Mutex,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Lets the clients following a session know when it changes, whoever changed it.
+/// Only changes made through this instance of the server are seen.
+#[derive(Default)]
+pub struct SessionEvents {
+    channels: Mutex<HashMap<String, broadcast::Sender<()>>>,
+}
+
+impl SessionEvents {
+    /// Start following a session.
+    pub fn subscribe(&self, session_id: &str) -> broadcast::Receiver<()> {
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(session_id.to_string())
+            .or_insert_with(|| broadcast::channel(16).0)
+            .subscribe()
+    }
+
+    /// Tell everyone following a session that it has changed.
+    pub fn notify(&self, session_id: &str) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(session_id)
+            && sender.send(()).is_err()
+        {
+            // Nobody is following the session any more.
+            channels.remove(session_id);
+        }
+    }
+}
+
+/// A message from a client playing over a WebSocket.
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum SocketRequest {
+    Choose { choice: String },
+    Back,
+    Restart,
+}
+
+/// A message to a client playing over a WebSocket.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SocketMessage {
+    View(CurrentNodeView),
+    Error { error: String },
+}
+
+pub async fn session_socket(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    // Check the session and its token up front, so a missing session is a 404 rather than a
+    // socket that closes straight away. Browsers can't set headers on a WebSocket, so they pass
+    // the token in the query string.
+    get_own_session(&state, &session_id, &token).await?;
+    Ok(ws.on_upgrade(move |socket| play_over_socket(socket, state, session_id)))
+}
+
+async fn play_over_socket(mut socket: WebSocket, state: AppState, session_id: String) {
+    let mut updates = state.events.subscribe(&session_id);
+    if !send_view(&mut socket, &state, &session_id).await {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                };
+                let result = match serde_json::from_str(&text) {
+                    Ok(request) => handle_request(&state, &session_id, request).await,
+                    Err(e) => Err(format!("invalid message: {e}")),
+                };
+                // On success, the new view is sent once the update comes through below.
+                if let Err(error) = result
+                    && !send(&mut socket, &SocketMessage::Error { error }).await
+                {
+                    return;
+                }
+            }
+            update = updates.recv() => {
+                if let Err(RecvError::Closed) = update {
+                    return;
+                }
+                if !send_view(&mut socket, &state, &session_id).await {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_request(
+    state: &AppState,
+    session_id: &str,
+    request: SocketRequest,
+) -> Result<(), String> {
+    let (story, mut session) = get_session(state, session_id)
+        .await
+        .map_err(|(_, body)| body.0.error)?;
+    let result = match request {
+        SocketRequest::Choose { .. } if session.is_shared() => {
+            Err("this session is shared, so its choices are voted on".to_string())
+        }
+        SocketRequest::Choose { choice } => {
+            let from_node_id = session.current_node_id().to_string();
+            match story.choose_option(&mut session, choice) {
+                ChoiceResult::Success => {
+                    state.record_choice(session_id, &from_node_id, &session);
+                    Ok(())
+                }
+                result => Err(result.to_string()),
+            }
+        }
+        SocketRequest::Back => {
+            if story.go_back(&mut session) {
+                Ok(())
+            } else {
+                Err("there is no choice to undo".to_string())
+            }
+        }
+        SocketRequest::Restart => {
+            story.restart_session(&mut session);
+            state.record_visit(session_id, &session);
+            Ok(())
+        }
+    };
+    if result.is_ok() {
+        state
+            .save_changed_session(session_id, &mut session)
+            .await
+            .map_err(|(_, body)| body.0.error)?;
+    }
+
+    result
+}
+
+/// Send the session's current view, or an error and close the socket if the session is gone.
+/// Returns whether the socket is still open.
+async fn send_view(socket: &mut WebSocket, state: &AppState, session_id: &str) -> bool {
+    match read_session(state, session_id).await {
+        Ok((story, session)) => {
+            let view = story.get_current_node_view(&session);
+            send(socket, &SocketMessage::View(view)).await
+        }
+        Err((_, body)) => {
+            let error = body.0.error;
+            send(socket, &SocketMessage::Error { error }).await;
+            let _ = socket.send(Message::Close(None)).await;
+            false
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &SocketMessage) -> bool {
+    let text = serde_json::to_string(message).expect("Failed to serialize message");
+    socket.send(Message::Text(text.into())).await.is_ok()
+}
+
+pub async fn session_events(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    get_readable_session(&state, &session_id, &token).await?;
+    let follower = Follower {
+        updates: state.events.subscribe(&session_id),
+        state,
+        session_id,
+        seen: None,
+        pending: VecDeque::new(),
+        started: false,
+        finished: false,
+    };
+    let events = stream::unfold(follower, |mut follower| async move {
+        let event = follower.next_event().await?;
+        Some((Ok(event), follower))
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// What a client following a session over server-sent events has been told so far.
+struct Seen {
+    /// The session's [`cyoa::Session::events_recorded`], which keeps counting once old events
+    /// are dropped from its history.
+    events_recorded: usize,
+    variables: HashMap<String, Value>,
+    game_over: bool,
+    participants: usize,
+    votes: Vec<(String, usize)>,
+}
+
+/// Turns changes to a session into server-sent events.
+struct Follower {
+    state: AppState,
+    session_id: String,
+    updates: broadcast::Receiver<()>,
+    seen: Option<Seen>,
+    pending: VecDeque<Event>,
+    started: bool,
+    finished: bool,
+}
+
+impl Follower {
+    async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            if self.finished {
+                return None;
+            }
+            if self.started {
+                if let Err(RecvError::Closed) = self.updates.recv().await {
+                    return None;
+                }
+            } else {
+                self.started = true;
+            }
+            self.catch_up().await;
+        }
+    }
+
+    /// Queue events for everything that has happened to the session since it was last seen.
+    async fn catch_up(&mut self) {
+        let (story, session) = match read_session(&self.state, &self.session_id).await {
+            Ok(found) => found,
+            Err((_, body)) => {
+                self.pending
+                    .push_back(json_event("error", json!({ "error": body.0.error })));
+                self.finished = true;
+                return;
+            }
+        };
+
+        let history = story.history(&session);
+        let view = story.get_current_node_view(&session);
+        if let Some(seen) = &self.seen {
+            let new_events = session
+                .events_recorded()
+                .saturating_sub(seen.events_recorded);
+            for event in history
+                .iter()
+                .skip(history.len().saturating_sub(new_events))
+            {
+                let data = serde_json::to_value(event).expect("Failed to serialize event");
+                let name = data["event"].as_str().unwrap_or("history").to_string();
+                self.pending.push_back(json_event(&name, data));
+            }
+
+            let changed = session.variables().filter(|(name, value)| {
+                seen.variables
+                    .get(*name)
+                    .is_none_or(|old| old.to_string() != value.to_string())
+            });
+            for (name, value) in changed {
+                self.pending.push_back(json_event(
+                    "variable_changed",
+                    json!({ "name": name, "value": value }),
+                ));
+            }
+        }
+        let participants = session.participants().len();
+        let votes = story.vote_tally(&session);
+        let votes_changed = self
+            .seen
+            .as_ref()
+            .is_some_and(|seen| seen.participants != participants || seen.votes != votes);
+        if votes_changed {
+            let status = vote_status(&story, &session);
+            self.pending
+                .push_back(json_event("votes", serde_json::to_value(status).unwrap()));
+        }
+
+        let game_over = view.game_over;
+        let was_over = self.seen.as_ref().is_some_and(|seen| seen.game_over);
+        self.pending
+            .push_back(json_event("view", serde_json::to_value(view).unwrap()));
+        if game_over && !was_over {
+            self.pending.push_back(json_event("game_over", json!({})));
+        }
+
+        self.seen = Some(Seen {
+            events_recorded: session.events_recorded(),
+            variables: session
+                .variables()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            game_over,
+            participants,
+            votes,
+        });
+    }
+}
+
+fn json_event(name: &str, data: serde_json::Value) -> Event {
+    Event::default().event(name).data(data.to_string())
+}
@@ -0,0 +1,119 @@
+//! The server's logs, written with `tracing` to stderr and, with the `otel` feature, exported
+//! to an OpenTelemetry collector.
+
+use crate::{ServeArgs, request_id::REQUEST_ID_HEADER};
+use axum::{body::Body, http::Request};
+use clap::ValueEnum;
+use tower_http::{
+    classify::{ServerErrorsAsFailures, SharedClassifier},
+    trace::{DefaultOnResponse, MakeSpan, TraceLayer},
+};
+use tracing::{Level, Span, field};
+use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum LogFormat {
+    /// Lines meant to be read by people.
+    Text,
+    /// One JSON object a line, for log aggregators.
+    Json,
+}
+
+/// Start writing logs, as set by `--log-level`, `--log-format` and, with the `otel` feature,
+/// `--otlp-endpoint`. Without `--log-level`, the `RUST_LOG` environment variable is used, and
+/// failing that `info`.
+pub fn init(args: &ServeArgs) -> Result<(), String> {
+    let filter = match &args.log_level {
+        Some(level) => {
+            EnvFilter::try_new(level).map_err(|e| format!("Invalid log level '{level}': {e}"))?
+        }
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    let output = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let output = match args.log_format {
+        LogFormat::Text => output.boxed(),
+        LogFormat::Json => output.json().boxed(),
+    };
+    let subscriber = tracing_subscriber::registry().with(filter).with(output);
+
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(args.otlp_endpoint.as_deref().map(otlp_layer).transpose()?);
+
+    subscriber.init();
+
+    Ok(())
+}
+
+/// Sends spans, and the events logged inside them, to the OpenTelemetry collector at
+/// `endpoint`, e.g. `http://localhost:4318/v1/traces`.
+#[cfg(feature = "otel")]
+fn otlp_layer<S>(endpoint: &str) -> Result<impl Layer<S>, String>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| format!("Failed to export traces to '{endpoint}': {e}"))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("cyoa").build())
+        .build();
+
+    Ok(tracing_opentelemetry::layer().with_tracer(provider.tracer("cyoa")))
+}
+
+pub type RequestLogLayer = TraceLayer<SharedClassifier<ServerErrorsAsFailures>, RequestSpan>;
+
+/// Logs every response at `info`, inside a span for the request.
+pub fn layer() -> RequestLogLayer {
+    TraceLayer::new_for_http()
+        .make_span_with(RequestSpan)
+        .on_response(DefaultOnResponse::new().level(Level::INFO))
+}
+
+/// A span for each request, with its request id and the id of the session it is for, if any,
+/// so everything logged while handling it can be traced back to the request and the session.
+#[derive(Clone, Copy)]
+pub struct RequestSpan;
+
+impl MakeSpan<Body> for RequestSpan {
+    fn make_span(&mut self, request: &Request<Body>) -> Span {
+        // Only the path is logged, since the query string can hold a session token.
+        let path = request.uri().path();
+        let span = tracing::info_span!(
+            "request",
+            method = %request.method(),
+            path,
+            request_id = field::Empty,
+            session_id = field::Empty,
+        );
+        if let Some(request_id) = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+        {
+            span.record("request_id", request_id);
+        }
+        if let Some(session_id) = session_id(path) {
+            span.record("session_id", session_id);
+        }
+
+        span
+    }
+}
+
+/// The session id in a path such as `/stories/cave/session/{session_id}/current`.
+fn session_id(path: &str) -> Option<&str> {
+    let mut segments = path.split('/');
+    segments.find(|&segment| segment == "session")?;
+    segments
+        .next()
+        .filter(|&segment| !segment.is_empty() && segment != "import")
+}
@@ -0,0 +1,315 @@
+//! A minimal [Language Server Protocol](https://microsoft.github.io/language-server-protocol/)
+//! server for `.cyoa` stories, run by editors as `cyoa lsp`. It reports the same problems as
+//! `cyoa validate` as the story is edited, jumps to where nodes and variables are defined, and
+//! completes node ids after `->`.
+
+use crate::validate::{Locations, Severity, validate};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    process::ExitCode,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct LspArgs {
+    /// Talk over stdin and stdout, which is the only transport supported. Accepted because
+    /// editors pass it.
+    #[arg(long)]
+    stdio: bool,
+}
+
+// Error codes defined by JSON-RPC and the protocol.
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+// Values from the protocol's enumerations.
+const FULL_SYNC: u8 = 1;
+const SEVERITY_ERROR: u8 = 1;
+const SEVERITY_WARNING: u8 = 2;
+const COMPLETION_KIND_REFERENCE: u8 = 18;
+
+/// Serve requests from an editor until it asks the server to exit.
+pub fn run(_args: LspArgs) -> ExitCode {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut server = Server::default();
+
+    loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => message,
+            // The editor went away without asking the server to exit.
+            Ok(None) => return ExitCode::FAILURE,
+            Err(e) => {
+                eprintln!("Failed to read a message: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let method = message["method"].as_str().unwrap_or_default();
+        if method == "exit" {
+            return if server.shutting_down {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            };
+        }
+
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+        let outgoing = match message.get("id") {
+            Some(id) => {
+                let response = match server.request(method, &params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, error)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": error },
+                    }),
+                };
+                vec![response]
+            }
+            None => server.notification(method, &params),
+        };
+        for message in outgoing {
+            if let Err(e) = write_message(&mut output, &message) {
+                eprintln!("Failed to write a message: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+}
+
+/// Reads one message, framed by a `Content-Length` header. `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            length = value.trim().parse().ok();
+        }
+    }
+    let length: usize = length.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "a message has no Content-Length",
+        )
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()
+}
+
+#[derive(Default)]
+struct Server {
+    /// The text of every open document, by URI.
+    documents: HashMap<String, String>,
+    shutting_down: bool,
+}
+
+impl Server {
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": FULL_SYNC,
+                    "definitionProvider": true,
+                    "completionProvider": { "triggerCharacters": [">"] },
+                },
+                "serverInfo": { "name": "cyoa", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => {
+                self.shutting_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => {
+                let (source, offset) = self.document_position(params)?;
+                Ok(
+                    definition(source, offset).map_or(Value::Null, |(line, column)| {
+                        let position = lsp_position(source, line, column);
+                        json!({
+                            "uri": params["textDocument"]["uri"],
+                            "range": { "start": position, "end": position },
+                        })
+                    }),
+                )
+            }
+            "textDocument/completion" => {
+                let (source, offset) = self.document_position(params)?;
+                Ok(Value::Array(completions(source, offset)))
+            }
+            _ => Err((
+                METHOD_NOT_FOUND,
+                format!("there is no method named '{method}'"),
+            )),
+        }
+    }
+
+    /// Handles a notification, returning any notifications to send back.
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.documents.insert(uri.to_string(), text.to_string());
+            }
+            // Changes are always the whole document, as asked for in `initialize`.
+            "textDocument/didChange" => {
+                let Some(text) = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str())
+                else {
+                    return Vec::new();
+                };
+                self.documents.insert(uri.to_string(), text.to_string());
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, Vec::new())];
+            }
+            _ => return Vec::new(),
+        }
+
+        let source = &self.documents[uri];
+        vec![publish_diagnostics(uri, diagnostics(source))]
+    }
+
+    /// The open document a request is about, and the byte offset of its position.
+    fn document_position(&self, params: &Value) -> Result<(&str, usize), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+        let source = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| (INVALID_PARAMS, format!("'{uri}' isn't open")))?;
+        let line = params["position"]["line"].as_u64().unwrap_or_default() as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or_default() as usize;
+
+        Ok((source, byte_offset(source, line, character)))
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+/// The problems `cyoa validate` finds, each covering the rest of the line it starts on.
+/// Problems without a location are put at the start of the story.
+fn diagnostics(source: &str) -> Vec<Value> {
+    validate(source)
+        .into_iter()
+        .map(|diagnostic| {
+            let line = diagnostic.line.unwrap_or(1);
+            let column = diagnostic.column.unwrap_or(1);
+            let line_length = source
+                .lines()
+                .nth(line - 1)
+                .map_or(0, |text| text.chars().count());
+            json!({
+                "range": {
+                    "start": lsp_position(source, line, column),
+                    "end": lsp_position(source, line, line_length + 1),
+                },
+                "severity": match diagnostic.severity {
+                    Severity::Error => SEVERITY_ERROR,
+                    Severity::Warning => SEVERITY_WARNING,
+                },
+                "source": "cyoa",
+                "message": diagnostic.message,
+            })
+        })
+        .collect()
+}
+
+/// A 1-based line and column in characters, as the protocol's 0-based line and column in
+/// UTF-16 code units.
+fn lsp_position(source: &str, line: usize, column: usize) -> Value {
+    let text = source.lines().nth(line - 1).unwrap_or_default();
+    let character: usize = text.chars().take(column - 1).map(char::len_utf16).sum();
+    json!({ "line": line - 1, "character": character })
+}
+
+/// The protocol's 0-based line and UTF-16 column as a byte offset into the source.
+fn byte_offset(source: &str, line: usize, character: usize) -> usize {
+    let line_start: usize = source.split_inclusive('\n').take(line).map(str::len).sum();
+    let text = source[line_start..].lines().next().unwrap_or_default();
+    let mut units = 0;
+    for (index, c) in text.char_indices() {
+        if units >= character {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+
+    line_start + text.len()
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Where the node or variable named at `offset` is defined. Names inside `{...}` and after
+/// `SET` are variables, and anything else is looked up as a node first.
+fn definition(source: &str, offset: usize) -> Option<(usize, usize)> {
+    let start = source[..offset]
+        .rfind(|c| !is_name_char(c))
+        .map_or(0, |i| i + 1);
+    let end = source[offset..]
+        .find(|c| !is_name_char(c))
+        .map_or(source.len(), |i| offset + i);
+    let name = &source[start..end];
+    if name.is_empty() {
+        return None;
+    }
+
+    let locations = Locations::new(source);
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let before = source[line_start..start].trim_start();
+    if source[..start].ends_with('{') || before == "SET " || before.starts_with("SET ") {
+        return locations.variable(name);
+    }
+    locations.node(name).or_else(|| locations.variable(name))
+}
+
+/// The node ids to offer when the cursor is after `->`, e.g. `"Go left." -> le|`.
+fn completions(source: &str, offset: usize) -> Vec<Value> {
+    let before = source[..offset].trim_end_matches(is_name_char);
+    if !before.trim_end().ends_with("->") {
+        return Vec::new();
+    }
+
+    source
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix('='))
+        .map(|rest| {
+            rest.trim_start()
+                .chars()
+                .take_while(|c| is_name_char(*c))
+                .collect::<String>()
+        })
+        .filter(|id| !id.is_empty())
+        .map(|id| json!({ "label": id, "kind": COMPLETION_KIND_REFERENCE }))
+        .collect()
+}
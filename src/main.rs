@@ -1,30 +1,457 @@
-mod engine;
+mod analytics;
+mod bench_server;
+mod compile;
+mod config;
+mod convert;
+mod cookies;
+mod cors;
+mod debug;
+mod debug_api;
+mod explore;
+mod export;
+mod fmt;
+mod golden;
+mod graph;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod leaderboard;
+mod limits;
+mod live;
+mod logging;
+mod lsp;
+mod metrics;
+mod new;
+mod play;
+mod preview;
+mod rate_limit;
+mod render;
+mod request_id;
+mod rpc;
+mod spectate;
+mod stateless;
+mod stats;
+mod store;
+mod story_tests;
+mod tls;
+mod tokens;
+mod validate;
+mod voting;
+mod walk;
+mod webhooks;
 
 use axum::{
     Json, Router,
-    extract::{Path, State},
-    http::StatusCode,
-    routing::{get, post},
+    extract::{DefaultBodyLimit, FromRequestParts, Path, Query, Request, State},
+    http::{HeaderMap, StatusCode, header, request::Parts},
+    middleware::{self, Next},
+    response::{Html, Response},
+    routing::{delete, get, post, put},
 };
-use clap::Parser;
-use engine::{ChoiceResult, CurrentNodeView, Engine, Session};
-use serde::Serialize;
+use clap::{Parser, Subcommand, ValueEnum};
+use cyoa::{
+    Analysis, ChoiceResult, CurrentNodeView, Engine, HistoryEvent, ParseError, ReadStoryError,
+    Session, SessionSnapshot, Value,
+    engine::parser::{ProgramPart, parse_program},
+};
+use leaderboard::Leaderboard;
+use live::SessionEvents;
+use logging::LogFormat;
+use metrics::Metrics;
+use rate_limit::{ClientAddr, RateLimit};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{collections::HashMap, fs, sync::Arc};
+use std::{
+    convert::Infallible,
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    net::{IpAddr, SocketAddr},
+    ops::{Deref, DerefMut},
+    path::{Path as FilePath, PathBuf},
+    process::ExitCode,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use store::{MemoryStore, SessionStore, StoreError, open_store};
 use tokio::{
     net::TcpListener,
-    sync::{Mutex, RwLock},
+    sync::{Mutex, OwnedMutexGuard, Semaphore, broadcast::error::RecvError},
+};
+use tower_http::compression::CompressionLayer;
+use tracing::{error, info, warn};
+use utoipa::{
+    IntoParams, Modify, OpenApi, PartialSchema, ToSchema,
+    openapi::{
+        Server,
+        security::{Http, HttpAuthScheme, SecurityScheme},
+    },
 };
 use uuid::Uuid;
+use webhooks::{WebhookEvent, Webhooks};
+
+/// The page served at `/play` by `--serve-ui`.
+const PLAY_PAGE: &str = include_str!("web/play.html");
+/// The page served at `/docs` by `--swagger-ui`.
+const DOCS_PAGE: &str = include_str!("web/docs.html");
+
+/// What to do with sessions that were started before their story was reloaded.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ReloadPolicy {
+    /// Carry sessions over to the new story, restarting ones that can't be carried over.
+    Migrate,
+    /// Restart every session that was started before the reload.
+    Reset,
+    /// Drop every session that was started before the reload.
+    Invalidate,
+}
+
+/// Which endpoints need an API key.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum ApiKeyScope {
+    /// Only endpoints that manage the server: `/reload`, `/clear_expired_sessions`, `/stats`,
+    /// `/metrics` and everything under `/admin`.
+    Admin,
+    /// Every endpoint except the `/play` and `/docs` pages and `/openapi.json`.
+    All,
+}
 
+/// Options applied to every engine, including ones built by a reload.
+#[derive(Clone)]
+struct StorySettings {
+    history_depth: usize,
+    max_checkpoints: usize,
+    max_transcript: usize,
+    /// The plugins providing functions the story calls.
+    #[cfg(feature = "plugins")]
+    plugins: Option<Arc<cyoa::engine::plugins::Plugins>>,
+}
+
+/// Everything the routes for a single story need.
 struct SharedState {
-    story: Engine<'static>,
-    sessions: RwLock<HashMap<String, Arc<Mutex<Session>>>>,
+    story_id: String,
+    source_path: PathBuf,
+    story: RwLock<Arc<Engine>>,
+    settings: StorySettings,
+    sessions: Arc<dyn SessionStore>,
+    session_locks: SessionLocks,
+    /// How long a session can go without being played before it expires.
     session_timeout_hours: f32,
+    reload_policy: ReloadPolicy,
+    events: SessionEvents,
+    /// How long a shared session's players have to vote, if votes close on their own.
+    vote_window: Option<Duration>,
+    /// Whether reading a session needs its token, as well as changing it.
+    private_sessions: bool,
+    /// Whether clients can see inside sessions, as set by `--debug`.
+    debug: bool,
+    /// Whether `POST /session` also sets a cookie with the new session, as set by
+    /// `--cookie-sessions`.
+    cookie_sessions: bool,
+    /// Whether the session cookie is only sent over HTTPS, as it is with `--tls-cert` or
+    /// `--secure-cookies`.
+    secure_cookies: bool,
+    /// What stateless sessions' state is signed with, if `--stateless-secret` was given.
+    stateless_key: Option<ring::hmac::Key>,
+    /// Whether sessions started on the same day share a seed, as set by `--daily-seed`.
+    daily_seed: bool,
+    /// How many sessions there can be across every story, if there is a limit.
+    max_sessions: Option<usize>,
+    /// Whether to turn new sessions away once there are `max_sessions`, rather than evicting
+    /// the least recently active session to make room.
+    reject_when_full: bool,
+    metrics: Arc<Metrics>,
+    webhooks: Arc<Webhooks>,
+    /// Where endings are recorded, if `--leaderboard` was given.
+    leaderboard: Option<Arc<Leaderboard>>,
+}
+
+impl SharedState {
+    /// Sessions for every story share one store, so their keys are namespaced by story.
+    fn session_key(&self, session_id: &str) -> String {
+        format!("{}/{session_id}", self.story_id)
+    }
+
+    /// The current engine for this story. Requests hold on to the engine they started
+    /// with, so a reload never swaps it out from under them.
+    fn story(&self) -> Arc<Engine> {
+        Arc::clone(&self.story.read().unwrap())
+    }
+
+    /// Start a session at the beginning of the story. With `--daily-seed`, it shares today's
+    /// seed with every other session started today.
+    fn new_session(&self, story: &Engine) -> Session {
+        if self.daily_seed {
+            story.new_seeded_session(daily_seed(SystemTime::now()))
+        } else {
+            story.new_session()
+        }
+    }
+
+    /// Write back a session the player has changed, letting anyone following it know.
+    async fn save_changed_session(
+        &self,
+        session_id: &str,
+        session: &mut LockedSession,
+    ) -> Result<(), ApiError> {
+        self.save_session(session_id, session).await?;
+        self.events.notify(session_id);
+        Ok(())
+    }
+
+    /// Write back a locked session. It fails with a `409` if another server sharing the store
+    /// has written it since it was read.
+    async fn save_session(
+        &self,
+        session_id: &str,
+        session: &mut LockedSession,
+    ) -> Result<(), ApiError> {
+        // The version has to go up with every write, so the next write can tell whether it
+        // still has the latest session.
+        if session.version() == session.read_version {
+            session.bump_version();
+        }
+        self.sessions
+            .update(
+                &self.session_key(session_id),
+                &session.session,
+                session.read_version,
+            )
+            .await
+            .map_err(store_error)?;
+        session.read_version = session.version();
+        Ok(())
+    }
+
+    /// Let the webhook for `event`, if there is one, know about a session.
+    fn send_webhook(
+        &self,
+        event: WebhookEvent,
+        session_id: &str,
+        story: &Engine,
+        session: &Session,
+        achievement: Option<&str>,
+    ) {
+        self.webhooks.send(
+            event,
+            &self.story_id,
+            session_id,
+            story,
+            session,
+            achievement,
+        );
+    }
+
+    /// Count a session arriving at its current node, and send any achievement for it.
+    fn record_visit(&self, session_id: &str, session: &Session) {
+        self.record_arrival(session_id, &Visit::of(&self.story(), session), session);
+    }
+
+    /// Count a visit worked out earlier. Any achievement is sent with `session` as it is now.
+    fn record_arrival(&self, session_id: &str, visit: &Visit, session: &Session) {
+        self.metrics.node_visited(&self.story_id, &visit.node_id);
+        if let Some(variant) = &visit.variant {
+            self.metrics
+                .variant_shown(&self.story_id, &visit.node_id, variant);
+        }
+        for (choice_id, met) in &visit.requirements {
+            self.metrics
+                .requirement_evaluated(&self.story_id, &visit.node_id, choice_id, *met);
+        }
+        if let Some(achievement) = &visit.achievement {
+            self.send_webhook(
+                WebhookEvent::Achievement,
+                session_id,
+                &self.story(),
+                session,
+                Some(achievement),
+            );
+        }
+    }
+
+    /// Log and count a choice taken by a session at `from_node_id`, and the node it led to.
+    fn record_choice(&self, session_id: &str, from_node_id: &str, session: &Session) {
+        let choice = TakenChoice::of(&self.story(), from_node_id.to_string(), session);
+        self.record_taken_choice(session_id, &choice, session);
+    }
+
+    /// Log and count a choice worked out earlier. Webhooks are sent with `session` as it is
+    /// now.
+    fn record_taken_choice(&self, session_id: &str, choice: &TakenChoice, session: &Session) {
+        info!(
+            %session_id,
+            node_id = choice.visit.node_id,
+            "Took a choice"
+        );
+        self.metrics
+            .choice_made(&self.story_id, &choice.from_node_id, &choice.visit.node_id);
+        if let Some(variant) = &choice.variant {
+            self.metrics
+                .variant_choice_made(&self.story_id, &choice.from_node_id, variant);
+        }
+        self.record_arrival(session_id, &choice.visit, session);
+
+        if choice.game_over {
+            let story = self.story();
+            self.send_webhook(WebhookEvent::GameOver, session_id, &story, session, None);
+            if let Some(leaderboard) = &self.leaderboard {
+                leaderboard.record(&self.story_id, session);
+            }
+        }
+    }
+
+    /// Check there is room for one more session under `--max-sessions`, evicting the least
+    /// recently active session if need be.
+    async fn make_room_for_session(&self) -> Result<(), ApiError> {
+        let Some(max_sessions) = self.max_sessions else {
+            return Ok(());
+        };
+        while self.sessions.count().await.map_err(store_error)? >= max_sessions {
+            if self.reject_when_full {
+                return Err(api_error(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the server has too many sessions, try again later",
+                ));
+            }
+            let Some(session_key) = self
+                .sessions
+                .evict_least_recently_active()
+                .await
+                .map_err(store_error)?
+            else {
+                break;
+            };
+            info!(session = %session_key, "Evicted session to make room for a new one");
+        }
+
+        Ok(())
+    }
+
+    /// Re-read the story's source file, swapping in a new engine if the story has changed.
+    /// Returns whether the story changed.
+    fn reload(&self) -> Result<bool, String> {
+        let engine = build_story(&self.source_path, self.settings.clone())?;
+        let mut story = self.story.write().unwrap();
+        if story.version() == engine.version() {
+            return Ok(false);
+        }
+        *story = Arc::new(engine);
+        info!(story_id = %self.story_id, "Reloaded story");
+
+        Ok(true)
+    }
 }
 
 type AppState = Arc<SharedState>;
 
+/// What a session arriving at its current node looked like, worked out from the session at the
+/// time so that it can be recorded later.
+struct Visit {
+    node_id: String,
+    /// The variant of the node's narration the session was shown, if the node has variants.
+    variant: Option<String>,
+    requirements: Vec<(String, bool)>,
+    /// The node's achievement, if the session had never been there before.
+    achievement: Option<String>,
+}
+
+impl Visit {
+    fn of(story: &Engine, session: &Session) -> Self {
+        let node_id = session.current_node_id();
+        Visit {
+            node_id: node_id.to_string(),
+            variant: story.shown_variant(node_id, session).map(str::to_string),
+            requirements: story
+                .evaluate_requirements(session)
+                .into_iter()
+                .map(|(choice_id, met)| (choice_id.to_string(), met))
+                .collect(),
+            achievement: webhooks::achievement(story, node_id)
+                .filter(|_| webhooks::first_visit(story, session))
+                .map(str::to_string),
+        }
+    }
+}
+
+/// A choice a session took at `from_node_id`, worked out from the session right after it was
+/// taken so that it can be recorded later.
+struct TakenChoice {
+    from_node_id: String,
+    /// The variant of `from_node_id` the session was shown, if the node has variants.
+    variant: Option<String>,
+    visit: Visit,
+    game_over: bool,
+}
+
+impl TakenChoice {
+    fn of(story: &Engine, from_node_id: String, session: &Session) -> Self {
+        TakenChoice {
+            variant: story
+                .shown_variant(&from_node_id, session)
+                .map(str::to_string),
+            from_node_id,
+            visit: Visit::of(story, session),
+            game_over: story.is_game_over(session),
+        }
+    }
+}
+
+/// State for the routes that aren't tied to a particular story.
+struct ServerState {
+    stories: Vec<AppState>,
+    sessions: Arc<dyn SessionStore>,
+    session_timeout_hours: f32,
+    max_sessions: Option<usize>,
+    metrics: Arc<Metrics>,
+    leaderboard: Option<Arc<Leaderboard>>,
+    /// `--session-rate-limit` and `--hourly-session-limit`, shared by every story.
+    session_limits: Vec<RateLimit>,
+}
+
+impl ServerState {
+    /// Count a session created over a transport that doesn't go through the session routes'
+    /// layers, e.g. JSON-RPC, towards the same limits.
+    fn check_session_limits(&self, client: &ClientAddr) -> Result<(), ApiError> {
+        self.session_limits
+            .iter()
+            .try_for_each(|limit| limit.check(client))
+    }
+
+    /// The story with the given id, for the endpoints that aren't under `/stories/{story_id}`.
+    /// The id can be left out when only one story is served.
+    fn find_story(&self, story_id: Option<&str>) -> Result<AppState, ApiError> {
+        match story_id {
+            Some(story_id) => self
+                .stories
+                .iter()
+                .find(|story| story.story_id == story_id)
+                .cloned()
+                .ok_or_else(|| {
+                    api_error(
+                        StatusCode::NOT_FOUND,
+                        format!("there is no story with id '{story_id}'"),
+                    )
+                }),
+            None => match self.stories.as_slice() {
+                [story] => Ok(Arc::clone(story)),
+                _ => Err(api_error(
+                    StatusCode::BAD_REQUEST,
+                    "a story id is needed when more than one story is served",
+                )),
+            },
+        }
+    }
+}
+
+/// The seed shared by every session started on the same UTC day as `now`. It is the same on
+/// every server, and under 2^53 so JavaScript clients can read it exactly.
+fn daily_seed(now: SystemTime) -> u64 {
+    let day = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / (24 * 60 * 60);
+    walk::Rng::new(day).next_u64() >> 11
+}
+
 fn get_available_port() -> u16 {
     std::net::TcpListener::bind("127.0.0.1:0")
         .expect("Failed to find an available port")
@@ -33,145 +460,2947 @@ fn get_available_port() -> u16 {
         .port()
 }
 
-fn write_port_to_file(port: u16) {
+/// Parses a number of seconds for `--vote-window-secs`, which has to be more than zero.
+fn parse_vote_window(secs: &str) -> Result<Duration, String> {
+    let invalid = || format!("'{secs}' isn't a positive number of seconds");
+    let secs: f32 = secs.parse().map_err(|_| invalid())?;
+    match Duration::try_from_secs_f32(secs) {
+        Ok(window) if !window.is_zero() => Ok(window),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses an IP address for `--host`, also accepting IPv6 addresses in brackets, e.g. `[::]`.
+fn parse_host(host: &str) -> Result<IpAddr, String> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    unbracketed
+        .parse()
+        .map_err(|_| format!("'{host}' isn't an IP address"))
+}
+
+/// Let whoever started the server know which port it is listening on, through `--port-file`
+/// and `--print-port`.
+fn report_port(args: &ServeArgs, port: u16) -> Result<(), String> {
     let data = json!({ "port": port }).to_string();
-    fs::write("port.json", data).expect("Failed to write port to file");
+    if args.print_port {
+        println!("{data}");
+    }
+    if !args.no_port_file {
+        fs::write(&args.port_file, data).map_err(|e| {
+            format!(
+                "Failed to write port to '{}': {e}",
+                args.port_file.display()
+            )
+        })?;
+    }
+
+    Ok(())
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-struct Args {
-    #[arg(short, long)]
-    source: String,
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    // Without a subcommand, the stories are served over HTTP.
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Play a story interactively in the terminal.
+    Play(play::PlayArgs),
+    /// Check a story for errors without running it.
+    Validate(validate::ValidateArgs),
+    /// Print the graph of a story's nodes and choices.
+    Graph(graph::GraphArgs),
+    /// Print a summary of the size and shape of a story.
+    Stats(stats::StatsArgs),
+    /// Play a story many times with random choices, looking for runtime problems.
+    Walk(walk::WalkArgs),
+    /// Search every way a story can be played for a way to reach a node, e.g. to check that
+    /// an ending can't be reached without an item.
+    Explore(explore::ExploreArgs),
+    /// Run test files that play through a story and check what happens.
+    Test(story_tests::TestArgs),
+    /// Save a playthrough with the text shown at each step, to check later with `verify`.
+    Record(golden::RecordArgs),
+    /// Replay transcripts saved by `record`, failing if a story no longer shows the same text.
+    Verify(golden::VerifyArgs),
+    /// Rewrite stories in the canonical format.
+    Fmt(fmt::FmtArgs),
+    /// Step through a story while inspecting and changing its state.
+    Debug(debug::DebugArgs),
+    /// Create a starter story to build on.
+    New(new::NewArgs),
+    /// Convert a story written for another tool into a `.cyoa` story.
+    Convert(convert::ConvertArgs),
+    /// Write a story out in another format.
+    Export(export::ExportArgs),
+    /// Check a story and save it in a compiled form that loads quickly.
+    Compile(compile::CompileArgs),
+    /// Run a language server for editors, which checks stories as they are edited.
+    Lsp(lsp::LspArgs),
+    /// Print a story's source split into spans classified for syntax highlighting.
+    Tokens(tokens::TokensArgs),
+    /// Play a story on a running server with many simulated players and report how quickly it
+    /// answered.
+    BenchServer(bench_server::BenchServerArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// A TOML file of options, e.g. `port = 8080` or `source = ["cave.cyoa", "forest.cyoa"]`.
+    /// Options given on the command line or as `CYOA_*` environment variables take precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// A story file or a directory of `.cyoa` files. Can be given more than once. Files ending
+    /// in `.json` are read as JSON stories and files ending in `.cyoab` as compiled stories.
+    #[arg(short, long, required = true)]
+    source: Vec<String>,
+    /// The address to listen on, e.g. `0.0.0.0` for every IPv4 interface or `[::]` for every
+    /// IPv6 one
+    #[arg(long, default_value = "127.0.0.1", value_parser = parse_host)]
+    host: IpAddr,
     #[arg(short, long, default_value_t = get_available_port())]
     port: u16,
+    /// Where to write the port the server is listening on, as `{"port": 8080}`
+    #[arg(long, default_value = "port.json")]
+    port_file: PathBuf,
+    /// Don't write the port to a file
+    #[arg(long, conflicts_with = "port_file")]
+    no_port_file: bool,
+    /// Print the port the server is listening on to stdout as a line of JSON, `{"port":8080}`
+    #[arg(long)]
+    print_port: bool,
+    /// Listen on a Unix domain socket at this path instead of a TCP port. No `port.json` is
+    /// written.
+    #[cfg(unix)]
+    #[arg(
+        long,
+        conflicts_with_all = ["host", "port", "port_file", "print_port", "tls_cert", "rate_limit", "session_rate_limit", "hourly_session_limit"],
+    )]
+    unix_socket: Option<PathBuf>,
     #[arg(long, default_value_t = String::new())]
     prefix: String,
     #[arg(long, default_value_t = 24.0)]
     session_timeout_hours: f32,
+    /// Persist sessions to a database, e.g. `sqlite://sessions.db` or `redis://127.0.0.1:6379`
+    #[arg(long)]
+    store: Option<String>,
+    /// Save sessions to this file when the server is stopped with Ctrl+C or `SIGTERM`, and
+    /// read them back when it starts. An alternative to `--store` for routine restarts.
+    #[arg(long, conflicts_with = "store")]
+    session_file: Option<PathBuf>,
+    /// How many choices a player can undo with `/back`
+    #[arg(long, default_value_t = 10)]
+    history_depth: usize,
+    /// How many checkpoints each session can hold
+    #[arg(long, default_value_t = 5)]
+    max_checkpoints: usize,
+    /// How many events each session's history keeps, dropping the oldest beyond that. Zero
+    /// turns the history off.
+    #[arg(long, default_value_t = 1000)]
+    max_transcript: usize,
+    /// A WebAssembly plugin providing functions stories call. Can be given more than once.
+    #[cfg(feature = "plugins")]
+    #[arg(long)]
+    plugin: Vec<PathBuf>,
+    /// Reload stories automatically when their source files change
+    #[arg(long)]
+    watch: bool,
+    /// What to do with existing sessions when a story is reloaded
+    #[arg(long, value_enum, default_value_t = ReloadPolicy::Migrate)]
+    reload_policy: ReloadPolicy,
+    /// How many seconds the players sharing a session have to vote once the first vote is
+    /// cast. Without it, a vote closes when everyone has voted or the host ends it.
+    #[arg(long, value_parser = parse_vote_window)]
+    vote_window_secs: Option<Duration>,
+    /// Require a session's token to read it, not just to change it
+    #[arg(long)]
+    private_sessions: bool,
+    /// Let clients see inside sessions, to help test stories and clients. Not for public
+    /// servers, since it gives away what players haven't been told.
+    #[arg(long)]
+    debug: bool,
+    /// Keep each browser's session in a cookie set by `POST /session`, and serve endpoints
+    /// such as `/session/current` that find the session from it
+    #[arg(long)]
+    cookie_sessions: bool,
+    /// Mark the session cookie as `Secure`, for servers behind a proxy that serves HTTPS. It
+    /// always is with `--tls-cert`.
+    #[arg(long, requires = "cookie_sessions")]
+    secure_cookies: bool,
+    /// Serve sessions under `/stateless` that the server doesn't store, with their state
+    /// signed by this secret and kept by the client. At least 32 characters.
+    #[arg(long)]
+    stateless_secret: Option<String>,
+    /// Give every session started on the same UTC day the same seed, so players can compare
+    /// runs of the day's story
+    #[arg(long)]
+    daily_seed: bool,
+    /// Require `Authorization: Bearer <key>` with this key. Can be given more than once.
+    #[arg(long)]
+    api_key: Vec<String>,
+    /// A file of API keys to accept, one per line. Blank lines and lines starting with `#`
+    /// are ignored.
+    #[arg(long)]
+    api_keys_file: Option<PathBuf>,
+    /// Which endpoints need an API key, if any keys are given
+    #[arg(long, value_enum, default_value_t = ApiKeyScope::Admin)]
+    api_key_scope: ApiKeyScope,
+    /// How many requests a second each client IP address can make
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+    /// How many requests a client can make at once before `--rate-limit` slows it down.
+    /// Defaults to the rate limit.
+    #[arg(long, requires = "rate_limit", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_burst: Option<u32>,
+    /// How many sessions a minute each client IP address can create
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    session_rate_limit: Option<u32>,
+    /// How many sessions an hour each client IP address can create
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    hourly_session_limit: Option<u32>,
+    /// Tell clients apart by the last address in `X-Forwarded-For` rather than the address
+    /// they connect from, for servers behind a reverse proxy
+    #[arg(long)]
+    trust_proxy: bool,
+    /// How many sessions there can be at once, across every story. Once there are this many,
+    /// the least recently active session is evicted to make room for a new one.
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_sessions: Option<usize>,
+    /// Turn new sessions away with a `503` once there are `--max-sessions`, instead of evicting
+    /// old ones
+    #[arg(long, requires = "max_sessions")]
+    reject_when_full: bool,
+    /// How long a request can take before it is given up on with a `503`. Should be longer
+    /// than the 60 seconds a `wait=true` request can wait for a session to change.
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u64).range(1..))]
+    request_timeout_secs: u64,
+    /// How many requests can be handled at once. Requests beyond that are turned away with a
+    /// `503`.
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    max_concurrent_requests: Option<usize>,
+    /// How long a request's path and query string can be, in bytes
+    #[arg(long, default_value_t = 2048)]
+    max_uri_length: usize,
+    /// How large a request's body can be, in bytes
+    #[arg(long, default_value_t = 2 * 1024 * 1024)]
+    max_body_bytes: usize,
+    /// A URL to POST a JSON payload to whenever a session is created or imported
+    #[arg(long)]
+    on_session_created: Option<Url>,
+    /// A URL to POST a JSON payload to whenever a session reaches an ending
+    #[arg(long)]
+    on_game_over: Option<Url>,
+    /// A URL to POST a JSON payload to the first time a session reaches a node the story names
+    /// with `META achievement_<node_id> "..."`
+    #[arg(long)]
+    on_achievement: Option<Url>,
+    /// Keep a leaderboard of the endings sessions reach in this file, served at `/leaderboard`
+    #[arg(long)]
+    leaderboard: Option<PathBuf>,
+    /// Let browser pages from this origin, e.g. `https://example.com`, call the API. Can be
+    /// given more than once, or as `*` to allow any origin.
+    #[arg(long)]
+    cors_origin: Vec<String>,
+    /// Don't compress responses, e.g. because a reverse proxy in front already does
+    #[arg(long)]
+    no_compression: bool,
+    /// A PEM certificate chain to serve HTTPS with, instead of plain HTTP
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM private key for `--tls-cert`
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Which logs to write, e.g. `debug` or `cyoa=debug,tower_http=info`. Defaults to
+    /// `RUST_LOG`, or `info` if that isn't set.
+    #[arg(long)]
+    log_level: Option<String>,
+    /// How to write logs
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Export request spans to the OpenTelemetry collector at this URL, e.g.
+    /// `http://localhost:4318/v1/traces`
+    #[cfg(feature = "otel")]
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    /// Also serve the API over gRPC on this port, as described by `proto/cyoa.proto`
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc_port: Option<u16>,
+    /// Serve a page for playing the stories in a browser at `{prefix}/play`
+    #[arg(long)]
+    serve_ui: bool,
+    /// Serve Swagger UI for browsing the API at `{prefix}/docs`
+    #[arg(long)]
+    swagger_ui: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 struct CreateSessionResponse {
     session_id: String,
+    /// Needed to change the session, in the `X-Session-Token` header or the `token` query
+    /// parameter.
+    session_token: String,
 }
 
-async fn create_session(State(state): State<AppState>) -> Json<CreateSessionResponse> {
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session",
+    tag = "sessions",
+    params(("story_id" = String, Path, description = "The story's id"),),
+    responses(
+        (status = 200, description = "A new session at the start of the story. With `--cookie-sessions`, it is also kept in a cookie", body = CreateSessionResponse),
+        (status = 429, description = "With `--session-rate-limit`, this client has created too many sessions", body = ErrorResponse),
+        (status = 503, description = "With `--max-sessions` and `--reject-when-full`, there are too many sessions", body = ErrorResponse),
+    ),
+)]
+async fn create_session(
+    State(state): State<AppState>,
+) -> Result<(HeaderMap, Json<CreateSessionResponse>), ApiError> {
+    state.make_room_for_session().await?;
     let session_id = Uuid::new_v4().to_string();
-    let session = state.story.new_session();
+    let session_token = Uuid::new_v4().to_string();
+    let story = state.story();
+    let mut session = state.new_session(&story);
+    session.set_secret(&session_token);
+    state.metrics.session_created();
+    state.record_visit(&session_id, &session);
+    state.send_webhook(
+        WebhookEvent::SessionCreated,
+        &session_id,
+        &story,
+        &session,
+        None,
+    );
     state
         .sessions
-        .write()
+        .insert(&state.session_key(&session_id), session)
         .await
-        .insert(session_id.clone(), Arc::new(Mutex::new(session)));
-    println!("Created new session with ID: {session_id}");
+        .map_err(store_error)?;
+    info!(%session_id, "Created new session");
+
+    let mut headers = HeaderMap::new();
+    if state.cookie_sessions {
+        headers.insert(
+            header::SET_COOKIE,
+            cookies::set_cookie(
+                &state.story_id,
+                &session_id,
+                &session_token,
+                state.secure_cookies,
+            ),
+        );
+    }
 
-    Json(CreateSessionResponse { session_id })
+    Ok((
+        headers,
+        Json(CreateSessionResponse {
+            session_id,
+            session_token,
+        }),
+    ))
 }
 
-async fn clear_expired_sessions(state: &SharedState) {
-    let mut sessions = state.sessions.write().await;
-    let mut expired_sessions: Vec<String> = Vec::new();
-    for (session_id, session_arc) in sessions.iter() {
-        let session = session_arc.lock().await;
-        if session.is_expired(state.session_timeout_hours) {
-            println!("Session {session_id} has expired and will be removed.");
-            expired_sessions.push(session_id.clone());
-        }
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/import",
+    tag = "sessions",
+    params(("story_id" = String, Path, description = "The story's id"),),
+    request_body = SessionSnapshot,
+    responses(
+        (status = 200, description = "A new session restored from the snapshot", body = CreateSessionResponse),
+        (status = 400, description = "The snapshot doesn't fit the story", body = ErrorResponse),
+        (status = 429, description = "With `--session-rate-limit`, this client has created too many sessions", body = ErrorResponse),
+        (status = 503, description = "With `--max-sessions` and `--reject-when-full`, there are too many sessions", body = ErrorResponse),
+    ),
+)]
+async fn import_session(
+    State(state): State<AppState>,
+    Json(snapshot): Json<SessionSnapshot>,
+) -> Result<Json<CreateSessionResponse>, ApiError> {
+    let story = state.story();
+    let mut session = story
+        .restore_session(snapshot)
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    state.make_room_for_session().await?;
+    let session_id = Uuid::new_v4().to_string();
+    let session_token = Uuid::new_v4().to_string();
+    session.set_secret(&session_token);
+    state.metrics.session_created();
+    state.send_webhook(
+        WebhookEvent::SessionCreated,
+        &session_id,
+        &story,
+        &session,
+        None,
+    );
+    state
+        .sessions
+        .insert(&state.session_key(&session_id), session)
+        .await
+        .map_err(store_error)?;
+    info!(%session_id, "Imported session");
+
+    Ok(Json(CreateSessionResponse {
+        session_id,
+        session_token,
+    }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/stories/{story_id}/session/{session_id}",
+    tag = "sessions",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+    ),
+    responses(
+        (status = 204, description = "The session was deleted"),
+        (status = 401, description = "The session's token is missing", body = ErrorResponse),
+        (status = 403, description = "The session's token is wrong", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn delete_session(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+) -> Result<StatusCode, ApiError> {
+    // Held until the session is gone, so a choice being taken can't write it back afterwards.
+    let _session = get_own_session(&state, &session_id, &token).await?;
+    if state
+        .sessions
+        .remove(&state.session_key(&session_id))
+        .await
+        .map_err(store_error)?
+    {
+        state.events.notify(&session_id);
+        info!(%session_id, "Deleted session");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(session_not_found())
     }
+}
 
-    for session_id in expired_sessions {
-        sessions.remove(&session_id);
+#[utoipa::path(
+    post,
+    path = "/clear_expired_sessions",
+    tag = "sessions",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Expired sessions were removed"),
+        (status = 401, description = "With `--api-key`, the API key is missing or wrong", body = ErrorResponse),
+        (status = 503, description = "The session store is unavailable", body = ErrorResponse),
+    ),
+)]
+async fn clear_expired_sessions(
+    State(state): State<Arc<ServerState>>,
+) -> Result<StatusCode, ApiError> {
+    let expired_sessions = state
+        .sessions
+        .sweep_expired(state.session_timeout_hours)
+        .await
+        .map_err(store_error)?;
+    state.metrics.sessions_expired(expired_sessions.len());
+    for session_key in expired_sessions {
+        info!(session = %session_key, "Removed expired session");
     }
+
+    Ok(StatusCode::OK)
 }
 
-type ApiError = (StatusCode, Json<serde_json::Value>);
+#[derive(Serialize, ToSchema)]
+struct ServerStats {
+    /// How many sessions there are, across every story
+    sessions: usize,
+    /// The most sessions there can be, if there is a limit
+    max_sessions: Option<usize>,
+}
 
-fn session_not_found() -> ApiError {
+#[utoipa::path(
+    get,
+    path = "/stats",
+    tag = "sessions",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, body = ServerStats),
+        (status = 401, description = "With `--api-key`, the API key is missing or wrong", body = ErrorResponse),
+        (status = 503, description = "The session store is unavailable", body = ErrorResponse),
+    ),
+)]
+async fn get_stats(State(state): State<Arc<ServerState>>) -> Result<Json<ServerStats>, ApiError> {
+    Ok(Json(ServerStats {
+        sessions: state.sessions.count().await.map_err(store_error)?,
+        max_sessions: state.max_sessions,
+    }))
+}
+
+/// The most sessions `/admin/sessions` returns at once.
+const MAX_SESSIONS_PAGE: usize = 500;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SessionPageQuery {
+    /// How many sessions to skip
+    #[serde(default)]
+    offset: usize,
+    /// How many sessions to return, at most 500
+    #[serde(default = "default_sessions_page")]
+    limit: usize,
+}
+
+fn default_sessions_page() -> usize {
+    50
+}
+
+#[derive(Serialize, ToSchema)]
+struct SessionListing {
+    story_id: String,
+    session_id: String,
+    /// When the session was started, in milliseconds since the Unix epoch, if known
+    created_at: Option<u64>,
+    /// When the session was last played, in milliseconds since the Unix epoch
+    last_active_at: u64,
+    current_node_id: String,
+    /// How many choices have been taken, including ones later undone
+    turns: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SessionPage {
+    /// How many sessions there are, across every story
+    total: usize,
+    offset: usize,
+    limit: usize,
+    sessions: Vec<SessionListing>,
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/sessions",
+    tag = "sessions",
+    security(("api_key" = [])),
+    params(SessionPageQuery),
+    responses(
+        (status = 200, body = SessionPage),
+        (status = 401, description = "With `--api-key`, the API key is missing or wrong", body = ErrorResponse),
+        (status = 503, description = "The session store is unavailable", body = ErrorResponse),
+    ),
+)]
+async fn list_sessions(
+    State(state): State<Arc<ServerState>>,
+    Query(query): Query<SessionPageQuery>,
+) -> Result<Json<SessionPage>, ApiError> {
+    let limit = query.limit.min(MAX_SESSIONS_PAGE);
+    let sessions = state
+        .sessions
+        .list(query.offset, limit)
+        .await
+        .map_err(store_error)?
+        .into_iter()
+        .map(|(session_key, session)| {
+            let (story_id, session_id) = session_key.split_once('/').unwrap_or(("", &session_key));
+            SessionListing {
+                story_id: story_id.to_string(),
+                session_id: session_id.to_string(),
+                created_at: session.created_at().map(unix_millis),
+                last_active_at: unix_millis(session.last_active_at()),
+                current_node_id: session.current_node_id().to_string(),
+                turns: session.turns(),
+            }
+        })
+        .collect();
+
+    Ok(Json(SessionPage {
+        total: state.sessions.count().await.map_err(store_error)?,
+        offset: query.offset,
+        limit,
+        sessions,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/stories/{story_id}/analysis",
+    tag = "stories",
+    security(("api_key" = [])),
+    params(("story_id" = String, Path, description = "The story's id")),
+    responses(
+        (status = 200, description = "Which nodes can be reached, how many choices they are from `START`, which can lead to an ending, and where the story loops", body = Analysis),
+        (status = 401, description = "With `--api-key`, the API key is missing or wrong", body = ErrorResponse),
+        (status = 404, description = "No such story", body = ErrorResponse),
+    ),
+)]
+async fn get_story_analysis(
+    State(state): State<Arc<ServerState>>,
+    Path(story_id): Path<String>,
+) -> Result<Json<Analysis>, ApiError> {
+    let story = state
+        .stories
+        .iter()
+        .find(|story| story.story_id == story_id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "story not found"))?
+        .story();
+    Ok(Json(story.analysis()))
+}
+
+#[derive(Serialize, ToSchema)]
+struct StoryListing {
+    id: String,
+    /// With `--daily-seed`, the seed shared by every session started today
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daily_seed: Option<u64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/stories",
+    tag = "stories",
+    responses((status = 200, body = Vec<StoryListing>)),
+)]
+async fn list_stories(State(state): State<Arc<ServerState>>) -> Json<Vec<StoryListing>> {
+    let stories = state
+        .stories
+        .iter()
+        .map(|story| StoryListing {
+            id: story.story_id.clone(),
+            daily_seed: story.daily_seed.then(|| daily_seed(SystemTime::now())),
+        })
+        .collect();
+
+    Json(stories)
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReloadResult {
+    id: String,
+    reloaded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/reload",
+    tag = "stories",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Every story was reloaded or unchanged", body = Vec<ReloadResult>),
+        (status = 400, description = "At least one story has errors and wasn't reloaded", body = Vec<ReloadResult>),
+        (status = 401, description = "With `--api-key`, the API key is missing or wrong", body = ErrorResponse),
+    ),
+)]
+async fn reload_stories(
+    State(state): State<Arc<ServerState>>,
+) -> (StatusCode, Json<Vec<ReloadResult>>) {
+    let mut status = StatusCode::OK;
+    let results = state
+        .stories
+        .iter()
+        .map(|story| {
+            let (reloaded, error) = match story.reload() {
+                Ok(reloaded) => (reloaded, None),
+                Err(e) => {
+                    error!("{e}");
+                    status = StatusCode::BAD_REQUEST;
+                    (false, Some(e))
+                }
+            };
+            ReloadResult {
+                id: story.story_id.clone(),
+                reloaded,
+                error,
+            }
+        })
+        .collect();
+
+    (status, Json(results))
+}
+
+fn modified_time(path: &FilePath) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Poll the stories' source files, reloading any that change.
+async fn watch_stories(stories: Vec<AppState>) {
+    let mut last_modified: Vec<_> = stories
+        .iter()
+        .map(|story| modified_time(&story.source_path))
+        .collect();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        for (story, last_modified) in stories.iter().zip(last_modified.iter_mut()) {
+            let modified = modified_time(&story.source_path);
+            if modified != *last_modified {
+                *last_modified = modified;
+                if let Err(e) = story.reload() {
+                    error!("{e}");
+                }
+            }
+        }
+    }
+}
+
+/// The body of every error response.
+#[derive(Serialize, ToSchema)]
+struct ErrorResponse {
+    error: String,
+    /// Identifies the request in the server's logs, the same as the `X-Request-Id` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+type ApiError = (StatusCode, Json<ErrorResponse>);
+
+fn api_error(status: StatusCode, error: impl ToString) -> ApiError {
     (
-        StatusCode::NOT_FOUND,
-        Json(json!({ "error": "session not found" })),
+        status,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            request_id: request_id::current(),
+        }),
     )
 }
 
-async fn get_session_arc(state: &SharedState, session_id: &str) -> Option<Arc<Mutex<Session>>> {
-    state.sessions.read().await.get(session_id).map(Arc::clone)
+fn session_not_found() -> ApiError {
+    api_error(StatusCode::NOT_FOUND, "session not found")
 }
 
-async fn get_current(
-    State(state): State<AppState>,
-    Path(session_id): Path<String>,
-) -> Result<Json<CurrentNodeView>, ApiError> {
-    let session_arc = get_session_arc(&state, &session_id)
-        .await
-        .ok_or_else(session_not_found)?;
-    let mut session = session_arc.lock().await;
-    session.update_last_active_at();
-    Ok(Json(state.story.get_current_node_view(&session)))
+fn session_expired() -> ApiError {
+    api_error(StatusCode::GONE, "session expired")
 }
 
-async fn choose_option(
-    State(state): State<AppState>,
-    Path((session_id, option)): Path<(String, String)>,
-) -> Result<(StatusCode, Json<ChoiceResult>), ApiError> {
-    let session_arc = get_session_arc(&state, &session_id)
-        .await
-        .ok_or_else(session_not_found)?;
-    let mut session = session_arc.lock().await;
-    let result = state.story.choose_option(&mut session, option);
+/// The response for a session store that couldn't do what it was asked.
+fn store_error(e: StoreError) -> ApiError {
+    match e {
+        StoreError::Conflict => api_error(
+            StatusCode::CONFLICT,
+            "the session was changed by another request, try again",
+        ),
+        StoreError::Unavailable(_) => {
+            error!("{e}");
+            api_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "the session store is unavailable, try again later",
+            )
+        }
+    }
+}
 
-    let status = match &result {
-        ChoiceResult::Success => StatusCode::OK,
-        ChoiceResult::InvalidOption { .. } => StatusCode::BAD_REQUEST,
-    };
+/// The secret token a player presents to prove a session is theirs, taken from the
+/// `X-Session-Token` header or, for clients that can't set headers, the `token` query parameter.
+struct SessionToken(Option<String>);
 
-    Ok((status, Json(result)))
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
-    write_port_to_file(args.port);
-    let source = fs::read_to_string(args.source).expect("Failed to read source file");
-    let source: &'static str = Box::leak(source.into_boxed_str());
-    let story = match Engine::from_program(source) {
-        Ok(engine) => engine,
-        Err(e) => {
-            eprintln!("Failed to build engine due to the following errors:\n");
-            for (i, error) in e.iter().enumerate() {
-                eprintln!("{}. {error}", i + 1);
+impl<S: Send + Sync> FromRequestParts<S> for SessionToken {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get("x-session-token")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let query = || {
+            Query::<TokenQuery>::try_from_uri(&parts.uri)
+                .ok()
+                .and_then(|Query(query)| query.token)
+        };
+
+        Ok(SessionToken(header.or_else(query)))
+    }
+}
+
+fn check_session_token(session: &Session, token: &SessionToken) -> Result<(), ApiError> {
+    match &token.0 {
+        _ if session.check_secret(token.0.as_deref()) => Ok(()),
+        None => Err(api_error(
+            StatusCode::UNAUTHORIZED,
+            "this session needs its session token",
+        )),
+        Some(_) => Err(api_error(StatusCode::FORBIDDEN, "wrong session token")),
+    }
+}
+
+/// Idempotency keys longer than this are turned away.
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// A key the client makes up for a choice, taken from the `Idempotency-Key` header, so that
+/// if the request is sent again, e.g. after a network error, the choice isn't taken twice.
+struct IdempotencyKey(Option<String>);
+
+impl<S: Send + Sync> FromRequestParts<S> for IdempotencyKey {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get("idempotency-key") else {
+            return Ok(IdempotencyKey(None));
+        };
+        match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => {
+                Ok(IdempotencyKey(Some(key.to_string())))
             }
-            return;
+            _ => Err(api_error(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Idempotency-Key should be between 1 and {MAX_IDEMPOTENCY_KEY_LENGTH} visible characters"
+                ),
+            )),
+        }
+    }
+}
+
+/// The session version a player expects to be changing, taken from the `If-Match` header, e.g.
+/// `If-Match: "3"`, or the `expected_version` query parameter. Without either, any version will
+/// do.
+struct ExpectedVersion(Option<u64>);
+
+#[derive(Deserialize)]
+struct ExpectedVersionQuery {
+    expected_version: Option<u64>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ExpectedVersion {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(value) = parts.headers.get(header::IF_MATCH) {
+            let version = value
+                .to_str()
+                .ok()
+                .map(|value| value.trim().trim_start_matches("W/").trim_matches('"'))
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| {
+                    api_error(
+                        StatusCode::BAD_REQUEST,
+                        "If-Match should be a session version, e.g. \"3\"",
+                    )
+                })?;
+            return Ok(ExpectedVersion(Some(version)));
+        }
+        let Query(query) = Query::<ExpectedVersionQuery>::try_from_uri(&parts.uri)
+            .map_err(|e| api_error(StatusCode::BAD_REQUEST, e.body_text()))?;
+
+        Ok(ExpectedVersion(query.expected_version))
+    }
+}
+
+fn check_session_version(session: &Session, expected: &ExpectedVersion) -> Result<(), ApiError> {
+    match expected.0 {
+        Some(version) if version != session.version() => Err(api_error(
+            StatusCode::CONFLICT,
+            format!(
+                "the session has changed since version {version}, it is now at version {}",
+                session.version()
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reject requests that don't carry one of the server's API keys as
+/// `Authorization: Bearer <key>`.
+async fn require_api_key(
+    State(keys): State<Arc<Vec<String>>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let key = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match key {
+        Some(key) if keys.iter().any(|expected| secrets_match(expected, key)) => {
+            Ok(next.run(request).await)
         }
+        _ => Err(api_error(
+            StatusCode::UNAUTHORIZED,
+            "missing or wrong API key",
+        )),
+    }
+}
+
+/// Compare every byte, so how long the check takes doesn't give the secret away.
+fn secrets_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The keys given with `--api-key` and `--api-keys-file`.
+fn read_api_keys(args: &ServeArgs) -> Result<Vec<String>, String> {
+    let mut keys = args.api_key.clone();
+    if let Some(path) = &args.api_keys_file {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read API keys file '{}': {e}", path.display()))?;
+        keys.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+
+    Ok(keys)
+}
+
+/// How many locks the sessions of a story share. See [`SessionLocks`].
+const SESSION_LOCKS: usize = 1024;
+
+/// Locks that let only one request at a time change a session, so a request never writes back
+/// a copy of a session that another request changed after it was read. Sessions share a fixed
+/// number of locks, picked by hashing their key, so there is nothing to clean up when sessions
+/// go away. The locks are only held within this instance of the server.
+struct SessionLocks(Vec<Arc<Mutex<()>>>);
+
+impl Default for SessionLocks {
+    fn default() -> Self {
+        SessionLocks((0..SESSION_LOCKS).map(|_| Arc::default()).collect())
+    }
+}
+
+impl SessionLocks {
+    async fn lock(&self, session_key: &str) -> OwnedMutexGuard<()> {
+        let mut hasher = DefaultHasher::new();
+        session_key.hash(&mut hasher);
+        let lock = &self.0[hasher.finish() as usize % self.0.len()];
+        Arc::clone(lock).lock_owned().await
+    }
+}
+
+/// A session read by a request that is going to change it. No other request can read it to
+/// change it until this is dropped, which should be after it has been written back.
+struct LockedSession {
+    session: Session,
+    /// The session's version in the store, which it has to still be at when it is written
+    /// back.
+    read_version: u64,
+    _lock: OwnedMutexGuard<()>,
+}
+
+impl Deref for LockedSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl DerefMut for LockedSession {
+    fn deref_mut(&mut self) -> &mut Session {
+        &mut self.session
+    }
+}
+
+/// Look up a session for a request that changes it, which needs the session's token.
+async fn get_own_session(
+    state: &SharedState,
+    session_id: &str,
+    token: &SessionToken,
+) -> Result<(Arc<Engine>, LockedSession), ApiError> {
+    let (story, session) = get_session(state, session_id).await?;
+    check_session_token(&session, token)?;
+    Ok((story, session))
+}
+
+/// Look up a session for a request that only reads it, which needs the session's token
+/// with `--private-sessions`.
+async fn get_readable_session(
+    state: &SharedState,
+    session_id: &str,
+    token: &SessionToken,
+) -> Result<(Arc<Engine>, Session), ApiError> {
+    let (story, session) = read_session(state, session_id).await?;
+    if state.private_sessions {
+        check_session_token(&session, token)?;
+    }
+    Ok((story, session))
+}
+
+/// Look up a session for a request that changes it, locking it and marking it as active.
+/// The caller is responsible for writing the session back before the lock is dropped.
+async fn get_session(
+    state: &SharedState,
+    session_id: &str,
+) -> Result<(Arc<Engine>, LockedSession), ApiError> {
+    let lock = state
+        .session_locks
+        .lock(&state.session_key(session_id))
+        .await;
+    let (story, session, read_version) = load_session(state, session_id).await?;
+    let mut session = LockedSession {
+        session,
+        read_version,
+        _lock: lock,
     };
+    if session.version() != read_version {
+        // Saved straight away, so a session is only carried over to a new story once.
+        state.save_changed_session(session_id, &mut session).await?;
+    }
+    session.update_last_active_at();
+    Ok((story, session))
+}
 
-    let state: AppState = Arc::new(SharedState {
-        story,
-        sessions: RwLock::new(HashMap::new()),
-        session_timeout_hours: args.session_timeout_hours,
-    });
+/// Look up a session for a request that only reads it. It isn't locked, and is marked as
+/// active in the store without being written back, so it can't undo another request's changes.
+async fn read_session(
+    state: &SharedState,
+    session_id: &str,
+) -> Result<(Arc<Engine>, Session), ApiError> {
+    let (story, session, read_version) = load_session(state, session_id).await?;
+    if session.version() != read_version {
+        // The session has to be saved after being carried over to the new story, which is
+        // only safe while it is locked.
+        let (story, session) = get_session(state, session_id).await?;
+        return Ok((story, session.clone()));
+    }
+    state
+        .sessions
+        .touch(&state.session_key(session_id))
+        .await
+        .map_err(store_error)?;
+    Ok((story, session))
+}
 
-    let prefix = args.prefix.clone();
-    let app = Router::new()
-        .route(
-            format!("{prefix}/clear_expired_sessions").as_str(),
-            post(|State(state): State<AppState>| async move {
-                clear_expired_sessions(&state).await;
-                StatusCode::OK
-            }),
-        )
-        .route(format!("{prefix}/session").as_str(), post(create_session))
-        .route(
-            format!("{prefix}/session/{{session_id}}/current").as_str(),
-            get(get_current),
-        )
-        .route(
-            format!("{prefix}/session/{{session_id}}/choose/{{option}}").as_str(),
-            post(choose_option),
-        )
-        .with_state(state);
+/// Look up a session, along with the engine to play it against. Sessions from before the
+/// story was reloaded are dealt with according to the reload policy. Also returns the
+/// version the session is stored at, which it has moved on from if the reload policy
+/// changed it, so that it needs saving.
+async fn load_session(
+    state: &SharedState,
+    session_id: &str,
+) -> Result<(Arc<Engine>, Session, u64), ApiError> {
+    let story = state.story();
+    let session_key = state.session_key(session_id);
+    let mut session = state
+        .sessions
+        .get(&session_key)
+        .await
+        .map_err(store_error)?
+        .ok_or_else(session_not_found)?;
+    let read_version = session.version();
+    // A session can outlive its timeout until the next sweep, but it can't be played.
+    if session.is_expired(state.session_timeout_hours) {
+        state
+            .sessions
+            .remove(&session_key)
+            .await
+            .map_err(store_error)?;
+        return Err(session_expired());
+    }
 
-    let addr = format!("127.0.0.1:{}", args.port);
-    let listener = TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    if !story.session_is_current(&session) {
+        session = match state.reload_policy {
+            ReloadPolicy::Migrate => match story.migrate_session(session.clone()) {
+                Ok(migrated) => migrated,
+                Err(e) => {
+                    warn!(%session_id, "Session could not be migrated and will restart: {e}");
+                    story.restart_session(&mut session);
+                    session
+                }
+            },
+            ReloadPolicy::Reset => {
+                info!(
+                    %session_id,
+                    "Session is from an older version of its story and will restart"
+                );
+                story.restart_session(&mut session);
+                session
+            }
+            ReloadPolicy::Invalidate => {
+                state
+                    .sessions
+                    .remove(&session_key)
+                    .await
+                    .map_err(store_error)?;
+                info!(
+                    %session_id,
+                    "Session is from an older version of its story and has been removed"
+                );
+                return Err(session_not_found());
+            }
+        };
+    }
+    story.rebase_session(&mut session);
+
+    Ok((story, session, read_version))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct CurrentQuery {
+    /// With `--debug`, also return every variable in the session
+    #[serde(default)]
+    include_vars: bool,
+    /// Hold the request until the session changes, or until `timeout_secs` have passed
+    #[serde(default)]
+    wait: bool,
+    /// With `wait`, the version the client has already seen. The request returns straight away
+    /// if the session is at a different version. Defaults to the session's current version
+    since_version: Option<u64>,
+    /// With `wait`, how many seconds to wait for a change before returning the current node
+    /// anyway. Defaults to 30, and can be at most 60
+    timeout_secs: Option<u64>,
+}
+
+/// How long a long-polling request to `/current` waits by default, and at most.
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/session/{session_id}/current",
+    tag = "play",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+        CurrentQuery,
+    ),
+    responses(
+        (status = 200, description = "JSON by default, or plain text or HTML if the `Accept` header prefers them. With `wait`, once the session has changed or the wait has timed out", content(
+            (CurrentNodeView = "application/json"),
+            (String = "text/plain"),
+            (String = "text/html"),
+        )),
+        (status = 401, description = "With `--private-sessions`, the session's token is missing", body = ErrorResponse),
+        (status = 403, description = "With `--private-sessions`, the session's token is wrong, or `include_vars` was asked for without `--debug`", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn get_current(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Query(query): Query<CurrentQuery>,
+    token: SessionToken,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if query.include_vars && !state.debug {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            "include_vars needs the server to be started with --debug",
+        ));
+    }
+    let (mut story, mut session) = get_readable_session(&state, &session_id, &token).await?;
+    if query.wait {
+        let since_version = query.since_version.unwrap_or(session.version());
+        let timeout = query
+            .timeout_secs
+            .map_or(DEFAULT_WAIT, Duration::from_secs)
+            .min(MAX_WAIT);
+        let deadline = tokio::time::Instant::now() + timeout;
+        // Subscribed only once the session is known to exist, then read again in case it
+        // changed in between.
+        let mut changes = state.events.subscribe(&session_id);
+        (story, session) = get_readable_session(&state, &session_id, &token).await?;
+        while session.version() == since_version {
+            match tokio::time::timeout_at(deadline, changes.recv()).await {
+                // Falling behind only means there were several changes.
+                Ok(Ok(()) | Err(RecvError::Lagged(_))) => {
+                    (story, session) = get_readable_session(&state, &session_id, &token).await?;
+                }
+                Ok(Err(RecvError::Closed)) | Err(_) => break,
+            }
+        }
+    }
+    let mut view = story.get_current_node_view(&session);
+    if query.include_vars {
+        view.variables = Some(
+            session
+                .variables()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+        );
+    }
+    let title = story.metadata("title").unwrap_or(&state.story_id);
+    Ok(render::current_node(&headers, view, title))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/session/{session_id}/export",
+    tag = "sessions",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+    ),
+    responses(
+        (status = 200, body = SessionSnapshot),
+        (status = 401, description = "With `--private-sessions`, the session's token is missing", body = ErrorResponse),
+        (status = 403, description = "With `--private-sessions`, the session's token is wrong", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn export_session(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+) -> Result<Json<SessionSnapshot>, ApiError> {
+    let (story, session) = get_readable_session(&state, &session_id, &token).await?;
+    Ok(Json(story.snapshot_session(&session)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/session/{session_id}/history",
+    tag = "play",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+    ),
+    responses(
+        (status = 200, body = Vec<HistoryEvent>),
+        (status = 401, description = "With `--private-sessions`, the session's token is missing", body = ErrorResponse),
+        (status = 403, description = "With `--private-sessions`, the session's token is wrong", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn get_history(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+) -> Result<Json<Vec<HistoryEvent>>, ApiError> {
+    let (story, session) = get_readable_session(&state, &session_id, &token).await?;
+    Ok(Json(story.history(&session).to_vec()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/choose/{option}",
+    tag = "play",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("option" = String, Path, description = "The id of the choice to take"),
+        ("If-Match" = Option<String>, Header, description = "The session version the choice is meant for, e.g. `\"3\"`"),
+        ("expected_version" = Option<u64>, Query, description = "The same as `If-Match`, for clients that can't set headers"),
+        ("Idempotency-Key" = Option<String>, Header, description = "A key made up by the client. If a choice is sent again with the same key, the first result is returned without taking the choice again"),
+    ),
+    responses(
+        (status = 200, description = "The choice was taken", body = ChoiceResult),
+        (status = 400, description = "The choice isn't at the current node", body = ChoiceResult),
+        (status = 403, description = "The choice's requirement isn't met", body = ChoiceResult),
+        (status = 409, description = "The story has already ended, with a `ChoiceResult`. Otherwise the session is shared, so its choices are voted on, or it has changed since the expected version", body = ErrorResponse),
+        (status = 410, description = "The session has expired", body = ChoiceResult),
+        (status = 422, description = "The idempotency key was already used for a different choice", body = ErrorResponse),
+        (status = 500, description = "The choice's command failed", body = ChoiceResult),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn choose_option(
+    State(state): State<AppState>,
+    Path((session_id, option)): Path<(String, String)>,
+    token: SessionToken,
+    expected_version: ExpectedVersion,
+    idempotency_key: IdempotencyKey,
+) -> Result<(StatusCode, Json<ChoiceResult>), ApiError> {
+    let (story, mut session) = match get_own_session(&state, &session_id, &token).await {
+        Err((StatusCode::GONE, _)) => {
+            let result = ChoiceResult::SessionExpired;
+            return Ok((choice_status(&result), Json(result)));
+        }
+        result => result?,
+    };
+    if session.is_shared() {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            "this session is shared, so its choices are voted on",
+        ));
+    }
+    // A retry is answered before the version is checked, since the first attempt moved it on.
+    if let Some(key) = &idempotency_key.0
+        && let Some((choice, result)) = session.choice_for_key(key)
+    {
+        if choice != option {
+            return Err(api_error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "this Idempotency-Key was already used for a different choice",
+            ));
+        }
+        return Ok((choice_status(result), Json(result.clone())));
+    }
+    check_session_version(&session, &expected_version)?;
+    let from_node_id = session.current_node_id().to_string();
+    let result = story.choose_option(&mut session, option.clone());
+    if let ChoiceResult::Success = result {
+        state.record_choice(&session_id, &from_node_id, &session);
+    }
+    if let Some(key) = idempotency_key.0 {
+        session.remember_choice_key(key, option, result.clone());
+    }
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+
+    Ok((choice_status(&result), Json(result)))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ChooseRequest {
+    /// The id of the choice to take
+    choice_id: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/choose",
+    tag = "play",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("If-Match" = Option<String>, Header, description = "The session version the choice is meant for, e.g. `\"3\"`"),
+        ("expected_version" = Option<u64>, Query, description = "The same as `If-Match`, for clients that can't set headers"),
+        ("Idempotency-Key" = Option<String>, Header, description = "A key made up by the client. If a choice is sent again with the same key, the first result is returned without taking the choice again"),
+    ),
+    request_body = ChooseRequest,
+    responses(
+        (status = 200, description = "The choice was taken", body = ChoiceResult),
+        (status = 400, description = "The choice isn't at the current node", body = ChoiceResult),
+        (status = 403, description = "The choice's requirement isn't met", body = ChoiceResult),
+        (status = 409, description = "The story has already ended, with a `ChoiceResult`. Otherwise the session is shared, so its choices are voted on, or it has changed since the expected version", body = ErrorResponse),
+        (status = 410, description = "The session has expired", body = ChoiceResult),
+        (status = 422, description = "The idempotency key was already used for a different choice", body = ErrorResponse),
+        (status = 500, description = "The choice's command failed", body = ChoiceResult),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn choose_option_from_body(
+    state: State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+    expected_version: ExpectedVersion,
+    idempotency_key: IdempotencyKey,
+    Json(request): Json<ChooseRequest>,
+) -> Result<(StatusCode, Json<ChoiceResult>), ApiError> {
+    choose_option(
+        state,
+        Path((session_id, request.choice_id)),
+        token,
+        expected_version,
+        idempotency_key,
+    )
+    .await
+}
+
+/// The status code a choice's result is sent with, so clients can tell the reasons a choice
+/// wasn't taken apart without reading the body.
+fn choice_status(result: &ChoiceResult) -> StatusCode {
+    match result {
+        ChoiceResult::Success => StatusCode::OK,
+        ChoiceResult::InvalidOption { .. } => StatusCode::BAD_REQUEST,
+        ChoiceResult::RequirementNotMet { .. } => StatusCode::FORBIDDEN,
+        ChoiceResult::GameAlreadyOver { .. } => StatusCode::CONFLICT,
+        ChoiceResult::SessionExpired => StatusCode::GONE,
+        ChoiceResult::RuntimeError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ReplayRequest {
+    /// The ids of the choices to take, in order
+    choices: Vec<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/replay",
+    tag = "play",
+    params(("story_id" = String, Path, description = "The story's id"), ("session_id" = String, Path)),
+    request_body = ReplayRequest,
+    responses(
+        (status = 200, description = "Every choice was taken", body = CurrentNodeView),
+        (status = 400, description = "One of the choices can't be taken, so none were taken. The status is the one `/choose` would have returned for it, so it can also be 403, 409 or 500", body = ErrorResponse),
+        (status = 409, description = "The session is shared, so its choices are voted on", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn replay_choices(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+    Json(request): Json<ReplayRequest>,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    let (story, mut session) = get_own_session(&state, &session_id, &token).await?;
+    if session.is_shared() {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            "this session is shared, so its choices are voted on",
+        ));
+    }
+
+    // Take every choice on a copy, so a bad one leaves the session as it was. Nothing is
+    // recorded until they have all been taken.
+    let mut replayed = session.clone();
+    let mut taken = Vec::with_capacity(request.choices.len());
+    for (i, choice) in request.choices.into_iter().enumerate() {
+        let from_node_id = replayed.current_node_id().to_string();
+        let result = story.choose_option(&mut replayed, choice.clone());
+        if !matches!(result, ChoiceResult::Success) {
+            return Err(api_error(
+                choice_status(&result),
+                format!(
+                    "choice {} ('{choice}') could not be taken, so no choices were taken: {result}",
+                    i + 1
+                ),
+            ));
+        }
+        taken.push(TakenChoice::of(&story, from_node_id, &replayed));
+    }
+
+    *session = replayed;
+    for choice in &taken {
+        state.record_taken_choice(&session_id, choice, &session);
+    }
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+
+    Ok(Json(story.get_current_node_view(&session)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/back",
+    tag = "play",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+    ),
+    responses(
+        (status = 200, body = CurrentNodeView),
+        (status = 400, description = "There is no choice to undo", body = ErrorResponse),
+        (status = 401, description = "The session's token is missing", body = ErrorResponse),
+        (status = 403, description = "The session's token is wrong", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn go_back(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    let (story, mut session) = get_own_session(&state, &session_id, &token).await?;
+    let went_back = story.go_back(&mut session);
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+
+    if went_back {
+        Ok(Json(story.get_current_node_view(&session)))
+    } else {
+        Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "there is no choice to undo",
+        ))
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/restart",
+    tag = "play",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+    ),
+    responses(
+        (status = 200, body = CurrentNodeView),
+        (status = 401, description = "The session's token is missing", body = ErrorResponse),
+        (status = 403, description = "The session's token is wrong", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn restart_session(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    let (story, mut session) = get_own_session(&state, &session_id, &token).await?;
+    story.restart_session(&mut session);
+    state.record_visit(&session_id, &session);
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+    Ok(Json(story.get_current_node_view(&session)))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SaveCheckpointRequest {
+    slot: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SaveCheckpointResponse {
+    slot: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/checkpoint",
+    tag = "checkpoints",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+    ),
+    request_body(content = Option<SaveCheckpointRequest>, description = "Leave out the slot to save to a new one"),
+    responses(
+        (status = 200, body = SaveCheckpointResponse),
+        (status = 400, description = "The session has no room for another checkpoint", body = ErrorResponse),
+        (status = 401, description = "The session's token is missing", body = ErrorResponse),
+        (status = 403, description = "The session's token is wrong", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+async fn save_checkpoint(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+    body: Option<Json<SaveCheckpointRequest>>,
+) -> Result<Json<SaveCheckpointResponse>, ApiError> {
+    let slot = body.and_then(|Json(body)| body.slot);
+    let (story, mut session) = get_own_session(&state, &session_id, &token).await?;
+    let result = story.save_checkpoint(&mut session, slot);
+    state.save_session(&session_id, &mut session).await?;
+
+    match result {
+        Ok(slot) => Ok(Json(SaveCheckpointResponse { slot })),
+        Err(e) => Err(api_error(StatusCode::BAD_REQUEST, e)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/checkpoint/{slot}/load",
+    tag = "checkpoints",
+    params(("story_id" = String, Path, description = "The story's id"), ("session_id" = String, Path), ("slot" = String, Path)),
+    responses(
+        (status = 200, body = CurrentNodeView),
+        (status = 404, description = "No such session or checkpoint", body = ErrorResponse),
+    ),
+)]
+async fn load_checkpoint(
+    State(state): State<AppState>,
+    Path((session_id, slot)): Path<(String, String)>,
+    token: SessionToken,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    let (story, mut session) = get_own_session(&state, &session_id, &token).await?;
+    let result = story.load_checkpoint(&mut session, &slot);
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+
+    match result {
+        Ok(()) => Ok(Json(story.get_current_node_view(&session))),
+        Err(e) => Err(api_error(StatusCode::NOT_FOUND, e)),
+    }
+}
+
+/// Expand the `--source` arguments into story files, reading every `.cyoa` file in a directory.
+fn find_story_files(sources: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for source in sources {
+        let path = PathBuf::from(source);
+        if path.is_dir() {
+            let entries = fs::read_dir(&path)
+                .map_err(|e| format!("Failed to read directory '{source}': {e}"))?;
+            let mut dir_files: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "cyoa"))
+                .collect();
+            dir_files.sort();
+            files.extend(dir_files);
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// The id of the story in a file: its file name without the extension.
+fn story_id(path: &FilePath) -> Result<String, String> {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .ok_or_else(|| format!("'{}' is not a story file", path.display()))
+}
+
+/// Whether a story file is in the JSON story format rather than `.cyoa` source.
+fn is_json_story(path: &FilePath) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// The definitions in a story written in the JSON story format.
+fn parse_json_story(path: &FilePath, source: &str) -> Result<Vec<ProgramPart>, String> {
+    serde_json::from_str(source)
+        .map_err(|e| format!("Failed to read JSON story '{}': {e}", path.display()))
+}
+
+/// Whether a story file was written by `cyoa compile`.
+fn is_compiled_story(path: &FilePath) -> bool {
+    path.extension().is_some_and(|ext| ext == "cyoab")
+}
+
+fn build_story(path: &FilePath, settings: StorySettings) -> Result<Engine, String> {
+    if is_compiled_story(path) {
+        let bytes = fs::read(path)
+            .map_err(|e| format!("Failed to read compiled story '{}': {e}", path.display()))?;
+        let mut engine = Engine::from_compiled(&bytes)
+            .map_err(|e| format!("Failed to load compiled story '{}': {e}", path.display()))?;
+        engine.set_history_depth(settings.history_depth);
+        engine.set_max_checkpoints(settings.max_checkpoints);
+        engine.set_max_transcript(settings.max_transcript);
+        #[cfg(feature = "plugins")]
+        if let Some(plugins) = &settings.plugins {
+            engine
+                .set_plugins(Arc::clone(plugins))
+                .map_err(|errors| describe_errors(path, &errors))?;
+        }
+        #[cfg(feature = "scripting")]
+        load_scripts(path, &mut engine)?;
+        return Ok(engine);
+    }
+
+    let read_error = |e| format!("Failed to read source file '{}': {e}", path.display());
+    let mut engine = if is_json_story(path) {
+        let source = fs::read_to_string(path).map_err(read_error)?;
+        engine_from_parts(&parse_json_story(path, &source)?, &settings)
+            .map_err(|errors| describe_errors(path, &errors))?
+    } else {
+        // Large stories are parsed as they are read, rather than read into memory first.
+        let file = fs::File::open(path).map_err(read_error)?;
+        match engine_from_reader(io::BufReader::new(file), &settings) {
+            Ok(engine) => engine,
+            Err(ReadStoryError::Io(e)) => return Err(read_error(e)),
+            Err(ReadStoryError::Unparsable { line }) => {
+                return Err(format!(
+                    "{}:{line}: The story could not be parsed from here on.",
+                    path.display()
+                ));
+            }
+            Err(ReadStoryError::Invalid(errors)) => return Err(describe_errors(path, &errors)),
+        }
+    };
+    engine.set_history_depth(settings.history_depth);
+    engine.set_max_checkpoints(settings.max_checkpoints);
+    engine.set_max_transcript(settings.max_transcript);
+    #[cfg(feature = "scripting")]
+    load_scripts(path, &mut engine)?;
+    Ok(engine)
+}
+
+#[cfg_attr(not(feature = "plugins"), allow(unused_variables))]
+fn engine_from_reader(
+    reader: impl io::BufRead,
+    settings: &StorySettings,
+) -> Result<Engine, ReadStoryError> {
+    #[cfg(feature = "plugins")]
+    if let Some(plugins) = &settings.plugins {
+        return Engine::from_reader_with_plugins(reader, Arc::clone(plugins));
+    }
+    Engine::from_reader(reader)
+}
+
+#[cfg_attr(not(feature = "plugins"), allow(unused_variables))]
+fn engine_from_parts(
+    parts: &[ProgramPart],
+    settings: &StorySettings,
+) -> Result<Engine, Vec<ParseError>> {
+    #[cfg(feature = "plugins")]
+    if let Some(plugins) = &settings.plugins {
+        return Engine::from_parts_with_plugins(parts, Arc::clone(plugins));
+    }
+    Engine::from_parts(parts)
+}
+
+/// Load the plugins given with `--plugin`, if there are any.
+#[cfg(feature = "plugins")]
+fn load_plugins(paths: &[PathBuf]) -> Result<Option<Arc<cyoa::engine::plugins::Plugins>>, String> {
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    cyoa::engine::plugins::Plugins::load(paths)
+        .map(|plugins| Some(Arc::new(plugins)))
+        .map_err(|e| e.to_string())
+}
+
+/// Load the script functions kept next to a story, e.g. `cave.rhai` for `cave.cyoa`, if there
+/// are any.
+#[cfg(feature = "scripting")]
+fn load_scripts(path: &FilePath, engine: &mut Engine) -> Result<(), String> {
+    let scripts_path = path.with_extension("rhai");
+    let source = match fs::read_to_string(&scripts_path) {
+        Ok(source) => source,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(format!(
+                "Failed to read scripts '{}': {e}",
+                scripts_path.display()
+            ));
+        }
+    };
+    engine.set_scripts(&source).map_err(|e| {
+        format!(
+            "Failed to compile scripts '{}': {e}",
+            scripts_path.display()
+        )
+    })
+}
+
+/// The definitions in a story file, for tools that look at its structure rather than play it.
+/// Fails if the story has errors.
+fn read_story_parts(path: &FilePath) -> Result<Vec<ProgramPart>, String> {
+    if is_compiled_story(path) {
+        return Err(format!(
+            "'{}' is a compiled story, which can only be played. Use its source instead.",
+            path.display()
+        ));
+    }
+    let source = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read source file '{}': {e}", path.display()))?;
+    if is_json_story(path) {
+        let parts = parse_json_story(path, &source)?;
+        Engine::from_parts(&parts).map_err(|errors| describe_errors(path, &errors))?;
+        return Ok(parts);
+    }
+    Engine::from_program(&source).map_err(|errors| describe_errors(path, &errors))?;
+
+    let (_, parts) = parse_program(&source).expect("Failed to parse nodes");
+    Ok(parts)
+}
+
+/// A numbered list of everything wrong with a story.
+fn describe_errors(path: &FilePath, errors: &[ParseError]) -> String {
+    let mut message = format!(
+        "Failed to build engine for '{}' due to the following errors:\n",
+        path.display()
+    );
+    for (i, error) in errors.iter().enumerate() {
+        message.push_str(&format!("\n{}. {error}", i + 1));
+    }
+    message
+}
+
+/// The OpenAPI document served at `/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "cyoa",
+        description = "Play choose-your-own-adventure stories over HTTP. When only one story is served, \
+            its routes are also available without the `/stories/{story_id}` part."
+    ),
+    paths(
+        list_stories,
+        reload_stories,
+        clear_expired_sessions,
+        get_stats,
+        metrics::get_metrics,
+        list_sessions,
+        analytics::get_analytics,
+        leaderboard::get_leaderboard,
+        preview::preview_node,
+        get_story_analysis,
+        create_session,
+        import_session,
+        delete_session,
+        get_current,
+        export_session,
+        get_history,
+        choose_option,
+        choose_option_from_body,
+        replay_choices,
+        go_back,
+        restart_session,
+        save_checkpoint,
+        load_checkpoint,
+        voting::join_session,
+        voting::get_votes,
+        voting::cast_vote,
+        voting::resolve_vote,
+        spectate::share_session,
+        spectate::get_current,
+        spectate::get_history,
+        debug_api::set_variables,
+        debug_api::jump_to_node,
+        cookies::get_current,
+        cookies::get_history,
+        cookies::choose_option,
+        cookies::go_back,
+        cookies::restart_session,
+        stateless::create_session,
+        stateless::get_current,
+        stateless::choose_option,
+        stateless::restart_session,
+    ),
+    modifiers(&ValueSchemas, &ApiKeyScheme)
+)]
+struct ApiDoc;
+
+/// utoipa takes any type named `Value` to mean arbitrary JSON, so the schemas for story
+/// values are added by hand.
+struct ValueSchemas;
+
+impl Modify for ValueSchemas {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let mut schemas = vec![(Value::name().to_string(), Value::schema())];
+        Value::schemas(&mut schemas);
+        openapi
+            .components
+            .get_or_insert_default()
+            .schemas
+            .extend(schemas);
+    }
+}
+
+/// Describes the `Authorization` header that `--api-key` asks for.
+struct ApiKeyScheme;
+
+impl Modify for ApiKeyScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_default()
+            .add_security_scheme(
+                "api_key",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+    }
+}
+
+/// The routes for one story. Creating sessions is limited separately from everything else, by
+/// `session_limits`.
+fn story_router(state: AppState, session_limits: &[RateLimit]) -> Router {
+    let mut create = post(create_session);
+    let mut import = post(import_session);
+    for limit in session_limits {
+        create = create.layer(limit.layer());
+        import = import.layer(limit.layer());
+    }
+
+    let mut router = Router::new()
+        .route("/session", create)
+        .route("/session/import", import)
+        .route("/session/{session_id}", delete(delete_session))
+        .route("/session/{session_id}/current", get(get_current))
+        .route("/session/{session_id}/export", get(export_session))
+        .route("/session/{session_id}/history", get(get_history))
+        .route("/session/{session_id}/ws", get(live::session_socket))
+        .route("/session/{session_id}/events", get(live::session_events))
+        .route("/session/{session_id}/join", post(voting::join_session))
+        .route("/session/{session_id}/votes", get(voting::get_votes))
+        .route("/session/{session_id}/vote", post(voting::cast_vote))
+        .route(
+            "/session/{session_id}/vote/resolve",
+            post(voting::resolve_vote),
+        )
+        .route("/session/{session_id}/share", post(spectate::share_session))
+        .route("/spectate/{token}/current", get(spectate::get_current))
+        .route("/spectate/{token}/history", get(spectate::get_history))
+        .route(
+            "/session/{session_id}/choose",
+            post(choose_option_from_body),
+        )
+        .route("/session/{session_id}/choose/{option}", post(choose_option))
+        .route("/session/{session_id}/replay", post(replay_choices))
+        .route("/session/{session_id}/back", post(go_back))
+        .route("/session/{session_id}/restart", post(restart_session))
+        .route("/session/{session_id}/checkpoint", post(save_checkpoint))
+        .route(
+            "/session/{session_id}/checkpoint/{slot}/load",
+            post(load_checkpoint),
+        );
+    if state.debug {
+        router = router
+            .route(
+                "/session/{session_id}/debug/variables",
+                put(debug_api::set_variables),
+            )
+            .route(
+                "/session/{session_id}/debug/node",
+                put(debug_api::jump_to_node),
+            );
+    }
+    if state.cookie_sessions {
+        router = router
+            .route("/session/current", get(cookies::get_current))
+            .route("/session/history", get(cookies::get_history))
+            .route("/session/choose/{option}", post(cookies::choose_option))
+            .route("/session/back", post(cookies::go_back))
+            .route("/session/restart", post(cookies::restart_session));
+    }
+    if state.stateless_key.is_some() {
+        router = router
+            .route("/stateless/session", post(stateless::create_session))
+            .route("/stateless/current", get(stateless::get_current))
+            .route("/stateless/choose/{option}", post(stateless::choose_option))
+            .route("/stateless/restart", post(stateless::restart_session));
+    }
+
+    router.with_state(state)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli: Cli = config::parse();
+    match cli.command {
+        Some(Command::Play(args)) => play::run(args),
+        Some(Command::Validate(args)) => validate::run(args),
+        Some(Command::Graph(args)) => graph::run(args),
+        Some(Command::Stats(args)) => stats::run(args),
+        Some(Command::Walk(args)) => walk::run(args),
+        Some(Command::Explore(args)) => explore::run(args),
+        Some(Command::Test(args)) => story_tests::run(args),
+        Some(Command::Record(args)) => golden::record(args),
+        Some(Command::Verify(args)) => golden::verify(args),
+        Some(Command::Fmt(args)) => fmt::run(args),
+        Some(Command::Debug(args)) => debug::run(args),
+        Some(Command::New(args)) => new::run(args),
+        Some(Command::Convert(args)) => convert::run(args),
+        Some(Command::Export(args)) => export::run(args),
+        Some(Command::Compile(args)) => compile::run(args),
+        Some(Command::Lsp(args)) => lsp::run(args),
+        Some(Command::Tokens(args)) => tokens::run(args),
+        Some(Command::BenchServer(args)) => bench_server::run(args).await,
+        None => serve(cli.serve).await,
+    }
+}
+
+async fn serve(args: ServeArgs) -> ExitCode {
+    if let Err(e) = logging::init(&args) {
+        eprintln!("{e}");
+        return ExitCode::FAILURE;
+    }
+
+    if let Some(secret) = &args.stateless_secret
+        && secret.len() < stateless::MIN_SECRET_LEN
+    {
+        error!(
+            "--stateless-secret must be at least {} characters long.",
+            stateless::MIN_SECRET_LEN
+        );
+        return ExitCode::FAILURE;
+    }
+
+    let settings = StorySettings {
+        history_depth: args.history_depth,
+        max_checkpoints: args.max_checkpoints,
+        max_transcript: args.max_transcript,
+        #[cfg(feature = "plugins")]
+        plugins: match load_plugins(&args.plugin) {
+            Ok(plugins) => plugins,
+            Err(e) => {
+                error!("{e}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+    let files = match find_story_files(&args.source) {
+        Ok(files) => files,
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if files.is_empty() {
+        error!("No stories were found.");
+        return ExitCode::FAILURE;
+    }
+
+    // Kept apart from `sessions` so it can be saved to `--session-file` on the way out.
+    let metrics = Arc::new(Metrics::default());
+    let mut session_file_store = None;
+    let sessions: Arc<dyn SessionStore> = match args.store.as_deref() {
+        Some(url) => match open_store(url, args.session_timeout_hours).await {
+            Ok(store) => Arc::from(store),
+            Err(e) => {
+                error!("{e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => {
+            let store = match &args.session_file {
+                Some(path) => {
+                    // Sessions for stories that are no longer served would never be seen again.
+                    let story_ids: Vec<String> = files
+                        .iter()
+                        .filter_map(|path| story_id(path).ok())
+                        .collect();
+                    let keep = |session_key: &str, session: &Session| {
+                        let story_id = session_key.split_once('/').map(|(story_id, _)| story_id);
+                        story_ids.iter().any(|id| Some(id.as_str()) == story_id)
+                            && !session.is_expired(args.session_timeout_hours)
+                    };
+                    match MemoryStore::load(path, keep) {
+                        Ok(store) => {
+                            info!(
+                                "Restored {} sessions from '{}'",
+                                store.session_count(),
+                                path.display()
+                            );
+                            store
+                        }
+                        Err(e) => {
+                            error!("{e}");
+                            return ExitCode::FAILURE;
+                        }
+                    }
+                }
+                None => MemoryStore::default(),
+            };
+            let store = Arc::new(store);
+            session_file_store = Some(Arc::clone(&store));
+            store
+        }
+    };
+
+    let webhooks = match Webhooks::new(
+        args.on_session_created.clone(),
+        args.on_game_over.clone(),
+        args.on_achievement.clone(),
+    ) {
+        Ok(webhooks) => Arc::new(webhooks),
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let leaderboard = match args.leaderboard.as_deref().map(Leaderboard::open) {
+        Some(Ok(leaderboard)) => Some(Arc::new(leaderboard)),
+        Some(Err(e)) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+
+    let mut stories: Vec<AppState> = Vec::new();
+    for path in files {
+        let story = story_id(&path).and_then(|story_id| {
+            if stories.iter().any(|story| story.story_id == story_id) {
+                return Err(format!("More than one story has the id '{story_id}'."));
+            }
+            let engine = build_story(&path, settings.clone())?;
+            Ok(SharedState {
+                story_id,
+                source_path: path,
+                story: RwLock::new(Arc::new(engine)),
+                settings: settings.clone(),
+                sessions: Arc::clone(&sessions),
+                session_locks: SessionLocks::default(),
+                session_timeout_hours: args.session_timeout_hours,
+                reload_policy: args.reload_policy,
+                events: SessionEvents::default(),
+                vote_window: args.vote_window_secs,
+                private_sessions: args.private_sessions,
+                debug: args.debug,
+                cookie_sessions: args.cookie_sessions,
+                secure_cookies: args.secure_cookies || args.tls_cert.is_some(),
+                stateless_key: args.stateless_secret.as_deref().map(stateless::signing_key),
+                daily_seed: args.daily_seed,
+                max_sessions: args.max_sessions,
+                reject_when_full: args.reject_when_full,
+                metrics: Arc::clone(&metrics),
+                webhooks: Arc::clone(&webhooks),
+                leaderboard: leaderboard.clone(),
+            })
+        });
+        match story {
+            Ok(story) => stories.push(Arc::new(story)),
+            Err(e) => {
+                error!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if args.watch {
+        tokio::spawn(watch_stories(stories.clone()));
+    }
+
+    // Limits shared by every story and transport, so a client can't get around them by switching
+    // stories or creating sessions over JSON-RPC, GraphQL or gRPC.
+    let session_limits: Vec<RateLimit> = [
+        (args.session_rate_limit, Duration::from_secs(60)),
+        (args.hourly_session_limit, Duration::from_secs(60 * 60)),
+    ]
+    .into_iter()
+    .filter_map(|(limit, period)| {
+        limit.map(|limit| RateLimit::new(limit, period, limit, args.trust_proxy))
+    })
+    .collect();
+
+    let server_state = Arc::new(ServerState {
+        stories: stories.clone(),
+        sessions: Arc::clone(&sessions),
+        session_timeout_hours: args.session_timeout_hours,
+        max_sessions: args.max_sessions,
+        metrics: Arc::clone(&metrics),
+        leaderboard,
+        session_limits: session_limits.clone(),
+    });
+
+    let api_keys = match read_api_keys(&args) {
+        Ok(keys) => Arc::new(keys),
+        Err(e) => {
+            error!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let require_api_key = middleware::from_fn_with_state(Arc::clone(&api_keys), require_api_key);
+
+    #[cfg(feature = "grpc")]
+    if let Some(port) = args.grpc_port {
+        let addr = SocketAddr::new(args.host, port);
+        let listener = match TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to listen on {addr}: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        // gRPC has no admin calls, so keys are only asked for when every endpoint needs one.
+        let grpc_keys = if args.api_key_scope == ApiKeyScope::All {
+            Arc::clone(&api_keys)
+        } else {
+            Arc::default()
+        };
+        tokio::spawn(grpc::serve(listener, Arc::clone(&server_state), grpc_keys));
+    }
+
+    let prefix = args.prefix.clone();
+    let mut admin = Router::new()
+        .route("/clear_expired_sessions", post(clear_expired_sessions))
+        .route("/reload", post(reload_stories))
+        .route("/stats", get(get_stats))
+        .route("/metrics", get(metrics::get_metrics))
+        .route("/admin/sessions", get(list_sessions))
+        .route("/admin/analytics", get(analytics::get_analytics))
+        .route(
+            "/admin/stories/{story_id}/preview",
+            post(preview::preview_node),
+        )
+        .route(
+            "/admin/stories/{story_id}/analysis",
+            get(get_story_analysis),
+        );
+    if !api_keys.is_empty() {
+        admin = admin.route_layer(require_api_key.clone());
+    }
+    let mut global = Router::new()
+        .route("/stories", get(list_stories))
+        .route("/rpc", post(rpc::handle))
+        .merge(admin);
+    if server_state.leaderboard.is_some() {
+        global = global.route("/leaderboard", get(leaderboard::get_leaderboard));
+    }
+    let mut api = global.with_state(Arc::clone(&server_state));
+    #[cfg(feature = "graphql")]
+    {
+        api = api.route(
+            "/graphql",
+            post(graphql::execute).with_state(graphql::schema(server_state)),
+        );
+    }
+
+    // With only one story, its routes are also served without the `/stories/{story_id}` part.
+    let single_story = stories.len() == 1;
+    for state in stories {
+        let story_id = state.story_id.clone();
+        let router = story_router(state, &session_limits);
+        if single_story {
+            api = api.merge(router.clone());
+        }
+        api = api.nest(&format!("/stories/{story_id}"), router);
+    }
+    // Only the routes added so far are covered, so the pages below stay open.
+    if !api_keys.is_empty() && args.api_key_scope == ApiKeyScope::All {
+        api = api.route_layer(require_api_key);
+    }
+
+    // Only the API's routes are counted, not the pages below.
+    api = api.route_layer(middleware::from_fn_with_state(
+        Arc::clone(&metrics),
+        metrics::track_requests,
+    ));
+
+    if args.serve_ui {
+        api = api.route("/play", get(|| async { Html(PLAY_PAGE) }));
+    }
+
+    let mut openapi = ApiDoc::openapi();
+    if !prefix.is_empty() {
+        openapi.servers = Some(vec![Server::new(&prefix)]);
+    }
+    api = api.route("/openapi.json", get(|| async { Json(openapi) }));
+    if args.swagger_ui {
+        api = api.route("/docs", get(|| async { Html(DOCS_PAGE) }));
+    }
+
+    let mut app = if prefix.is_empty() {
+        api
+    } else {
+        Router::new().nest(&prefix, api)
+    };
+    // Event streams and tiny responses are left uncompressed by the layer itself.
+    if !args.no_compression {
+        app = app.layer(CompressionLayer::new());
+    }
+    // Inside the rate limits, so requests turned away by them don't use up a permit.
+    let limits = limits::Limits {
+        timeout: Duration::from_secs(args.request_timeout_secs),
+        max_uri_length: args.max_uri_length,
+        concurrency: args
+            .max_concurrent_requests
+            .map(|limit| Arc::new(Semaphore::new(limit))),
+    };
+    app = app
+        .layer(middleware::from_fn_with_state(
+            Arc::new(limits),
+            limits::enforce,
+        ))
+        .layer(DefaultBodyLimit::max(args.max_body_bytes));
+    if let Some(limit) = args.rate_limit {
+        let burst = args.rate_limit_burst.unwrap_or(limit);
+        app = app.layer(rate_limit::layer(
+            limit,
+            Duration::from_secs(1),
+            burst,
+            args.trust_proxy,
+        ));
+    }
+    // Added last so preflight requests are answered before they count towards a rate limit.
+    if !args.cors_origin.is_empty() {
+        match cors::layer(&args.cors_origin) {
+            Ok(cors) => app = app.layer(cors),
+            Err(e) => {
+                error!("{e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    // Outside every other layer, so requests turned away by them are logged too.
+    app = app
+        .layer(logging::layer())
+        .layer(middleware::from_fn(request_id::assign));
+
+    let (Some(path), Some(store)) = (&args.session_file, session_file_store) else {
+        return listen(&args, app).await;
+    };
+    tokio::select! {
+        code = listen(&args, app) => return code,
+        () = shutdown_signal() => {}
+    }
+    match store.save(path).await {
+        Ok(()) => {
+            info!(
+                "Saved {} sessions to '{}'",
+                store.session_count(),
+                path.display()
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Resolves once the server is asked to stop, with Ctrl+C or, on Unix, `SIGTERM`.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        signal(SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// Serves `app` on the address or socket given by `args` until the server stops.
+async fn listen(args: &ServeArgs, app: Router) -> ExitCode {
+    #[cfg(unix)]
+    if let Some(path) = &args.unix_socket {
+        return serve_unix_socket(path, app).await;
+    }
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => match tls::load_config(cert, key).await {
+            Ok(config) => Some(config),
+            Err(e) => {
+                error!("{e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => None,
+    };
+
+    let addr = SocketAddr::new(args.host, args.port);
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on {addr}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let port = listener.local_addr().map_or(args.port, |addr| addr.port());
+    if let Err(e) = report_port(args, port) {
+        error!("{e}");
+        return ExitCode::FAILURE;
+    }
+    // Rate limits tell clients apart by their address.
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        Some(config) => {
+            let listener = listener.into_std().unwrap();
+            axum_server::from_tcp_rustls(listener, config)
+                .unwrap()
+                .serve(app)
+                .await
+                .unwrap();
+        }
+        None => axum::serve(listener, app).await.unwrap(),
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Serves `app` on a Unix domain socket at `path`, replacing any socket left there by an
+/// earlier run.
+#[cfg(unix)]
+async fn serve_unix_socket(path: &FilePath, app: Router) -> ExitCode {
+    use std::os::unix::fs::FileTypeExt;
+    use tokio::net::UnixListener;
+
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        let _ = fs::remove_file(path);
+    }
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on '{}': {e}", path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+    axum::serve(listener, app).await.unwrap();
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreResult;
+
+    const LOOP: &str = r#"
+= START
+    "Round and round."
+    "Go round." -> START
+    "Stop." -> end
+
+= end
+    "Done."
+"#;
+
+    /// A store that takes a while to answer, like one over the network, so that requests
+    /// racing each other interleave between reading a session and writing it back.
+    struct SlowStore(MemoryStore);
+
+    impl SlowStore {
+        async fn wait() {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl SessionStore for SlowStore {
+        async fn get(&self, session_id: &str) -> StoreResult<Option<Session>> {
+            let session = self.0.get(session_id).await;
+            Self::wait().await;
+            session
+        }
+
+        async fn insert(&self, session_id: &str, session: Session) -> StoreResult<()> {
+            self.0.insert(session_id, session).await
+        }
+
+        async fn update(
+            &self,
+            session_id: &str,
+            session: &Session,
+            read_version: u64,
+        ) -> StoreResult<()> {
+            Self::wait().await;
+            self.0.update(session_id, session, read_version).await
+        }
+
+        async fn touch(&self, session_id: &str) -> StoreResult<()> {
+            Self::wait().await;
+            self.0.touch(session_id).await
+        }
+
+        async fn remove(&self, session_id: &str) -> StoreResult<bool> {
+            Self::wait().await;
+            self.0.remove(session_id).await
+        }
+
+        async fn sweep_expired(&self, session_timeout_hours: f32) -> StoreResult<Vec<String>> {
+            self.0.sweep_expired(session_timeout_hours).await
+        }
+
+        async fn count(&self) -> StoreResult<usize> {
+            self.0.count().await
+        }
+
+        async fn list(&self, offset: usize, limit: usize) -> StoreResult<Vec<(String, Session)>> {
+            self.0.list(offset, limit).await
+        }
+
+        async fn evict_least_recently_active(&self) -> StoreResult<Option<String>> {
+            self.0.evict_least_recently_active().await
+        }
+
+        async fn insert_spectator_token(&self, token: &str, session_id: &str) -> StoreResult<()> {
+            self.0.insert_spectator_token(token, session_id).await
+        }
+
+        async fn spectated_session(&self, token: &str) -> StoreResult<Option<String>> {
+            self.0.spectated_session(token).await
+        }
+    }
+
+    /// A story served the way `main` would serve it, with every optional feature left off.
+    pub(crate) fn state(source: &str) -> AppState {
+        Arc::new(shared_state(source))
+    }
+
+    fn state_with_store(source: &str, sessions: Arc<dyn SessionStore>) -> AppState {
+        Arc::new(SharedState {
+            sessions,
+            ..shared_state(source)
+        })
+    }
+
+    fn shared_state(source: &str) -> SharedState {
+        let settings = StorySettings {
+            history_depth: 10,
+            max_checkpoints: 5,
+            max_transcript: 1000,
+            #[cfg(feature = "plugins")]
+            plugins: None,
+        };
+        SharedState {
+            story_id: "story".to_string(),
+            source_path: PathBuf::from("story.cyoa"),
+            story: RwLock::new(Arc::new(engine(source, &settings))),
+            settings,
+            sessions: Arc::new(MemoryStore::default()),
+            session_locks: SessionLocks::default(),
+            session_timeout_hours: 24.0,
+            reload_policy: ReloadPolicy::Migrate,
+            events: SessionEvents::default(),
+            vote_window: None,
+            private_sessions: false,
+            debug: false,
+            cookie_sessions: false,
+            secure_cookies: false,
+            stateless_key: None,
+            daily_seed: false,
+            max_sessions: None,
+            reject_when_full: false,
+            metrics: Arc::default(),
+            webhooks: Arc::new(Webhooks::new(None, None, None).unwrap()),
+            leaderboard: None,
+        }
+    }
+
+    fn engine(source: &str, settings: &StorySettings) -> Engine {
+        let mut engine = Engine::from_program(source).unwrap_or_else(|errors| {
+            panic!("The story has {} errors", errors.len());
+        });
+        engine.set_history_depth(settings.history_depth);
+        engine
+    }
+
+    /// Swap the story for a new version of it, as `--watch` does when its file changes.
+    fn reload(state: &AppState, source: &str) {
+        *state.story.write().unwrap() = Arc::new(engine(source, &state.settings));
+    }
+
+    /// Start a session, returning its id and token.
+    pub(crate) async fn new_session(state: &AppState) -> (String, SessionToken) {
+        let Ok((_, Json(created))) = create_session(State(Arc::clone(state))).await else {
+            panic!("The session couldn't be created");
+        };
+        (
+            created.session_id,
+            SessionToken(Some(created.session_token)),
+        )
+    }
+
+    pub(crate) async fn session(state: &AppState, session_id: &str) -> Session {
+        let Ok((_, session)) = read_session(state, session_id).await else {
+            panic!("No such session");
+        };
+        session
+    }
+
+    async fn choose(
+        state: &AppState,
+        session_id: &str,
+        token: &SessionToken,
+        choice: &str,
+        expected_version: Option<u64>,
+        idempotency_key: Option<&str>,
+    ) -> StatusCode {
+        let result = choose_option(
+            State(Arc::clone(state)),
+            Path((session_id.to_string(), choice.to_string())),
+            SessionToken(token.0.clone()),
+            ExpectedVersion(expected_version),
+            IdempotencyKey(idempotency_key.map(ToString::to_string)),
+        )
+        .await;
+        match result {
+            Ok((status, _)) | Err((status, _)) => status,
+        }
+    }
+
+    async fn current(state: &AppState, session_id: &str) -> StatusCode {
+        let query = CurrentQuery {
+            include_vars: false,
+            wait: false,
+            since_version: None,
+            timeout_secs: None,
+        };
+        let result = get_current(
+            State(Arc::clone(state)),
+            Path(session_id.to_string()),
+            Query(query),
+            SessionToken(None),
+            HeaderMap::new(),
+        )
+        .await;
+        match result {
+            Ok(response) => response.status(),
+            Err((status, _)) => status,
+        }
+    }
+
+    async fn replay(
+        state: &AppState,
+        session_id: &str,
+        token: SessionToken,
+        choices: &[&str],
+    ) -> Result<Json<CurrentNodeView>, ApiError> {
+        replay_choices(
+            State(Arc::clone(state)),
+            Path(session_id.to_string()),
+            token,
+            Json(ReplayRequest {
+                choices: choices.iter().map(ToString::to_string).collect(),
+            }),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn replay_takes_every_choice() {
+        let state = state(LOOP);
+        let (session_id, token) = new_session(&state).await;
+
+        let Ok(Json(view)) = replay(&state, &session_id, token, &["START", "end"]).await else {
+            panic!("The replay failed");
+        };
+
+        assert_eq!(view.node_id.as_deref(), Some("end"));
+        assert_eq!(view.turn, 2);
+        assert_eq!(session(&state, &session_id).await.current_node_id(), "end");
+    }
+
+    #[tokio::test]
+    async fn bad_replay_leaves_session_unchanged() {
+        let state = state(LOOP);
+        let (session_id, token) = new_session(&state).await;
+        let before = session(&state, &session_id).await;
+
+        let result = replay(&state, &session_id, token, &["START", "nowhere"]).await;
+
+        let Err((status, _)) = result else {
+            panic!("The replay should have failed");
+        };
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let after = session(&state, &session_id).await;
+        assert_eq!(after.current_node_id(), "START");
+        assert_eq!(after.turns(), 0);
+        assert_eq!(after.version(), before.version());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn reads_during_choices_lose_no_choices() {
+        let state = state_with_store(LOOP, Arc::new(SlowStore(MemoryStore::default())));
+        let (session_id, token) = new_session(&state).await;
+        let before = session(&state, &session_id).await.version();
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..20 {
+            let (chooser, reader) = (Arc::clone(&state), Arc::clone(&state));
+            let (choose_id, read_id) = (session_id.clone(), session_id.clone());
+            let token = SessionToken(token.0.clone());
+            tasks.spawn(
+                async move { choose(&chooser, &choose_id, &token, "START", None, None).await },
+            );
+            tasks.spawn(async move { current(&reader, &read_id).await });
+        }
+        while let Some(status) = tasks.join_next().await {
+            assert_eq!(status.unwrap(), StatusCode::OK);
+        }
+
+        let after = session(&state, &session_id).await;
+        assert_eq!(after.turns(), 20);
+        assert_eq!(after.version(), before + 20);
+    }
+
+    #[tokio::test]
+    async fn missing_sessions_are_not_found() {
+        let state = state(LOOP);
+        let token = SessionToken(Some("token".to_string()));
+
+        assert_eq!(
+            choose(&state, "missing", &token, "START", None, None).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(current(&state, "missing").await, StatusCode::NOT_FOUND);
+        assert!(
+            state
+                .sessions
+                .get(&state.session_key("missing"))
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn only_one_choice_is_taken_for_a_version() {
+        let state = state_with_store(LOOP, Arc::new(SlowStore(MemoryStore::default())));
+        let (session_id, token) = new_session(&state).await;
+        let version = session(&state, &session_id).await.version();
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..10 {
+            let (chooser, reader) = (Arc::clone(&state), Arc::clone(&state));
+            let (choose_id, read_id) = (session_id.clone(), session_id.clone());
+            let token = SessionToken(token.0.clone());
+            tasks.spawn(async move {
+                choose(&chooser, &choose_id, &token, "START", Some(version), None).await
+            });
+            tasks.spawn(async move { current(&reader, &read_id).await });
+        }
+        let mut taken = 0;
+        while let Some(status) = tasks.join_next().await {
+            match status.unwrap() {
+                StatusCode::OK => taken += 1,
+                status => assert_eq!(status, StatusCode::CONFLICT),
+            }
+        }
+
+        // Every read succeeds, and exactly one of the choices.
+        assert_eq!(taken, 11);
+        let after = session(&state, &session_id).await;
+        assert_eq!(after.turns(), 1);
+        assert_eq!(after.version(), version + 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn retried_choices_are_taken_once() {
+        let state = state_with_store(LOOP, Arc::new(SlowStore(MemoryStore::default())));
+        let (session_id, token) = new_session(&state).await;
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..10 {
+            let (chooser, reader) = (Arc::clone(&state), Arc::clone(&state));
+            let (choose_id, read_id) = (session_id.clone(), session_id.clone());
+            let token = SessionToken(token.0.clone());
+            tasks.spawn(async move {
+                choose(&chooser, &choose_id, &token, "START", None, Some("key")).await
+            });
+            tasks.spawn(async move { current(&reader, &read_id).await });
+        }
+        while let Some(status) = tasks.join_next().await {
+            assert_eq!(status.unwrap(), StatusCode::OK);
+        }
+        assert_eq!(session(&state, &session_id).await.turns(), 1);
+
+        let status = choose(&state, &session_id, &token, "end", None, Some("key")).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let after = session(&state, &session_id).await;
+        assert_eq!(after.current_node_id(), "START");
+        assert_eq!(after.turns(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn instances_sharing_a_store_take_one_choice() {
+        // Two servers behind a load balancer don't share their session locks, only the store.
+        let store: Arc<dyn SessionStore> = Arc::new(SlowStore(MemoryStore::default()));
+        let first = state_with_store(LOOP, Arc::clone(&store));
+        let second = state_with_store(LOOP, store);
+        let (session_id, token) = new_session(&first).await;
+
+        let other_token = SessionToken(token.0.clone());
+        let (a, b) = tokio::join!(
+            choose(&first, &session_id, &token, "START", None, None),
+            choose(&second, &session_id, &other_token, "START", None, None),
+        );
+        let mut statuses = [a, b];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::CONFLICT]);
+        assert_eq!(session(&first, &session_id).await.turns(), 1);
+    }
+
+    /// A store that can't be reached, like a database that's down.
+    struct DownStore;
+
+    impl DownStore {
+        fn down<T>() -> StoreResult<T> {
+            Err(StoreError::Unavailable("connection refused".to_string()))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl SessionStore for DownStore {
+        async fn get(&self, _: &str) -> StoreResult<Option<Session>> {
+            Self::down()
+        }
+
+        async fn insert(&self, _: &str, _: Session) -> StoreResult<()> {
+            Self::down()
+        }
+
+        async fn update(&self, _: &str, _: &Session, _: u64) -> StoreResult<()> {
+            Self::down()
+        }
+
+        async fn touch(&self, _: &str) -> StoreResult<()> {
+            Self::down()
+        }
+
+        async fn remove(&self, _: &str) -> StoreResult<bool> {
+            Self::down()
+        }
+
+        async fn sweep_expired(&self, _: f32) -> StoreResult<Vec<String>> {
+            Self::down()
+        }
+
+        async fn count(&self) -> StoreResult<usize> {
+            Self::down()
+        }
+
+        async fn list(&self, _: usize, _: usize) -> StoreResult<Vec<(String, Session)>> {
+            Self::down()
+        }
+
+        async fn evict_least_recently_active(&self) -> StoreResult<Option<String>> {
+            Self::down()
+        }
+
+        async fn insert_spectator_token(&self, _: &str, _: &str) -> StoreResult<()> {
+            Self::down()
+        }
+
+        async fn spectated_session(&self, _: &str) -> StoreResult<Option<String>> {
+            Self::down()
+        }
+    }
+
+    #[tokio::test]
+    async fn an_unavailable_store_is_reported() {
+        let state = state_with_store(LOOP, Arc::new(DownStore));
+        let result = create_session(State(Arc::clone(&state))).await;
+        assert_eq!(
+            result.map(|_| ()).map_err(|(status, _)| status),
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(
+            current(&state, "session").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[test]
+    fn vote_windows_must_be_positive() {
+        assert_eq!(parse_vote_window("1.5"), Ok(Duration::from_millis(1500)));
+        for secs in ["0", "-1", "NaN", "inf", "1e40", "soon"] {
+            assert!(parse_vote_window(secs).is_err(), "'{secs}' was accepted");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn deleted_sessions_stay_deleted() {
+        let state = state_with_store(LOOP, Arc::new(SlowStore(MemoryStore::default())));
+        let (session_id, token) = new_session(&state).await;
+
+        let wrong_token = SessionToken(Some("wrong".to_string()));
+        let result = delete_session(
+            State(Arc::clone(&state)),
+            Path(session_id.clone()),
+            wrong_token,
+        )
+        .await;
+        assert_eq!(
+            result.map_err(|(status, _)| status),
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        // One choice is being taken when the session is deleted, and more are sent while it is
+        // being deleted. Only the first can be taken.
+        let spawn_choice = || {
+            let (state, session_id) = (Arc::clone(&state), session_id.clone());
+            let token = SessionToken(token.0.clone());
+            tokio::spawn(
+                async move { choose(&state, &session_id, &token, "START", None, None).await },
+            )
+        };
+        let first = spawn_choice();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let deleted = tokio::spawn(delete_session(
+            State(Arc::clone(&state)),
+            Path(session_id.clone()),
+            SessionToken(token.0.clone()),
+        ));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let later: Vec<_> = (0..4).map(|_| spawn_choice()).collect();
+
+        assert_eq!(first.await.unwrap(), StatusCode::OK);
+        assert_eq!(deleted.await.unwrap().ok(), Some(StatusCode::NO_CONTENT));
+        for choice in later {
+            assert_eq!(choice.await.unwrap(), StatusCode::NOT_FOUND);
+        }
+        assert!(
+            state
+                .sessions
+                .get(&state.session_key(&session_id))
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    const LOOP_RENAMED: &str = r#"
+RENAMED end -> finish
+
+= START
+    "Round and round."
+    "Go round." -> START
+    "Stop." -> finish
+
+= finish
+    "Done."
+"#;
+
+    /// A session that has reached the end of [`LOOP`], and the story reloaded under it.
+    async fn session_from_before_reload(state: &AppState) -> String {
+        let (session_id, token) = new_session(state).await;
+        assert_eq!(
+            choose(state, &session_id, &token, "end", None, None).await,
+            StatusCode::OK
+        );
+        reload(state, LOOP_RENAMED);
+        session_id
+    }
+
+    async fn stored_session(state: &AppState, session_id: &str) -> Option<Session> {
+        state
+            .sessions
+            .get(&state.session_key(session_id))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrated_sessions_are_saved() {
+        let state = state(LOOP);
+        let session_id = session_from_before_reload(&state).await;
+
+        assert_eq!(current(&state, &session_id).await, StatusCode::OK);
+
+        let stored = stored_session(&state, &session_id).await.unwrap();
+        assert!(state.story().session_is_current(&stored));
+        assert_eq!(stored.current_node_id(), "finish");
+        assert_eq!(stored.turns(), 1);
+        // Reading it again doesn't migrate it again.
+        assert_eq!(current(&state, &session_id).await, StatusCode::OK);
+        let read_again = stored_session(&state, &session_id).await.unwrap();
+        assert_eq!(read_again.version(), stored.version());
+    }
+
+    #[tokio::test]
+    async fn reset_sessions_are_saved_at_the_start() {
+        let state = Arc::new(SharedState {
+            reload_policy: ReloadPolicy::Reset,
+            ..shared_state(LOOP)
+        });
+        let session_id = session_from_before_reload(&state).await;
+
+        assert_eq!(current(&state, &session_id).await, StatusCode::OK);
+
+        let stored = stored_session(&state, &session_id).await.unwrap();
+        assert!(state.story().session_is_current(&stored));
+        assert_eq!(stored.current_node_id(), "START");
+    }
+
+    #[tokio::test]
+    async fn invalidated_sessions_are_removed() {
+        let state = Arc::new(SharedState {
+            reload_policy: ReloadPolicy::Invalidate,
+            ..shared_state(LOOP)
+        });
+        let session_id = session_from_before_reload(&state).await;
+
+        assert_eq!(current(&state, &session_id).await, StatusCode::NOT_FOUND);
+        assert!(stored_session(&state, &session_id).await.is_none());
+    }
 }
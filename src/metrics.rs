@@ -0,0 +1,403 @@
+//! Counters for the server's traffic and the stories being played, served in the Prometheus
+//! text format at `/metrics`.
+
+use crate::ServerState;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{Method, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// The upper bounds, in seconds, of the buckets request latencies are counted in.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long requests to one route took.
+#[derive(Default)]
+struct Latencies {
+    /// How many requests fell in each of [`LATENCY_BUCKETS`], not counting smaller buckets.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum_seconds: f64,
+}
+
+/// Everything counted since the server started, shared by every story.
+#[derive(Default)]
+pub struct Metrics {
+    /// Requests by method, route and status code.
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// Latencies by method and route.
+    latencies: Mutex<BTreeMap<(String, String), Latencies>>,
+    sessions_created: AtomicU64,
+    sessions_expired: AtomicU64,
+    choices_made: AtomicU64,
+    /// How many times sessions have arrived at each node, by story and node id.
+    node_visits: Mutex<BTreeMap<(String, String), u64>>,
+    /// How many times each choice has been taken, by story, the node it was taken at and its id.
+    choices_taken: Mutex<BTreeMap<(String, String, String), u64>>,
+    /// How many times each text variant of a node has been shown, and how many times a choice
+    /// was taken after it, by story, node id and variant.
+    variants: Mutex<BTreeMap<(String, String, String), VariantCounts>>,
+    /// How many times each choice's requirement was met and not met when sessions arrived at
+    /// its node, by story, node id and choice id.
+    requirements: Mutex<BTreeMap<(String, String, String), RequirementCounts>>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct RequirementCounts {
+    pub met: u64,
+    pub unmet: u64,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct VariantCounts {
+    pub shown: u64,
+    pub choices_taken: u64,
+}
+
+impl Metrics {
+    fn record_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default() += 1;
+
+        let seconds = elapsed.as_secs_f64();
+        let mut latencies = self.latencies.lock().unwrap();
+        let latencies = latencies
+            .entry((method.to_string(), route.to_string()))
+            .or_default();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            latencies.buckets[bucket] += 1;
+        }
+        latencies.count += 1;
+        latencies.sum_seconds += seconds;
+    }
+
+    pub fn session_created(&self) {
+        self.sessions_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sessions_expired(&self, count: usize) {
+        self.sessions_expired
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn choice_made(&self, story_id: &str, node_id: &str, choice_id: &str) {
+        self.choices_made.fetch_add(1, Ordering::Relaxed);
+        *self
+            .choices_taken
+            .lock()
+            .unwrap()
+            .entry((
+                story_id.to_string(),
+                node_id.to_string(),
+                choice_id.to_string(),
+            ))
+            .or_default() += 1;
+    }
+
+    pub fn node_visited(&self, story_id: &str, node_id: &str) {
+        *self
+            .node_visits
+            .lock()
+            .unwrap()
+            .entry((story_id.to_string(), node_id.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Count a session arriving at a node and being shown the given variant of its narration.
+    pub fn variant_shown(&self, story_id: &str, node_id: &str, variant: &str) {
+        self.variants
+            .lock()
+            .unwrap()
+            .entry((
+                story_id.to_string(),
+                node_id.to_string(),
+                variant.to_string(),
+            ))
+            .or_default()
+            .shown += 1;
+    }
+
+    /// Count a choice taken at a node by a session shown the given variant of it.
+    pub fn variant_choice_made(&self, story_id: &str, node_id: &str, variant: &str) {
+        self.variants
+            .lock()
+            .unwrap()
+            .entry((
+                story_id.to_string(),
+                node_id.to_string(),
+                variant.to_string(),
+            ))
+            .or_default()
+            .choices_taken += 1;
+    }
+
+    /// The counts for each text variant shown in a story, by node id and variant.
+    pub fn variant_counts(&self, story_id: &str) -> HashMap<(String, String), VariantCounts> {
+        self.variants
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((story, _, _), _)| story == story_id)
+            .map(|((_, node_id, variant), counts)| ((node_id.clone(), variant.clone()), *counts))
+            .collect()
+    }
+
+    /// Count a choice's requirement being evaluated for a session arriving at its node.
+    pub fn requirement_evaluated(&self, story_id: &str, node_id: &str, choice_id: &str, met: bool) {
+        let mut requirements = self.requirements.lock().unwrap();
+        let counts = requirements
+            .entry((
+                story_id.to_string(),
+                node_id.to_string(),
+                choice_id.to_string(),
+            ))
+            .or_default();
+        if met {
+            counts.met += 1;
+        } else {
+            counts.unmet += 1;
+        }
+    }
+
+    /// The counts for each gated choice in a story, by the node it is at and its id.
+    pub fn requirement_counts(
+        &self,
+        story_id: &str,
+    ) -> HashMap<(String, String), RequirementCounts> {
+        self.requirements
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((story, _, _), _)| story == story_id)
+            .map(|((_, node_id, choice_id), counts)| {
+                ((node_id.clone(), choice_id.clone()), *counts)
+            })
+            .collect()
+    }
+
+    /// How many times sessions have arrived at each node of a story, by node id.
+    pub fn node_visits(&self, story_id: &str) -> HashMap<String, u64> {
+        self.node_visits
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((story, _), _)| story == story_id)
+            .map(|((_, node_id), count)| (node_id.clone(), *count))
+            .collect()
+    }
+
+    /// How many times each choice in a story has been taken, by the node it was taken at and
+    /// its id.
+    pub fn choices_taken(&self, story_id: &str) -> HashMap<(String, String), u64> {
+        self.choices_taken
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((story, _, _), _)| story == story_id)
+            .map(|((_, node_id, choice_id), count)| ((node_id.clone(), choice_id.clone()), *count))
+            .collect()
+    }
+
+    /// Everything counted so far, in the Prometheus text format.
+    fn render(&self, active_sessions: usize) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "cyoa_http_requests_total",
+            "counter",
+            "HTTP requests handled.",
+        );
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "cyoa_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{status}\"}} {count}",
+                escape(method),
+                escape(route),
+            );
+        }
+
+        write_header(
+            &mut out,
+            "cyoa_http_request_duration_seconds",
+            "histogram",
+            "How long HTTP requests took to handle.",
+        );
+        for ((method, route), latencies) in self.latencies.lock().unwrap().iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(latencies.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "cyoa_http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}",
+                );
+            }
+            let _ = writeln!(
+                out,
+                "cyoa_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                latencies.count,
+            );
+            let _ = writeln!(
+                out,
+                "cyoa_http_request_duration_seconds_sum{{{labels}}} {}",
+                latencies.sum_seconds,
+            );
+            let _ = writeln!(
+                out,
+                "cyoa_http_request_duration_seconds_count{{{labels}}} {}",
+                latencies.count,
+            );
+        }
+
+        write_header(
+            &mut out,
+            "cyoa_sessions_active",
+            "gauge",
+            "Sessions in the store, across every story.",
+        );
+        let _ = writeln!(out, "cyoa_sessions_active {active_sessions}");
+
+        let counters = [
+            (
+                "cyoa_sessions_created_total",
+                "Sessions created or imported.",
+                &self.sessions_created,
+            ),
+            (
+                "cyoa_sessions_expired_total",
+                "Sessions removed by /clear_expired_sessions.",
+                &self.sessions_expired,
+            ),
+            (
+                "cyoa_choices_made_total",
+                "Choices taken, directly or by a vote.",
+                &self.choices_made,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            write_header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        write_header(
+            &mut out,
+            "cyoa_node_visits_total",
+            "counter",
+            "Times sessions have arrived at each node.",
+        );
+        for ((story_id, node_id), count) in self.node_visits.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "cyoa_node_visits_total{{story=\"{}\",node=\"{}\"}} {count}",
+                escape(story_id),
+                escape(node_id),
+            );
+        }
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escapes a label value for the text format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The label for a request's method. Methods are made up by the client, so any that HTTP
+/// doesn't define are counted together rather than each getting a series of their own.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::PATCH => "PATCH",
+        Method::OPTIONS => "OPTIONS",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "other",
+    }
+}
+
+/// Counts each request by the route it matched, so ids in the path don't each get a series of
+/// their own.
+pub async fn track_requests(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = method_label(request.method());
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    metrics.record_request(
+        method,
+        &route,
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+
+    response
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "sessions",
+    security(("api_key" = [])),
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", content_type = "text/plain"),
+        (status = 401, description = "With `--api-key`, the API key is missing or wrong", body = crate::ErrorResponse),
+        (status = 503, description = "The session store is unavailable", body = crate::ErrorResponse),
+    ),
+)]
+pub async fn get_metrics(
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, crate::ApiError> {
+    let active_sessions = state.sessions.count().await.map_err(crate::store_error)?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(active_sessions),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn made_up_methods_share_a_label() {
+        assert_eq!(method_label(&Method::POST), "POST");
+        let made_up = Method::from_bytes(b"FOOBAR").unwrap();
+        assert_eq!(method_label(&made_up), "other");
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fs, path::PathBuf, process::ExitCode};
+
+#[derive(clap::Args, Debug)]
+pub struct NewArgs {
+    /// The name of the story. `.cyoa` is added if it is missing.
+    name: PathBuf,
+}
+
+/// A small but complete story that shows off each part of the syntax.
+const TEMPLATE: &str = r#"META title "{title}"
+META author "Your Name"
+
+SET gold 0
+
+= START
+    "You wake at the edge of a dark forest with {gold} gold in your pocket."
+    "Follow the path." -> path
+    "Search the bushes." -> bushes [THEN gold = 5]
+
+= bushes
+    "Hidden under the leaves is a small purse. You now have {gold} gold."
+    "Follow the path." -> path
+
+= path
+    "The path leads to a village inn. A room for the night costs 5 gold."
+    [IF gold > 0] "Pay for a room." -> END
+    "Sleep in the forest." -> START
+
+= END
+    "You sleep soundly. The end."
+"#;
+
+/// Write a starter story to a new file.
+pub fn run(args: NewArgs) -> ExitCode {
+    let mut path = args.name;
+    if path.extension().is_none_or(|ext| ext != "cyoa") {
+        path.as_mut_os_string().push(".cyoa");
+    }
+    if path.exists() {
+        eprintln!("'{}' already exists.", path.display());
+        return ExitCode::FAILURE;
+    }
+
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if let Err(e) = fs::write(&path, TEMPLATE.replace("{title}", &title(&name))) {
+        eprintln!("Failed to write '{}': {e}", path.display());
+        return ExitCode::FAILURE;
+    }
+
+    println!("Created {}. Try it out with:", path.display());
+    println!("\n    cyoa play {}", path.display());
+    ExitCode::SUCCESS
+}
+
+/// A title made from a file name, e.g. `my-story` becomes `My Story`.
+fn title(name: &str) -> String {
+    name.split(['-', '_', ' '])
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
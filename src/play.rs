@@ -0,0 +1,127 @@
+use crate::{StorySettings, build_story};
+use cyoa::{ChoiceResult, Engine, Session};
+use std::{
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct PlayArgs {
+    /// The story file to play
+    source: PathBuf,
+    /// How many choices can be undone with `back`
+    #[arg(long, default_value_t = 10)]
+    history_depth: usize,
+    /// A WebAssembly plugin providing functions the story calls. Can be given more than once.
+    #[cfg(feature = "plugins")]
+    #[arg(long)]
+    plugin: Vec<PathBuf>,
+}
+
+const HELP: &str = "Enter the number of a choice, or one of: back, restart, help, quit.";
+
+/// Play a story in the terminal until it ends or the player quits.
+pub fn run(args: PlayArgs) -> ExitCode {
+    let settings = StorySettings {
+        history_depth: args.history_depth,
+        max_checkpoints: 0,
+        max_transcript: usize::MAX,
+        #[cfg(feature = "plugins")]
+        plugins: match crate::load_plugins(&args.plugin) {
+            Ok(plugins) => plugins,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+    let story = match build_story(&args.source, settings) {
+        Ok(story) => story,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    play(&story, &mut story.new_session());
+    ExitCode::SUCCESS
+}
+
+/// Play a session in the terminal until the story ends or the player quits.
+pub(crate) fn play(story: &Engine, session: &mut Session) {
+    let mut lines = io::stdin().lock().lines();
+    println!("{HELP}");
+    loop {
+        let view = story.get_current_node_view(session);
+        println!("\n{}\n", view.display_text);
+        if view.game_over {
+            println!("The end.");
+            return;
+        }
+        for (i, choice) in view.choices.iter().enumerate() {
+            println!("  {}. {}", i + 1, choice.display_text);
+        }
+
+        // Keep asking until the input does something, so the scene isn't printed again
+        // after every typo.
+        loop {
+            print!("\n> ");
+            io::stdout().flush().expect("Failed to write to stdout");
+            let Some(Ok(line)) = lines.next() else {
+                return;
+            };
+
+            match handle_input(story, session, line.trim()) {
+                Input::Moved => break,
+                Input::Quit => return,
+                Input::Stay(message) => println!("{message}"),
+            }
+        }
+    }
+}
+
+enum Input {
+    /// The session changed, so the current node should be shown again.
+    Moved,
+    Quit,
+    /// Nothing changed. The message explains why.
+    Stay(String),
+}
+
+fn handle_input(story: &Engine, session: &mut Session, input: &str) -> Input {
+    match input {
+        "q" | "quit" => return Input::Quit,
+        "h" | "help" => return Input::Stay(HELP.to_string()),
+        "r" | "restart" => {
+            story.restart_session(session);
+            return Input::Moved;
+        }
+        "b" | "back" => {
+            return if story.go_back(session) {
+                Input::Moved
+            } else {
+                Input::Stay("There is nothing to go back to.".to_string())
+            };
+        }
+        _ => {}
+    }
+
+    let choices = story.get_current_node_view(session).choices;
+    let choice = input
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_sub(1))
+        .and_then(|index| choices.get(index));
+    let Some(choice) = choice else {
+        return Input::Stay(format!(
+            "'{input}' is not a choice. Enter a number from 1 to {}.",
+            choices.len()
+        ));
+    };
+
+    match story.choose_option(session, choice.id.clone()) {
+        ChoiceResult::Success => Input::Moved,
+        result => Input::Stay(result.to_string()),
+    }
+}
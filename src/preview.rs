@@ -0,0 +1,73 @@
+//! Any node of a story rendered with any variables, without a session, for editors that show
+//! authors a live preview of what they are writing.
+
+use crate::{ApiError, ErrorResponse, ServerState, api_error, debug_api::to_value};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use cyoa::{CurrentNodeView, RestoreError};
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc};
+use utoipa::ToSchema;
+
+#[derive(Deserialize, ToSchema)]
+pub struct PreviewRequest {
+    node_id: String,
+    /// Variables to render the node with, as plain JSON, e.g. `{ "gold": 15 }`. Variables left
+    /// out take their default values.
+    #[serde(default)]
+    variables: HashMap<String, serde_json::Value>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/stories/{story_id}/preview",
+    tag = "stories",
+    security(("api_key" = [])),
+    params(("story_id" = String, Path, description = "The story's id")),
+    request_body = PreviewRequest,
+    responses(
+        (status = 200, description = "The node as a session with the variables would see it. `version` is always 0", body = CurrentNodeView),
+        (status = 400, description = "The node or one of the variables doesn't exist, or a variable has the wrong type", body = ErrorResponse),
+        (status = 401, description = "With `--api-key`, the API key is missing or wrong", body = ErrorResponse),
+        (status = 404, description = "No such story", body = ErrorResponse),
+    ),
+)]
+pub async fn preview_node(
+    State(state): State<Arc<ServerState>>,
+    Path(story_id): Path<String>,
+    Json(request): Json<PreviewRequest>,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    let story = state
+        .stories
+        .iter()
+        .find(|story| story.story_id == story_id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "story not found"))?
+        .story();
+    let variables = request
+        .variables
+        .into_iter()
+        .map(|(name, json)| Ok((name.clone(), to_value(&name, json)?)))
+        .collect::<Result<HashMap<_, _>, ApiError>>()?;
+
+    let view = story
+        .preview_node(&request.node_id, &variables)
+        .map_err(|e| {
+            let message = match e {
+                RestoreError::UnknownNode { node_id } => {
+                    format!("there is no node with id '{node_id}'")
+                }
+                RestoreError::UnknownVariable { name } => {
+                    format!("there is no variable with name '{name}'")
+                }
+                RestoreError::MismatchedVariableType { name } => {
+                    format!("'{name}' can't be set to a value of a different type")
+                }
+            };
+            api_error(StatusCode::BAD_REQUEST, message)
+        })?;
+
+    Ok(Json(view))
+}
@@ -0,0 +1,128 @@
+//! Python bindings for scripting stories, e.g. for batch simulations and automated testing.
+//!
+//! ```python
+//! import cyoa
+//!
+//! story = cyoa.Engine(open("story.cyoa").read())
+//! session = story.new_session()
+//! view = story.current_view(session)
+//! story.choose(session, view["choices"][0]["id"])
+//! print(story.variables(session))
+//! ```
+
+use crate::{ChoiceResult, Engine, Session, Value};
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyDict, PyList},
+};
+
+/// A story loaded from the source of a `.cyoa` file.
+#[pyclass(name = "Engine", module = "cyoa")]
+pub struct PyEngine(Engine);
+
+/// One player's progress through a story.
+#[pyclass(name = "Session", module = "cyoa")]
+pub struct PySession(Session);
+
+#[pymethods]
+impl PySession {
+    /// The id of the node the player is currently at.
+    #[getter]
+    fn current_node_id(&self) -> &str {
+        self.0.current_node_id()
+    }
+}
+
+#[pymethods]
+impl PyEngine {
+    /// Load a story, raising a `ValueError` listing every problem with it if it is invalid.
+    #[new]
+    fn new(source: &str) -> PyResult<Self> {
+        match Engine::from_program(source) {
+            Ok(engine) => Ok(PyEngine(engine)),
+            Err(errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                Err(PyValueError::new_err(messages.join("\n")))
+            }
+        }
+    }
+
+    /// Set how many choices each session can undo with `go_back`. Undo is off by default.
+    fn set_history_depth(&mut self, history_depth: usize) {
+        self.0.set_history_depth(history_depth);
+    }
+
+    /// Start a new session at the beginning of the story.
+    fn new_session(&self) -> PySession {
+        PySession(self.0.new_session())
+    }
+
+    /// The session's current node, as a dict in the same format as the server's `/current` endpoint.
+    fn current_view<'py>(
+        &self,
+        py: Python<'py>,
+        session: &PySession,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let view = self.0.get_current_node_view(&session.0);
+        let choices = PyList::empty(py);
+        for choice in view.choices {
+            let dict = PyDict::new(py);
+            dict.set_item("display_text", choice.display_text)?;
+            dict.set_item("id", choice.id)?;
+            choices.append(dict)?;
+        }
+
+        let dict = PyDict::new(py);
+        dict.set_item("display_text", view.display_text)?;
+        dict.set_item("choices", choices)?;
+        dict.set_item("game_over", view.game_over)?;
+        dict.set_item("can_go_back", view.can_go_back)?;
+        dict.set_item("version", view.version)?;
+        dict.set_item("node_id", view.node_id)?;
+        dict.set_item("turn", view.turn)?;
+        Ok(dict)
+    }
+
+    /// Take the choice with the given id, raising a `ValueError` if it isn't available.
+    fn choose(&self, session: &mut PySession, choice_id: String) -> PyResult<()> {
+        match self.0.choose_option(&mut session.0, choice_id) {
+            ChoiceResult::Success => Ok(()),
+            result => Err(PyValueError::new_err(result.to_string())),
+        }
+    }
+
+    /// Undo the most recent choice. Returns whether there was a choice to undo.
+    fn go_back(&self, session: &mut PySession) -> bool {
+        self.0.go_back(&mut session.0)
+    }
+
+    /// Send the session back to the start of the story.
+    fn restart(&self, session: &mut PySession) {
+        self.0.restart_session(&mut session.0);
+    }
+
+    /// The session's variables as a dict. Strings are returned with their references filled in.
+    fn variables<'py>(&self, py: Python<'py>, session: &PySession) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new(py);
+        for (name, value) in session.0.variables() {
+            match value {
+                Value::Int(i) => dict.set_item(name, i)?,
+                Value::Bool(b) => dict.set_item(name, b)?,
+                Value::String(_) => {
+                    dict.set_item(name, self.0.value_to_string(&session.0, value))?
+                }
+            }
+        }
+
+        Ok(dict)
+    }
+}
+
+/// The `cyoa` Python module.
+#[pymodule]
+fn cyoa(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyEngine>()?;
+    m.add_class::<PySession>()?;
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//! Limits on how often each client, told apart by IP address, can call the server.
+
+use crate::{ApiError, api_error};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{HeaderMap, Request, Response, StatusCode, request::Parts},
+    response::IntoResponse,
+};
+use governor::{
+    clock::{Clock, DefaultClock, QuantaInstant},
+    middleware::NoOpMiddleware,
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tower_governor::{
+    GovernorError, GovernorLayer,
+    governor::{GovernorConfig, GovernorConfigBuilder},
+    key_extractor::KeyExtractor,
+};
+
+pub type RateLimitLayer = GovernorLayer<ClientIpKeyExtractor, NoOpMiddleware<QuantaInstant>, Body>;
+
+type RateLimitConfig = GovernorConfig<ClientIpKeyExtractor, NoOpMiddleware<QuantaInstant>>;
+
+/// Tells clients apart by the address they connect from or, with `--trust-proxy`, by the
+/// address the reverse proxy in front of the server says the request came from.
+#[derive(Clone, Copy)]
+pub struct ClientIpKeyExtractor {
+    trust_proxy: bool,
+}
+
+impl ClientIpKeyExtractor {
+    fn client_ip(&self, client: &ClientAddr) -> Option<IpAddr> {
+        self.trust_proxy
+            .then_some(client.forwarded_for)
+            .flatten()
+            .or(client.connected_from)
+    }
+}
+
+impl KeyExtractor for ClientIpKeyExtractor {
+    type Key = IpAddr;
+
+    fn extract<T>(&self, request: &Request<T>) -> Result<IpAddr, GovernorError> {
+        let client = ClientAddr::new(
+            request.headers(),
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip()),
+        );
+        self.client_ip(&client)
+            .ok_or(GovernorError::UnableToExtractKey)
+    }
+}
+
+/// Where a request came from, for transports that check a limit themselves rather than going
+/// through its layer.
+pub struct ClientAddr {
+    connected_from: Option<IpAddr>,
+    forwarded_for: Option<IpAddr>,
+}
+
+impl ClientAddr {
+    pub fn new(headers: &HeaderMap, connected_from: Option<IpAddr>) -> Self {
+        ClientAddr {
+            connected_from,
+            forwarded_for: forwarded_for(headers),
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientAddr {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let connected_from = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        Ok(ClientAddr::new(&parts.headers, connected_from))
+    }
+}
+
+/// The client's address from `X-Forwarded-For`. Proxies add the address they were connected
+/// from to the end of the header, so the last entry is the only one a client can't make up.
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .next_back()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// How often clients that have stopped making requests are forgotten.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A limit of `per_period` requests for every `period` from each client, allowing bursts of up
+/// to `burst` requests at once. Both counts must be at least one.
+pub fn layer(per_period: u32, period: Duration, burst: u32, trust_proxy: bool) -> RateLimitLayer {
+    RateLimit::new(per_period, period, burst, trust_proxy).layer()
+}
+
+/// A rate limit that can be put in front of routes as a layer and also checked directly, with
+/// both counting towards the same limit.
+#[derive(Clone)]
+pub struct RateLimit {
+    config: Arc<RateLimitConfig>,
+    key_extractor: ClientIpKeyExtractor,
+}
+
+impl RateLimit {
+    /// A limit of `per_period` requests for every `period` from each client, allowing bursts
+    /// of up to `burst` requests at once. Both counts must be at least one.
+    pub fn new(per_period: u32, period: Duration, burst: u32, trust_proxy: bool) -> Self {
+        let key_extractor = ClientIpKeyExtractor { trust_proxy };
+        let config: Arc<RateLimitConfig> = Arc::new(
+            GovernorConfigBuilder::default()
+                .key_extractor(key_extractor)
+                .period(period / per_period)
+                .burst_size(burst)
+                .finish()
+                .expect("Rate limits allow at least one request"),
+        );
+
+        let limiter = Arc::clone(config.limiter());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                limiter.retain_recent();
+            }
+        });
+
+        RateLimit {
+            config,
+            key_extractor,
+        }
+    }
+
+    pub fn layer(&self) -> RateLimitLayer {
+        GovernorLayer::new(Arc::clone(&self.config)).error_handler(too_many_requests)
+    }
+
+    /// Count a request from `client` towards the limit, as the layer would.
+    pub fn check(&self, client: &ClientAddr) -> Result<(), ApiError> {
+        let Some(ip) = self.key_extractor.client_ip(client) else {
+            return Err(api_error(StatusCode::INTERNAL_SERVER_ERROR, UNKNOWN_CLIENT));
+        };
+        self.config.limiter().check_key(&ip).map_err(|negative| {
+            let wait_time = negative.wait_time_from(DefaultClock::default().now());
+            api_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                too_many_requests_message(wait_time.as_secs()),
+            )
+        })
+    }
+}
+
+const UNKNOWN_CLIENT: &str = "couldn't tell which client the request came from";
+
+fn too_many_requests_message(wait_time: u64) -> String {
+    format!("too many requests, try again in {}s", wait_time.max(1))
+}
+
+/// Errors from the rate limiter, in the same shape as the API's other errors.
+fn too_many_requests(error: GovernorError) -> Response<Body> {
+    let (status, headers, message) = match error {
+        GovernorError::TooManyRequests { wait_time, headers } => (
+            StatusCode::TOO_MANY_REQUESTS,
+            headers,
+            too_many_requests_message(wait_time),
+        ),
+        GovernorError::UnableToExtractKey => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            None,
+            UNKNOWN_CLIENT.to_string(),
+        ),
+        GovernorError::Other { code, msg, headers } => {
+            (code, headers, msg.unwrap_or_else(|| code.to_string()))
+        }
+    };
+    let mut response = api_error(status, message).into_response();
+    if let Some(headers) = headers {
+        response.headers_mut().extend(headers);
+    }
+
+    response
+}
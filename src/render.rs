@@ -0,0 +1,151 @@
+//! The current node as plain text or HTML, for clients that ask for them in their `Accept`
+//! header instead of rendering the JSON themselves.
+
+use crate::export::escape_html;
+use axum::{
+    Json,
+    http::{HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use cyoa::CurrentNodeView;
+
+/// How wide plain text is wrapped.
+const TEXT_WIDTH: usize = 72;
+
+#[derive(Clone, Copy)]
+enum Format {
+    Json,
+    Text,
+    Html,
+}
+
+/// The format the client likes best, going by the quality values in its `Accept` header.
+/// JSON is picked when the header is missing or asks for nothing else that is on offer.
+fn preferred_format(headers: &HeaderMap) -> Format {
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Format::Json;
+    };
+
+    let mut best = (Format::Json, 0.0);
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let format = match params.next().unwrap_or("").to_ascii_lowercase().as_str() {
+            "application/json" => Format::Json,
+            "text/plain" => Format::Text,
+            "text/html" => Format::Html,
+            // Wildcards are left to default to JSON.
+            _ => continue,
+        };
+        let quality = params
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if quality > best.1 {
+            best = (format, quality);
+        }
+    }
+
+    best.0
+}
+
+/// Wraps each line of `text` at [`TEXT_WIDTH`] columns, breaking between words.
+fn wrap(text: &str) -> String {
+    let mut wrapped = String::new();
+    for (i, line) in text.lines().enumerate() {
+        if i > 0 {
+            wrapped.push('\n');
+        }
+        let mut width = 0;
+        for word in line.split_whitespace() {
+            let word_width = word.chars().count();
+            if width > 0 && width + 1 + word_width > TEXT_WIDTH {
+                wrapped.push('\n');
+                width = 0;
+            } else if width > 0 {
+                wrapped.push(' ');
+                width += 1;
+            }
+            wrapped.push_str(word);
+            width += word_width;
+        }
+    }
+
+    wrapped
+}
+
+/// The narration, then the choices numbered with their ids, so they can be sent to `/choose`.
+fn text(view: &CurrentNodeView) -> String {
+    let mut text = wrap(&view.display_text);
+    text.push_str("\n\n");
+    if view.game_over {
+        text.push_str("The end.\n");
+    }
+    for (i, choice) in view.choices.iter().enumerate() {
+        text.push_str(&format!(
+            "  {}. {} [{}]\n",
+            i + 1,
+            choice.display_text,
+            choice.id
+        ));
+    }
+
+    text
+}
+
+/// A page with the narration and a numbered list of choices, each with its id.
+fn html(view: &CurrentNodeView, title: &str) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n",
+        escape_html(title)
+    );
+    for paragraph in view
+        .display_text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+    {
+        html.push_str(&format!("<p>{}</p>\n", escape_html(paragraph)));
+    }
+    if view.game_over {
+        html.push_str("<p><em>The end.</em></p>\n");
+    } else {
+        html.push_str("<ol>\n");
+        for choice in &view.choices {
+            html.push_str(&format!(
+                "<li data-choice-id=\"{}\">{}</li>\n",
+                escape_html(&choice.id),
+                escape_html(&choice.display_text)
+            ));
+        }
+        html.push_str("</ol>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+
+    html
+}
+
+/// The view as JSON, plain text or HTML, whichever the request's `Accept` header prefers.
+/// HTML pages are titled `title`.
+pub fn current_node(headers: &HeaderMap, view: CurrentNodeView, title: &str) -> Response {
+    let mut response = match preferred_format(headers) {
+        Format::Json => Json(view).into_response(),
+        Format::Text => (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            text(&view),
+        )
+            .into_response(),
+        Format::Html => (
+            [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+            html(&view, title),
+        )
+            .into_response(),
+    };
+    // Caches have to keep each format apart.
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("accept"));
+
+    response
+}
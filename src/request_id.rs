@@ -0,0 +1,44 @@
+//! An id for every request, taken from its `X-Request-Id` header or made up, so a failed request
+//! a player reports can be found in the logs.
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Ids sent by clients that are longer than this are replaced, to keep the logs readable.
+const MAX_LENGTH: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Gives the request an id, which is sent back in the response's `X-Request-Id` header and can
+/// be read with [`current`] while the request is handled.
+pub async fn assign(mut request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_LENGTH)
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_string);
+    let header = HeaderValue::from_str(&request_id).expect("Request ids are valid headers");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, header.clone());
+
+    let mut response = REQUEST_ID.scope(request_id, next.run(request)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, header);
+
+    response
+}
+
+/// The id of the request being handled, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
@@ -0,0 +1,277 @@
+//! A [JSON-RPC 2.0](https://www.jsonrpc.org/specification) endpoint at `{prefix}/rpc`, for
+//! editors and chat bots that would rather send every call to one URL. Each method is backed
+//! by the handler for the matching HTTP endpoint.
+
+use crate::{
+    ApiError, AppState, ExpectedVersion, IdempotencyKey, MAX_IDEMPOTENCY_KEY_LENGTH, ServerState,
+    SessionToken, rate_limit::ClientAddr,
+};
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use cyoa::ChoiceResult;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use std::sync::Arc;
+
+// Error codes defined by the specification.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// For errors the HTTP API would have answered with a status other than 200, which is given as
+/// the error's `data.status`.
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+impl From<ApiError> for RpcError {
+    fn from((status, Json(error)): ApiError) -> Self {
+        RpcError {
+            code: SERVER_ERROR,
+            message: error.error,
+            data: Some(json!({ "status": status.as_u16() })),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+impl RpcResponse {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        let (result, error) = match outcome {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        RpcResponse {
+            jsonrpc: "2.0",
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Call {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateParams {
+    story_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SessionParams {
+    story_id: Option<String>,
+    session_id: String,
+    /// Can also be sent in the `X-Session-Token` header.
+    session_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChooseParams {
+    story_id: Option<String>,
+    session_id: String,
+    session_token: Option<String>,
+    choice: String,
+    expected_version: Option<u64>,
+    idempotency_key: Option<String>,
+}
+
+/// A method's parameters, which have to be given by name. Methods whose parameters are all
+/// optional can be called without any.
+fn params<T: DeserializeOwned>(params: Option<Value>) -> Result<T, RpcError> {
+    serde_json::from_value(params.unwrap_or_else(|| json!({})))
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("invalid params: {e}")))
+}
+
+fn to_value(result: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(result).map_err(|e| RpcError::new(INTERNAL_ERROR, e))
+}
+
+/// The token from the params, or else from the request.
+fn token(session_token: Option<String>, request_token: &SessionToken) -> SessionToken {
+    SessionToken(session_token.or_else(|| request_token.0.clone()))
+}
+
+fn story(state: &ServerState, story_id: Option<String>) -> Result<AppState, RpcError> {
+    Ok(state.find_story(story_id.as_deref())?)
+}
+
+async fn call(
+    state: &ServerState,
+    request_token: &SessionToken,
+    client: &ClientAddr,
+    method: &str,
+    raw_params: Option<Value>,
+) -> Result<Value, RpcError> {
+    match method {
+        "session.create" => {
+            let params: CreateParams = params(raw_params)?;
+            state.check_session_limits(client)?;
+            let (_, Json(created)) =
+                crate::create_session(State(story(state, params.story_id)?)).await?;
+            to_value(created)
+        }
+        "session.current" => {
+            let params: SessionParams = params(raw_params)?;
+            let story = story(state, params.story_id)?;
+            let (_, session) = crate::get_readable_session(
+                &story,
+                &params.session_id,
+                &token(params.session_token, request_token),
+            )
+            .await?;
+            to_value(story.story().get_current_node_view(&session))
+        }
+        "session.choose" => {
+            let params: ChooseParams = params(raw_params)?;
+            if params
+                .idempotency_key
+                .as_ref()
+                .is_some_and(|key| key.len() > MAX_IDEMPOTENCY_KEY_LENGTH)
+            {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!(
+                        "idempotency_key can be at most {MAX_IDEMPOTENCY_KEY_LENGTH} characters long"
+                    ),
+                ));
+            }
+            let (status, Json(result)) = crate::choose_option(
+                State(story(state, params.story_id)?),
+                Path((params.session_id, params.choice)),
+                token(params.session_token, request_token),
+                ExpectedVersion(params.expected_version),
+                IdempotencyKey(params.idempotency_key),
+            )
+            .await?;
+            if !matches!(result, ChoiceResult::Success) {
+                return Err(RpcError {
+                    code: SERVER_ERROR,
+                    message: result.to_string(),
+                    data: Some(json!({ "status": status.as_u16(), "result": result })),
+                });
+            }
+            to_value(result)
+        }
+        "session.delete" => {
+            let params: SessionParams = params(raw_params)?;
+            crate::delete_session(
+                State(story(state, params.story_id)?),
+                Path(params.session_id),
+                token(params.session_token, request_token),
+            )
+            .await?;
+            Ok(Value::Null)
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("there is no method named '{method}'"),
+        )),
+    }
+}
+
+/// Answers one entry of a request, or nothing if it is a notification.
+async fn respond(
+    state: &ServerState,
+    request_token: &SessionToken,
+    client: &ClientAddr,
+    entry: Value,
+) -> Option<RpcResponse> {
+    // A request without an `id` is a notification, which gets no response, but `"id": null`
+    // still does.
+    let id = match &entry {
+        Value::Object(object) => object.get("id").cloned(),
+        _ => Some(Value::Null),
+    };
+    let outcome = match serde_json::from_value::<Call>(entry) {
+        Ok(request) if request.jsonrpc == "2.0" => {
+            call(
+                state,
+                request_token,
+                client,
+                &request.method,
+                request.params,
+            )
+            .await
+        }
+        _ => Err(RpcError::new(INVALID_REQUEST, "invalid request")),
+    };
+
+    id.map(|id| RpcResponse::new(id, outcome))
+}
+
+/// Runs a JSON-RPC request, or a batch of them in order.
+pub async fn handle(
+    State(state): State<Arc<ServerState>>,
+    request_token: SessionToken,
+    client: ClientAddr,
+    body: Bytes,
+) -> Response {
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, format!("parse error: {e}"));
+            return Json(RpcResponse::new(Value::Null, Err(error))).into_response();
+        }
+    };
+    match request {
+        Value::Array(entries) if entries.is_empty() => {
+            let error = RpcError::new(INVALID_REQUEST, "a batch can't be empty");
+            Json(RpcResponse::new(Value::Null, Err(error))).into_response()
+        }
+        Value::Array(entries) => {
+            let mut responses = Vec::new();
+            for entry in entries {
+                responses.extend(respond(&state, &request_token, &client, entry).await);
+            }
+            if responses.is_empty() {
+                StatusCode::NO_CONTENT.into_response()
+            } else {
+                Json(responses).into_response()
+            }
+        }
+        entry => match respond(&state, &request_token, &client, entry).await {
+            Some(response) => Json(response).into_response(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        },
+    }
+}
@@ -0,0 +1,128 @@
+//! Read-only links that let an audience follow a session without being able to play it.
+
+use crate::{
+    ApiError, AppState, ErrorResponse, SessionToken, api_error, get_own_session, read_session,
+    store_error,
+};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use cyoa::{CurrentNodeView, Engine, HistoryEvent, Session};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::info;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Serialize, ToSchema)]
+pub struct ShareResponse {
+    /// Gives read-only access to the session through the `/spectate/{token}` routes.
+    spectator_token: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/share",
+    tag = "spectating",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+        ("X-Session-Token" = Option<String>, Header, description = "The token returned when the session was created"),
+    ),
+    responses(
+        (status = 200, body = ShareResponse),
+        (status = 401, description = "The session's token is missing", body = ErrorResponse),
+        (status = 403, description = "The session's token is wrong", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+pub async fn share_session(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+) -> Result<Json<ShareResponse>, ApiError> {
+    // Sharing doesn't change the session, so it is only marked as active.
+    let (_, _session) = get_own_session(&state, &session_id, &token).await?;
+    state
+        .sessions
+        .touch(&state.session_key(&session_id))
+        .await
+        .map_err(store_error)?;
+
+    let spectator_token = Uuid::new_v4().to_string();
+    state
+        .sessions
+        .insert_spectator_token(
+            &state.session_key(&spectator_token),
+            &state.session_key(&session_id),
+        )
+        .await
+        .map_err(store_error)?;
+    info!(%session_id, "Shared session with spectators");
+
+    Ok(Json(ShareResponse { spectator_token }))
+}
+
+/// Look up the session a spectator token was handed out for. Spectating doesn't count as
+/// playing, so the session isn't written back.
+async fn spectated_session(
+    state: &AppState,
+    token: &str,
+) -> Result<(Arc<Engine>, Session), ApiError> {
+    let not_found = || api_error(StatusCode::NOT_FOUND, "spectator token not found");
+    let session_key = state
+        .sessions
+        .spectated_session(&state.session_key(token))
+        .await
+        .map_err(store_error)?
+        .ok_or_else(not_found)?;
+    let session_id = session_key
+        .strip_prefix(&state.session_key(""))
+        .ok_or_else(not_found)?;
+
+    read_session(state, session_id).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/spectate/{token}/current",
+    tag = "spectating",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("token" = String, Path, description = "A spectator token from `/share`"),
+    ),
+    responses(
+        (status = 200, body = CurrentNodeView),
+        (status = 404, description = "No such token, or its session has ended", body = ErrorResponse),
+    ),
+)]
+pub async fn get_current(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    let (story, session) = spectated_session(&state, &token).await?;
+    Ok(Json(story.get_current_node_view(&session)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/spectate/{token}/history",
+    tag = "spectating",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("token" = String, Path, description = "A spectator token from `/share`"),
+    ),
+    responses(
+        (status = 200, body = Vec<HistoryEvent>),
+        (status = 404, description = "No such token, or its session has ended", body = ErrorResponse),
+    ),
+)]
+pub async fn get_history(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<Vec<HistoryEvent>>, ApiError> {
+    let (story, session) = spectated_session(&state, &token).await?;
+    Ok(Json(story.history(&session).to_vec()))
+}
@@ -0,0 +1,316 @@
+//! Sessions kept entirely by the client, enabled by `--stateless-secret`. Each response carries
+//! the session's whole state as a signed token, which the client sends back with its next
+//! request, so the server stores nothing and any instance can serve any player.
+
+use crate::{ApiError, AppState, ErrorResponse, api_error, webhooks::WebhookEvent};
+use axum::{
+    Json,
+    extract::{FromRequestParts, Path, State},
+    http::{StatusCode, request::Parts},
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use cyoa::{ChoiceResult, CurrentNodeView, Engine, Session, SessionSnapshot};
+use ring::{digest, hmac};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::info;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub const STATE_HEADER: &str = "x-session-state";
+
+/// How short `--stateless-secret` can be, since anyone who guesses it can forge any state.
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// The key tokens are signed with, made from `--stateless-secret`.
+pub fn signing_key(secret: &str) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())
+}
+
+/// Everything about a session that a token carries.
+#[derive(Serialize, Deserialize)]
+struct SessionState {
+    /// Made up when the session starts, to tell sessions apart in the logs and webhooks.
+    session_id: String,
+    /// So a token for one story can't be played against another.
+    story_id: String,
+    /// Also carries how many choices have been taken, as `turns`.
+    #[serde(flatten)]
+    snapshot: SessionSnapshot,
+    /// A SHA-256 hash chained over every choice taken, so two tokens at the same node can be
+    /// told apart by the path that led there.
+    history_hash: String,
+}
+
+impl SessionState {
+    fn sign(&self, key: &hmac::Key) -> String {
+        let payload = URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(self).expect("Failed to serialize session state"));
+        let tag = hmac::sign(key, payload.as_bytes());
+        format!("{payload}.{}", URL_SAFE_NO_PAD.encode(tag.as_ref()))
+    }
+
+    fn verify(token: &str, key: &hmac::Key) -> Option<Self> {
+        let (payload, tag) = token.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        hmac::verify(key, payload.as_bytes(), &tag).ok()?;
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+    }
+
+    fn record_choice(&mut self, choice_id: &str) {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(self.history_hash.as_bytes());
+        context.update(choice_id.as_bytes());
+        self.history_hash = hex(context.finish().as_ref());
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The token a client presents in the `X-Session-State` header.
+pub(crate) struct StateToken(String);
+
+impl<S: Send + Sync> FromRequestParts<S> for StateToken {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .headers
+            .get(STATE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(|token| StateToken(token.to_string()))
+            .ok_or_else(|| {
+                api_error(
+                    StatusCode::UNAUTHORIZED,
+                    "this request needs the session's state in an X-Session-State header",
+                )
+            })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct StatelessResponse {
+    /// The session's new state, to send in the `X-Session-State` header of the next request.
+    state: String,
+    view: CurrentNodeView,
+}
+
+/// The key tokens are signed with. Stateless routes are only served when there is one.
+fn key(state: &AppState) -> &hmac::Key {
+    state
+        .stateless_key
+        .as_ref()
+        .expect("Stateless routes are only served with --stateless-secret")
+}
+
+/// Check a token's signature and rebuild the session it describes.
+fn open(
+    state: &AppState,
+    token: &StateToken,
+) -> Result<(Arc<Engine>, SessionState, Session), ApiError> {
+    let session_state = SessionState::verify(&token.0, key(state))
+        .ok_or_else(|| api_error(StatusCode::FORBIDDEN, "the session state is invalid"))?;
+    if session_state.story_id != state.story_id {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "the session state is for a different story",
+        ));
+    }
+    let story = state.story();
+    let session = story
+        .restore_session(session_state.snapshot.clone())
+        .map_err(|_| {
+            api_error(
+                StatusCode::CONFLICT,
+                "the story has changed too much since the session state was made",
+            )
+        })?;
+
+    Ok((story, session_state, session))
+}
+
+/// Sign the session's new state and pair it with what the player sees now.
+fn respond(
+    state: &AppState,
+    story: &Engine,
+    mut session_state: SessionState,
+    session: &Session,
+) -> Json<StatelessResponse> {
+    session_state.snapshot = story.snapshot_session(session);
+    Json(StatelessResponse {
+        state: session_state.sign(key(state)),
+        view: story.get_current_node_view(session),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/stateless/session",
+    tag = "stateless",
+    params(("story_id" = String, Path, description = "The story's id")),
+    responses(
+        (status = 200, description = "A new session at the start of the story", body = StatelessResponse),
+        (status = 404, description = "The server wasn't started with `--stateless-secret`", body = ErrorResponse),
+    ),
+)]
+pub async fn create_session(State(state): State<AppState>) -> Json<StatelessResponse> {
+    let story = state.story();
+    let session = state.new_session(&story);
+    let session_id = Uuid::new_v4().to_string();
+    state.metrics.session_created();
+    state.record_visit(&session_id, &session);
+    state.send_webhook(
+        WebhookEvent::SessionCreated,
+        &session_id,
+        &story,
+        &session,
+        None,
+    );
+    info!(%session_id, "Created new stateless session");
+
+    let session_state = SessionState {
+        session_id,
+        story_id: state.story_id.clone(),
+        snapshot: story.snapshot_session(&session),
+        history_hash: String::new(),
+    };
+    respond(&state, &story, session_state, &session)
+}
+
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/stateless/current",
+    tag = "stateless",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("X-Session-State" = String, Header, description = "The state returned by the last request"),
+    ),
+    responses(
+        (status = 200, body = StatelessResponse),
+        (status = 401, description = "The session state is missing", body = ErrorResponse),
+        (status = 403, description = "The session state wasn't made by this server, or has been changed", body = ErrorResponse),
+        (status = 409, description = "The story has changed too much for the session state to fit it", body = ErrorResponse),
+    ),
+)]
+pub async fn get_current(
+    State(state): State<AppState>,
+    token: StateToken,
+) -> Result<Json<StatelessResponse>, ApiError> {
+    let (story, session_state, session) = open(&state, &token)?;
+    Ok(respond(&state, &story, session_state, &session))
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/stateless/choose/{option}",
+    tag = "stateless",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("option" = String, Path, description = "The id of the choice to take"),
+        ("X-Session-State" = String, Header, description = "The state returned by the last request"),
+    ),
+    responses(
+        (status = 200, description = "The choice was taken", body = StatelessResponse),
+        (status = 400, description = "The choice isn't available", body = ErrorResponse),
+        (status = 401, description = "The session state is missing", body = ErrorResponse),
+        (status = 403, description = "The session state wasn't made by this server, or has been changed", body = ErrorResponse),
+        (status = 409, description = "The story has changed too much for the session state to fit it", body = ErrorResponse),
+    ),
+)]
+pub async fn choose_option(
+    State(state): State<AppState>,
+    Path(option): Path<String>,
+    token: StateToken,
+) -> Result<Json<StatelessResponse>, ApiError> {
+    let (story, mut session_state, mut session) = open(&state, &token)?;
+    let from_node_id = session.current_node_id().to_string();
+    let result = story.choose_option(&mut session, option.clone());
+    if !matches!(result, ChoiceResult::Success) {
+        return Err(api_error(crate::choice_status(&result), result));
+    }
+    session_state.record_choice(&option);
+    state.record_choice(&session_state.session_id, &from_node_id, &session);
+
+    Ok(respond(&state, &story, session_state, &session))
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/stateless/restart",
+    tag = "stateless",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("X-Session-State" = String, Header, description = "The state returned by the last request"),
+    ),
+    responses(
+        (status = 200, description = "The session is back at the start of the story", body = StatelessResponse),
+        (status = 401, description = "The session state is missing", body = ErrorResponse),
+        (status = 403, description = "The session state wasn't made by this server, or has been changed", body = ErrorResponse),
+    ),
+)]
+pub async fn restart_session(
+    State(state): State<AppState>,
+    token: StateToken,
+) -> Result<Json<StatelessResponse>, ApiError> {
+    let (story, mut session_state, mut session) = open(&state, &token)?;
+    story.restart_session(&mut session);
+    state.record_visit(&session_state.session_id, &session);
+    session_state.history_hash = String::new();
+
+    Ok(respond(&state, &story, session_state, &session))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn session_state() -> SessionState {
+        SessionState {
+            session_id: "session".to_string(),
+            story_id: "story".to_string(),
+            snapshot: SessionSnapshot {
+                current_node_id: "START".to_string(),
+                variables: HashMap::new(),
+                variant: None,
+                turns: 2,
+            },
+            history_hash: String::new(),
+        }
+    }
+
+    #[test]
+    fn signed_state_is_read_back() {
+        let key = signing_key(&"k".repeat(MIN_SECRET_LEN));
+        let mut state = session_state();
+        state.record_choice("left_path");
+
+        let read = SessionState::verify(&state.sign(&key), &key).unwrap();
+        assert_eq!(read.session_id, "session");
+        assert_eq!(read.snapshot.turns, 2);
+        assert_eq!(read.history_hash, state.history_hash);
+        assert_ne!(read.history_hash, session_state().history_hash);
+    }
+
+    #[test]
+    fn changed_or_foreign_state_is_refused() {
+        let key = signing_key(&"k".repeat(MIN_SECRET_LEN));
+        let token = session_state().sign(&key);
+
+        let other_key = signing_key(&"o".repeat(MIN_SECRET_LEN));
+        assert!(SessionState::verify(&token, &other_key).is_none());
+
+        let (payload, tag) = token.split_once('.').unwrap();
+        let mut json: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap();
+        json["current_node_id"] = "end".into();
+        let forged = format!(
+            "{}.{tag}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&json).unwrap())
+        );
+        assert!(SessionState::verify(&forged, &key).is_none());
+        assert!(SessionState::verify("not a token", &key).is_none());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::read_story_parts;
+use cyoa::{
+    Analysis, Value,
+    engine::parser::{Command, Expression, FormatString, FormatStringPart, Node, ProgramPart},
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct StatsArgs {
+    /// The story file to summarise
+    source: PathBuf,
+}
+
+/// Where a variable appears in a story.
+#[derive(Default)]
+struct VariableUsage {
+    /// Nodes whose text or choices read the variable.
+    read_in: BTreeSet<String>,
+    /// Nodes with choices that set the variable.
+    set_in: BTreeSet<String>,
+}
+
+/// Print a summary of the size and shape of a story.
+pub fn run(args: StatsArgs) -> ExitCode {
+    let parts = match read_story_parts(&args.source) {
+        Ok(parts) => parts,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let nodes: Vec<(&str, &Node)> = parts
+        .iter()
+        .filter_map(|part| match part {
+            ProgramPart::NodeDefinition { id, node } => Some((id.as_str(), node)),
+            _ => None,
+        })
+        .collect();
+    let variables: Vec<(&str, &Value)> = parts
+        .iter()
+        .filter_map(|part| match part {
+            ProgramPart::VariableDefinition { name, value } => Some((name.as_str(), value)),
+            _ => None,
+        })
+        .collect();
+
+    let choice_count: usize = nodes.iter().map(|(_, node)| node.choices.len()).sum();
+    let word_count: usize = nodes
+        .iter()
+        .map(|(_, node)| {
+            word_count(&node.display_text)
+                + node
+                    .choices
+                    .iter()
+                    .map(|choice| word_count(&choice.text))
+                    .sum::<usize>()
+        })
+        .sum();
+    let endings: Vec<&str> = nodes
+        .iter()
+        .filter(|(_, node)| node.choices.is_empty())
+        .map(|(id, _)| *id)
+        .collect();
+
+    println!("Nodes:     {}", nodes.len());
+    println!("Choices:   {choice_count}");
+    println!("Words:     {word_count}");
+    println!("Endings:   {}", endings.len());
+
+    // Paths are measured to each ending by the fewest choices that could lead to it, ignoring
+    // requirements, so the longest path is the distance to the farthest ending rather than
+    // the longest possible playthrough, which loops could make endless.
+    let analysis = Analysis::of(nodes.iter().copied());
+    let ending_distances: Vec<usize> = endings
+        .iter()
+        .filter_map(|id| analysis.node(id)?.distance)
+        .collect();
+    match (ending_distances.iter().min(), ending_distances.iter().max()) {
+        (Some(shortest), Some(longest)) => {
+            println!(
+                "Shortest path to an ending: {}",
+                plural(*shortest, "choice")
+            );
+            println!("Longest path to an ending:  {}", plural(*longest, "choice"));
+        }
+        _ => println!("No ending can be reached from START."),
+    }
+
+    let mut usages: HashMap<&str, VariableUsage> = variables
+        .iter()
+        .map(|(name, _)| (*name, VariableUsage::default()))
+        .collect();
+    for (id, node) in &nodes {
+        let mut read = Vec::new();
+        names_in_string(&node.display_text, &mut read);
+        for choice in &node.choices {
+            names_in_string(&choice.text, &mut read);
+            if let Some(requirement) = &choice.requirement {
+                names_in_expression(requirement, &mut read);
+            }
+            if let Some(Command::Call { args, .. }) = &choice.command {
+                for arg in args {
+                    names_in_expression(arg, &mut read);
+                }
+            }
+            if let Some(Command::Set { name, .. }) = &choice.command
+                && let Some(usage) = usages.get_mut(name.as_str())
+            {
+                usage.set_in.insert(id.to_string());
+            }
+        }
+        for name in read {
+            if let Some(usage) = usages.get_mut(name) {
+                usage.read_in.insert(id.to_string());
+            }
+        }
+    }
+
+    println!("\nVariables: {}", variables.len());
+    for (name, value) in &variables {
+        let usage = &usages[name];
+        println!("  {name} = {value}");
+        println!("    read in: {}", list_or_none(&usage.read_in));
+        println!("    set in:  {}", list_or_none(&usage.set_in));
+    }
+
+    ExitCode::SUCCESS
+}
+
+fn word_count(text: &FormatString) -> usize {
+    text.to_string().split_whitespace().count()
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("{count} {noun}")
+    } else {
+        format!("{count} {noun}s")
+    }
+}
+
+fn list_or_none(ids: &BTreeSet<String>) -> String {
+    if ids.is_empty() {
+        "(nowhere)".to_string()
+    } else {
+        ids.iter().cloned().collect::<Vec<_>>().join(", ")
+    }
+}
+
+fn names_in_string<'a>(text: &'a FormatString, names: &mut Vec<&'a str>) {
+    for part in &text.0 {
+        if let FormatStringPart::Name(name) = part {
+            names.push(name);
+        }
+    }
+}
+
+fn names_in_expression<'a>(expression: &'a Expression, names: &mut Vec<&'a str>) {
+    match expression {
+        Expression::Value(Value::String(text)) => names_in_string(text, names),
+        Expression::Value(_) => {}
+        Expression::Name(name) => names.push(name),
+        Expression::Equals { left, right }
+        | Expression::NotEquals { left, right }
+        | Expression::GreaterThan { left, right }
+        | Expression::LessThan { left, right } => {
+            names_in_expression(left, names);
+            names_in_expression(right, names);
+        }
+        Expression::Call { args, .. } => {
+            for arg in args {
+                names_in_expression(arg, names);
+            }
+        }
+    }
+}
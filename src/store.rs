@@ -0,0 +1,107 @@
+mod memory;
+mod redis;
+mod sqlite;
+
+use async_trait::async_trait;
+use cyoa::Session;
+pub use memory::MemoryStore;
+pub use redis::RedisStore;
+pub use sqlite::SqliteStore;
+use std::fmt::Display;
+
+/// Why a store couldn't do what it was asked.
+#[derive(Debug)]
+pub enum StoreError {
+    /// The session was written by someone else, e.g. another server sharing the store, since
+    /// it was read, so it wasn't overwritten.
+    Conflict,
+    /// The database couldn't be reached or failed, with what it said.
+    Unavailable(String),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Conflict => f.write_str("The session was changed since it was read."),
+            Self::Unavailable(message) => {
+                f.write_fmt(format_args!("The session store failed: {message}"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Somewhere to keep sessions between requests.
+///
+/// Sessions are handed out and written back by value, so handlers follow a
+/// `get` -> mutate -> `update` pattern and never hold on to backend internals. The server
+/// holds a lock on the session from `get` to `update`, so requests to one server don't
+/// interleave. Servers sharing a store are kept apart by `update`, which only writes a session
+/// that is still at the version it was read at.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn get(&self, session_id: &str) -> StoreResult<Option<Session>>;
+
+    async fn insert(&self, session_id: &str, session: Session) -> StoreResult<()>;
+
+    /// Overwrite a session that still exists and is at `read_version`, the
+    /// [`Session::version`] it had when it was read. Fails with [`StoreError::Conflict`] if it
+    /// has been written since.
+    async fn update(
+        &self,
+        session_id: &str,
+        session: &Session,
+        read_version: u64,
+    ) -> StoreResult<()>;
+
+    /// Mark a session as active now without writing the rest of it, for requests that only
+    /// read it.
+    async fn touch(&self, session_id: &str) -> StoreResult<()>;
+
+    /// Returns whether a session with the given id existed.
+    async fn remove(&self, session_id: &str) -> StoreResult<bool>;
+
+    /// Remove every session that has been inactive for at least the given number of hours,
+    /// returning the ids of the removed sessions.
+    async fn sweep_expired(&self, session_timeout_hours: f32) -> StoreResult<Vec<String>>;
+
+    /// How many sessions there are, for every story.
+    async fn count(&self) -> StoreResult<usize>;
+
+    /// Up to `limit` sessions with their ids, skipping the first `offset`, in order of id.
+    async fn list(&self, offset: usize, limit: usize) -> StoreResult<Vec<(String, Session)>>;
+
+    /// Remove the session that has been inactive for the longest, returning its id.
+    async fn evict_least_recently_active(&self) -> StoreResult<Option<String>>;
+
+    /// Remember a token that lets spectators look up a session without knowing its id.
+    async fn insert_spectator_token(&self, token: &str, session_id: &str) -> StoreResult<()>;
+
+    /// The id of the session a spectator token was handed out for.
+    async fn spectated_session(&self, token: &str) -> StoreResult<Option<String>>;
+}
+
+/// Open the session store described by a URL such as `sqlite://sessions.db` or
+/// `redis://127.0.0.1:6379`.
+pub async fn open_store(
+    url: &str,
+    session_timeout_hours: f32,
+) -> Result<Box<dyn SessionStore>, String> {
+    if let Some(path) = url.strip_prefix("sqlite://") {
+        let store = SqliteStore::open(path)
+            .map_err(|e| format!("Failed to open session store '{url}': {e}"))?;
+        Ok(Box::new(store))
+    } else if url.starts_with("redis://") || url.starts_with("rediss://") {
+        let store = RedisStore::open(url, session_timeout_hours)
+            .await
+            .map_err(|e| format!("Failed to open session store '{url}': {e}"))?;
+        Ok(Box::new(store))
+    } else {
+        Err(format!(
+            "Unsupported store '{url}', expected 'sqlite://path/to/sessions.db' or 'redis://host:port'"
+        ))
+    }
+}
@@ -0,0 +1,214 @@
+use super::{SessionStore, StoreError, StoreResult};
+use async_trait::async_trait;
+use cyoa::Session;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+/// How many expired sessions are removed before other tasks get a turn.
+const SWEEP_BATCH: usize = 256;
+
+/// Sessions kept in memory only. Everything is lost when the server stops, unless it is saved
+/// to a file with [`MemoryStore::save`].
+///
+/// Sessions are spread over the shards of a [`DashMap`], so requests for different sessions
+/// rarely wait for each other.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: DashMap<String, Session>,
+    spectator_tokens: DashMap<String, String>,
+}
+
+/// Everything in a [`MemoryStore`], as written to a session file.
+#[derive(Default, Serialize, Deserialize)]
+struct SessionFile {
+    sessions: HashMap<String, Session>,
+    spectator_tokens: HashMap<String, String>,
+}
+
+impl MemoryStore {
+    /// Read the sessions saved to `path`, keeping only those that `keep` accepts along with
+    /// their spectator tokens. A missing file is read as no sessions.
+    pub fn load(path: &Path, keep: impl Fn(&str, &Session) -> bool) -> Result<Self, String> {
+        let mut file = match fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str::<SessionFile>(&data)
+                .map_err(|e| format!("Failed to read session file '{}': {e}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SessionFile::default(),
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read session file '{}': {e}",
+                    path.display()
+                ));
+            }
+        };
+        file.sessions
+            .retain(|session_id, session| keep(session_id, session));
+        file.spectator_tokens
+            .retain(|_, session_id| file.sessions.contains_key(session_id));
+
+        Ok(MemoryStore {
+            sessions: file.sessions.into_iter().collect(),
+            spectator_tokens: file.spectator_tokens.into_iter().collect(),
+        })
+    }
+
+    /// How many sessions are held, which unlike [`SessionStore::count`] can't fail.
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Write every session to `path`, to be read back with [`MemoryStore::load`]. The file is
+    /// replaced in one step, so it is never left half written.
+    pub async fn save(&self, path: &Path) -> Result<(), String> {
+        let file = SessionFile {
+            sessions: self
+                .sessions
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect(),
+            spectator_tokens: self
+                .spectator_tokens
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect(),
+        };
+        let data = serde_json::to_string(&file).expect("Failed to serialize sessions");
+
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, data)
+            .and_then(|()| fs::rename(&temp_path, path))
+            .map_err(|e| format!("Failed to write session file '{}': {e}", path.display()))
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn get(&self, session_id: &str) -> StoreResult<Option<Session>> {
+        Ok(self
+            .sessions
+            .get(session_id)
+            .map(|session| session.value().clone()))
+    }
+
+    async fn insert(&self, session_id: &str, session: Session) -> StoreResult<()> {
+        self.sessions.insert(session_id.to_string(), session);
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        session_id: &str,
+        session: &Session,
+        read_version: u64,
+    ) -> StoreResult<()> {
+        if let Some(mut existing) = self.sessions.get_mut(session_id) {
+            if existing.version() != read_version {
+                return Err(StoreError::Conflict);
+            }
+            *existing = session.clone();
+        }
+        Ok(())
+    }
+
+    async fn touch(&self, session_id: &str) -> StoreResult<()> {
+        if let Some(mut session) = self.sessions.get_mut(session_id) {
+            session.update_last_active_at();
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, session_id: &str) -> StoreResult<bool> {
+        self.spectator_tokens
+            .retain(|_, spectated| spectated != session_id);
+        Ok(self.sessions.remove(session_id).is_some())
+    }
+
+    /// Expired sessions are found while only reading each shard, then removed a batch at a
+    /// time, so requests for other sessions aren't held up by a large sweep.
+    async fn sweep_expired(&self, session_timeout_hours: f32) -> StoreResult<Vec<String>> {
+        let candidates: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|entry| entry.value().is_expired(session_timeout_hours))
+            .map(|entry| entry.key().clone())
+            .collect();
+
+        let mut expired_sessions = Vec::new();
+        for batch in candidates.chunks(SWEEP_BATCH) {
+            for session_id in batch {
+                // A session may have been played since it was found.
+                if self
+                    .sessions
+                    .remove_if(session_id, |_, session| {
+                        session.is_expired(session_timeout_hours)
+                    })
+                    .is_some()
+                {
+                    expired_sessions.push(session_id.clone());
+                }
+            }
+            tokio::task::yield_now().await;
+        }
+
+        if !expired_sessions.is_empty() {
+            let expired: HashSet<&str> = expired_sessions.iter().map(String::as_str).collect();
+            self.spectator_tokens
+                .retain(|_, spectated| !expired.contains(spectated.as_str()));
+        }
+
+        Ok(expired_sessions)
+    }
+
+    async fn count(&self) -> StoreResult<usize> {
+        Ok(self.sessions.len())
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> StoreResult<Vec<(String, Session)>> {
+        let mut session_ids: Vec<String> = self
+            .sessions
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        session_ids.sort();
+        Ok(session_ids
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .filter_map(|session_id| {
+                // Sessions removed since their ids were collected are left out.
+                let session = self.sessions.get(&session_id)?.value().clone();
+                Some((session_id, session))
+            })
+            .collect())
+    }
+
+    async fn evict_least_recently_active(&self) -> StoreResult<Option<String>> {
+        let Some(session_id) = self
+            .sessions
+            .iter()
+            .min_by_key(|entry| entry.value().last_active_at())
+            .map(|entry| entry.key().clone())
+        else {
+            return Ok(None);
+        };
+        Ok(self.remove(&session_id).await?.then_some(session_id))
+    }
+
+    async fn insert_spectator_token(&self, token: &str, session_id: &str) -> StoreResult<()> {
+        self.spectator_tokens
+            .insert(token.to_string(), session_id.to_string());
+        Ok(())
+    }
+
+    async fn spectated_session(&self, token: &str) -> StoreResult<Option<String>> {
+        Ok(self
+            .spectator_tokens
+            .get(token)
+            .map(|session_id| session_id.value().clone()))
+    }
+}
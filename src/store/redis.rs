@@ -0,0 +1,241 @@
+use super::{SessionStore, StoreError, StoreResult};
+use async_trait::async_trait;
+use cyoa::Session;
+use redis::{AsyncCommands, Client, RedisError, RedisResult, Script, aio::ConnectionManager};
+use std::{
+    sync::LazyLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const KEY_PREFIX: &str = "cyoa:session:";
+const SPECTATOR_KEY_PREFIX: &str = "cyoa:spectate:";
+/// A sorted set of every session's id, scored by when it was last active in milliseconds
+/// since the Unix epoch, so sessions can be counted and evicted without scanning every key.
+const ACTIVITY_KEY: &str = "cyoa:sessions";
+
+/// Overwrite a session if it still exists and is at the version it was read at, and record
+/// it as active. Returns 1 if it was written, 0 if it has gone and -1 if it has moved on.
+static UPDATE: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+local data = redis.call('GET', KEYS[1])
+if not data then
+    return 0
+end
+if (cjson.decode(data).version or 0) ~= tonumber(ARGV[2]) then
+    return -1
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[3])
+redis.call('ZADD', KEYS[2], ARGV[4], ARGV[5])
+return 1
+",
+    )
+});
+
+/// Refresh a session's TTL and record it as active, if it still exists.
+static TOUCH: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r"
+if redis.call('EXPIRE', KEYS[1], ARGV[1]) == 1 then
+    redis.call('ZADD', KEYS[2], ARGV[2], ARGV[3])
+end
+return 0
+",
+    )
+});
+
+/// Sessions stored in Redis, so several server instances can share them.
+/// Each key is given a TTL of the session timeout, which is refreshed on every
+/// write, so Redis expires inactive sessions by itself. Sessions stored before the activity
+/// index was kept join it the next time they are played.
+pub struct RedisStore {
+    conn: ConnectionManager,
+    ttl_seconds: u64,
+}
+
+impl From<RedisError> for StoreError {
+    fn from(e: RedisError) -> Self {
+        StoreError::Unavailable(e.to_string())
+    }
+}
+
+fn key(session_id: &str) -> String {
+    format!("{KEY_PREFIX}{session_id}")
+}
+
+fn spectator_key(token: &str) -> String {
+    format!("{SPECTATOR_KEY_PREFIX}{token}")
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl RedisStore {
+    pub async fn open(url: &str, session_timeout_hours: f32) -> RedisResult<Self> {
+        let client = Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+        let ttl_seconds = ((session_timeout_hours * 60.0 * 60.0) as u64).max(1);
+
+        Ok(RedisStore { conn, ttl_seconds })
+    }
+
+    /// Take the sessions Redis has expired out of the activity index, returning their ids.
+    async fn forget_expired(&self) -> StoreResult<Vec<String>> {
+        let cutoff = unix_millis(SystemTime::now()).saturating_sub(self.ttl_seconds * 1000);
+        let (expired, ()): (Vec<String>, ()) = redis::pipe()
+            .atomic()
+            .zrangebyscore(ACTIVITY_KEY, "-inf", cutoff)
+            .zrembyscore(ACTIVITY_KEY, "-inf", cutoff)
+            .ignore()
+            .query_async(&mut self.conn.clone())
+            .await?;
+
+        Ok(expired)
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisStore {
+    /// [`SessionStore::touch`] only refreshes a session's TTL, so when it was last active is
+    /// worked out from how much of the TTL is left.
+    async fn get(&self, session_id: &str) -> StoreResult<Option<Session>> {
+        let (data, ttl_millis): (Option<String>, i64) = redis::pipe()
+            .get(key(session_id))
+            .pttl(key(session_id))
+            .query_async(&mut self.conn.clone())
+            .await?;
+
+        let Some(mut session) = data.and_then(|data| serde_json::from_str::<Session>(&data).ok())
+        else {
+            return Ok(None);
+        };
+        if let Ok(ttl_millis) = u64::try_from(ttl_millis) {
+            let idle = Duration::from_secs(self.ttl_seconds)
+                .saturating_sub(Duration::from_millis(ttl_millis));
+            session.set_last_active_at(SystemTime::now() - idle);
+        }
+        Ok(Some(session))
+    }
+
+    async fn insert(&self, session_id: &str, session: Session) -> StoreResult<()> {
+        let data = serde_json::to_string(&session).expect("Failed to serialize session");
+        let () = redis::pipe()
+            .atomic()
+            .set_ex(key(session_id), data, self.ttl_seconds)
+            .ignore()
+            .zadd(ACTIVITY_KEY, session_id, unix_millis(SystemTime::now()))
+            .ignore()
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    /// The version is checked and the session written in one script, so another server can't
+    /// write the session in between.
+    async fn update(
+        &self,
+        session_id: &str,
+        session: &Session,
+        read_version: u64,
+    ) -> StoreResult<()> {
+        let data = serde_json::to_string(session).expect("Failed to serialize session");
+        let written: i64 = UPDATE
+            .key(key(session_id))
+            .key(ACTIVITY_KEY)
+            .arg(data)
+            .arg(read_version)
+            .arg(self.ttl_seconds)
+            .arg(unix_millis(SystemTime::now()))
+            .arg(session_id)
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+
+        // A session that has gone isn't a conflict, it just isn't brought back.
+        if written < 0 {
+            Err(StoreError::Conflict)
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn touch(&self, session_id: &str) -> StoreResult<()> {
+        let _: i64 = TOUCH
+            .key(key(session_id))
+            .key(ACTIVITY_KEY)
+            .arg(self.ttl_seconds)
+            .arg(unix_millis(SystemTime::now()))
+            .arg(session_id)
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, session_id: &str) -> StoreResult<bool> {
+        let (removed,): (usize,) = redis::pipe()
+            .atomic()
+            .del(key(session_id))
+            .zrem(ACTIVITY_KEY, session_id)
+            .ignore()
+            .query_async(&mut self.conn.clone())
+            .await?;
+
+        Ok(removed > 0)
+    }
+
+    /// Redis expires sessions itself, so this only takes them out of the activity index.
+    async fn sweep_expired(&self, _session_timeout_hours: f32) -> StoreResult<Vec<String>> {
+        self.forget_expired().await
+    }
+
+    async fn count(&self) -> StoreResult<usize> {
+        self.forget_expired().await?;
+        Ok(self.conn.clone().zcard(ACTIVITY_KEY).await?)
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> StoreResult<Vec<(String, Session)>> {
+        self.forget_expired().await?;
+        let mut session_ids: Vec<String> = self.conn.clone().zrange(ACTIVITY_KEY, 0, -1).await?;
+        session_ids.sort();
+        let mut sessions = Vec::new();
+        for session_id in session_ids.into_iter().skip(offset).take(limit) {
+            // Sessions can expire between listing the ids and reading them.
+            if let Some(session) = self.get(&session_id).await? {
+                sessions.push((session_id, session));
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    async fn evict_least_recently_active(&self) -> StoreResult<Option<String>> {
+        self.forget_expired().await?;
+        let least_recent: Vec<String> = self.conn.clone().zrange(ACTIVITY_KEY, 0, 0).await?;
+        let Some(session_id) = least_recent.into_iter().next() else {
+            return Ok(None);
+        };
+        Ok(self.remove(&session_id).await?.then_some(session_id))
+    }
+
+    async fn insert_spectator_token(&self, token: &str, session_id: &str) -> StoreResult<()> {
+        // A token outliving its session is harmless, since looking the session up fails.
+        let () = self
+            .conn
+            .clone()
+            .set_ex(spectator_key(token), session_id, self.ttl_seconds)
+            .await?;
+        Ok(())
+    }
+
+    async fn spectated_session(&self, token: &str) -> StoreResult<Option<String>> {
+        // Spectators following along keep the token alive, like players keep their session.
+        Ok(redis::cmd("GETEX")
+            .arg(spectator_key(token))
+            .arg("EX")
+            .arg(self.ttl_seconds)
+            .query_async(&mut self.conn.clone())
+            .await?)
+    }
+}
@@ -0,0 +1,294 @@
+use super::{SessionStore, StoreError, StoreResult};
+use async_trait::async_trait;
+use cyoa::Session;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Sessions persisted to a SQLite database so they survive a server restart.
+/// Every session is read from and written to the database on access, on a thread that is
+/// allowed to block, so the server's other requests carry on while the database is busy.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Unavailable(e.to_string())
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// A session read back from its row. The `last_active_at` column is kept up to date by
+/// [`SessionStore::touch`] without rewriting `data`, so it takes precedence.
+fn session_from_row(data: &str, last_active_at: f64) -> Option<Session> {
+    let mut session: Session = serde_json::from_str(data).ok()?;
+    session.set_last_active_at(UNIX_EPOCH + Duration::from_secs_f64(last_active_at.max(0.0)));
+    Some(session)
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                last_active_at REAL NOT NULL,
+                data TEXT NOT NULL
+            )",
+            (),
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS spectator_tokens (
+                token TEXT PRIMARY KEY,
+                session_id TEXT NOT NULL
+            )",
+            (),
+        )?;
+
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run `query` against the database on a blocking thread.
+    async fn query<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> StoreResult<T> {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || query(&conn.lock().unwrap()))
+            .await
+            .map_err(|e| StoreError::Unavailable(e.to_string()))?
+            .map_err(StoreError::from)
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn get(&self, session_id: &str) -> StoreResult<Option<Session>> {
+        let session_id = session_id.to_string();
+        let row: Option<(String, f64)> = self
+            .query(move |conn| {
+                conn.query_row(
+                    "SELECT data, last_active_at FROM sessions WHERE id = ?1",
+                    params![session_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+            })
+            .await?;
+
+        Ok(row.and_then(|(data, last_active_at)| session_from_row(&data, last_active_at)))
+    }
+
+    async fn insert(&self, session_id: &str, session: Session) -> StoreResult<()> {
+        let session_id = session_id.to_string();
+        let data = serde_json::to_string(&session).expect("Failed to serialize session");
+        let last_active_at = unix_seconds(session.last_active_at());
+        self.query(move |conn| {
+            conn.execute(
+                "INSERT INTO sessions (id, last_active_at, data) VALUES (?1, ?2, ?3)
+                ON CONFLICT(id) DO UPDATE SET last_active_at = ?2, data = ?3",
+                params![session_id, last_active_at, data],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// The version is read from the saved session, so databases written before it was
+    /// checked need no new column.
+    async fn update(
+        &self,
+        session_id: &str,
+        session: &Session,
+        read_version: u64,
+    ) -> StoreResult<()> {
+        let session_id = session_id.to_string();
+        let data = serde_json::to_string(session).expect("Failed to serialize session");
+        let last_active_at = unix_seconds(session.last_active_at());
+        let conflict = self
+            .query(move |conn| {
+                let updated = conn.execute(
+                    "UPDATE sessions SET last_active_at = ?2, data = ?3
+                    WHERE id = ?1 AND coalesce(json_extract(data, '$.version'), 0) = ?4",
+                    params![session_id, last_active_at, data, read_version as i64],
+                )?;
+                // A session that has gone isn't a conflict, it just isn't brought back.
+                if updated > 0 {
+                    return Ok(false);
+                }
+                conn.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM sessions WHERE id = ?1)",
+                    params![session_id],
+                    |row| row.get(0),
+                )
+            })
+            .await?;
+
+        if conflict {
+            Err(StoreError::Conflict)
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn touch(&self, session_id: &str) -> StoreResult<()> {
+        let session_id = session_id.to_string();
+        let now = unix_seconds(SystemTime::now());
+        self.query(move |conn| {
+            conn.execute(
+                "UPDATE sessions SET last_active_at = ?2 WHERE id = ?1",
+                params![session_id, now],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, session_id: &str) -> StoreResult<bool> {
+        let session_id = session_id.to_string();
+        let removed = self
+            .query(move |conn| {
+                conn.execute(
+                    "DELETE FROM spectator_tokens WHERE session_id = ?1",
+                    params![session_id],
+                )?;
+                conn.execute("DELETE FROM sessions WHERE id = ?1", params![session_id])
+            })
+            .await?;
+
+        Ok(removed > 0)
+    }
+
+    async fn sweep_expired(&self, session_timeout_hours: f32) -> StoreResult<Vec<String>> {
+        let cutoff = unix_seconds(SystemTime::now()) - session_timeout_hours as f64 * 60.0 * 60.0;
+        self.query(move |conn| {
+            let expired_sessions = conn
+                .prepare("DELETE FROM sessions WHERE last_active_at <= ?1 RETURNING id")?
+                .query_map(params![cutoff], |row| row.get(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            conn.execute(
+                "DELETE FROM spectator_tokens WHERE session_id NOT IN (SELECT id FROM sessions)",
+                (),
+            )?;
+
+            Ok(expired_sessions)
+        })
+        .await
+    }
+
+    async fn count(&self) -> StoreResult<usize> {
+        let count: i64 = self
+            .query(|conn| conn.query_row("SELECT COUNT(*) FROM sessions", (), |row| row.get(0)))
+            .await?;
+        Ok(count as usize)
+    }
+
+    async fn list(&self, offset: usize, limit: usize) -> StoreResult<Vec<(String, Session)>> {
+        let rows: Vec<(String, String, f64)> = self
+            .query(move |conn| {
+                conn.prepare(
+                    "SELECT id, data, last_active_at FROM sessions ORDER BY id LIMIT ?1 OFFSET ?2",
+                )?
+                .query_map(params![limit as i64, offset as i64], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .collect()
+            })
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|(session_id, data, last_active_at)| {
+                Some((session_id, session_from_row(&data, last_active_at)?))
+            })
+            .collect())
+    }
+
+    async fn evict_least_recently_active(&self) -> StoreResult<Option<String>> {
+        let session_id: Option<String> = self
+            .query(|conn| {
+                conn.query_row(
+                    "SELECT id FROM sessions ORDER BY last_active_at LIMIT 1",
+                    (),
+                    |row| row.get(0),
+                )
+                .optional()
+            })
+            .await?;
+        let Some(session_id) = session_id else {
+            return Ok(None);
+        };
+        Ok(self.remove(&session_id).await?.then_some(session_id))
+    }
+
+    async fn insert_spectator_token(&self, token: &str, session_id: &str) -> StoreResult<()> {
+        let (token, session_id) = (token.to_string(), session_id.to_string());
+        self.query(move |conn| {
+            conn.execute(
+                "INSERT INTO spectator_tokens (token, session_id) VALUES (?1, ?2)",
+                params![token, session_id],
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn spectated_session(&self, token: &str) -> StoreResult<Option<String>> {
+        let token = token.to_string();
+        self.query(move |conn| {
+            conn.query_row(
+                "SELECT session_id FROM spectator_tokens WHERE token = ?1",
+                params![token],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cyoa::Engine;
+
+    #[tokio::test]
+    async fn stale_updates_are_conflicts() {
+        let story = Engine::from_program("= START\n    \"Hello.\"\n").unwrap();
+        let store = SqliteStore::open(":memory:").unwrap();
+        let mut session = story.new_session();
+        store.insert("session", session.clone()).await.unwrap();
+
+        let read_version = session.version();
+        session.bump_version();
+        store
+            .update("session", &session, read_version)
+            .await
+            .unwrap();
+        let saved = store.get("session").await.unwrap().unwrap();
+        assert_eq!(saved.version(), read_version + 1);
+
+        // Another server saved the session after this one read it.
+        assert!(matches!(
+            store.update("session", &session, read_version).await,
+            Err(StoreError::Conflict)
+        ));
+
+        store.remove("session").await.unwrap();
+        store
+            .update("session", &session, read_version)
+            .await
+            .unwrap();
+        assert!(store.get("session").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{StorySettings, build_story};
+use cyoa::{ChoiceResult, Engine};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct TestArgs {
+    /// The story file to test
+    source: PathBuf,
+    /// Test files, or directories of `.cyoatest` files
+    #[arg(required = true)]
+    tests: Vec<PathBuf>,
+}
+
+/// One line of a test file.
+enum Step {
+    Choose(String),
+    Back,
+    Restart,
+    ExpectNode(String),
+    ExpectTextContains(String),
+    /// The expected value is written as it would be in the story, e.g. `15` or `"rusty key"`.
+    ExpectVariable {
+        name: String,
+        value: String,
+    },
+}
+
+fn parse_step(line: &str) -> Result<Step, String> {
+    let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    match keyword {
+        "choose" if !rest.is_empty() => Ok(Step::Choose(rest.to_string())),
+        "back" if rest.is_empty() => Ok(Step::Back),
+        "restart" if rest.is_empty() => Ok(Step::Restart),
+        "expect" => {
+            if let Some(text) = rest.strip_prefix("text contains ") {
+                let text = text.trim();
+                let text = text
+                    .strip_prefix('"')
+                    .and_then(|text| text.strip_suffix('"'))
+                    .ok_or_else(|| format!("Expected a quoted string, found '{text}'."))?;
+                return Ok(Step::ExpectTextContains(text.to_string()));
+            }
+
+            let (name, value) = rest
+                .split_once('=')
+                .ok_or_else(|| format!("Expected 'NAME = VALUE', found '{rest}'."))?;
+            let (name, value) = (name.trim(), value.trim());
+            if name == "node" {
+                Ok(Step::ExpectNode(value.to_string()))
+            } else {
+                Ok(Step::ExpectVariable {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+            }
+        }
+        _ => Err(format!("Unknown step '{line}'.")),
+    }
+}
+
+/// Run every step in a test file against a new session, stopping at the first failure.
+/// Failures are returned with the line number they happened on.
+fn run_test(story: &Engine, test: &str) -> Result<(), (usize, String)> {
+    let mut session = story.new_session();
+    for (i, line) in test.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fail = |message: String| (i + 1, message);
+        let view = story.get_current_node_view(&session);
+        match parse_step(line).map_err(fail)? {
+            Step::Choose(choice_id) => {
+                let result = story.choose_option(&mut session, choice_id);
+                if !matches!(result, ChoiceResult::Success) {
+                    return Err(fail(result.to_string()));
+                }
+            }
+            Step::Back => {
+                if !story.go_back(&mut session) {
+                    return Err(fail("There is no choice to go back from.".to_string()));
+                }
+            }
+            Step::Restart => story.restart_session(&mut session),
+            Step::ExpectNode(node_id) => {
+                if session.current_node_id() != node_id {
+                    return Err(fail(format!(
+                        "Expected to be at the node with id '{node_id}', but was at '{}'.",
+                        session.current_node_id()
+                    )));
+                }
+            }
+            Step::ExpectTextContains(text) => {
+                if !view.display_text.contains(&text) {
+                    return Err(fail(format!(
+                        "Expected the text to contain \"{text}\", but it was \"{}\".",
+                        view.display_text
+                    )));
+                }
+            }
+            Step::ExpectVariable { name, value } => match session.variable(&name) {
+                Some(actual) if actual.to_string() == value => {}
+                Some(actual) => {
+                    return Err(fail(format!(
+                        "Expected '{name}' to be {value}, but it was {actual}."
+                    )));
+                }
+                None => {
+                    return Err(fail(format!("There is no variable with name '{name}'.")));
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+fn find_test_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let entries = fs::read_dir(path)
+                .map_err(|e| format!("Failed to read directory '{}': {e}", path.display()))?;
+            let mut found: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "cyoatest"))
+                .collect();
+            found.sort();
+            files.extend(found);
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Run test files against a story, failing if any of them fail.
+pub fn run(args: TestArgs) -> ExitCode {
+    let settings = StorySettings {
+        history_depth: usize::MAX,
+        max_checkpoints: 0,
+        max_transcript: usize::MAX,
+        #[cfg(feature = "plugins")]
+        plugins: None,
+    };
+    let files = build_story(&args.source, settings)
+        .and_then(|story| find_test_files(&args.tests).map(|files| (story, files)));
+    let (story, files) = match files {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut failures = 0;
+    for file in &files {
+        match read_test(file).and_then(|test| {
+            run_test(&story, &test)
+                .map_err(|(line, message)| format!("{}:{line}: {message}", file.display()))
+        }) {
+            Ok(()) => println!("PASS {}", file.display()),
+            Err(e) => {
+                failures += 1;
+                println!("FAIL {e}");
+            }
+        }
+    }
+
+    println!("\n{} passed, {failures} failed", files.len() - failures);
+    if failures == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn read_test(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map_err(|e| format!("{}: Failed to read test file: {e}", path.display()))
+}
@@ -0,0 +1,23 @@
+//! Serving HTTPS directly, with the certificate and key given by `--tls-cert` and `--tls-key`.
+
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::Path;
+
+/// Picks the cryptography used for TLS, both when serving HTTPS and when sending webhooks.
+pub fn install_crypto_provider() {
+    // Fails if a provider is already installed, which is just as good.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+}
+
+/// Reads a PEM certificate chain and the private key that goes with it.
+pub async fn load_config(cert: &Path, key: &Path) -> Result<RustlsConfig, String> {
+    install_crypto_provider();
+
+    RustlsConfig::from_pem_file(cert, key).await.map_err(|e| {
+        format!(
+            "Failed to load the TLS certificate '{}' and key '{}': {e}",
+            cert.display(),
+            key.display(),
+        )
+    })
+}
@@ -0,0 +1,83 @@
+use clap::ValueEnum;
+use cyoa::{TokenKind, tokenize};
+use serde::Serialize;
+use std::{fs, path::PathBuf, process::ExitCode};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TokensFormat {
+    /// One `line:column kind text` line per span
+    Text,
+    /// A list of spans, for editor plugins and other tools
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TokensArgs {
+    /// The story file to split up
+    source: PathBuf,
+    #[arg(long, value_enum, default_value_t = TokensFormat::Text)]
+    format: TokensFormat,
+}
+
+/// A span of the story, with its position given both ways. Lines and columns start at 1, and
+/// columns count characters.
+#[derive(Serialize)]
+struct Span<'a> {
+    kind: TokenKind,
+    text: &'a str,
+    start: usize,
+    end: usize,
+    line: usize,
+    column: usize,
+}
+
+/// Print a story's source as spans classified for syntax highlighting.
+pub fn run(args: TokensArgs) -> ExitCode {
+    let source = match fs::read_to_string(&args.source) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!(
+                "Failed to read source file '{}': {e}",
+                args.source.display()
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let spans: Vec<Span> = tokenize(&source)
+        .into_iter()
+        .map(|token| {
+            let before = &source[..token.start];
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            Span {
+                kind: token.kind,
+                text: &source[token.start..token.end],
+                start: token.start,
+                end: token.end,
+                line: before.matches('\n').count() + 1,
+                column: before[line_start..].chars().count() + 1,
+            }
+        })
+        .collect();
+
+    match args.format {
+        TokensFormat::Text => {
+            for span in &spans {
+                let kind = serde_json::to_value(span.kind).expect("Failed to serialize kind");
+                println!(
+                    "{}:{} {} {}",
+                    span.line,
+                    span.column,
+                    kind.as_str().unwrap_or_default(),
+                    span.text
+                );
+            }
+        }
+        TokensFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&spans).expect("Failed to serialize spans")
+        ),
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,233 @@
+use clap::ValueEnum;
+use cyoa::{
+    Analysis, Engine, ParseError,
+    engine::parser::{ProgramPart, parse_program},
+};
+use serde::Serialize;
+use std::{collections::HashMap, fs, path::PathBuf, process::ExitCode};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OutputFormat {
+    /// One `file:line:column: severity: message` line per problem
+    Text,
+    /// A single JSON object, for editors and other tools
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ValidateArgs {
+    /// The story file to check
+    source: PathBuf,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+}
+
+#[derive(Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem with a story. Lines and columns start at 1.
+#[derive(Serialize)]
+pub(crate) struct Diagnostic {
+    pub(crate) severity: Severity,
+    pub(crate) message: String,
+    pub(crate) line: Option<usize>,
+    pub(crate) column: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    file: &'a str,
+    valid: bool,
+    diagnostics: &'a [Diagnostic],
+}
+
+/// Check a story for errors and warnings. Fails if there are any errors.
+pub fn run(args: ValidateArgs) -> ExitCode {
+    let source = match fs::read_to_string(&args.source) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!(
+                "Failed to read source file '{}': {e}",
+                args.source.display()
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let diagnostics = validate(&source);
+    let valid = !diagnostics.iter().any(|d| d.severity == Severity::Error);
+    let file = args.source.display().to_string();
+    match args.format {
+        OutputFormat::Text => {
+            for diagnostic in &diagnostics {
+                let location = match (diagnostic.line, diagnostic.column) {
+                    (Some(line), Some(column)) => format!("{file}:{line}:{column}"),
+                    _ => file.clone(),
+                };
+                let severity = match diagnostic.severity {
+                    Severity::Error => "error",
+                    Severity::Warning => "warning",
+                };
+                println!("{location}: {severity}: {}", diagnostic.message);
+            }
+        }
+        OutputFormat::Json => {
+            let report = Report {
+                file: &file,
+                valid,
+                diagnostics: &diagnostics,
+            };
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&report).expect("Failed to serialize report")
+            );
+        }
+    }
+
+    if valid {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+pub(crate) fn validate(source: &str) -> Vec<Diagnostic> {
+    let locations = Locations::new(source);
+    let mut diagnostics = Vec::new();
+
+    let (rest, parts) = parse_program(source).expect("Failed to parse nodes");
+    if !rest.trim().is_empty() {
+        let (line, column) = locations.position(source.len() - rest.len());
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: "The story could not be parsed from here on, so everything after this point is ignored.".to_string(),
+            line: Some(line),
+            column: Some(column),
+        });
+    }
+
+    if let Err(errors) = Engine::from_program(source) {
+        for error in errors {
+            let position = match &error {
+                ParseError::BadReferenceInRename { old_id, .. } => locations.rename(old_id),
+                ParseError::InterpolationCycle { names } => locations.variable(&names[0]),
+                _ => error.node_id().and_then(|id| locations.node(id)),
+            };
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                message: error.to_string(),
+                line: position.map(|(line, _)| line),
+                column: position.map(|(_, column)| column),
+            });
+        }
+    }
+
+    let analysis = Analysis::of(parts.iter().filter_map(|part| match part {
+        ProgramPart::NodeDefinition { id, node } => Some((id.as_str(), node)),
+        _ => None,
+    }));
+    // Without a `START` node, which is already reported as an error, nothing can be reached.
+    if analysis.node("START").is_some() {
+        // A story without any endings is warned about once, rather than at every node.
+        let has_ending = analysis.nodes.iter().any(|node| node.can_end);
+        if !has_ending {
+            let position = locations.node("START");
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                message: "The story has no endings, so a player can never finish it.".to_string(),
+                line: position.map(|(line, _)| line),
+                column: position.map(|(_, column)| column),
+            });
+        }
+        for node in &analysis.nodes {
+            let message = if !node.reachable {
+                format!(
+                    "The node with id '{}' can never be reached from the 'START' node.",
+                    node.id
+                )
+            } else if has_ending && !node.can_end {
+                format!(
+                    "No chain of choices leads from the node with id '{}' to an ending, so a player who reaches it can never finish the story.",
+                    node.id
+                )
+            } else {
+                continue;
+            };
+            let position = locations.node(&node.id);
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                message,
+                line: position.map(|(line, _)| line),
+                column: position.map(|(_, column)| column),
+            });
+        }
+    }
+
+    diagnostics.sort_by_key(|d| (d.line, d.column, d.severity));
+    diagnostics
+}
+
+/// Finds where things are defined in a story's source.
+pub(crate) struct Locations<'a> {
+    source: &'a str,
+    /// The line and column of the first definition of each kind with each name.
+    definitions: HashMap<(&'static str, &'a str), (usize, usize)>,
+}
+
+impl<'a> Locations<'a> {
+    /// The keywords that start the definitions that can be found.
+    const KEYWORDS: [&'static str; 3] = ["=", "SET", "RENAMED"];
+
+    pub(crate) fn new(source: &'a str) -> Self {
+        let mut definitions = HashMap::new();
+        for (number, line) in source.lines().enumerate() {
+            let trimmed = line.trim_start();
+            for keyword in Self::KEYWORDS {
+                if let Some(rest) = trimmed.strip_prefix(keyword) {
+                    let rest = rest.trim_start();
+                    let end = rest
+                        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    let column = line[..line.len() - trimmed.len()].chars().count() + 1;
+                    definitions
+                        .entry((keyword, &rest[..end]))
+                        .or_insert((number + 1, column));
+                }
+            }
+        }
+
+        Locations {
+            source,
+            definitions,
+        }
+    }
+
+    /// The line and column of a byte offset into the source.
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let before = &self.source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (line, before[line_start..].chars().count() + 1)
+    }
+
+    /// Where the first definition starting with `keyword` and then `name` is.
+    fn find(&self, keyword: &'static str, name: &str) -> Option<(usize, usize)> {
+        self.definitions.get(&(keyword, name)).copied()
+    }
+
+    pub(crate) fn node(&self, id: &str) -> Option<(usize, usize)> {
+        self.find("=", id)
+    }
+
+    pub(crate) fn variable(&self, name: &str) -> Option<(usize, usize)> {
+        self.find("SET", name)
+    }
+
+    fn rename(&self, old_id: &str) -> Option<(usize, usize)> {
+        self.find("RENAMED", old_id)
+    }
+}
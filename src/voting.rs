@@ -0,0 +1,358 @@
+//! Shared sessions, where several players vote on each choice.
+
+use crate::{
+    ApiError, AppState, ErrorResponse, SessionToken, api_error, check_session_token, choice_status,
+    get_session, read_session,
+};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use cyoa::{CurrentNodeView, Engine, Session, VoteError};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Serialize, ToSchema)]
+pub struct JoinResponse {
+    /// Needed to vote, so it should be kept as secret as the session token.
+    participant_id: String,
+    /// Whether this player is the session's host, who can end a vote early.
+    host: bool,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct VoteRequest {
+    participant_id: String,
+    /// The id of the choice to vote for
+    choice: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ResolveRequest {
+    participant_id: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct VoteCount {
+    choice: String,
+    votes: usize,
+}
+
+/// Where the vote on the current choice stands.
+#[derive(Serialize, ToSchema)]
+pub struct VoteStatus {
+    participants: usize,
+    votes: Vec<VoteCount>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/join",
+    tag = "voting",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+    ),
+    responses(
+        (status = 200, body = JoinResponse),
+        (status = 401, description = "The session isn't shared yet and no session token was given", body = ErrorResponse),
+        (status = 403, description = "Wrong session token", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+pub async fn join_session(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    token: SessionToken,
+) -> Result<Json<JoinResponse>, ApiError> {
+    let (story, mut session) = get_session(&state, &session_id).await?;
+    // Only the session's owner can share it. Once it's shared, anyone who can read it can join.
+    if !session.is_shared() || state.private_sessions {
+        check_session_token(&session, &token)?;
+    }
+    let participant_id = Uuid::new_v4().to_string();
+    story.join_session(&mut session, participant_id.clone());
+    let host = session.participants().len() == 1;
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+    info!(%session_id, %participant_id, "Player joined session");
+
+    Ok(Json(JoinResponse {
+        participant_id,
+        host,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stories/{story_id}/session/{session_id}/votes",
+    tag = "voting",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+    ),
+    responses(
+        (status = 200, body = VoteStatus),
+        (status = 404, description = "No such session", body = ErrorResponse),
+    ),
+)]
+pub async fn get_votes(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+) -> Result<Json<VoteStatus>, ApiError> {
+    let (story, session) = read_session(&state, &session_id).await?;
+    Ok(Json(vote_status(&story, &session)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/vote",
+    tag = "voting",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+    ),
+    request_body = VoteRequest,
+    responses(
+        (status = 200, description = "The vote was counted. If it was the last vote needed, the choice has already been taken.", body = VoteStatus),
+        (status = 400, description = "The choice isn't available", body = ErrorResponse),
+        (status = 403, description = "The player hasn't joined the session, or the choice's requirement isn't met", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+        (status = 409, description = "The story has already ended", body = ErrorResponse),
+        (status = 500, description = "The vote was counted, but the choice's command failed", body = ErrorResponse),
+    ),
+)]
+pub async fn cast_vote(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Json(request): Json<VoteRequest>,
+) -> Result<Json<VoteStatus>, ApiError> {
+    let (story, mut session) = get_session(&state, &session_id).await?;
+    check_participant(&session, &request.participant_id)?;
+    let first_vote = story.vote_tally(&session).is_empty();
+    story
+        .cast_vote(&mut session, &request.participant_id, request.choice)
+        .map_err(vote_error)?;
+
+    if story.everyone_has_voted(&session) {
+        let from_node_id = session.current_node_id().to_string();
+        match story.resolve_vote(&mut session) {
+            Ok(choice) => {
+                state.record_choice(&session_id, &from_node_id, &session);
+                info!(%session_id, %choice, "Session voted");
+            }
+            Err(e) => {
+                // The vote still counts, so the choice is tried again when the next vote comes in.
+                state
+                    .save_changed_session(&session_id, &mut session)
+                    .await?;
+                return Err(vote_error(e));
+            }
+        }
+    } else if first_vote && let Some(window) = state.vote_window {
+        // Close the vote once the window is over, unless the story has moved on by then.
+        let round = session.events_recorded();
+        let state = state.clone();
+        let session_id = session_id.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            let Ok((story, mut session)) = get_session(&state, &session_id).await else {
+                return;
+            };
+            let from_node_id = session.current_node_id().to_string();
+            if session.events_recorded() == round
+                && let Ok(choice) = story.resolve_vote(&mut session)
+            {
+                info!(%session_id, %choice, "Session voted");
+                state.record_choice(&session_id, &from_node_id, &session);
+                // Nobody is waiting for the result. A store that is down has been logged, and a
+                // conflict means another server has moved the session on already.
+                let _ = state.save_changed_session(&session_id, &mut session).await;
+            }
+        });
+    }
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+
+    Ok(Json(vote_status(&story, &session)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/stories/{story_id}/session/{session_id}/vote/resolve",
+    tag = "voting",
+    params(
+        ("story_id" = String, Path, description = "The story's id"),
+        ("session_id" = String, Path),
+    ),
+    request_body = ResolveRequest,
+    responses(
+        (status = 200, description = "The choice with the most votes was taken", body = CurrentNodeView),
+        (status = 400, description = "Nobody has voted yet", body = ErrorResponse),
+        (status = 403, description = "Only the host can end a vote early", body = ErrorResponse),
+        (status = 404, description = "No such session", body = ErrorResponse),
+        (status = 409, description = "The story has already ended", body = ErrorResponse),
+        (status = 500, description = "The choice's command failed", body = ErrorResponse),
+    ),
+)]
+pub async fn resolve_vote(
+    State(state): State<AppState>,
+    Path(session_id): Path<String>,
+    Json(request): Json<ResolveRequest>,
+) -> Result<Json<CurrentNodeView>, ApiError> {
+    let (story, mut session) = get_session(&state, &session_id).await?;
+    check_participant(&session, &request.participant_id)?;
+    if session.participants().first() != Some(&request.participant_id) {
+        return Err(api_error(
+            StatusCode::FORBIDDEN,
+            "only the host can end a vote early",
+        ));
+    }
+    let from_node_id = session.current_node_id().to_string();
+    let choice = story.resolve_vote(&mut session).map_err(vote_error)?;
+    info!(%session_id, %choice, "Session voted");
+    state.record_choice(&session_id, &from_node_id, &session);
+    state
+        .save_changed_session(&session_id, &mut session)
+        .await?;
+
+    Ok(Json(story.get_current_node_view(&session)))
+}
+
+/// Participant ids are only handed to the players who joined, so they're what lets a player
+/// vote.
+fn check_participant(session: &Session, participant_id: &str) -> Result<(), ApiError> {
+    if session.participants().iter().any(|p| p == participant_id) {
+        Ok(())
+    } else {
+        Err(api_error(
+            StatusCode::FORBIDDEN,
+            "not a player in this session",
+        ))
+    }
+}
+
+fn vote_error(error: VoteError) -> ApiError {
+    let status = match &error {
+        VoteError::UnknownParticipant { .. } => StatusCode::FORBIDDEN,
+        VoteError::RequirementNotMet { .. } => StatusCode::FORBIDDEN,
+        VoteError::ChoiceFailed(result) => choice_status(result),
+        _ => StatusCode::BAD_REQUEST,
+    };
+    api_error(status, error)
+}
+
+pub fn vote_status(story: &Engine, session: &Session) -> VoteStatus {
+    VoteStatus {
+        participants: session.participants().len(),
+        votes: story
+            .vote_tally(session)
+            .into_iter()
+            .map(|(choice, votes)| VoteCount { choice, votes })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{new_session, session, state};
+    use std::sync::Arc;
+
+    const FORK: &str = r#"
+= START
+    "Left or right?"
+    "Go left." -> left
+    "Go right." -> right
+
+= left
+    "The left."
+
+= right
+    "The right."
+"#;
+
+    async fn join(
+        state: &AppState,
+        session_id: &str,
+        token: SessionToken,
+    ) -> Result<Json<JoinResponse>, ApiError> {
+        join_session(
+            State(Arc::clone(state)),
+            Path(session_id.to_string()),
+            token,
+        )
+        .await
+    }
+
+    async fn vote(
+        state: &AppState,
+        session_id: &str,
+        participant_id: &str,
+        choice: &str,
+    ) -> Result<Json<VoteStatus>, ApiError> {
+        cast_vote(
+            State(Arc::clone(state)),
+            Path(session_id.to_string()),
+            Json(VoteRequest {
+                participant_id: participant_id.to_string(),
+                choice: choice.to_string(),
+            }),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn sharing_a_session_needs_its_token() {
+        let state = state(FORK);
+        let (session_id, token) = new_session(&state).await;
+
+        let Err((status, _)) = join(&state, &session_id, SessionToken(None)).await else {
+            panic!("A session shouldn't be shared without its token");
+        };
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let Err((status, _)) = join(&state, &session_id, SessionToken(Some("wrong".into()))).await
+        else {
+            panic!("A session shouldn't be shared with the wrong token");
+        };
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let Ok(Json(owner)) = join(&state, &session_id, token).await else {
+            panic!("The owner couldn't share the session");
+        };
+        assert!(owner.host);
+        // Once it's shared, anyone can join.
+        let Ok(Json(guest)) = join(&state, &session_id, SessionToken(None)).await else {
+            panic!("A guest couldn't join the shared session");
+        };
+        assert!(!guest.host);
+    }
+
+    #[tokio::test]
+    async fn only_players_can_vote() {
+        let state = state(FORK);
+        let (session_id, token) = new_session(&state).await;
+        let Ok(Json(host)) = join(&state, &session_id, token).await else {
+            panic!("The owner couldn't share the session");
+        };
+
+        let Err((status, _)) = vote(&state, &session_id, "stranger", "left").await else {
+            panic!("Someone who hasn't joined shouldn't be able to vote");
+        };
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            session(&state, &session_id).await.current_node_id(),
+            "START"
+        );
+
+        let Ok(_) = vote(&state, &session_id, &host.participant_id, "left").await else {
+            panic!("The host couldn't vote");
+        };
+        assert_eq!(session(&state, &session_id).await.current_node_id(), "left");
+    }
+}
@@ -0,0 +1,217 @@
+use crate::{StorySettings, build_story, read_story_parts};
+use cyoa::{Engine, engine::parser::ProgramPart};
+use std::{
+    collections::{BTreeSet, HashSet},
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    process::ExitCode,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct WalkArgs {
+    /// The story file to play through
+    source: PathBuf,
+    /// How many playthroughs to simulate
+    #[arg(long, default_value_t = 1000)]
+    runs: usize,
+    /// Seed for choosing at random, to repeat a previous walk. Defaults to the current time.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Give up on a playthrough that hasn't ended after this many choices
+    #[arg(long, default_value_t = 1000)]
+    max_steps: usize,
+}
+
+/// A small, fast pseudo-random number generator (SplitMix64). Walks only need to be
+/// repeatable from a seed, not unpredictable.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    /// A seed that differs from run to run.
+    pub fn seed_from_time() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// How a single playthrough went.
+enum Outcome {
+    /// The story ended at the given node after this many choices.
+    Ended { node_id: String, steps: usize },
+    /// The story was still going after the maximum number of choices.
+    TimedOut,
+    /// The engine panicked.
+    Panicked { message: String, path: Vec<String> },
+}
+
+/// Play a story many times, taking random choices, to find problems that only show up
+/// at runtime.
+pub fn run(args: WalkArgs) -> ExitCode {
+    let settings = StorySettings {
+        history_depth: 0,
+        max_checkpoints: 0,
+        max_transcript: usize::MAX,
+        #[cfg(feature = "plugins")]
+        plugins: None,
+    };
+    let (story, parts) = match build_story(&args.source, settings)
+        .and_then(|story| read_story_parts(&args.source).map(|parts| (story, parts)))
+    {
+        Ok(story) => story,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let all_nodes: BTreeSet<&str> = parts
+        .iter()
+        .filter_map(|part| match part {
+            ProgramPart::NodeDefinition { id, .. } => Some(id.as_str()),
+            _ => None,
+        })
+        .collect();
+    let endings: BTreeSet<&str> = parts
+        .iter()
+        .filter_map(|part| match part {
+            ProgramPart::NodeDefinition { id, node } if node.choices.is_empty() => {
+                Some(id.as_str())
+            }
+            _ => None,
+        })
+        .collect();
+
+    let seed = args.seed.unwrap_or_else(Rng::seed_from_time);
+    let mut rng = Rng::new(seed);
+
+    // Panics are reported in the summary instead of being printed as they happen.
+    panic::set_hook(Box::new(|_| {}));
+    let mut visited: HashSet<String> = HashSet::new();
+    let mut endings_reached: HashSet<String> = HashSet::new();
+    let mut stuck_at: BTreeSet<String> = BTreeSet::new();
+    let mut total_steps = 0;
+    let mut ended_runs = 0;
+    let mut timed_out_runs = 0;
+    let mut panics = Vec::new();
+    for run in 0..args.runs {
+        match walk(&story, &mut rng, args.max_steps, &mut visited) {
+            Outcome::Ended { node_id, steps } => {
+                ended_runs += 1;
+                total_steps += steps;
+                if endings.contains(node_id.as_str()) {
+                    endings_reached.insert(node_id);
+                } else {
+                    // Every choice at this node has a requirement that wasn't met.
+                    stuck_at.insert(node_id);
+                }
+            }
+            Outcome::TimedOut => timed_out_runs += 1,
+            Outcome::Panicked { message, path } => panics.push((run, message, path)),
+        }
+    }
+    let _ = panic::take_hook();
+
+    println!("Seed: {seed}");
+    println!(
+        "Runs: {} ({ended_runs} ended, {timed_out_runs} still going after {} choices, {} panicked)",
+        args.runs,
+        args.max_steps,
+        panics.len()
+    );
+    if ended_runs > 0 {
+        println!(
+            "Average choices per ending: {:.1}",
+            total_steps as f64 / ended_runs as f64
+        );
+    }
+
+    print_list(
+        "Endings never reached",
+        endings
+            .iter()
+            .filter(|id| !endings_reached.contains(**id))
+            .copied(),
+    );
+    print_list(
+        "Nodes never visited",
+        all_nodes
+            .iter()
+            .filter(|id| !visited.contains(**id))
+            .copied(),
+    );
+    print_list(
+        "Dead ends with no available choices",
+        stuck_at.iter().map(String::as_str),
+    );
+
+    for (run, message, path) in &panics {
+        println!("\nRun {} panicked: {message}", run + 1);
+        println!("  Path: {}", path.join(" -> "));
+    }
+
+    if panics.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn print_list<'a>(title: &str, items: impl Iterator<Item = &'a str>) {
+    let items: Vec<&str> = items.collect();
+    if !items.is_empty() {
+        println!("\n{title}:");
+        for item in items {
+            println!("  {item}");
+        }
+    }
+}
+
+/// Play through the story once, taking a random available choice at every node.
+fn walk(story: &Engine, rng: &mut Rng, max_steps: usize, visited: &mut HashSet<String>) -> Outcome {
+    let mut path = Vec::new();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut session = story.new_session();
+        for steps in 0..max_steps {
+            let node_id = session.current_node_id().to_string();
+            visited.insert(node_id.clone());
+            path.push(node_id.clone());
+
+            let view = story.get_current_node_view(&session);
+            if view.game_over {
+                return Outcome::Ended { node_id, steps };
+            }
+            let choice = &view.choices[rng.below(view.choices.len())];
+            story.choose_option(&mut session, choice.id.clone());
+        }
+
+        Outcome::TimedOut
+    }));
+
+    result.unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Outcome::Panicked { message, path }
+    })
+}
@@ -0,0 +1,86 @@
+//! JavaScript bindings for running stories entirely in the browser.
+//!
+//! ```js
+//! import init, { Story } from "./pkg/cyoa.js";
+//!
+//! await init();
+//! const story = new Story(source);
+//! const session = story.new_session();
+//! const view = JSON.parse(story.current_view_json(session));
+//! story.choose(session, view.choices[0].id);
+//! ```
+
+use crate::{ChoiceResult, Engine, Session, SessionSnapshot, tokenize};
+use wasm_bindgen::prelude::*;
+
+/// A story loaded from the source of a `.cyoa` file.
+#[wasm_bindgen]
+pub struct Story(Engine);
+
+/// One player's progress through a story.
+#[wasm_bindgen(js_name = Session)]
+pub struct StorySession(Session);
+
+#[wasm_bindgen]
+impl Story {
+    /// Load a story, throwing an error listing every problem with it if it is invalid.
+    #[wasm_bindgen(constructor)]
+    pub fn new(source: &str) -> Result<Story, JsError> {
+        match Engine::from_program(source) {
+            Ok(engine) => Ok(Story(engine)),
+            Err(errors) => {
+                let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                Err(JsError::new(&messages.join("\n")))
+            }
+        }
+    }
+
+    /// Set how many choices each session can undo with `go_back`. Undo is off by default.
+    pub fn set_history_depth(&mut self, history_depth: usize) {
+        self.0.set_history_depth(history_depth);
+    }
+
+    /// Start a new session at the beginning of the story.
+    pub fn new_session(&self) -> StorySession {
+        StorySession(self.0.new_session())
+    }
+
+    /// The session's current node, as JSON in the same format as the server's `/current` endpoint.
+    pub fn current_view_json(&self, session: &StorySession) -> String {
+        serde_json::to_string(&self.0.get_current_node_view(&session.0))
+            .expect("Failed to serialize view")
+    }
+
+    /// Take the choice with the given id, throwing an error if it isn't available.
+    pub fn choose(&self, session: &mut StorySession, choice_id: String) -> Result<(), JsError> {
+        match self.0.choose_option(&mut session.0, choice_id) {
+            ChoiceResult::Success => Ok(()),
+            result => Err(JsError::new(&result.to_string())),
+        }
+    }
+
+    /// Undo the most recent choice. Returns whether there was a choice to undo.
+    pub fn go_back(&self, session: &mut StorySession) -> bool {
+        self.0.go_back(&mut session.0)
+    }
+
+    /// Save the session's progress as JSON, e.g. to keep in `localStorage`.
+    pub fn export_session_json(&self, session: &StorySession) -> String {
+        serde_json::to_string(&self.0.snapshot_session(&session.0))
+            .expect("Failed to serialize session")
+    }
+
+    /// Resume a session saved with `export_session_json`.
+    pub fn import_session_json(&self, json: &str) -> Result<StorySession, JsError> {
+        let snapshot: SessionSnapshot = serde_json::from_str(json)?;
+        let session = self.0.restore_session(snapshot)?;
+        Ok(StorySession(session))
+    }
+}
+
+/// The source of a story split into spans for syntax highlighting, as a JSON list of
+/// `{"kind", "start", "end"}` objects. Offsets are in bytes of the source's UTF-8 encoding.
+#[wasm_bindgen]
+pub fn tokens_json(source: &str) -> String {
+    serde_json::to_string(&tokenize(source)).expect("Failed to serialize tokens")
+}
@@ -0,0 +1,162 @@
+//! JSON posted to the URLs given by `--on-session-created`, `--on-game-over` and
+//! `--on-achievement`, so bots and analytics pipelines can follow playthroughs as they happen.
+
+use cyoa::{Engine, HistoryEvent, Session};
+use reqwest::{Client, Url};
+use serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// How long a webhook has to answer before it is given up on.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    SessionCreated,
+    GameOver,
+    Achievement,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    story_id: &'a str,
+    session_id: &'a str,
+    /// The node the session is at, which for `game_over` is the ending it reached.
+    node_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    achievement: Option<&'a str>,
+    stats: Stats,
+    /// When the event happened, in milliseconds since the Unix epoch.
+    timestamp: u64,
+}
+
+#[derive(Serialize)]
+struct Stats {
+    /// How many choices have been taken, including ones later undone.
+    turns: usize,
+    /// How many different nodes the session has arrived at.
+    nodes_visited: usize,
+    /// How long ago the session was started, in milliseconds, if known.
+    played_ms: Option<u64>,
+}
+
+/// The achievement for arriving at a node, named by a `META achievement_<node_id> "..."` line
+/// in the story.
+pub fn achievement<'a>(story: &'a Engine, node_id: &str) -> Option<&'a str> {
+    story.metadata(&format!("achievement_{node_id}"))
+}
+
+/// Whether the session has just arrived at its current node for the first time.
+pub fn first_visit(story: &Engine, session: &Session) -> bool {
+    story
+        .history(session)
+        .iter()
+        .filter(|event| {
+            matches!(event, HistoryEvent::NodeVisited { node_id, .. }
+                if node_id == session.current_node_id())
+        })
+        .count()
+        <= 1
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Where to send each event. Events without a URL aren't sent.
+pub struct Webhooks {
+    client: Client,
+    on_session_created: Option<Url>,
+    on_game_over: Option<Url>,
+    on_achievement: Option<Url>,
+}
+
+impl Webhooks {
+    pub fn new(
+        on_session_created: Option<Url>,
+        on_game_over: Option<Url>,
+        on_achievement: Option<Url>,
+    ) -> Result<Self, String> {
+        crate::tls::install_crypto_provider();
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(|e| format!("Failed to set up webhooks: {e}"))?;
+
+        Ok(Webhooks {
+            client,
+            on_session_created,
+            on_game_over,
+            on_achievement,
+        })
+    }
+
+    fn url(&self, event: WebhookEvent) -> Option<&Url> {
+        match event {
+            WebhookEvent::SessionCreated => self.on_session_created.as_ref(),
+            WebhookEvent::GameOver => self.on_game_over.as_ref(),
+            WebhookEvent::Achievement => self.on_achievement.as_ref(),
+        }
+    }
+
+    /// Post the event in the background, if it has a URL. A webhook that fails is logged and
+    /// not retried, so a slow or broken receiver never holds up players.
+    pub fn send(
+        &self,
+        event: WebhookEvent,
+        story_id: &str,
+        session_id: &str,
+        story: &Engine,
+        session: &Session,
+        achievement: Option<&str>,
+    ) {
+        let Some(url) = self.url(event) else {
+            return;
+        };
+
+        let mut nodes_visited: Vec<&str> = story
+            .history(session)
+            .iter()
+            .filter_map(|event| match event {
+                HistoryEvent::NodeVisited { node_id, .. } => Some(node_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        nodes_visited.sort_unstable();
+        nodes_visited.dedup();
+        let now = SystemTime::now();
+        let payload = Payload {
+            event,
+            story_id,
+            session_id,
+            node_id: session.current_node_id(),
+            achievement,
+            stats: Stats {
+                turns: session.turns(),
+                nodes_visited: nodes_visited.len(),
+                played_ms: session.created_at().map(|created_at| {
+                    now.duration_since(created_at)
+                        .unwrap_or_default()
+                        .as_millis() as u64
+                }),
+            },
+            timestamp: unix_millis(now),
+        };
+
+        let request = self.client.post(url.clone()).json(&payload);
+        let session_id = session_id.to_string();
+        tokio::spawn(async move {
+            let result = request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                warn!(%session_id, ?event, "Webhook failed: {e}");
+            }
+        });
+    }
+}